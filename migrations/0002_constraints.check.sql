@@ -0,0 +1,50 @@
+SELECT 'sessions whose user does not exist' AS problem, count(*) AS row_count
+FROM sessions WHERE user_id NOT IN (SELECT id FROM users)
+UNION ALL
+SELECT 'API tokens whose user does not exist', count(*)
+FROM api_tokens WHERE user_id NOT IN (SELECT id FROM users)
+UNION ALL
+SELECT 'tweets whose author does not exist', count(*)
+FROM tweets WHERE user_id NOT IN (SELECT id FROM users)
+UNION ALL
+SELECT 'replies to tweets that do not exist', count(*)
+FROM tweets WHERE responding_to IS NOT NULL AND responding_to NOT IN (SELECT id FROM tweets)
+UNION ALL
+SELECT 'tweets without content', count(*)
+FROM tweets WHERE content IS NULL
+UNION ALL
+SELECT 'tweets with a negative counter', count(*)
+FROM tweets WHERE like_count < 0 OR retweet_count < 0 OR reply_count < 0
+UNION ALL
+SELECT 'likes of tweets that do not exist', count(*)
+FROM likes WHERE tweet_id NOT IN (SELECT id FROM tweets)
+UNION ALL
+SELECT 'likes by users that do not exist', count(*)
+FROM likes WHERE user_id NOT IN (SELECT id FROM users)
+UNION ALL
+SELECT 'retweets of tweets that do not exist', count(*)
+FROM retweets WHERE tweet_id NOT IN (SELECT id FROM tweets)
+UNION ALL
+SELECT 'retweets by users that do not exist', count(*)
+FROM retweets WHERE user_id NOT IN (SELECT id FROM users)
+UNION ALL
+SELECT 'follows from users that do not exist', count(*)
+FROM follows WHERE follower_id NOT IN (SELECT id FROM users)
+UNION ALL
+SELECT 'follows of users that do not exist', count(*)
+FROM follows WHERE following_id NOT IN (SELECT id FROM users)
+UNION ALL
+SELECT 'users following themselves', count(*)
+FROM follows WHERE follower_id = following_id
+UNION ALL
+SELECT 'webhooks whose user does not exist', count(*)
+FROM webhooks WHERE user_id NOT IN (SELECT id FROM users)
+UNION ALL
+SELECT 'webhook deliveries whose webhook does not exist', count(*)
+FROM webhook_deliveries WHERE webhook_id NOT IN (SELECT id FROM webhooks)
+UNION ALL
+SELECT 'webmentions of tweets that do not exist', count(*)
+FROM webmentions WHERE tweet_id NOT IN (SELECT id FROM tweets)
+UNION ALL
+SELECT 'webmentions with an unknown status', count(*)
+FROM webmentions WHERE status NOT IN ('pending', 'verified', 'rejected');
@@ -0,0 +1,45 @@
+DROP INDEX IF EXISTS retweets_user_id_idx;
+DROP INDEX IF EXISTS likes_user_id_idx;
+DROP INDEX IF EXISTS follows_following_id_idx;
+DROP INDEX IF EXISTS sessions_token_idx;
+DROP INDEX IF EXISTS tweets_responding_to_idx;
+DROP INDEX IF EXISTS tweets_user_id_created_at_idx;
+DROP INDEX IF EXISTS tweets_created_at_idx;
+
+ALTER TABLE webmentions
+    DROP CONSTRAINT IF EXISTS webmentions_status_check,
+    DROP CONSTRAINT IF EXISTS webmentions_tweet_id_fkey;
+
+ALTER TABLE webhook_deliveries
+    DROP CONSTRAINT IF EXISTS webhook_deliveries_webhook_id_fkey;
+
+ALTER TABLE webhooks
+    DROP CONSTRAINT IF EXISTS webhooks_user_id_fkey;
+
+ALTER TABLE follows
+    DROP CONSTRAINT IF EXISTS follows_not_self_check,
+    DROP CONSTRAINT IF EXISTS follows_following_id_fkey,
+    DROP CONSTRAINT IF EXISTS follows_follower_id_fkey;
+
+ALTER TABLE retweets
+    DROP CONSTRAINT IF EXISTS retweets_user_id_fkey,
+    DROP CONSTRAINT IF EXISTS retweets_tweet_id_fkey;
+
+ALTER TABLE likes
+    DROP CONSTRAINT IF EXISTS likes_user_id_fkey,
+    DROP CONSTRAINT IF EXISTS likes_tweet_id_fkey;
+
+ALTER TABLE api_tokens
+    DROP CONSTRAINT IF EXISTS api_tokens_user_id_fkey;
+
+ALTER TABLE sessions
+    DROP CONSTRAINT IF EXISTS sessions_user_id_fkey;
+
+ALTER TABLE tweets
+    DROP CONSTRAINT IF EXISTS tweets_counts_check,
+    DROP CONSTRAINT IF EXISTS tweets_responding_to_fkey,
+    DROP CONSTRAINT IF EXISTS tweets_user_id_fkey,
+    ALTER COLUMN reply_count DROP NOT NULL,
+    ALTER COLUMN retweet_count DROP NOT NULL,
+    ALTER COLUMN like_count DROP NOT NULL,
+    ALTER COLUMN content DROP NOT NULL;
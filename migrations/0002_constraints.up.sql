@@ -0,0 +1,65 @@
+UPDATE tweets SET like_count = 0 WHERE like_count IS NULL;
+UPDATE tweets SET retweet_count = 0 WHERE retweet_count IS NULL;
+UPDATE tweets SET reply_count = 0 WHERE reply_count IS NULL;
+
+ALTER TABLE tweets
+    ALTER COLUMN content SET NOT NULL,
+    ALTER COLUMN like_count SET NOT NULL,
+    ALTER COLUMN retweet_count SET NOT NULL,
+    ALTER COLUMN reply_count SET NOT NULL,
+    ADD CONSTRAINT tweets_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    ADD CONSTRAINT tweets_responding_to_fkey
+        FOREIGN KEY (responding_to) REFERENCES tweets (id) ON DELETE SET NULL,
+    ADD CONSTRAINT tweets_counts_check
+        CHECK (like_count >= 0 AND retweet_count >= 0 AND reply_count >= 0);
+
+ALTER TABLE sessions
+    ADD CONSTRAINT sessions_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+
+ALTER TABLE api_tokens
+    ADD CONSTRAINT api_tokens_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+
+ALTER TABLE likes
+    ADD CONSTRAINT likes_tweet_id_fkey
+        FOREIGN KEY (tweet_id) REFERENCES tweets (id) ON DELETE CASCADE,
+    ADD CONSTRAINT likes_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+
+ALTER TABLE retweets
+    ADD CONSTRAINT retweets_tweet_id_fkey
+        FOREIGN KEY (tweet_id) REFERENCES tweets (id) ON DELETE CASCADE,
+    ADD CONSTRAINT retweets_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+
+ALTER TABLE follows
+    ADD CONSTRAINT follows_follower_id_fkey
+        FOREIGN KEY (follower_id) REFERENCES users (id) ON DELETE CASCADE,
+    ADD CONSTRAINT follows_following_id_fkey
+        FOREIGN KEY (following_id) REFERENCES users (id) ON DELETE CASCADE,
+    ADD CONSTRAINT follows_not_self_check
+        CHECK (follower_id <> following_id);
+
+ALTER TABLE webhooks
+    ADD CONSTRAINT webhooks_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+
+ALTER TABLE webhook_deliveries
+    ADD CONSTRAINT webhook_deliveries_webhook_id_fkey
+        FOREIGN KEY (webhook_id) REFERENCES webhooks (id) ON DELETE CASCADE;
+
+ALTER TABLE webmentions
+    ADD CONSTRAINT webmentions_tweet_id_fkey
+        FOREIGN KEY (tweet_id) REFERENCES tweets (id) ON DELETE CASCADE,
+    ADD CONSTRAINT webmentions_status_check
+        CHECK (status IN ('pending', 'verified', 'rejected'));
+
+CREATE INDEX tweets_created_at_idx ON tweets (created_at DESC);
+CREATE INDEX tweets_user_id_created_at_idx ON tweets (user_id, created_at DESC);
+CREATE INDEX tweets_responding_to_idx ON tweets (responding_to);
+CREATE INDEX sessions_token_idx ON sessions (token);
+CREATE INDEX follows_following_id_idx ON follows (following_id);
+CREATE INDEX likes_user_id_idx ON likes (user_id);
+CREATE INDEX retweets_user_id_idx ON retweets (user_id);
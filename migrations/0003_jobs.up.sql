@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS jobs (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    kind VARCHAR(64) NOT NULL,
+    payload TEXT NOT NULL,
+    -- At most one job of a kind with the same key is queued or running.
+    unique_key VARCHAR(256),
+    status VARCHAR(16) NOT NULL DEFAULT 'queued',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    max_attempts INTEGER NOT NULL,
+    run_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    locked_at TIMESTAMPTZ,
+    last_error TEXT,
+    finished_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    CONSTRAINT jobs_status_check
+        CHECK (status IN ('queued', 'running', 'done', 'dead')),
+    CONSTRAINT jobs_attempts_check
+        CHECK (attempts >= 0 AND max_attempts >= 1)
+);
+
+CREATE UNIQUE INDEX jobs_unique_key_idx ON jobs (kind, unique_key)
+    WHERE unique_key IS NOT NULL AND status IN ('queued', 'running');
+CREATE INDEX jobs_queued_run_at_idx ON jobs (run_at) WHERE status = 'queued';
+CREATE INDEX jobs_running_locked_at_idx ON jobs (locked_at) WHERE status = 'running';
@@ -0,0 +1,4 @@
+ALTER TABLE sessions
+    DROP COLUMN IF EXISTS two_factor_verified,
+    DROP COLUMN IF EXISTS scopes,
+    DROP COLUMN IF EXISTS expires_at;
@@ -0,0 +1,6 @@
+ALTER TABLE sessions
+    -- Never, when NULL.
+    ADD COLUMN expires_at TIMESTAMPTZ,
+    -- Space separated, as in OAuth.
+    ADD COLUMN scopes VARCHAR(256) NOT NULL DEFAULT 'read write',
+    ADD COLUMN two_factor_verified BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    -- The method and path, e.g. POST /tweets.
+    endpoint VARCHAR(256) NOT NULL,
+    key VARCHAR(255) NOT NULL,
+    -- Of the request body, so that a key reused for another request is caught.
+    request_hash VARCHAR(64) NOT NULL,
+    -- The response, once there is one. NULL while the first request is still
+    -- being answered.
+    status INTEGER,
+    content_type TEXT,
+    location TEXT,
+    body BYTEA,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (user_id, endpoint, key)
+);
+
+CREATE INDEX idempotency_keys_expires_at_idx ON idempotency_keys (expires_at);
@@ -0,0 +1,3 @@
+-- The tokens can't be recovered from their hashes, so they are revoked.
+DELETE FROM api_tokens;
+ALTER TABLE api_tokens RENAME COLUMN token_hash TO token;
@@ -0,0 +1,3 @@
+-- Only the hex SHA-256 of each token is kept from here on.
+ALTER TABLE api_tokens RENAME COLUMN token TO token_hash;
+UPDATE api_tokens SET token_hash = encode(sha256(convert_to(token_hash, 'UTF8')), 'hex');
@@ -0,0 +1,4 @@
+-- Retries are queued as jobs, so that they survive a restart; this is when
+-- the next one is due, for showing on the webhooks page.
+ALTER TABLE webhook_deliveries
+    ADD COLUMN next_attempt_at TIMESTAMPTZ;
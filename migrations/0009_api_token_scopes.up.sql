@@ -0,0 +1,3 @@
+ALTER TABLE api_tokens
+    -- Space separated, as in OAuth. Tokens from before could do everything.
+    ADD COLUMN scopes VARCHAR(256) NOT NULL DEFAULT 'create';
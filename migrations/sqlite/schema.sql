@@ -0,0 +1,61 @@
+-- The tables behind the stores, for the `sqlite` feature. Ids and timestamps
+-- come from the app, so there are no defaults for them here. Timestamps are
+-- RFC 3339 text with a fixed number of digits, so that they sort as text.
+
+CREATE TABLE IF NOT EXISTS users (
+    id BLOB PRIMARY KEY,
+    password TEXT NOT NULL,
+    username TEXT NOT NULL UNIQUE,
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id BLOB PRIMARY KEY,
+    token TEXT NOT NULL,
+    user_id BLOB NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at TEXT NOT NULL,
+    expires_at TEXT,
+    scopes TEXT NOT NULL DEFAULT 'read write',
+    two_factor_verified INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS tweets (
+    id BLOB PRIMARY KEY,
+    user_id BLOB NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    responding_to BLOB REFERENCES tweets (id) ON DELETE SET NULL,
+    content TEXT NOT NULL,
+    like_count INTEGER NOT NULL DEFAULT 0,
+    retweet_count INTEGER NOT NULL DEFAULT 0,
+    reply_count INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    CHECK (like_count >= 0 AND retweet_count >= 0 AND reply_count >= 0)
+);
+
+CREATE TABLE IF NOT EXISTS likes (
+    tweet_id BLOB NOT NULL REFERENCES tweets (id) ON DELETE CASCADE,
+    user_id BLOB NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (tweet_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS follows (
+    follower_id BLOB NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    following_id BLOB NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (follower_id, following_id),
+    CHECK (follower_id <> following_id)
+);
+
+CREATE TABLE IF NOT EXISTS retweets (
+    tweet_id BLOB NOT NULL REFERENCES tweets (id) ON DELETE CASCADE,
+    user_id BLOB NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (tweet_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS tweets_created_at_idx ON tweets (created_at DESC);
+CREATE INDEX IF NOT EXISTS tweets_user_id_created_at_idx ON tweets (user_id, created_at DESC);
+CREATE INDEX IF NOT EXISTS tweets_responding_to_idx ON tweets (responding_to);
+CREATE INDEX IF NOT EXISTS sessions_token_idx ON sessions (token);
+CREATE INDEX IF NOT EXISTS follows_following_id_idx ON follows (following_id);
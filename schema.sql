@@ -1,48 +0,0 @@
-CREATE EXTENSION IF NOT EXISTS "uuid-ossp";
-
-CREATE TABLE IF NOT EXISTS users (
-    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
-    password VARCHAR(1024) NOT NULL,
-    username VARCHAR(24) NOT NULL UNIQUE,
-    created_at TIMESTAMPTZ DEFAULT now()
-);
-
-CREATE TABLE IF NOT EXISTS sessions (
-    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
-    token VARCHAR(64) NOT NULL,
-    user_id UUID NOT NULL,
-    created_at TIMESTAMPTZ DEFAULT now()
-);
-
-CREATE TABLE IF NOT EXISTS tweets (
-    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
-    user_id UUID NOT NULL,
-    responding_to UUID,
-    content VARCHAR(280),
-    like_count BIGINT DEFAULT 0,
-    retweet_count BIGINT DEFAULT 0,
-    reply_count BIGINT DEFAULT 0,
-    created_at TIMESTAMPTZ DEFAULT now(),
-    updated_at TIMESTAMPTZ DEFAULT now()
-);
-
-CREATE TABLE IF NOT EXISTS likes (
-    tweet_id UUID NOT NULL,
-    user_id UUID NOT NULL,
-    created_at TIMESTAMPTZ DEFAULT now(),
-    PRIMARY KEY(tweet_id, user_id)
-);
-
-CREATE TABLE IF NOT EXISTS follows (
-    follower_id UUID NOT NULL,
-    following_id UUID NOT NULL,
-    created_at TIMESTAMPTZ DEFAULT now(),
-    PRIMARY KEY(follower_id, following_id)
-);
-
-CREATE TABLE IF NOT EXISTS retweets (
-    tweet_id UUID NOT NULL,
-    user_id UUID NOT NULL,
-    created_at TIMESTAMPTZ DEFAULT now(),
-    PRIMARY KEY(tweet_id, user_id)
-)
@@ -1,42 +1,161 @@
+use std::sync::Arc;
+
 use log::info;
 use sqlx::{Pool, Postgres};
 use thruster::{
-    context::typed_hyper_context::TypedHyperContext, m, middleware::cookies::cookies,
-    middleware_fn, App, HyperRequest, MiddlewareNext, MiddlewareResult,
+    context::typed_hyper_context::TypedHyperContext, errors::ThrusterError, m,
+    middleware::cookies::cookies, middleware_fn, parser::middleware_traits::MiddlewareTuple, App,
+    HyperRequest, MiddlewareNext, MiddlewareResult,
 };
-use tokio::time::Instant;
 
 use crate::{
+    background::Background,
+    cache::Caches,
+    config::Config,
     controllers::{
+        feeds::{hashtag_feed, timeline_feed, user_feed},
+        health::{healthz, readyz},
+        live::{live_events, LiveFeed},
+        metrics::metrics,
+        micropub::{fetch_user_from_form_token, micropub_create, micropub_query},
         pages::{home, reply as reply_page, signin, signup, single_tweet},
+        streaming::streaming,
         tweets::{create_tweet, like_tweet, reply, retweet},
-        users::{authenticate, create_user, fetch_user_from_cookie, sign_in_user},
+        users::{
+            authenticate, create_api_token, create_user, fetch_user_from_api_token,
+            fetch_user_from_cookie, follow_user, sign_in_user, sign_out_user,
+        },
+        webhooks::{create_webhook, webhooks_page},
+        webmentions::receive_webmention,
     },
-    models::users::User,
+    counters::spawn_reconciler,
+    database::Database,
+    errors::{catch_panics, error_pages, not_found, AppError},
+    events::Events,
+    health::Health,
+    idempotency::{self, idempotent},
+    jobs,
+    metrics::Metrics,
+    models::{api_tokens::ApiToken, sessions::Session, users::User},
+    rate_limits::{rate_limit, RateLimiter},
+    stores::Stores,
+    telemetry::{trace_requests, Routes},
+    webhooks::spawn_dispatcher,
+    webmentions::{ReqwestFetcher, Webmentions},
 };
 
 pub type Ctx = TypedHyperContext<RequestConfig>;
 
 pub struct ServerConfig {
-    pub pool: Pool<Postgres>,
+    pub config: Arc<Config>,
+    pub database: Database,
+    pub stores: Stores,
+    pub events: Events,
+    pub live_feed: LiveFeed,
+    pub webmentions: Webmentions,
+    pub metrics: Arc<Metrics>,
+    pub health: Health,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub routes: Routes,
 }
 
 #[derive(Clone)]
 pub struct RequestConfig {
-    pub pool: Pool<Postgres>,
+    pub config: Arc<Config>,
+    /// For what the stores don't cover yet, such as webhooks and webmentions,
+    /// which need it to be Postgres; see `postgres`.
+    pub database: Database,
+    pub stores: Stores,
+    pub events: Events,
+    /// New tweets and counts, rendered for `/events`.
+    pub live_feed: LiveFeed,
+    pub webmentions: Webmentions,
+    pub metrics: Arc<Metrics>,
+    pub health: Health,
+    /// `None` when rate limits are off.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// The templates of the routes below, for `telemetry::route_template`.
+    pub routes: Routes,
+    /// Set by `trace_requests` from `X-Request-Id`, or generated.
+    pub request_id: String,
     pub user: Option<User>,
+    /// The session `user` signed in with, for what it allows. `None` for API
+    /// tokens.
+    pub session: Option<Session>,
+    /// The API token `user` was found by, for rate limits and its scopes.
+    pub api_token: Option<ApiToken>,
 }
 
 fn generate_context(request: HyperRequest, state: &ServerConfig, _path: &str) -> Ctx {
     Ctx::new(
         request,
         RequestConfig {
-            pool: state.pool.clone(),
+            config: state.config.clone(),
+            database: state.database.clone(),
+            stores: state.stores.clone(),
+            events: state.events.clone(),
+            live_feed: state.live_feed.clone(),
+            webmentions: state.webmentions.clone(),
+            metrics: state.metrics.clone(),
+            health: state.health.clone(),
+            rate_limiter: state.rate_limiter.clone(),
+            routes: state.routes.clone(),
+            request_id: String::new(),
             user: None,
+            session: None,
+            api_token: None,
         },
     )
 }
 
+/// The Postgres pool, for what the stores don't cover. The routes that need it
+/// aren't added on SQLite, so the 404 there is only a backstop.
+pub fn postgres(context: &Ctx) -> Result<&Pool<Postgres>, ThrusterError<Ctx>> {
+    context
+        .extra
+        .database
+        .postgres()
+        .ok_or_else(|| AppError::NotFound.into_thruster_error(context))
+}
+
+/// Turns off what needs Postgres, for running on SQLite.
+fn without_postgres(config: &mut Config) {
+    info!("Running on SQLite, so what needs Postgres is turned off");
+
+    config.features.live_events = false;
+    config.features.streaming = false;
+    config.features.micropub = false;
+    config.features.webhooks = false;
+    config.features.webmentions = false;
+    config.cache.enabled = false;
+    config.jobs.in_process = false;
+    #[cfg(feature = "sqlite")]
+    {
+        config.database.max_connections = crate::stores::sqlite::MAX_CONNECTIONS;
+        config.database.min_connections = 0;
+    }
+}
+
+/// `App::get` and `App::post` that also add the route's template to `routes`.
+trait RouteExt {
+    fn get_route(self, routes: &Routes, path: &str, middleware: MiddlewareTuple<Ctx>) -> Self;
+    fn post_route(self, routes: &Routes, path: &str, middleware: MiddlewareTuple<Ctx>) -> Self;
+}
+
+impl RouteExt for App<HyperRequest, Ctx, ServerConfig> {
+    fn get_route(self, routes: &Routes, path: &str, middleware: MiddlewareTuple<Ctx>) -> Self {
+        routes.add("GET", path);
+
+        self.get(path, middleware)
+    }
+
+    fn post_route(self, routes: &Routes, path: &str, middleware: MiddlewareTuple<Ctx>) -> Self {
+        routes.add("POST", path);
+
+        self.post(path, middleware)
+    }
+}
+
 #[middleware_fn]
 async fn ping(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
     context.body("pong");
@@ -44,79 +163,294 @@ async fn ping(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<
     Ok(context)
 }
 
-#[middleware_fn]
-async fn profiling(mut context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
-    let start_time = Instant::now();
-
-    let method = context
-        .hyper_request
-        .as_ref()
-        .unwrap()
-        .request
-        .method()
-        .clone();
-    let path_and_query = context
-        .hyper_request
-        .as_ref()
-        .unwrap()
-        .request
-        .uri()
-        .path_and_query()
-        .unwrap()
-        .clone();
-
-    context = match next(context).await {
-        Ok(context) => context,
-        Err(e) => e.context,
+pub async fn app(
+    database: Database,
+    mut config: Config,
+    health: Health,
+    background: Background,
+) -> Result<App<HyperRequest, Ctx, ServerConfig>, Box<dyn std::error::Error>> {
+    let pool = database.postgres().cloned();
+    if pool.is_none() {
+        without_postgres(&mut config);
+    }
+    let features = config.features.clone();
+
+    let events = match &pool {
+        Some(pool) => Events::listen(pool).await?,
+        None => Events::without_listener(),
+    };
+    let fetcher = Arc::new(ReqwestFetcher::new(&config.outbound)?);
+    let webmentions = match &pool {
+        Some(pool) => Webmentions::spawn(
+            pool.clone(),
+            fetcher,
+            features.webmentions,
+            background.clone(),
+        ),
+        None => Webmentions::off(fetcher, background.clone()),
+    };
+    let metrics_registry = Arc::new(Metrics::new(config.database.max_connections)?);
+    let mut stores = match &database {
+        Database::Postgres(pool) => Stores::postgres(pool.clone()),
+        #[cfg(feature = "sqlite")]
+        Database::Sqlite(pool) => Stores::sqlite(pool.clone()),
+    };
+    if let Some(pool) = &pool {
+        if features.webhooks {
+            spawn_dispatcher(pool.clone(), &events, background.clone());
+        }
+        spawn_reconciler(pool.clone(), &config.counters);
+        if config.jobs.in_process {
+            jobs::worker(pool, &config, &background)?.spawn(
+                pool.clone(),
+                health.clone(),
+                background,
+            );
+        }
+        Metrics::spawn_pool_sampler(metrics_registry.clone(), pool.clone());
+        if config.cache.enabled {
+            let caches = Arc::new(Caches::new(&config.cache, &metrics_registry));
+            Caches::listen(caches.clone(), pool).await?;
+            stores = Stores::cached(stores, caches);
+        }
+        idempotency::spawn_pruner(pool.clone());
+    }
+    let live_feed = LiveFeed::spawn(&events, stores.tweets.clone());
+    let rate_limiter = RateLimiter::from_config(&config.rate_limits, pool.as_ref()).map(Arc::new);
+    let metrics_enabled = config.metrics.enabled;
+    let routes = Routes::default();
+    let state = ServerConfig {
+        config: Arc::new(config),
+        stores,
+        database,
+        events,
+        live_feed,
+        webmentions,
+        metrics: metrics_registry,
+        health,
+        rate_limiter,
+        routes: routes.clone(),
     };
 
-    let elapsed_time = start_time.elapsed();
-    info!(
-        "{}μs\t\t{}\t{}\t{}",
-        elapsed_time.as_micros(),
-        method,
-        context.status,
-        path_and_query,
-    );
+    let mut app = App::<HyperRequest, Ctx, ServerConfig>::create(generate_context, state)
+        .middleware(
+            "/",
+            m![trace_requests, error_pages, catch_panics, not_found],
+        )
+        .get_route(&routes, "/ping", m![ping])
+        .get_route(&routes, "/healthz", m![healthz])
+        .get_route(&routes, "/readyz", m![readyz])
+        .get_route(
+            &routes,
+            "/",
+            m![cookies, fetch_user_from_cookie, rate_limit, home],
+        )
+        .get_route(&routes, "/signup", m![rate_limit, signup])
+        .get_route(&routes, "/signin", m![rate_limit, signin])
+        // Not `idempotent`, as keys are per user and there isn't one yet. A
+        // repeated sign up is refused for its taken username instead.
+        .post_route(&routes, "/users", m![rate_limit, create_user])
+        .post_route(&routes, "/sessions", m![rate_limit, sign_in_user])
+        .post_route(&routes, "/signout", m![cookies, rate_limit, sign_out_user])
+        .post_route(
+            &routes,
+            "/tweets",
+            m![
+                cookies,
+                fetch_user_from_cookie,
+                rate_limit,
+                authenticate,
+                idempotent,
+                create_tweet
+            ],
+        )
+        .get_route(
+            &routes,
+            "/tweets/:id",
+            m![cookies, fetch_user_from_cookie, rate_limit, single_tweet],
+        )
+        .post_route(
+            &routes,
+            "/tweets/:id/likes",
+            m![
+                cookies,
+                fetch_user_from_cookie,
+                rate_limit,
+                authenticate,
+                idempotent,
+                like_tweet
+            ],
+        )
+        .post_route(
+            &routes,
+            "/tweets/:id/retweets",
+            m![
+                cookies,
+                fetch_user_from_cookie,
+                rate_limit,
+                authenticate,
+                idempotent,
+                retweet
+            ],
+        )
+        .get_route(
+            &routes,
+            "/tweets/:id/replies",
+            m![
+                cookies,
+                fetch_user_from_cookie,
+                rate_limit,
+                authenticate,
+                reply_page
+            ],
+        )
+        .post_route(
+            &routes,
+            "/tweets/:id/replies",
+            m![
+                cookies,
+                fetch_user_from_cookie,
+                rate_limit,
+                authenticate,
+                idempotent,
+                reply
+            ],
+        )
+        .post_route(
+            &routes,
+            "/follows",
+            m![
+                cookies,
+                fetch_user_from_cookie,
+                rate_limit,
+                authenticate,
+                idempotent,
+                follow_user
+            ],
+        );
 
-    Ok(context)
-}
+    // API tokens are only kept in Postgres.
+    if pool.is_some() {
+        app = app.post_route(
+            &routes,
+            "/api_tokens",
+            m![
+                cookies,
+                fetch_user_from_cookie,
+                rate_limit,
+                authenticate,
+                idempotent,
+                create_api_token
+            ],
+        );
+    }
 
-pub async fn app(
-    pool: Pool<Postgres>,
-) -> Result<App<HyperRequest, Ctx, ServerConfig>, Box<dyn std::error::Error>> {
-    Ok(
-        App::<HyperRequest, Ctx, ServerConfig>::create(generate_context, ServerConfig { pool })
-            .middleware("/", m![profiling])
-            .get("/ping", m![ping])
-            .get("/", m![cookies, fetch_user_from_cookie, home])
-            .get("/signup", m![signup])
-            .get("/signin", m![signin])
-            .post("/users", m![create_user])
-            .post("/sessions", m![sign_in_user])
-            .post(
-                "/tweets",
-                m![cookies, fetch_user_from_cookie, authenticate, create_tweet],
-            )
-            .get(
-                "/tweets/:id",
-                m![cookies, fetch_user_from_cookie, single_tweet],
+    if metrics_enabled {
+        app = app.get_route(&routes, "/metrics", m![metrics]);
+    }
+
+    if features.live_events {
+        app = app.get_route(
+            &routes,
+            "/events",
+            m![cookies, fetch_user_from_cookie, rate_limit, live_events],
+        );
+    }
+
+    if features.streaming {
+        app = app.get_route(
+            &routes,
+            "/streaming",
+            m![
+                fetch_user_from_api_token,
+                rate_limit,
+                authenticate,
+                streaming
+            ],
+        );
+    }
+
+    if features.micropub {
+        app = app
+            .get_route(
+                &routes,
+                "/micropub",
+                m![
+                    fetch_user_from_api_token,
+                    rate_limit,
+                    authenticate,
+                    micropub_query
+                ],
             )
-            .post(
-                "/tweets/:id/likes",
-                m![cookies, fetch_user_from_cookie, authenticate, like_tweet],
+            .post_route(
+                &routes,
+                "/micropub",
+                m![
+                    fetch_user_from_api_token,
+                    fetch_user_from_form_token,
+                    rate_limit,
+                    authenticate,
+                    idempotent,
+                    micropub_create
+                ],
+            );
+    }
+
+    if features.webmentions {
+        app = app.post_route(&routes, "/webmention", m![rate_limit, receive_webmention]);
+    }
+
+    if features.webhooks {
+        app = app
+            .get_route(
+                &routes,
+                "/webhooks",
+                m![
+                    cookies,
+                    fetch_user_from_cookie,
+                    rate_limit,
+                    authenticate,
+                    webhooks_page
+                ],
             )
-            .post(
-                "/tweets/:id/retweets",
-                m![cookies, fetch_user_from_cookie, authenticate, retweet],
+            .post_route(
+                &routes,
+                "/webhooks",
+                m![
+                    cookies,
+                    fetch_user_from_cookie,
+                    rate_limit,
+                    authenticate,
+                    idempotent,
+                    create_webhook
+                ],
+            );
+    }
+
+    if features.feeds {
+        app = app
+            .get_route(&routes, "/feed.atom", m![rate_limit, timeline_feed])
+            .get_route(&routes, "/feed.rss", m![rate_limit, timeline_feed])
+            .get_route(&routes, "/feed.json", m![rate_limit, timeline_feed])
+            .get_route(&routes, "/users/:id/feed.atom", m![rate_limit, user_feed])
+            .get_route(&routes, "/users/:id/feed.rss", m![rate_limit, user_feed])
+            .get_route(&routes, "/users/:id/feed.json", m![rate_limit, user_feed])
+            .get_route(
+                &routes,
+                "/hashtags/:tag/feed.atom",
+                m![rate_limit, hashtag_feed],
             )
-            .get(
-                "/tweets/:id/replies",
-                m![cookies, fetch_user_from_cookie, authenticate, reply_page],
+            .get_route(
+                &routes,
+                "/hashtags/:tag/feed.rss",
+                m![rate_limit, hashtag_feed],
             )
-            .post(
-                "/tweets/:id/replies",
-                m![cookies, fetch_user_from_cookie, authenticate, reply],
-            ),
-    )
+            .get_route(
+                &routes,
+                "/hashtags/:tag/feed.json",
+                m![rate_limit, hashtag_feed],
+            );
+    }
+
+    Ok(app)
 }
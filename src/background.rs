@@ -0,0 +1,77 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tokio::sync::Notify;
+
+/// Counts the background work in progress, such as sending webmentions and
+/// delivering webhooks, so that shutting down can wait for it to finish
+/// instead of cutting it off.
+#[derive(Clone, Default)]
+pub struct Background {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    running: AtomicUsize,
+    finished: Notify,
+}
+
+/// Marks a piece of work as running until dropped.
+pub struct Job {
+    inner: Arc<Inner>,
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        if self.inner.running.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.finished.notify_waiters();
+        }
+    }
+}
+
+impl Background {
+    /// For work done inside a long-running loop, such as a queue consumer,
+    /// where only the item being handled needs to be waited for.
+    pub fn job(&self) -> Job {
+        self.inner.running.fetch_add(1, Ordering::SeqCst);
+
+        Job {
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        let job = self.job();
+
+        tokio::spawn(async move {
+            future.await;
+            drop(job);
+        });
+    }
+
+    pub fn running(&self) -> usize {
+        self.inner.running.load(Ordering::SeqCst)
+    }
+
+    /// Waits until nothing is running. Work started meanwhile is waited for
+    /// too, so callers bound this with a timeout.
+    pub async fn drain(&self) {
+        loop {
+            // Created before checking, so that a job finishing in between
+            // still wakes it.
+            let finished = self.inner.finished.notified();
+
+            if self.running() == 0 {
+                return;
+            }
+
+            finished.await;
+        }
+    }
+}
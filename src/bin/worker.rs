@@ -0,0 +1,57 @@
+//! Runs the job workers without the web server, for running them apart from
+//! it. Set `jobs.in_process = false` for the server in that case.
+
+use std::time::Duration;
+
+use brutalist_twitter::{
+    background::Background, bootstrap, config::Config, health::Health, jobs, migrations, server,
+    telemetry,
+};
+use log::{info, warn};
+use tokio::time::timeout;
+
+fn exit_with(e: impl std::fmt::Display) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(1);
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (config, args) = Config::load(&args).unwrap_or_else(|e| exit_with(e));
+    if let Some(other) = args.first() {
+        exit_with(format!("Unknown argument {:?}", other));
+    }
+
+    telemetry::init(&config.logging);
+
+    let pool = bootstrap::connect(&config)
+        .await
+        .unwrap_or_else(|e| exit_with(e));
+    // The server owns migrating; jobs may depend on tables it hasn't made yet.
+    match migrations::current_version(&pool).await {
+        Ok(Some(version)) if version >= migrations::latest_version() => (),
+        Ok(_) => exit_with("The database needs migrating first; see the migrate command"),
+        Err(e) => exit_with(e),
+    }
+
+    let health = Health::default();
+    let background = Background::default();
+    jobs::worker(&pool, &config, &background)
+        .unwrap_or_else(|e| exit_with(e))
+        .spawn(pool.clone(), health.clone(), background.clone());
+
+    server::signal().await;
+    health.begin_shutdown();
+
+    let shutdown_timeout = Duration::from_secs(config.server.shutdown_background_timeout_secs);
+    info!("Waiting for {} running job(s)", background.running());
+    if timeout(shutdown_timeout, background.drain()).await.is_err() {
+        warn!(
+            "Gave up on {} job(s); they will run again once their lease is up",
+            background.running()
+        );
+    }
+
+    pool.close().await;
+}
@@ -0,0 +1,162 @@
+use std::{fmt, time::Duration};
+
+use log::{info, LevelFilter};
+use sqlx::{postgres::PgConnectOptions, ConnectOptions, PgPool, Pool, Postgres};
+use thruster::{App, HyperRequest};
+
+use crate::{
+    app::{self, Ctx, ServerConfig},
+    background::Background,
+    config::{Config, ConfigError},
+    database::{self, Database},
+    health::Health,
+    migrations::{self, MigrationError},
+    telemetry,
+};
+
+const SLOW_STATEMENT: Duration = Duration::from_secs(1);
+
+/// Where the process runs, which decides who owns the database pool and the
+/// HTTP server. Everything else about starting up is shared.
+pub enum Deployment {
+    /// Connects to the configured database; the caller serves the app on the
+    /// configured address.
+    Standalone,
+    /// Shuttle provisions the database and runs the server itself.
+    Shuttle(PgPool),
+}
+
+#[derive(Debug)]
+pub enum BootstrapError {
+    Config(ConfigError),
+    Database(sqlx::Error),
+    Migration(MigrationError),
+    /// Building the app failed, e.g. listening for events.
+    App(String),
+    /// Asked of a SQLite database, for something only Postgres has.
+    RequiresPostgres,
+}
+
+impl fmt::Display for BootstrapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootstrapError::Config(e) => write!(f, "{}", e),
+            BootstrapError::Database(e) => write!(f, "Could not connect to the database: {}", e),
+            BootstrapError::Migration(e) => write!(f, "{}", e),
+            BootstrapError::App(e) => write!(f, "Could not create app: {}", e),
+            BootstrapError::RequiresPostgres => {
+                write!(f, "This needs Postgres, but database.url is SQLite")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BootstrapError {}
+
+impl From<ConfigError> for BootstrapError {
+    fn from(e: ConfigError) -> BootstrapError {
+        BootstrapError::Config(e)
+    }
+}
+
+impl From<sqlx::Error> for BootstrapError {
+    fn from(e: sqlx::Error) -> BootstrapError {
+        BootstrapError::Database(e)
+    }
+}
+
+impl From<MigrationError> for BootstrapError {
+    fn from(e: MigrationError) -> BootstrapError {
+        BootstrapError::Migration(e)
+    }
+}
+
+/// Statements are logged at debug level, and slow ones as warnings. These are
+/// log lines rather than spans of their own, as sqlx has none, but they carry
+/// the span of the request that ran them and so its id.
+pub async fn connect(config: &Config) -> Result<Pool<Postgres>, BootstrapError> {
+    if database::is_sqlite_url(&config.database.url) {
+        return Err(BootstrapError::RequiresPostgres);
+    }
+
+    let mut options: PgConnectOptions = config.database.url.parse()?;
+    options
+        .log_statements(LevelFilter::Debug)
+        .log_slow_statements(LevelFilter::Warn, SLOW_STATEMENT);
+
+    Ok(config.database.pool_options().connect_with(options).await?)
+}
+
+/// Postgres, or with the `sqlite` feature SQLite for a `sqlite:` URL. SQLite
+/// needs no migrating, as its schema is created on connecting.
+pub async fn connect_database(config: &Config) -> Result<Database, BootstrapError> {
+    #[cfg(feature = "sqlite")]
+    if database::is_sqlite_url(&config.database.url) {
+        let pool = crate::stores::sqlite::connect(&config.database.url).await?;
+
+        return Ok(Database::Sqlite(pool));
+    }
+
+    Ok(Database::Postgres(connect(config).await?))
+}
+
+/// Brings the database up to date and builds the app. `health` and
+/// `background` are kept by the caller for shutting down, along with the
+/// database that is returned.
+pub async fn start(
+    config: Config,
+    deployment: Deployment,
+    health: Health,
+    background: Background,
+) -> Result<(App<HyperRequest, Ctx, ServerConfig>, Database), BootstrapError> {
+    telemetry::init(&config.logging);
+    info!("Starting server...");
+
+    let database = match deployment {
+        Deployment::Standalone => connect_database(&config).await?,
+        Deployment::Shuttle(pool) => Database::Postgres(pool),
+    };
+
+    if let Some(pool) = database.postgres() {
+        migrations::run(pool).await?;
+    }
+
+    let app = app::app(database.clone(), config, health, background)
+        .await
+        .map_err(|e| BootstrapError::App(e.to_string()))?;
+
+    info!("Server started...");
+
+    Ok((app, database))
+}
+
+/// For `--check`: the config has been loaded and validated by the time this
+/// runs, so what's left is that the database answers and is not ahead of us.
+pub async fn check(config: &Config) -> Result<(), BootstrapError> {
+    telemetry::init(&config.logging);
+    println!("Configuration is valid");
+
+    let database = connect_database(config).await?;
+    let pool = match database.postgres() {
+        Some(pool) => pool,
+        None => {
+            println!("Connected to the SQLite database, which needs no migrating");
+            database.close().await;
+
+            return Ok(());
+        }
+    };
+    sqlx::query("SELECT 1").execute(pool).await?;
+    println!("Connected to the database");
+
+    let pending = migrations::status(pool)
+        .await?
+        .iter()
+        .filter(|status| status.applied_at.is_none())
+        .count();
+    println!("{} pending migration(s)", pending);
+
+    pool.close().await;
+
+    Ok(())
+}
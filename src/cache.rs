@@ -0,0 +1,313 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use log::{error, warn};
+use prometheus::{Gauge, IntCounter};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgListener, Pool, Postgres, Transaction};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::{
+    config::CacheConfig,
+    events::{self, Event, EventKind},
+    metrics::Metrics,
+    models::{
+        sessions::SessionWithUser,
+        tweets::{Tweet, TweetWithUserInfo},
+        users::User,
+    },
+};
+
+/// For changes that aren't events, such as sessions being revoked, which
+/// would leak if sent to live clients.
+pub const CHANNEL: &str = "bitter_cache";
+
+/// What to drop from the caches of every instance.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Invalidation {
+    Session { id: Uuid },
+    User { id: Uuid },
+    Tweet { id: Uuid },
+}
+
+/// Queues `invalidation` on the transaction, so that it is only sent if the
+/// change commits. The instance making the change should drop its own copy
+/// straight away too, rather than wait for the notification to come back.
+pub async fn invalidate(
+    transaction: &mut Transaction<'_, Postgres>,
+    invalidation: Invalidation,
+) -> Result<(), sqlx::Error> {
+    let payload =
+        serde_json::to_string(&invalidation).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(CHANNEL)
+        .bind(payload)
+        .execute(transaction)
+        .await?;
+
+    Ok(())
+}
+
+struct Entry<V> {
+    value: V,
+    expires_at: Instant,
+    /// Its key's place in `Entries::order`.
+    seq: u64,
+}
+
+struct Entries<K, V> {
+    values: HashMap<K, Entry<V>>,
+    /// Keys in the order they were put in, which with a single `ttl` is also
+    /// the order they expire in.
+    order: BTreeMap<u64, K>,
+    next_seq: u64,
+    /// Bumped by everything that removes entries; see `TtlCache::generation`.
+    generation: u64,
+}
+
+impl<K: Clone + Eq + Hash, V> Entries<K, V> {
+    fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.values.remove(key) {
+            self.order.remove(&entry.seq);
+        }
+    }
+}
+
+/// A map whose entries expire `ttl` after being put in, holding at most
+/// `capacity` of them. Lookups are counted in the metrics under its name.
+pub struct TtlCache<K, V> {
+    entries: Mutex<Entries<K, V>>,
+    capacity: usize,
+    ttl: Duration,
+    hits: IntCounter,
+    misses: IntCounter,
+    hit_ratio: Gauge,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> TtlCache<K, V> {
+    pub fn new(name: &str, config: &CacheConfig, metrics: &Metrics) -> TtlCache<K, V> {
+        let (hits, misses, hit_ratio) = metrics.cache(name);
+
+        TtlCache {
+            entries: Mutex::new(Entries {
+                values: HashMap::new(),
+                order: BTreeMap::new(),
+                next_seq: 0,
+                generation: 0,
+            }),
+            capacity: config.capacity,
+            ttl: Duration::from_secs(config.ttl_secs),
+            hits,
+            misses,
+            hit_ratio,
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, Entries<K, V>> {
+        // Nothing that changes the maps can panic part way, so a panic
+        // elsewhere can't leave them out of step.
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn record(&self, hit: bool) {
+        if hit {
+            self.hits.inc();
+        } else {
+            self.misses.inc();
+        }
+
+        let hits = self.hits.get() as f64;
+        self.hit_ratio.set(hits / (hits + self.misses.get() as f64));
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut entries = self.entries();
+
+        let value = match entries.values.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        self.record(value.is_some());
+
+        value
+    }
+
+    /// To take before loading a value to `insert`, so that a value loaded
+    /// before something was removed or invalidated isn't put back after it.
+    pub fn generation(&self) -> u64 {
+        self.entries().generation
+    }
+
+    /// Does nothing if anything has been removed since `generation` was
+    /// taken. When full, the entry closest to expiring goes first.
+    pub fn insert(&self, generation: u64, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries();
+        if entries.generation != generation {
+            return;
+        }
+
+        entries.remove(&key);
+        while entries.values.len() >= self.capacity {
+            match entries.order.pop_first() {
+                Some((_, soonest)) => {
+                    entries.values.remove(&soonest);
+                }
+                None => break,
+            }
+        }
+
+        let seq = entries.next_seq;
+        entries.next_seq += 1;
+        entries.order.insert(seq, key.clone());
+        entries.values.insert(
+            key,
+            Entry {
+                value,
+                expires_at: Instant::now() + self.ttl,
+                seq,
+            },
+        );
+    }
+
+    pub fn remove(&self, key: &K) {
+        let mut entries = self.entries();
+        entries.generation += 1;
+        entries.remove(key);
+    }
+
+    /// Drops the entries for which `remove` is true.
+    pub fn remove_where(&self, remove: impl Fn(&K, &V) -> bool) {
+        let mut entries = self.entries();
+        entries.generation += 1;
+
+        let removed: Vec<K> = entries
+            .values
+            .iter()
+            .filter(|(key, entry)| remove(key, &entry.value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &removed {
+            entries.remove(key);
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries();
+        entries.generation += 1;
+        entries.values.clear();
+        entries.order.clear();
+    }
+}
+
+/// The caches in front of the stores; see `stores::cached`.
+pub struct Caches {
+    /// By token.
+    pub sessions: TtlCache<String, SessionWithUser>,
+    pub users: TtlCache<Uuid, User>,
+    pub tweets: TtlCache<Uuid, Tweet>,
+    /// By tweet and viewer, as whether they have liked or retweeted it is part
+    /// of it.
+    pub tweets_with_user_info: TtlCache<(Uuid, Option<Uuid>), TweetWithUserInfo>,
+}
+
+impl Caches {
+    pub fn new(config: &CacheConfig, metrics: &Metrics) -> Caches {
+        Caches {
+            sessions: TtlCache::new("sessions", config, metrics),
+            users: TtlCache::new("users", config, metrics),
+            tweets: TtlCache::new("tweets", config, metrics),
+            tweets_with_user_info: TtlCache::new("tweets_with_user_info", config, metrics),
+        }
+    }
+
+    pub fn invalidate(&self, invalidation: &Invalidation) {
+        match invalidation {
+            Invalidation::Session { id } => self
+                .sessions
+                .remove_where(|_, signed_in| signed_in.session.id == *id),
+            Invalidation::User { id } => {
+                self.users.remove(id);
+                self.sessions
+                    .remove_where(|_, signed_in| signed_in.user.id == *id);
+            }
+            // Replies too, which stop being replies when it is deleted.
+            Invalidation::Tweet { id } => {
+                self.tweets.remove_where(|tweet_id, tweet| {
+                    tweet_id == id || tweet.responding_to == Some(*id)
+                });
+                self.tweets_with_user_info
+                    .remove_where(|(tweet_id, _), tweet| {
+                        tweet_id == id || tweet.responding_to == Some(*id)
+                    });
+            }
+        }
+    }
+
+    fn clear(&self) {
+        self.sessions.clear();
+        self.users.clear();
+        self.tweets.clear();
+        self.tweets_with_user_info.clear();
+    }
+
+    /// Drops what other instances change, from `CHANNEL` and from the events
+    /// that change tweets.
+    ///
+    /// Notifications sent while the connection is down are lost, so the caches
+    /// are cleared whenever it drops.
+    pub async fn listen(caches: Arc<Caches>, pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+        let mut listener = PgListener::connect_with(pool).await?;
+        listener.listen_all([CHANNEL, events::CHANNEL]).await?;
+
+        tokio::spawn(async move {
+            loop {
+                let notification = match listener.try_recv().await {
+                    Ok(Some(notification)) => notification,
+                    Ok(None) => {
+                        warn!("Lost the cache invalidation connection; clearing the caches");
+                        caches.clear();
+                        continue;
+                    }
+                    Err(_e) => {
+                        error!("Could not receive cache invalidations: {:#?}", _e);
+                        caches.clear();
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                if notification.channel() == CHANNEL {
+                    match serde_json::from_str::<Invalidation>(notification.payload()) {
+                        Ok(invalidation) => caches.invalidate(&invalidation),
+                        Err(_e) => warn!("Ignoring malformed invalidation: {:#?}", _e),
+                    }
+                } else if let Ok(Event {
+                    kind: EventKind::CountsChanged { tweet_id, .. },
+                    ..
+                }) = serde_json::from_str::<Event>(notification.payload())
+                {
+                    caches.invalidate(&Invalidation::Tweet { id: tweet_id });
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
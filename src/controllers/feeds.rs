@@ -0,0 +1,310 @@
+use std::str::FromStr;
+
+use askama::Template;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use thruster::{
+    context::context_ext::ContextExt, middleware::cookies::HasCookies, middleware_fn, Context,
+    MiddlewareNext, MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::Ctx,
+    errors::{AppError, OrAppError},
+    metrics::Metrics,
+    models::tweets::{is_valid_hashtag, TweetWithUserInfo},
+    urls::{base_url, tweet_url},
+};
+
+#[derive(Clone, Copy, PartialEq)]
+enum FeedFormat {
+    Atom,
+    Rss,
+    Json,
+}
+
+impl FeedFormat {
+    fn from_path(path: &str) -> Option<FeedFormat> {
+        if path.ends_with(".atom") {
+            Some(FeedFormat::Atom)
+        } else if path.ends_with(".rss") {
+            Some(FeedFormat::Rss)
+        } else if path.ends_with(".json") {
+            Some(FeedFormat::Json)
+        } else {
+            None
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            FeedFormat::Atom => "application/atom+xml; charset=utf-8",
+            FeedFormat::Rss => "application/rss+xml; charset=utf-8",
+            FeedFormat::Json => "application/feed+json; charset=utf-8",
+        }
+    }
+}
+
+struct FeedInfo {
+    title: String,
+    self_url: String,
+    home_url: String,
+    updated: DateTime<Utc>,
+}
+
+#[derive(Template)]
+#[template(path = "feeds/atom.xml")]
+struct AtomFeed<'a> {
+    info: &'a FeedInfo,
+    base_url: &'a str,
+    feed: &'a [TweetWithUserInfo],
+}
+
+#[derive(Template)]
+#[template(path = "feeds/rss.xml")]
+struct RssFeed<'a> {
+    info: &'a FeedInfo,
+    base_url: &'a str,
+    feed: &'a [TweetWithUserInfo],
+}
+
+impl<'a> AtomFeed<'a> {
+    fn tweet_url(&self, tweet: &TweetWithUserInfo) -> String {
+        tweet_url(self.base_url, &tweet.id)
+    }
+}
+
+impl<'a> RssFeed<'a> {
+    fn tweet_url(&self, tweet: &TweetWithUserInfo) -> String {
+        tweet_url(self.base_url, &tweet.id)
+    }
+}
+
+#[derive(Serialize)]
+struct JsonFeed {
+    version: &'static str,
+    title: String,
+    home_page_url: String,
+    feed_url: String,
+    items: Vec<JsonFeedItem>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedItem {
+    id: String,
+    url: String,
+    content_text: String,
+    date_published: String,
+    date_modified: String,
+    authors: Vec<JsonFeedAuthor>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedAuthor {
+    name: String,
+}
+
+/// The newest `updated_at` in the feed, falling back to the epoch for an empty
+/// feed so that the value is stable between requests.
+fn last_updated(feed: &[TweetWithUserInfo]) -> DateTime<Utc> {
+    feed.iter()
+        .map(|tweet| tweet.updated_at)
+        .max()
+        .unwrap_or_else(|| Utc.timestamp(0, 0))
+}
+
+fn etag_for(format: FeedFormat, feed: &[TweetWithUserInfo]) -> String {
+    let mut hasher = Sha256::new();
+
+    hasher.update([format as u8]);
+    for tweet in feed {
+        hasher.update(tweet.id.as_bytes());
+        hasher.update(tweet.updated_at.timestamp_micros().to_le_bytes());
+    }
+
+    format!("W/\"{:x}\"", hasher.finalize())
+}
+
+fn http_date(date: &DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Checks `If-None-Match` first and only falls back to `If-Modified-Since` when
+/// no entity tag was sent, as RFC 7232 requires.
+fn is_not_modified(context: &Ctx, etag: &str, last_modified: &DateTime<Utc>) -> bool {
+    if let Some(if_none_match) = context.get_header("If-None-Match").pop() {
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag == etag);
+    }
+
+    context
+        .get_header("If-Modified-Since")
+        .pop()
+        .and_then(|since| DateTime::parse_from_rfc2822(&since).ok())
+        .map(|since| last_modified.timestamp() <= since.timestamp())
+        .unwrap_or(false)
+}
+
+fn render_feed(
+    metrics: &Metrics,
+    format: FeedFormat,
+    info: &FeedInfo,
+    base_url: &str,
+    feed: &[TweetWithUserInfo],
+) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(match format {
+        FeedFormat::Atom => metrics.render(&AtomFeed {
+            info,
+            base_url,
+            feed,
+        })?,
+        FeedFormat::Rss => metrics.render(&RssFeed {
+            info,
+            base_url,
+            feed,
+        })?,
+        FeedFormat::Json => serde_json::to_string(&JsonFeed {
+            version: "https://jsonfeed.org/version/1.1",
+            title: info.title.clone(),
+            home_page_url: info.home_url.clone(),
+            feed_url: info.self_url.clone(),
+            items: feed
+                .iter()
+                .map(|tweet| JsonFeedItem {
+                    id: tweet_url(base_url, &tweet.id),
+                    url: tweet_url(base_url, &tweet.id),
+                    content_text: tweet.content.clone(),
+                    date_published: tweet.created_at.to_rfc3339(),
+                    date_modified: tweet.updated_at.to_rfc3339(),
+                    authors: vec![JsonFeedAuthor {
+                        name: tweet.username.clone(),
+                    }],
+                })
+                .collect(),
+        })?,
+    })
+}
+
+fn request_path(context: &Ctx) -> String {
+    context
+        .hyper_request
+        .as_ref()
+        .map(|request| request.request.uri().path().to_string())
+        .unwrap_or_default()
+}
+
+fn respond_with_feed(
+    mut context: Ctx,
+    format: FeedFormat,
+    title: String,
+    feed: Vec<TweetWithUserInfo>,
+) -> MiddlewareResult<Ctx> {
+    let base_url = base_url(&context);
+    let last_modified = last_updated(&feed);
+    let etag = etag_for(format, &feed);
+
+    context.set("ETag", &etag);
+    context.set("Last-Modified", &http_date(&last_modified));
+    context.set("Cache-Control", "public, max-age=60");
+
+    if is_not_modified(&context, &etag, &last_modified) {
+        context.status(304);
+
+        return Ok(context);
+    }
+
+    let info = FeedInfo {
+        title,
+        self_url: format!("{}{}", base_url, request_path(&context)),
+        home_url: format!("{}/", base_url),
+        updated: last_modified,
+    };
+    let body = render_feed(&context.extra.metrics, format, &info, &base_url, &feed)
+        .map_err(|e| AppError::Internal(format!("{:#?}", e)))
+        .or_app_error(&context)?;
+
+    context.set("Content-Type", format.content_type());
+    context.body(&body);
+
+    Ok(context)
+}
+
+#[middleware_fn]
+pub async fn timeline_feed(context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let format = FeedFormat::from_path(&request_path(&context))
+        .ok_or(AppError::NotFound)
+        .or_app_error(&context)?;
+
+    let feed = context
+        .extra
+        .stores
+        .tweets
+        .get_recent_tweets(None, None)
+        .await
+        .or_app_error(&context)?;
+
+    respond_with_feed(context, format, "Bitter".to_string(), feed)
+}
+
+#[middleware_fn]
+pub async fn user_feed(context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let format = FeedFormat::from_path(&request_path(&context))
+        .ok_or(AppError::NotFound)
+        .or_app_error(&context)?;
+    let author_id = context
+        .params()
+        .get("id")
+        .and_then(|id_string| Uuid::from_str(&id_string.param).ok())
+        .ok_or(AppError::NotFound)
+        .or_app_error(&context)?;
+
+    let author = context
+        .extra
+        .stores
+        .users
+        .get_user(&author_id)
+        .await
+        .or_app_error(&context)?;
+    let feed = context
+        .extra
+        .stores
+        .tweets
+        .get_recent_tweets_for_author(&author.id, None, None)
+        .await
+        .or_app_error(&context)?;
+
+    respond_with_feed(
+        context,
+        format,
+        format!("{} on Bitter", author.username),
+        feed,
+    )
+}
+
+#[middleware_fn]
+pub async fn hashtag_feed(context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let format = FeedFormat::from_path(&request_path(&context))
+        .ok_or(AppError::NotFound)
+        .or_app_error(&context)?;
+    let hashtag = context
+        .params()
+        .get("tag")
+        .map(|tag| tag.param.trim_start_matches('#').to_lowercase())
+        .filter(|tag| is_valid_hashtag(tag))
+        .ok_or(AppError::Validation("Bad request".to_string()))
+        .or_app_error(&context)?;
+
+    let feed = context
+        .extra
+        .stores
+        .tweets
+        .get_recent_tweets_for_hashtag(&hashtag, None, None)
+        .await
+        .or_app_error(&context)?;
+
+    respond_with_feed(context, format, format!("#{} on Bitter", hashtag), feed)
+}
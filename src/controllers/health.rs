@@ -0,0 +1,41 @@
+use thruster::{middleware_fn, Context, MiddlewareNext, MiddlewareResult};
+
+use crate::{
+    app::Ctx,
+    errors::{AppError, OrAppError},
+    health::Readiness,
+};
+
+/// Liveness: the process is up and serving requests. Deliberately checks
+/// nothing else, so that a database outage doesn't get every instance
+/// restarted.
+#[middleware_fn]
+pub async fn healthz(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    context.set("Content-Type", "application/json");
+    context.set("Cache-Control", "no-store");
+    context.body(r#"{"status":"ok"}"#);
+
+    Ok(context)
+}
+
+/// Readiness: 200 when this instance should get traffic, 503 with the same
+/// breakdown when it shouldn't.
+#[middleware_fn]
+pub async fn readyz(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let readiness = Readiness::check(
+        &context.extra.database,
+        context.extra.config.database.max_connections,
+        &context.extra.health,
+    )
+    .await;
+    let body = serde_json::to_string(&readiness)
+        .map_err(|e| AppError::Internal(format!("{:#?}", e)))
+        .or_app_error(&context)?;
+
+    context.status(if readiness.ready { 200 } else { 503 });
+    context.set("Content-Type", "application/json");
+    context.set("Cache-Control", "no-store");
+    context.body(&body);
+
+    Ok(context)
+}
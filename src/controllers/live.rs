@@ -0,0 +1,293 @@
+use std::{collections::HashSet, convert::Infallible, str::FromStr, sync::Arc, time::Duration};
+
+use askama::Template;
+use hyper::Body;
+use log::error;
+use serde::Serialize;
+use thruster::{middleware_fn, Context, MiddlewareNext, MiddlewareResult};
+use tokio::{
+    sync::broadcast::{self, error::RecvError, Receiver},
+    time::{interval, Interval},
+};
+use uuid::Uuid;
+
+use crate::{
+    app::Ctx,
+    events::{Event, EventKind, Events},
+    health::Health,
+    models::tweets::TweetWithUserInfo,
+    stores::TweetStore,
+};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Rendered events a slow page may fall behind by before it misses some.
+const CAPACITY: usize = 256;
+
+#[derive(Template)]
+#[template(path = "tweet.html")]
+struct TweetFragment {
+    tweet: TweetWithUserInfo,
+}
+
+#[derive(Serialize)]
+struct TweetMessage {
+    id: Uuid,
+    responding_to: Option<Uuid>,
+    html: String,
+}
+
+#[derive(Serialize)]
+struct CountsMessage {
+    id: Uuid,
+    like_count: i64,
+    retweet_count: i64,
+    reply_count: i64,
+}
+
+/// What the page that opened the stream is currently showing.
+#[derive(Default)]
+struct Subscription {
+    /// New top level tweets, as shown on the home timeline.
+    timeline: bool,
+    /// New replies to this tweet, as shown on its permalink page.
+    replies_to: Option<Uuid>,
+    /// Tweets whose counts are on screen.
+    tweets: HashSet<Uuid>,
+}
+
+impl Subscription {
+    fn from_query(query: &str) -> Subscription {
+        let mut subscription = Subscription::default();
+
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "timeline" => subscription.timeline = value == "home",
+                "replies_to" => subscription.replies_to = Uuid::from_str(&value).ok(),
+                "tweets" => subscription
+                    .tweets
+                    .extend(value.split(',').filter_map(|id| Uuid::from_str(id).ok())),
+                _ => (),
+            }
+        }
+
+        subscription
+    }
+
+    fn wants_tweet(&self, responding_to: &Option<Uuid>) -> bool {
+        match responding_to {
+            None => self.timeline,
+            Some(parent) => self.replies_to.as_ref() == Some(parent),
+        }
+    }
+}
+
+/// An event as sent to browsers, rendered once for every stream.
+struct LiveEvent {
+    id: Uuid,
+    /// The tweet it is about.
+    tweet_id: Uuid,
+    kind: LiveEventKind,
+}
+
+enum LiveEventKind {
+    /// A new tweet, or reply to `responding_to`.
+    Tweet {
+        responding_to: Option<Uuid>,
+        data: String,
+    },
+    Counts {
+        data: String,
+    },
+}
+
+/// Renders events for the pages streaming them. A new tweet is looked up and
+/// rendered once, as no viewer can have liked or retweeted it yet, rather than
+/// once for each open page.
+#[derive(Clone)]
+pub struct LiveFeed {
+    sender: broadcast::Sender<Arc<LiveEvent>>,
+}
+
+async fn render(tweets: &dyn TweetStore, event: Event) -> Option<LiveEvent> {
+    match event.kind {
+        EventKind::TweetCreated {
+            tweet_id,
+            responding_to,
+            ..
+        } => {
+            let tweet = tweets
+                .get_tweet_with_user_info(&tweet_id, None)
+                .await
+                .map_err(|_e| error!("_e: {:#?}", _e))
+                .ok()?;
+            let html = TweetFragment { tweet }
+                .render()
+                .map_err(|_e| error!("_e: {:#?}", _e))
+                .ok()?;
+            let data = serde_json::to_string(&TweetMessage {
+                id: tweet_id,
+                responding_to,
+                html,
+            })
+            .ok()?;
+
+            Some(LiveEvent {
+                id: event.id,
+                tweet_id,
+                kind: LiveEventKind::Tweet {
+                    responding_to,
+                    data,
+                },
+            })
+        }
+        EventKind::CountsChanged {
+            tweet_id,
+            like_count,
+            retweet_count,
+            reply_count,
+        } => {
+            let data = serde_json::to_string(&CountsMessage {
+                id: tweet_id,
+                like_count,
+                retweet_count,
+                reply_count,
+            })
+            .ok()?;
+
+            Some(LiveEvent {
+                id: event.id,
+                tweet_id,
+                kind: LiveEventKind::Counts { data },
+            })
+        }
+        _ => None,
+    }
+}
+
+impl LiveFeed {
+    pub fn spawn(events: &Events, tweets: Arc<dyn TweetStore>) -> LiveFeed {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        let mut receiver = events.subscribe();
+        let feed = LiveFeed {
+            sender: sender.clone(),
+        };
+
+        tokio::spawn(async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(event) => event,
+                    // Pages reload what they missed when they reconnect.
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+
+                // Nothing to render for when no page is open.
+                if sender.receiver_count() == 0 {
+                    continue;
+                }
+
+                if let Some(event) = render(tweets.as_ref(), event).await {
+                    let _ = sender.send(Arc::new(event));
+                }
+            }
+        });
+
+        feed
+    }
+}
+
+struct Stream {
+    subscription: Subscription,
+    receiver: Receiver<Arc<LiveEvent>>,
+    heartbeat: Interval,
+    health: Health,
+}
+
+/// Formats a server-sent event. `data` may span several lines, each of which
+/// needs its own `data:` prefix.
+fn sse_message(id: &Uuid, event: &str, data: &str) -> String {
+    let mut message = format!("id: {}\nevent: {}\n", id, event);
+
+    for line in data.lines() {
+        message.push_str("data: ");
+        message.push_str(line);
+        message.push('\n');
+    }
+    message.push('\n');
+
+    message
+}
+
+impl Stream {
+    fn message(&mut self, event: &LiveEvent) -> Option<String> {
+        match &event.kind {
+            LiveEventKind::Tweet {
+                responding_to,
+                data,
+            } if self.subscription.wants_tweet(responding_to) => {
+                // Counts for the new tweet are now on screen too.
+                self.subscription.tweets.insert(event.tweet_id);
+
+                Some(sse_message(&event.id, "tweet", data))
+            }
+            LiveEventKind::Counts { data }
+                if self.subscription.tweets.contains(&event.tweet_id) =>
+            {
+                Some(sse_message(&event.id, "counts", data))
+            }
+            _ => None,
+        }
+    }
+
+    /// Waits for the next chunk to write. Returns `None` once the event source
+    /// or the server is shutting down, which ends the response; the browser
+    /// reconnects to another instance.
+    async fn next_chunk(&mut self) -> Option<String> {
+        loop {
+            tokio::select! {
+                received = self.receiver.recv() => match received {
+                    Ok(event) => {
+                        if let Some(message) = self.message(&event) {
+                            return Some(message);
+                        }
+                    }
+                    // The browser reloads what it missed when it reconnects, so
+                    // lagging behind is not worth ending the stream over.
+                    Err(RecvError::Lagged(_)) => (),
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = self.heartbeat.tick() => return Some(": heartbeat\n\n".to_string()),
+                _ = self.health.shutdown_begun() => return None,
+            }
+        }
+    }
+}
+
+#[middleware_fn]
+pub async fn live_events(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let query = context
+        .hyper_request
+        .as_ref()
+        .and_then(|request| request.request.uri().query().map(|query| query.to_string()))
+        .unwrap_or_default();
+
+    let stream = Stream {
+        subscription: Subscription::from_query(&query),
+        receiver: context.extra.live_feed.sender.subscribe(),
+        heartbeat: interval(HEARTBEAT_INTERVAL),
+        health: context.extra.health.clone(),
+    };
+
+    context.set("Content-Type", "text/event-stream");
+    context.set("Cache-Control", "no-cache");
+    context.set("X-Accel-Buffering", "no");
+    context.body = Body::wrap_stream(futures::stream::unfold(stream, |mut stream| async move {
+        stream
+            .next_chunk()
+            .await
+            .map(|chunk| (Ok::<_, Infallible>(chunk), stream))
+    }));
+
+    Ok(context)
+}
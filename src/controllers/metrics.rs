@@ -0,0 +1,37 @@
+use thruster::{
+    middleware::cookies::HasCookies, middleware_fn, Context, MiddlewareNext, MiddlewareResult,
+};
+
+use crate::{
+    app::Ctx,
+    errors::{AppError, OrAppError},
+};
+
+/// Only routed when `metrics.enabled` is set. With a `metrics.token`, anything
+/// without it gets the same 401 as any other unauthenticated request.
+#[middleware_fn]
+pub async fn metrics(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    if let Some(token) = context.extra.config.metrics.token.as_ref() {
+        let expected = format!("Bearer {}", token);
+
+        if !context
+            .get_header("Authorization")
+            .iter()
+            .any(|authorization| authorization == &expected)
+        {
+            return Err(AppError::Unauthorized.into_thruster_error(&context));
+        }
+    }
+
+    let body = context
+        .extra
+        .metrics
+        .export(&context.extra.database)
+        .map_err(|e| AppError::Internal(format!("{:#?}", e)))
+        .or_app_error(&context)?;
+
+    context.set("Content-Type", prometheus::TEXT_FORMAT);
+    context.body(&body);
+
+    Ok(context)
+}
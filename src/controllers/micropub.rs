@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use thruster::{
+    middleware::cookies::HasCookies, middleware_fn, Context, MiddlewareNext, MiddlewareResult,
+};
+
+use crate::{
+    app::Ctx,
+    controllers::users::{sign_in_with_api_token, signed_in_user},
+    errors::{AppError, OrAppError},
+    idempotency::request_body,
+    urls::{base_url, tweet_id_from_url, tweet_url},
+};
+
+const MAX_CONTENT_LENGTH: usize = 280;
+
+/// The properties of an `h-entry` this endpoint understands, from either a
+/// form-encoded or a JSON request.
+#[derive(Default)]
+struct Entry {
+    content: Option<String>,
+    in_reply_to: Option<String>,
+    like_of: Option<String>,
+    repost_of: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JsonEntry {
+    #[serde(rename = "type")]
+    kind: Vec<String>,
+    #[serde(default)]
+    properties: HashMap<String, Vec<Value>>,
+}
+
+impl Entry {
+    fn from_form(body: &str) -> Result<Entry, &'static str> {
+        let mut entry = Entry::default();
+        let mut is_entry = true;
+
+        for (key, value) in form_urlencoded::parse(body.as_bytes()) {
+            let value = value.into_owned();
+
+            match key.as_ref() {
+                "h" => is_entry = value == "entry",
+                "content" => entry.content = Some(value),
+                "in-reply-to" => entry.in_reply_to = Some(value),
+                "like-of" => entry.like_of = Some(value),
+                "repost-of" => entry.repost_of = Some(value),
+                _ => (),
+            }
+        }
+
+        if is_entry {
+            Ok(entry)
+        } else {
+            Err("Only h=entry is supported")
+        }
+    }
+
+    fn from_json(body: &str) -> Result<Entry, &'static str> {
+        let JsonEntry { kind, properties } =
+            serde_json::from_str(body).map_err(|_| "Malformed JSON")?;
+
+        if kind.iter().all(|kind| kind != "h-entry") {
+            return Err("Only h-entry is supported");
+        }
+
+        // Values are either plain strings or, for content, `{"value": ...}` or
+        // `{"html": ...}` objects.
+        let first = |name: &str| {
+            properties
+                .get(name)
+                .and_then(|values| values.first())
+                .and_then(|value| match value {
+                    Value::String(value) => Some(value.clone()),
+                    Value::Object(object) => object
+                        .get("value")
+                        .or_else(|| object.get("html"))
+                        .and_then(|value| value.as_str())
+                        .map(|value| value.to_string()),
+                    _ => None,
+                })
+        };
+
+        Ok(Entry {
+            content: first("content"),
+            in_reply_to: first("in-reply-to"),
+            like_of: first("like-of"),
+            repost_of: first("repost-of"),
+        })
+    }
+}
+
+fn query_params(context: &Ctx) -> Vec<(String, String)> {
+    context
+        .hyper_request
+        .as_ref()
+        .and_then(|request| request.request.uri().query())
+        .map(|query| {
+            form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn respond_json(mut context: Ctx, status: u16, body: Value) -> MiddlewareResult<Ctx> {
+    context.status(status);
+    context.set("Content-Type", "application/json");
+    context.body(&body.to_string());
+
+    Ok(context)
+}
+
+/// Micropub errors are JSON bodies rather than the usual error pages, so that
+/// posting clients can show them.
+fn invalid_request(context: Ctx, description: &str) -> MiddlewareResult<Ctx> {
+    respond_json(
+        context,
+        400,
+        json!({ "error": "invalid_request", "error_description": description }),
+    )
+}
+
+/// Micropub clients may send their token as an `access_token` form field
+/// rather than in `Authorization`. Goes after `fetch_user_from_api_token`.
+#[middleware_fn]
+pub async fn fetch_user_from_form_token(
+    mut context: Ctx,
+    next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let is_form = context
+        .get_header("Content-Type")
+        .pop()
+        .map(|content_type| content_type.starts_with("application/x-www-form-urlencoded"))
+        .unwrap_or(false);
+
+    if context.extra.user.is_none() && is_form {
+        let body = request_body(&mut context).await.or_app_error(&context)?;
+        let token = form_urlencoded::parse(&body)
+            .find(|(key, _)| key == "access_token")
+            .map(|(_, token)| token.into_owned());
+
+        if let Some(token) = token {
+            sign_in_with_api_token(&mut context, &token).await;
+        }
+    }
+
+    next(context).await
+}
+
+/// Answers `q=config`, `q=syndicate-to` and `q=source` queries.
+#[middleware_fn]
+pub async fn micropub_query(context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let params = query_params(&context);
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    match param("q") {
+        Some("config") => respond_json(
+            context,
+            200,
+            json!({ "q": ["config", "source", "syndicate-to"], "syndicate-to": [] }),
+        ),
+        Some("syndicate-to") => respond_json(context, 200, json!({ "syndicate-to": [] })),
+        Some("source") => {
+            let tweet_id = match param("url").and_then(tweet_id_from_url) {
+                Some(tweet_id) => tweet_id,
+                None => return invalid_request(context, "Expected the url of a tweet"),
+            };
+
+            let tweet = context
+                .extra
+                .stores
+                .tweets
+                .get_tweet(&tweet_id)
+                .await
+                .or_app_error(&context)?;
+
+            let base_url = base_url(&context);
+            let mut properties = json!({
+                "content": [tweet.content],
+                "published": [tweet.created_at.to_rfc3339()],
+                "url": [tweet_url(&base_url, &tweet.id)],
+            });
+            if let Some(responding_to) = tweet.responding_to {
+                properties["in-reply-to"] = json!([tweet_url(&base_url, &responding_to)]);
+            }
+
+            // Clients may ask for a subset with `properties[]=content`.
+            let wanted: Vec<&str> = params
+                .iter()
+                .filter(|(key, _)| key == "properties" || key == "properties[]")
+                .map(|(_, value)| value.as_str())
+                .collect();
+            if !wanted.is_empty() {
+                if let Value::Object(all) = &mut properties {
+                    all.retain(|name, _| wanted.contains(&name.as_str()));
+                }
+
+                return respond_json(context, 200, json!({ "properties": properties }));
+            }
+
+            respond_json(
+                context,
+                200,
+                json!({ "type": ["h-entry"], "properties": properties }),
+            )
+        }
+        _ => invalid_request(context, "Unsupported query"),
+    }
+}
+
+/// Creates an `h-entry`: a like for `like-of`, a retweet for `repost-of`, and
+/// otherwise a tweet, replying when `in-reply-to` is set.
+#[middleware_fn]
+pub async fn micropub_create(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let is_json = context
+        .get_header("Content-Type")
+        .pop()
+        .map(|content_type| content_type.starts_with("application/json"))
+        .unwrap_or(false);
+    let body = context
+        .body_string()
+        .await
+        .map_err(AppError::bad_request)
+        .or_app_error(&context)?;
+
+    let entry = if is_json {
+        Entry::from_json(&body)
+    } else {
+        Entry::from_form(&body)
+    };
+    let entry = match entry {
+        Ok(entry) => entry,
+        Err(description) => return invalid_request(context, description),
+    };
+
+    let user_id = signed_in_user(&context)?.id;
+    let may_create = context
+        .extra
+        .api_token
+        .as_ref()
+        .map(|api_token| api_token.has_scope("create"))
+        .unwrap_or(false);
+    if !may_create {
+        return respond_json(
+            context,
+            403,
+            json!({
+                "error": "insufficient_scope",
+                "error_description": "The token needs the create scope",
+                "scope": "create",
+            }),
+        );
+    }
+    let base_url = base_url(&context);
+
+    let location = if let Some(like_of) = entry.like_of {
+        let tweet_id = match tweet_id_from_url(&like_of) {
+            Some(tweet_id) => tweet_id,
+            None => return invalid_request(context, "like-of must be the url of a tweet"),
+        };
+
+        context
+            .extra
+            .stores
+            .social_graph
+            .like(&tweet_id, &user_id)
+            .await
+            .or_app_error(&context)?;
+        context.extra.metrics.likes.inc();
+
+        tweet_url(&base_url, &tweet_id)
+    } else if let Some(repost_of) = entry.repost_of {
+        let tweet_id = match tweet_id_from_url(&repost_of) {
+            Some(tweet_id) => tweet_id,
+            None => return invalid_request(context, "repost-of must be the url of a tweet"),
+        };
+
+        context
+            .extra
+            .stores
+            .social_graph
+            .retweet(&tweet_id, &user_id)
+            .await
+            .or_app_error(&context)?;
+        context.extra.metrics.retweets.inc();
+
+        tweet_url(&base_url, &tweet_id)
+    } else {
+        let content = match entry.content.filter(|content| !content.trim().is_empty()) {
+            Some(content) if content.chars().count() <= MAX_CONTENT_LENGTH => content,
+            Some(_) => return invalid_request(context, "content is longer than 280 characters"),
+            None => return invalid_request(context, "content is required"),
+        };
+        let responding_to = match entry.in_reply_to.as_deref().map(tweet_id_from_url) {
+            Some(None) => {
+                return invalid_request(context, "in-reply-to must be the url of a tweet")
+            }
+            Some(tweet_id) => tweet_id,
+            None => None,
+        };
+
+        let tweet = context
+            .extra
+            .stores
+            .tweets
+            .create_tweet(&user_id, responding_to, content)
+            .await
+            .or_app_error(&context)?;
+        context.extra.metrics.tweets_created.inc();
+        let url = tweet_url(&base_url, &tweet.id);
+
+        context
+            .extra
+            .webmentions
+            .send_later(url.clone(), tweet.content);
+
+        url
+    };
+
+    context.status(201);
+    context.set("Location", &location);
+
+    Ok(context)
+}
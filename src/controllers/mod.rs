@@ -1,3 +1,11 @@
+pub mod feeds;
+pub mod health;
+pub mod live;
+pub mod metrics;
+pub mod micropub;
 pub mod pages;
+pub mod streaming;
 pub mod tweets;
 pub mod users;
+pub mod webhooks;
+pub mod webmentions;
@@ -1,20 +1,16 @@
 use std::str::FromStr;
 
 use askama::Template;
-use log::{error, info};
 use thruster::{
-    context::context_ext::ContextExt,
-    errors::{ErrorSet, ThrusterError},
-    middleware_fn, Context, MiddlewareNext, MiddlewareResult,
+    context::context_ext::ContextExt, middleware_fn, Context, MiddlewareNext, MiddlewareResult,
 };
 use uuid::Uuid;
 
 use crate::{
     app::Ctx,
-    models::{
-        tweets::{Tweet, TweetWithUserInfo},
-        users::User,
-    },
+    errors::{AppError, OrAppError},
+    idempotency,
+    models::{tweets::TweetWithUserInfo, users::User, webmentions::Webmention},
 };
 
 #[derive(Template)]
@@ -24,7 +20,13 @@ pub struct SignUp;
 #[middleware_fn]
 pub async fn signup(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
     context.set("Content-Type", "text/html");
-    context.body(&SignUp.render().unwrap());
+    context.body(
+        &context
+            .extra
+            .metrics
+            .render(&SignUp)
+            .or_app_error(&context)?,
+    );
 
     Ok(context)
 }
@@ -36,7 +38,13 @@ pub struct SignIn;
 #[middleware_fn]
 pub async fn signin(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
     context.set("Content-Type", "text/html");
-    context.body(&SignIn.render().unwrap());
+    context.body(
+        &context
+            .extra
+            .metrics
+            .render(&SignIn)
+            .or_app_error(&context)?,
+    );
 
     Ok(context)
 }
@@ -46,6 +54,7 @@ pub async fn signin(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareR
 pub struct Feed<'a> {
     user: Option<&'a User>,
     feed: Vec<TweetWithUserInfo>,
+    idempotency_key: String,
 }
 
 #[middleware_fn]
@@ -55,22 +64,21 @@ pub async fn home(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareRes
 
     context.set("Content-Type", "text/html");
     context.body(
-        &Feed {
-            user: user.as_ref(),
-            feed: Tweet::get_recent_tweets_with_user_info(
-                &context.extra.pool,
-                user_id.as_ref(),
-                None,
-            )
-            .await
-            .map_err(|_e| {
-                error!("_e: {:#?}", _e);
-
-                ThrusterError::generic_error(Ctx::new_without_request(context.extra.clone()))
-            })?,
-        }
-        .render()
-        .unwrap(),
+        &context
+            .extra
+            .metrics
+            .render(&Feed {
+                user: user.as_ref(),
+                feed: context
+                    .extra
+                    .stores
+                    .tweets
+                    .get_recent_tweets(user_id.as_ref(), None)
+                    .await
+                    .or_app_error(&context)?,
+                idempotency_key: idempotency::new_key(),
+            })
+            .or_app_error(&context)?,
     );
 
     Ok(context)
@@ -81,6 +89,7 @@ pub async fn home(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareRes
 pub struct ReplyTo {
     user: User,
     tweet: TweetWithUserInfo,
+    idempotency_key: String,
 }
 
 #[middleware_fn]
@@ -89,19 +98,34 @@ pub async fn reply(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareRe
         .params()
         .get("id")
         .and_then(|id_string| Uuid::from_str(&id_string.param).ok())
-        .ok_or(ThrusterError::generic_error(Ctx::new_without_request(
-            context.extra.clone(),
-        )))?;
-    let user = context.extra.user.take().unwrap();
-    let tweet = Tweet::get_tweet_with_user_info(&context.extra.pool, &tweet_id, Some(&user.id))
+        .ok_or(AppError::NotFound)
+        .or_app_error(&context)?;
+    let user = context
+        .extra
+        .user
+        .take()
+        .ok_or(AppError::Unauthorized)
+        .or_app_error(&context)?;
+    let tweet = context
+        .extra
+        .stores
+        .tweets
+        .get_tweet_with_user_info(&tweet_id, Some(&user.id))
         .await
-        .map_err(|_e| {
-            error!("_e: {:#?}", _e);
-            ThrusterError::generic_error(Ctx::new_without_request(context.extra.clone()))
-        })?;
+        .or_app_error(&context)?;
 
     context.set("Content-Type", "text/html");
-    context.body(&ReplyTo { user, tweet }.render().unwrap());
+    context.body(
+        &context
+            .extra
+            .metrics
+            .render(&ReplyTo {
+                user,
+                tweet,
+                idempotency_key: idempotency::new_key(),
+            })
+            .or_app_error(&context)?,
+    );
 
     Ok(context)
 }
@@ -112,46 +136,53 @@ pub struct SingleTweet<'a> {
     user: Option<&'a User>,
     tweet: TweetWithUserInfo,
     replies: Vec<TweetWithUserInfo>,
+    reactions: Vec<Webmention>,
 }
 
 #[middleware_fn]
 pub async fn single_tweet(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
-    let user_id = context.extra.user.as_ref().map(|v| v.id.clone());
+    let user_id = context.extra.user.as_ref().map(|v| v.id);
 
     let tweet_id = context
         .params()
         .get("id")
         .and_then(|id_string| Uuid::from_str(&id_string.param).ok())
-        .ok_or(ThrusterError::generic_error(Ctx::new_without_request(
-            context.extra.clone(),
-        )))?;
-    let tweet = Tweet::get_tweet_with_user_info(&context.extra.pool, &tweet_id, user_id.as_ref())
+        .ok_or(AppError::NotFound)
+        .or_app_error(&context)?;
+    let tweet = context
+        .extra
+        .stores
+        .tweets
+        .get_tweet_with_user_info(&tweet_id, user_id.as_ref())
+        .await
+        .or_app_error(&context)?;
+    let replies = context
+        .extra
+        .stores
+        .tweets
+        .get_replies(&tweet_id, user_id.as_ref(), None)
         .await
-        .map_err(|_e| {
-            error!("_e: {:#?}", _e);
-            ThrusterError::generic_error(Ctx::new_without_request(context.extra.clone()))
-        })?;
-    let replies = Tweet::get_tweet_replies_with_user_info(
-        &context.extra.pool,
-        &tweet_id,
-        user_id.as_ref(),
-        None,
-    )
-    .await
-    .map_err(|_e| {
-        error!("_e: {:#?}", _e);
-        ThrusterError::generic_error(Ctx::new_without_request(context.extra.clone()))
-    })?;
+        .or_app_error(&context)?;
+    let reactions = match context.extra.database.postgres() {
+        Some(pool) => Webmention::get_verified_webmentions_for_tweet(pool, &tweet_id)
+            .await
+            .or_app_error(&context)?,
+        None => vec![],
+    };
 
     context.set("Content-Type", "text/html");
+    context.set("Link", "</webmention>; rel=\"webmention\"");
     context.body(
-        &SingleTweet {
-            user: context.extra.user.as_ref(),
-            tweet,
-            replies,
-        }
-        .render()
-        .unwrap(),
+        &context
+            .extra
+            .metrics
+            .render(&SingleTweet {
+                user: context.extra.user.as_ref(),
+                tweet,
+                replies,
+                reactions,
+            })
+            .or_app_error(&context)?,
     );
 
     Ok(context)
@@ -163,8 +194,12 @@ pub struct UserPage<'a> {
     user: Option<&'a User>,
     page_user: User,
     feed: Vec<TweetWithUserInfo>,
-    following_count: u64,
-    foller_count: u64,
+    following_count: usize,
+    follower_count: i64,
+    /// Whether the page is the signed in user's own, which gets the form for
+    /// tweeting.
+    is_own_page: bool,
+    idempotency_key: String,
 }
 
 #[middleware_fn]
@@ -176,34 +211,52 @@ pub async fn user_page(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> Middlewa
         .params()
         .get("id")
         .and_then(|id_string| Uuid::from_str(&id_string.param).ok())
-        .ok_or(ThrusterError::generic_error(Ctx::new_without_request(
-            context.extra.clone(),
-        )))?;
-    let page_user = User::get_user_for_id(&context.extra.pool, &page_user_id)
+        .ok_or(AppError::NotFound)
+        .or_app_error(&context)?;
+    let page_user = context
+        .extra
+        .stores
+        .users
+        .get_user(&page_user_id)
         .await
-        .map_err(|_e| {
-            error!("_e: {:#?}", _e);
-            ThrusterError::generic_error(Ctx::new_without_request(context.extra.clone()))
-        })?;
+        .or_app_error(&context)?;
+    let following_count = context
+        .extra
+        .stores
+        .social_graph
+        .get_following_ids(&page_user_id)
+        .await
+        .or_app_error(&context)?
+        .len();
+    let follower_count = context
+        .extra
+        .stores
+        .social_graph
+        .get_follower_count(&page_user_id)
+        .await
+        .or_app_error(&context)?;
 
     context.set("Content-Type", "text/html");
     context.body(
-        &UserPage {
-            user: user.as_ref(),
-            feed: Tweet::get_recent_tweets_with_user_info(
-                &context.extra.pool,
-                user_id.as_ref(),
-                None,
-            )
-            .await
-            .map_err(|_e| {
-                error!("_e: {:#?}", _e);
-                ThrusterError::generic_error(Ctx::new_without_request(context.extra.clone()))
-            })?,
-            page_user,
-        }
-        .render()
-        .unwrap(),
+        &context
+            .extra
+            .metrics
+            .render(&UserPage {
+                user: user.as_ref(),
+                feed: context
+                    .extra
+                    .stores
+                    .tweets
+                    .get_recent_tweets_for_author(&page_user_id, user_id.as_ref(), None)
+                    .await
+                    .or_app_error(&context)?,
+                following_count,
+                follower_count,
+                is_own_page: user_id == Some(page_user.id),
+                page_user,
+                idempotency_key: idempotency::new_key(),
+            })
+            .or_app_error(&context)?,
     );
 
     Ok(context)
@@ -0,0 +1,421 @@
+use std::{collections::HashSet, str::FromStr, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use hyper::upgrade::Upgraded;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use thruster::{
+    middleware::cookies::HasCookies, middleware_fn, Context, MiddlewareNext, MiddlewareResult,
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc, oneshot},
+    time::{interval, Instant},
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::derive_accept_key,
+        protocol::{frame::coding::CloseCode, CloseFrame, Role, WebSocketConfig},
+        Message,
+    },
+    WebSocketStream,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::Ctx,
+    controllers::users::signed_in_user,
+    errors::{AppError, OrAppError},
+    events::{Event, EventKind, Events},
+    health::Health,
+    models::users::User,
+};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A client that hasn't answered a ping in this long is considered gone.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Messages queued for a client before it is disconnected for reading too
+/// slowly. It can reconnect and resume from its last event id.
+const MAX_QUEUED_MESSAGES: usize = 256;
+
+const MAX_INCOMING_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Events held for a resuming client that has yet to subscribe.
+const MAX_PENDING_EVENTS: usize = 1024;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "stream", content = "hashtag")]
+pub enum StreamName {
+    /// Tweets from the user and the accounts they follow.
+    Timeline,
+    /// Tweets that @-mention the user.
+    Mentions,
+    /// Likes, retweets and replies to the user's tweets, and new followers.
+    Notifications,
+    Hashtag(String),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe { streams: Vec<StreamName> },
+    Unsubscribe { streams: Vec<StreamName> },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage<'a> {
+    Event {
+        id: &'a Uuid,
+        stream: &'a StreamName,
+        event: &'a EventKind,
+    },
+    Subscribed {
+        streams: Vec<&'a StreamName>,
+    },
+    /// The requested `last_event_id` is too old to resume from.
+    ResyncRequired,
+    Error {
+        message: &'a str,
+    },
+}
+
+impl<'a> ServerMessage<'a> {
+    fn to_message(&self) -> Message {
+        Message::Text(serde_json::to_string(self).unwrap_or_default())
+    }
+}
+
+struct Connection {
+    user: User,
+    following: HashSet<Uuid>,
+    streams: HashSet<StreamName>,
+}
+
+impl Connection {
+    /// The stream `event` should be delivered on, if any.
+    fn route(&self, event: &EventKind) -> Option<StreamName> {
+        let wants = |stream: &StreamName| self.streams.contains(stream);
+        let user_id = &self.user.id;
+
+        match event {
+            EventKind::TweetCreated {
+                user_id: author_id,
+                responding_to_user_id,
+                mentions,
+                hashtags,
+                ..
+            } => {
+                if wants(&StreamName::Timeline)
+                    && (author_id == user_id || self.following.contains(author_id))
+                {
+                    return Some(StreamName::Timeline);
+                }
+                if wants(&StreamName::Mentions)
+                    && mentions
+                        .iter()
+                        .any(|username| username.eq_ignore_ascii_case(&self.user.username))
+                {
+                    return Some(StreamName::Mentions);
+                }
+                if wants(&StreamName::Notifications)
+                    && responding_to_user_id.as_ref() == Some(user_id)
+                    && author_id != user_id
+                {
+                    return Some(StreamName::Notifications);
+                }
+
+                hashtags
+                    .iter()
+                    .map(|hashtag| StreamName::Hashtag(hashtag.clone()))
+                    .find(|stream| wants(stream))
+            }
+            EventKind::TweetLiked {
+                tweet_user_id,
+                user_id: actor_id,
+                ..
+            }
+            | EventKind::TweetRetweeted {
+                tweet_user_id,
+                user_id: actor_id,
+                ..
+            } if wants(&StreamName::Notifications)
+                && tweet_user_id == user_id
+                && actor_id != user_id =>
+            {
+                Some(StreamName::Notifications)
+            }
+            EventKind::UserFollowed { following_id, .. }
+                if wants(&StreamName::Notifications) && following_id == user_id =>
+            {
+                Some(StreamName::Notifications)
+            }
+            _ => None,
+        }
+    }
+
+    fn handle(&mut self, text: &str) -> Message {
+        match serde_json::from_str::<ClientMessage>(text) {
+            Ok(ClientMessage::Subscribe { streams }) => {
+                for stream in streams {
+                    if let StreamName::Hashtag(hashtag) = stream {
+                        self.streams
+                            .insert(StreamName::Hashtag(hashtag.to_lowercase()));
+                    } else {
+                        self.streams.insert(stream);
+                    }
+                }
+            }
+            Ok(ClientMessage::Unsubscribe { streams }) => {
+                for stream in streams {
+                    self.streams.remove(&stream);
+                }
+            }
+            Err(_e) => {
+                return ServerMessage::Error {
+                    message: "Unrecognized message",
+                }
+                .to_message()
+            }
+        }
+
+        ServerMessage::Subscribed {
+            streams: self.streams.iter().collect(),
+        }
+        .to_message()
+    }
+
+    /// Also keeps `following` current, so that the timeline picks up accounts
+    /// followed since connecting.
+    fn deliver(&mut self, event: &Event) -> Option<Message> {
+        if let EventKind::UserFollowed {
+            follower_id,
+            following_id,
+        } = &event.kind
+        {
+            if follower_id == &self.user.id {
+                self.following.insert(*following_id);
+            }
+        }
+
+        self.route(&event.kind).map(|stream| {
+            ServerMessage::Event {
+                id: &event.id,
+                stream: &stream,
+                event: &event.kind,
+            }
+            .to_message()
+        })
+    }
+}
+
+fn close(code: CloseCode, reason: &'static str) -> Message {
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    }))
+}
+
+/// Writes queued messages until the queue closes, or sends `close` straight
+/// away when asked to, skipping anything still queued.
+async fn write(
+    mut sink: futures::stream::SplitSink<WebSocketStream<Upgraded>, Message>,
+    mut queue: mpsc::Receiver<Message>,
+    mut close: oneshot::Receiver<Message>,
+) {
+    loop {
+        tokio::select! {
+            biased;
+            frame = &mut close => {
+                if let Ok(frame) = frame {
+                    let _ = sink.send(frame).await;
+                }
+                break;
+            }
+            message = queue.recv() => match message {
+                Some(message) => {
+                    if sink.send(message).await.is_err() {
+                        break;
+                    }
+                }
+                None => break,
+            },
+        }
+    }
+
+    let _ = sink.close().await;
+}
+
+async fn serve(
+    socket: WebSocketStream<Upgraded>,
+    mut connection: Connection,
+    events: Events,
+    health: Health,
+    last_event_id: Option<Uuid>,
+) {
+    let (sink, mut source) = socket.split();
+    let (queue, queued) = mpsc::channel(MAX_QUEUED_MESSAGES);
+    let (close_sender, close_receiver) = oneshot::channel();
+    let writer = tokio::spawn(write(sink, queued, close_receiver));
+
+    let mut resumed = events.resume(last_event_id.as_ref());
+    let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+    let mut last_heard = Instant::now();
+
+    // Which of the missed events to send depends on the streams, which the
+    // client only names once it has reconnected, so they wait for its first
+    // subscribe, along with anything that arrives in the meantime.
+    let mut pending = last_event_id.map(|_| std::mem::take(&mut resumed.missed));
+    let mut backlog: Vec<Message> = vec![];
+    if !resumed.complete {
+        backlog.push(ServerMessage::ResyncRequired.to_message());
+    }
+
+    let outcome = 'connection: loop {
+        for message in backlog.drain(..) {
+            if queue.try_send(message).is_err() {
+                break 'connection Some(close(CloseCode::Again, "Too many queued messages"));
+            }
+        }
+
+        tokio::select! {
+            incoming = source.next() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    last_heard = Instant::now();
+                    backlog.push(connection.handle(&text));
+
+                    if !connection.streams.is_empty() {
+                        if let Some(missed) = pending.take() {
+                            backlog.extend(
+                                missed.iter().filter_map(|event| connection.deliver(event)),
+                            );
+                        }
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => break None,
+                Some(Ok(_)) => last_heard = Instant::now(),
+                Some(Err(_e)) => {
+                    warn!("Closing websocket: {:#?}", _e);
+                    break None;
+                }
+            },
+            received = resumed.receiver.recv() => match received {
+                Ok(event) => match pending.as_mut() {
+                    Some(missed) if missed.len() >= MAX_PENDING_EVENTS => {
+                        break Some(close(CloseCode::Again, "Fell too far behind"));
+                    }
+                    Some(missed) => missed.push(event),
+                    None => backlog.extend(connection.deliver(&event)),
+                },
+                Err(RecvError::Lagged(_)) => {
+                    break Some(close(CloseCode::Again, "Fell too far behind"));
+                }
+                Err(RecvError::Closed) => break Some(close(CloseCode::Restart, "Shutting down")),
+            },
+            _ = health.shutdown_begun() => break Some(close(CloseCode::Restart, "Shutting down")),
+            _ = heartbeat.tick() => {
+                if last_heard.elapsed() > HEARTBEAT_TIMEOUT {
+                    break Some(close(CloseCode::Policy, "Heartbeat timeout"));
+                }
+                backlog.push(Message::Ping(vec![]));
+            }
+        }
+    };
+
+    match outcome {
+        Some(frame) => {
+            let _ = close_sender.send(frame);
+        }
+        None => drop(queue),
+    }
+
+    if let Err(_e) = writer.await {
+        error!("_e: {:#?}", _e);
+    }
+}
+
+/// Upgrades to a WebSocket carrying the timeline, mentions, notifications and
+/// hashtag streams of the token's owner. Pass `last_event_id` to resume after a
+/// reconnect; the events missed on the streams the client subscribes to are
+/// sent after its first subscribe.
+#[middleware_fn]
+pub async fn streaming(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let key = context
+        .get_header("Sec-WebSocket-Key")
+        .pop()
+        .filter(|_| {
+            context
+                .get_header("Upgrade")
+                .iter()
+                .any(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+        })
+        .ok_or(AppError::Validation(
+            "Expected a websocket upgrade".to_string(),
+        ))
+        .or_app_error(&context)?;
+
+    let last_event_id = context
+        .hyper_request
+        .as_ref()
+        .and_then(|request| request.request.uri().query())
+        .and_then(|query| {
+            form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "last_event_id")
+                .and_then(|(_, id)| Uuid::from_str(&id).ok())
+        });
+
+    let user = signed_in_user(&context)?.clone();
+    let following = context
+        .extra
+        .stores
+        .social_graph
+        .get_following_ids(&user.id)
+        .await
+        .or_app_error(&context)?
+        .into_iter()
+        .collect();
+    let connection = Connection {
+        user,
+        following,
+        streams: HashSet::new(),
+    };
+
+    let upgrade = context
+        .hyper_request
+        .as_mut()
+        .map(|request| hyper::upgrade::on(&mut request.request))
+        .ok_or(AppError::Internal(
+            "The request has no body to upgrade".to_string(),
+        ))
+        .or_app_error(&context)?;
+    let events = context.extra.events.clone();
+    let health = context.extra.health.clone();
+
+    tokio::spawn(async move {
+        match upgrade.await {
+            Ok(upgraded) => {
+                let socket = WebSocketStream::from_raw_socket(
+                    upgraded,
+                    Role::Server,
+                    Some(WebSocketConfig {
+                        max_message_size: Some(MAX_INCOMING_MESSAGE_SIZE),
+                        max_frame_size: Some(MAX_INCOMING_MESSAGE_SIZE),
+                        ..WebSocketConfig::default()
+                    }),
+                )
+                .await;
+
+                serve(socket, connection, events, health, last_event_id).await;
+            }
+            Err(_e) => error!("_e: {:#?}", _e),
+        }
+    });
+
+    context.status(101);
+    context.set("Upgrade", "websocket");
+    context.set("Connection", "Upgrade");
+    context.set("Sec-WebSocket-Accept", &derive_accept_key(key.as_bytes()));
+
+    Ok(context)
+}
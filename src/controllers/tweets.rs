@@ -1,18 +1,17 @@
 use std::str::FromStr;
 
-use log::error;
 use serde::Deserialize;
 use thruster::{
-    context::context_ext::ContextExt,
-    errors::{ErrorSet, ThrusterError},
-    middleware::cookies::HasCookies,
-    middleware_fn, MiddlewareNext, MiddlewareResult,
+    context::context_ext::ContextExt, middleware::cookies::HasCookies, middleware_fn,
+    MiddlewareNext, MiddlewareResult,
 };
 use uuid::Uuid;
 
 use crate::{
     app::Ctx,
-    models::{likes::Like, retweets::Retweet, tweets::Tweet},
+    controllers::users::signed_in_user,
+    errors::{AppError, OrAppError},
+    urls::{base_url, tweet_url},
 };
 
 #[derive(Deserialize)]
@@ -22,28 +21,30 @@ pub struct CreateTweetReq {
 
 #[middleware_fn]
 pub async fn create_tweet(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
-    let CreateTweetReq { content } =
-        serde_urlencoded::from_str(&context.body_string().await.map_err(|_e| {
-            ThrusterError::parsing_error(
-                Ctx::new_without_request(context.extra.clone()),
-                "Bad request",
-            )
-        })?)
-        .map_err(|_e| {
-            ThrusterError::parsing_error(
-                Ctx::new_without_request(context.extra.clone()),
-                "Bad request",
-            )
-        })?;
-
-    Tweet::create_tweet(
-        &context.extra.pool,
-        &context.extra.user.as_ref().unwrap().id,
-        None,
-        content,
+    let CreateTweetReq { content } = serde_urlencoded::from_str(
+        &context
+            .body_string()
+            .await
+            .map_err(AppError::bad_request)
+            .or_app_error(&context)?,
     )
-    .await
-    .map_err(|_e| ThrusterError::generic_error(Ctx::new_without_request(context.extra.clone())))?;
+    .map_err(AppError::bad_request)
+    .or_app_error(&context)?;
+
+    let user_id = signed_in_user(&context)?.id;
+    let tweet = context
+        .extra
+        .stores
+        .tweets
+        .create_tweet(&user_id, None, content)
+        .await
+        .or_app_error(&context)?;
+    context.extra.metrics.tweets_created.inc();
+
+    context
+        .extra
+        .webmentions
+        .send_later(tweet_url(&base_url(&context), &tweet.id), tweet.content);
 
     context.redirect("/");
 
@@ -61,39 +62,33 @@ pub async fn reply(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareRe
         .params()
         .get("id")
         .and_then(|id_string| Uuid::from_str(&id_string.param).ok())
-        .ok_or(ThrusterError::generic_error(Ctx::new_without_request(
-            context.extra.clone(),
-        )))?;
-
-    let ReplyReq { content } =
-        serde_urlencoded::from_str(&context.body_string().await.map_err(|_e| {
-            error!("_e: {:#?}", _e);
-
-            ThrusterError::parsing_error(
-                Ctx::new_without_request(context.extra.clone()),
-                "Bad request",
-            )
-        })?)
-        .map_err(|_e| {
-            error!("_e: {:#?}", _e);
-
-            ThrusterError::parsing_error(
-                Ctx::new_without_request(context.extra.clone()),
-                "Bad request",
-            )
-        })?;
-
-    Tweet::create_tweet(
-        &context.extra.pool,
-        &context.extra.user.as_ref().unwrap().id,
-        Some(responding_to),
-        content,
+        .ok_or(AppError::NotFound)
+        .or_app_error(&context)?;
+
+    let ReplyReq { content } = serde_urlencoded::from_str(
+        &context
+            .body_string()
+            .await
+            .map_err(AppError::bad_request)
+            .or_app_error(&context)?,
     )
-    .await
-    .map_err(|_e| {
-        error!("_e: {:#?}", _e);
-        ThrusterError::generic_error(Ctx::new_without_request(context.extra.clone()))
-    })?;
+    .map_err(AppError::bad_request)
+    .or_app_error(&context)?;
+
+    let user_id = signed_in_user(&context)?.id;
+    let tweet = context
+        .extra
+        .stores
+        .tweets
+        .create_tweet(&user_id, Some(responding_to), content)
+        .await
+        .or_app_error(&context)?;
+    context.extra.metrics.tweets_created.inc();
+
+    context
+        .extra
+        .webmentions
+        .send_later(tweet_url(&base_url(&context), &tweet.id), tweet.content);
 
     context.redirect("/");
 
@@ -106,20 +101,18 @@ pub async fn like_tweet(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> Middlew
         .params()
         .get("id")
         .and_then(|id_string| Uuid::from_str(&id_string.param).ok())
-        .ok_or(ThrusterError::generic_error(Ctx::new_without_request(
-            context.extra.clone(),
-        )))?;
-
-    Like::create_like(
-        &context.extra.pool,
-        &tweet_id,
-        &context.extra.user.as_ref().unwrap().id,
-    )
-    .await
-    .map_err(|_e| {
-        error!("_e: {:#?}", _e);
-        ThrusterError::generic_error(Ctx::new_without_request(context.extra.clone()))
-    })?;
+        .ok_or(AppError::NotFound)
+        .or_app_error(&context)?;
+
+    let user_id = signed_in_user(&context)?.id;
+    context
+        .extra
+        .stores
+        .social_graph
+        .like(&tweet_id, &user_id)
+        .await
+        .or_app_error(&context)?;
+    context.extra.metrics.likes.inc();
 
     let location = context
         .get_header("Referer")
@@ -138,20 +131,18 @@ pub async fn retweet(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> Middleware
         .params()
         .get("id")
         .and_then(|id_string| Uuid::from_str(&id_string.param).ok())
-        .ok_or(ThrusterError::generic_error(Ctx::new_without_request(
-            context.extra.clone(),
-        )))?;
-
-    Retweet::create_retweet(
-        &context.extra.pool,
-        &tweet_id,
-        &context.extra.user.as_ref().unwrap().id,
-    )
-    .await
-    .map_err(|_e| {
-        error!("_e: {:#?}", _e);
-        ThrusterError::generic_error(Ctx::new_without_request(context.extra.clone()))
-    })?;
+        .ok_or(AppError::NotFound)
+        .or_app_error(&context)?;
+
+    let user_id = signed_in_user(&context)?.id;
+    context
+        .extra
+        .stores
+        .social_graph
+        .retweet(&tweet_id, &user_id)
+        .await
+        .or_app_error(&context)?;
+    context.extra.metrics.retweets.inc();
 
     let location = context
         .get_header("Referer")
@@ -2,20 +2,54 @@ use argon2::{
     password_hash::{rand_core::OsRng, SaltString},
     Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
 };
-use log::error;
 use serde::Deserialize;
 use thruster::{
-    errors::{ErrorSet, ThrusterError},
-    middleware::cookies::CookieOptions,
-    middleware_fn, MiddlewareNext, MiddlewareResult,
+    errors::ThrusterError,
+    middleware::cookies::{CookieOptions, HasCookies, SameSite},
+    middleware_fn, Context, MiddlewareNext, MiddlewareResult,
 };
 use uuid::Uuid;
 
 use crate::{
-    app::Ctx,
-    models::{sessions::Session, users::User},
+    app::{postgres, Ctx},
+    config::CookieConfig,
+    errors::{AppError, OrAppError},
+    models::{
+        api_tokens::{ApiToken, DEFAULT_SCOPES, SCOPES},
+        sessions::SessionWithUser,
+        users::User,
+    },
+    stores::StoreError,
+    telemetry::record_user,
+    urls::local_redirect,
 };
 
+/// Options for the session cookie, from the `[cookies]` config.
+fn session_cookie_options(config: &CookieConfig) -> CookieOptions {
+    CookieOptions {
+        http_only: true,
+        secure: config.secure,
+        domain: config.domain.clone().unwrap_or_default(),
+        same_site: Some(if config.same_site.eq_ignore_ascii_case("strict") {
+            SameSite::Strict
+        } else {
+            SameSite::Lax
+        }),
+        ..CookieOptions::default()
+    }
+}
+
+/// Whether `password` matches the stored `hash`. A hash that doesn't parse is
+/// an error rather than a failed sign in, since no password could match it.
+pub fn verify_password(password: &str, hash: &str) -> Result<bool, AppError> {
+    let hash = PasswordHash::new(hash)
+        .map_err(|e| AppError::Internal(format!("Unreadable password hash: {}", e)))?;
+
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok())
+}
+
 #[derive(Deserialize)]
 pub struct CreatUserReq {
     pub username: String,
@@ -24,61 +58,48 @@ pub struct CreatUserReq {
 
 #[middleware_fn]
 pub async fn create_user(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
-    let CreatUserReq { username, password } =
-        serde_urlencoded::from_str(&context.body_string().await.map_err(|_e| {
-            error!("_e: {:#?}", _e);
-            ThrusterError::parsing_error(
-                Ctx::new_without_request(context.extra.clone()),
-                "Bad request",
-            )
-        })?)
-        .map_err(|_e| {
-            error!("_e: {:#?}", _e);
-            ThrusterError::parsing_error(
-                Ctx::new_without_request(context.extra.clone()),
-                "Bad request",
-            )
-        })?;
+    let CreatUserReq { username, password } = serde_urlencoded::from_str(
+        &context
+            .body_string()
+            .await
+            .map_err(AppError::bad_request)
+            .or_app_error(&context)?,
+    )
+    .map_err(AppError::bad_request)
+    .or_app_error(&context)?;
 
     let salt = SaltString::generate(&mut OsRng);
     let password_hash = Argon2::default()
         .hash_password(password.as_bytes(), salt.as_ref())
         .map(|h| h.to_string())
-        .map_err(|_e| {
-            error!("_e: {:#?}", _e);
-
-            ThrusterError::parsing_error(
-                Ctx::new_without_request(context.extra.clone()),
-                "Bad request",
-            )
-        })?
+        .map_err(|e| AppError::Internal(format!("{:#?}", e)))
+        .or_app_error(&context)?
         .to_string();
 
-    let user = User::create_user(&context.extra.pool, &username, &password_hash)
+    let user = context
+        .extra
+        .stores
+        .users
+        .create_user(&username, &password_hash)
         .await
-        .map_err(|_e| {
-            error!("_e: {:#?}", _e);
+        .map_err(|e| match AppError::from(e) {
+            AppError::Conflict(_) => AppError::Conflict("That username is taken.".to_string()),
+            e => e,
+        })
+        .or_app_error(&context)?;
+    context.extra.metrics.signups.inc();
 
-            ThrusterError::generic_error(Ctx::new_without_request(context.extra.clone()))
-        })?;
-
-    let session = Session::create_session(&context.extra.pool, &user.id)
+    let session = context
+        .extra
+        .stores
+        .sessions
+        .create_session(&user.id)
         .await
-        .map_err(|_e| {
-            error!("_e: {:#?}", _e);
-
-            ThrusterError::generic_error(Ctx::new_without_request(context.extra.clone()))
-        })?;
+        .or_app_error(&context)?;
 
+    let options = session_cookie_options(&context.extra.config.cookies);
     context.redirect("/");
-    context.cookie(
-        "Session",
-        &session.token,
-        &CookieOptions {
-            http_only: true,
-            ..CookieOptions::default()
-        },
-    );
+    context.cookie("Session", &session.token, &options);
 
     Ok(context)
 }
@@ -91,58 +112,74 @@ pub struct SignInReq {
 
 #[middleware_fn]
 pub async fn sign_in_user(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
-    let SignInReq { username, password } =
-        serde_urlencoded::from_str(&context.body_string().await.map_err(|_e| {
-            error!("_e: {:#?}", _e);
-            ThrusterError::parsing_error(
-                Ctx::new_without_request(context.extra.clone()),
-                "Bad request",
-            )
-        })?)
-        .map_err(|_e| {
-            error!("_e: {:#?}", _e);
-            ThrusterError::parsing_error(
-                Ctx::new_without_request(context.extra.clone()),
-                "Bad request",
-            )
-        })?;
-
-    let user = User::get_user_for_username(&context.extra.pool, &username)
-        .await
-        .map_err(|_e| {
-            error!("_e: {:#?}", _e);
-
-            ThrusterError::generic_error(Ctx::new_without_request(context.extra.clone()))
-        })?;
-
-    if Argon2::default()
-        .verify_password(
-            password.as_bytes(),
-            &PasswordHash::new(&user.password).unwrap(),
-        )
-        .is_ok()
-    {
-        let session = Session::create_session(&context.extra.pool, &user.id)
+    let SignInReq { username, password } = serde_urlencoded::from_str(
+        &context
+            .body_string()
             .await
-            .map_err(|_e| {
-                error!("_e: {:#?}", _e);
+            .map_err(AppError::bad_request)
+            .or_app_error(&context)?,
+    )
+    .map_err(AppError::bad_request)
+    .or_app_error(&context)?;
 
-                ThrusterError::generic_error(Ctx::new_without_request(context.extra.clone()))
-            })?;
+    // An unknown username gets the same answer as a wrong password.
+    let user = context
+        .extra
+        .stores
+        .users
+        .get_user_for_username(&username)
+        .await
+        .map_err(|e| match AppError::from(e) {
+            AppError::NotFound => {
+                context.extra.metrics.sign_in_failures.inc();
 
+                AppError::Unauthorized
+            }
+            e => e,
+        })
+        .or_app_error(&context)?;
+
+    if verify_password(&password, &user.password).or_app_error(&context)? {
+        let session = context
+            .extra
+            .stores
+            .sessions
+            .create_session(&user.id)
+            .await
+            .or_app_error(&context)?;
+
+        let options = session_cookie_options(&context.extra.config.cookies);
         context.redirect("/");
-        context.cookie(
-            "Session",
-            &session.token,
-            &CookieOptions {
-                http_only: true,
-                ..CookieOptions::default()
-            },
-        );
+        context.cookie("Session", &session.token, &options);
     } else {
-        return Err(ThrusterError::unauthorized_error(context));
+        context.extra.metrics.sign_in_failures.inc();
+
+        return Err(AppError::Unauthorized.into_thruster_error(&context));
+    }
+
+    Ok(context)
+}
+
+/// Revokes the session, so that the token stops working wherever it was
+/// copied to, not just in this browser.
+#[middleware_fn]
+pub async fn sign_out_user(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    if let Some(session_token) = context.cookies.get("Session") {
+        let token = session_token.value.clone();
+
+        match context.extra.stores.sessions.revoke_session(&token).await {
+            Ok(()) | Err(StoreError::NotFound) => (),
+            Err(e) => return Err(AppError::from(e).into_thruster_error(&context)),
+        }
     }
 
+    let options = CookieOptions {
+        expires: 1,
+        ..session_cookie_options(&context.extra.config.cookies)
+    };
+    context.redirect("/");
+    context.cookie("Session", "", &options);
+
     Ok(context)
 }
 
@@ -153,21 +190,40 @@ pub struct FollowUser {
 
 #[middleware_fn]
 pub async fn follow_user(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
-    let FollowUser { user_id } =
-        serde_urlencoded::from_str(&context.body_string().await.map_err(|_e| {
-            error!("_e: {:#?}", _e);
-            ThrusterError::parsing_error(
-                Ctx::new_without_request(context.extra.clone()),
-                "Bad request",
-            )
-        })?)
-        .map_err(|_e| {
-            error!("_e: {:#?}", _e);
-            ThrusterError::parsing_error(
-                Ctx::new_without_request(context.extra.clone()),
-                "Bad request",
-            )
-        })?;
+    // Read first, as reading the body takes the request's headers with it.
+    let referer = context.get_header("Referer").pop();
+    let FollowUser { user_id } = serde_urlencoded::from_str(
+        &context
+            .body_string()
+            .await
+            .map_err(AppError::bad_request)
+            .or_app_error(&context)?,
+    )
+    .map_err(AppError::bad_request)
+    .or_app_error(&context)?;
+
+    let follower_id = signed_in_user(&context)?.id;
+
+    if follower_id == user_id {
+        return Err(
+            AppError::Validation("You can't follow yourself.".to_string())
+                .into_thruster_error(&context),
+        );
+    }
+
+    context
+        .extra
+        .stores
+        .social_graph
+        .follow(&follower_id, &user_id)
+        .await
+        .or_app_error(&context)?;
+
+    let location = referer
+        .map(|referer| local_redirect(&context, &referer))
+        .unwrap_or_else(|| "/".to_string());
+
+    context.redirect(&location);
 
     Ok(context)
 }
@@ -180,33 +236,157 @@ pub async fn fetch_user_from_cookie(
     let session_token = context.cookies.get("Session");
 
     if let Some(session_token) = session_token {
-        let session =
-            Session::get_session_from_token(&context.extra.pool, &session_token.value).await;
+        let signed_in = context
+            .extra
+            .stores
+            .sessions
+            .get_session_with_user(&session_token.value)
+            .await;
 
-        if let Ok(session) = session {
-            context.extra.user = User::get_user_for_id(&context.extra.pool, &session.user_id)
-                .await
-                .ok();
+        if let Ok(SessionWithUser { session, user }) = signed_in {
+            context.extra.user = Some(user);
+            context.extra.session = Some(session);
+            record_user(&context);
         } else {
-            context.cookie(
-                "Session",
-                "",
-                &CookieOptions {
-                    http_only: true,
-                    expires: 1,
-                    ..CookieOptions::default()
-                },
-            );
+            let options = CookieOptions {
+                expires: 1,
+                ..session_cookie_options(&context.extra.config.cookies)
+            };
+            context.cookie("Session", "", &options);
+        }
+    }
+
+    next(context).await
+}
+
+/// The token from `Authorization: Bearer <token>`, or, on WebSocket upgrades
+/// only, from the `access_token` query parameter, as browsers' `WebSocket`
+/// cannot set headers. Elsewhere a token in the URL would only end up in
+/// proxy logs and browser history.
+fn bearer_token(context: &Ctx) -> Option<String> {
+    let from_header = context.get_header("Authorization").pop().and_then(|value| {
+        value
+            .strip_prefix("Bearer ")
+            .map(|token| token.trim().to_string())
+    });
+    let is_websocket_upgrade = context
+        .get_header("Upgrade")
+        .iter()
+        .any(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+
+    from_header.or_else(|| {
+        if !is_websocket_upgrade {
+            return None;
         }
+
+        context
+            .hyper_request
+            .as_ref()
+            .and_then(|request| request.request.uri().query())
+            .and_then(|query| {
+                form_urlencoded::parse(query.as_bytes())
+                    .find(|(key, _)| key == "access_token")
+                    .map(|(_, token)| token.into_owned())
+            })
+    })
+}
+
+/// Signs in the owner of `token`, if it is one.
+pub async fn sign_in_with_api_token(context: &mut Ctx, token: &str) {
+    let api_token = match context.extra.database.postgres() {
+        Some(pool) => ApiToken::get_api_token_from_token(pool, token).await.ok(),
+        None => None,
+    };
+
+    if let Some(api_token) = api_token {
+        context.extra.user = context
+            .extra
+            .stores
+            .users
+            .get_user(&api_token.user_id)
+            .await
+            .ok();
+        context.extra.api_token = Some(api_token);
+        record_user(context);
+    }
+}
+
+#[middleware_fn]
+pub async fn fetch_user_from_api_token(
+    mut context: Ctx,
+    next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    if let Some(token) = bearer_token(&context) {
+        sign_in_with_api_token(&mut context, &token).await;
     }
 
     next(context).await
 }
 
+#[derive(Deserialize)]
+pub struct CreateApiTokenReq {
+    pub name: String,
+    /// Space separated, from `SCOPES`, and empty for a token that can only
+    /// read. `DEFAULT_SCOPES` when left out.
+    pub scope: Option<String>,
+}
+
+/// Issues a new API token for the signed in user. The token is only ever shown
+/// in this response.
+#[middleware_fn]
+pub async fn create_api_token(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let CreateApiTokenReq { name, scope } = serde_urlencoded::from_str(
+        &context
+            .body_string()
+            .await
+            .map_err(AppError::bad_request)
+            .or_app_error(&context)?,
+    )
+    .map_err(AppError::bad_request)
+    .or_app_error(&context)?;
+
+    let scopes = scope.unwrap_or_else(|| DEFAULT_SCOPES.to_string());
+    if scopes
+        .split_whitespace()
+        .any(|scope| !SCOPES.contains(&scope))
+    {
+        return Err(
+            AppError::Validation(format!("Scopes must be some of {}.", SCOPES.join(", ")))
+                .into_thruster_error(&context),
+        );
+    }
+
+    let user_id = signed_in_user(&context)?.id;
+    let pool = postgres(&context)?;
+    let (_, token) = ApiToken::create_api_token(pool, &user_id, &name, &scopes)
+        .await
+        .or_app_error(&context)?;
+
+    context.status(201);
+    context.set("Content-Type", "text/plain");
+    context.set("Cache-Control", "no-store");
+    context.body(&token);
+
+    Ok(context)
+}
+
+/// The user `authenticate` let through. Errors rather than panics if a route is
+/// ever wired up without it.
+pub fn signed_in_user(context: &Ctx) -> Result<&User, ThrusterError<Ctx>> {
+    context
+        .extra
+        .user
+        .as_ref()
+        .ok_or_else(|| AppError::Unauthorized.into_thruster_error(context))
+}
+
 #[middleware_fn]
 pub async fn authenticate(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
-    if let None = context.extra.user.as_ref() {
-        Err(ThrusterError::unauthorized_error(context))
+    if context.extra.user.is_none() {
+        Err(AppError::Unauthorized.into_thruster_error(&context))
     } else {
         next(context).await
     }
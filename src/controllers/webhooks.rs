@@ -0,0 +1,100 @@
+use askama::Template;
+use thruster::{middleware_fn, Context, MiddlewareNext, MiddlewareResult};
+
+use crate::{
+    app::{postgres, Ctx},
+    controllers::users::signed_in_user,
+    errors::{AppError, OrAppError},
+    idempotency,
+    models::{
+        users::User,
+        webhooks::{Webhook, WebhookDelivery},
+    },
+    outbound,
+    webhooks::EVENT_TYPES,
+};
+
+#[derive(Template)]
+#[template(path = "webhooks.html")]
+pub struct Webhooks<'a> {
+    user: Option<&'a User>,
+    event_types: &'a [&'a str],
+    webhooks: Vec<(Webhook, Vec<WebhookDelivery>)>,
+    idempotency_key: String,
+}
+
+#[middleware_fn]
+pub async fn webhooks_page(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let user = signed_in_user(&context)?.clone();
+    let user_id = user.id;
+    let pool = postgres(&context)?.clone();
+
+    let users_webhooks = Webhook::get_webhooks_for_user(&pool, &user_id)
+        .await
+        .or_app_error(&context)?;
+
+    let mut webhooks = vec![];
+    for webhook in users_webhooks {
+        let deliveries = WebhookDelivery::get_recent_deliveries_for_webhook(&pool, &webhook.id)
+            .await
+            .or_app_error(&context)?;
+
+        webhooks.push((webhook, deliveries));
+    }
+
+    context.set("Content-Type", "text/html");
+    context.body(
+        &context
+            .extra
+            .metrics
+            .render(&Webhooks {
+                user: Some(&user),
+                event_types: &EVENT_TYPES,
+                webhooks,
+                idempotency_key: idempotency::new_key(),
+            })
+            .or_app_error(&context)?,
+    );
+
+    Ok(context)
+}
+
+#[middleware_fn]
+pub async fn create_webhook(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let body = context
+        .body_string()
+        .await
+        .map_err(AppError::bad_request)
+        .or_app_error(&context)?;
+
+    // `event_types` repeats once per checked box, which serde_urlencoded can't
+    // collect into a Vec.
+    let mut url = None;
+    let mut event_types = vec![];
+    for (key, value) in form_urlencoded::parse(body.as_bytes()) {
+        match key.as_ref() {
+            "url" => url = Some(value.trim().to_string()),
+            "event_types" if EVENT_TYPES.contains(&value.as_ref()) => {
+                event_types.push(value.into_owned())
+            }
+            _ => (),
+        }
+    }
+
+    // Checked again on delivery, against what the hostname resolves to then.
+    let url = url
+        .filter(|url| outbound::is_allowed(&context.extra.config.outbound, url))
+        .filter(|_| !event_types.is_empty())
+        .ok_or(AppError::Validation("Bad request".to_string()))
+        .or_app_error(&context)?;
+
+    let user_id = signed_in_user(&context)?.id;
+    let pool = postgres(&context)?;
+    Webhook::create_webhook(pool, &user_id, &url, &event_types)
+        .await
+        .or_app_error(&context)?;
+
+    context.redirect("/webhooks");
+
+    Ok(context)
+}
@@ -0,0 +1,69 @@
+use serde::Deserialize;
+use thruster::{middleware_fn, Context, MiddlewareNext, MiddlewareResult};
+
+use crate::{
+    app::{postgres, Ctx},
+    errors::{AppError, OrAppError},
+    models::webmentions::Webmention,
+    urls::{base_url, is_same_origin, tweet_id_from_url},
+};
+
+#[derive(Deserialize)]
+pub struct WebmentionReq {
+    pub source: String,
+    pub target: String,
+}
+
+fn is_http_url(url: &str) -> bool {
+    reqwest::Url::parse(url)
+        .map(|url| url.scheme() == "http" || url.scheme() == "https")
+        .unwrap_or(false)
+}
+
+/// Accepts a webmention for one of our tweets, on this host. Verification
+/// happens in the background, so this only checks that the request is well
+/// formed.
+#[middleware_fn]
+pub async fn receive_webmention(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let WebmentionReq { source, target } = serde_urlencoded::from_str(
+        &context
+            .body_string()
+            .await
+            .map_err(AppError::bad_request)
+            .or_app_error(&context)?,
+    )
+    .map_err(AppError::bad_request)
+    .or_app_error(&context)?;
+
+    let base_url = base_url(&context);
+    let tweet_id = Some(&target)
+        .filter(|target| is_http_url(target) && is_http_url(&source) && **target != source)
+        .filter(|target| is_same_origin(target, &base_url))
+        .and_then(|target| tweet_id_from_url(target))
+        .ok_or(AppError::Validation("Bad request".to_string()))
+        .or_app_error(&context)?;
+
+    context
+        .extra
+        .stores
+        .tweets
+        .get_tweet(&tweet_id)
+        .await
+        .map_err(|_| AppError::Validation("Target is not a tweet".to_string()))
+        .or_app_error(&context)?;
+
+    let pool = postgres(&context)?;
+    let webmention = Webmention::create_webmention(pool, &tweet_id, &source, &target)
+        .await
+        .or_app_error(&context)?;
+
+    context.extra.webmentions.verify_later(webmention.id);
+
+    context.status(202);
+    context.body("Accepted");
+
+    Ok(context)
+}
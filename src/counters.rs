@@ -0,0 +1,256 @@
+use std::time::Duration;
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Postgres};
+use uuid::Uuid;
+
+use crate::{
+    background::Background,
+    config::CountersConfig,
+    events::publish,
+    jobs::{enqueue, Enqueue, Job, JobResult},
+    models::tweets::TweetCounts,
+};
+
+/// A tweet whose stored counts don't match its likes, retweets and replies.
+#[derive(Debug, FromRow)]
+pub struct Drift {
+    pub tweet_id: Uuid,
+    pub like_count: i64,
+    pub actual_like_count: i64,
+    pub retweet_count: i64,
+    pub actual_retweet_count: i64,
+    pub reply_count: i64,
+    pub actual_reply_count: i64,
+}
+
+impl Drift {
+    fn has_drifted(&self) -> bool {
+        self.like_count != self.actual_like_count
+            || self.retweet_count != self.actual_retweet_count
+            || self.reply_count != self.actual_reply_count
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checked: u64,
+    pub drifted: Vec<Drift>,
+    pub fixed: u64,
+    /// Drifted tweets that were being written to when fixing, and so were left
+    /// for the next run rather than waited for.
+    pub skipped: u64,
+}
+
+/// Checks every tweet's counts, `batch_size` tweets at a time in id order, and
+/// unless `dry_run` fixes the ones that have drifted.
+///
+/// Checking takes no locks. Fixing locks only the drifted tweets of a batch,
+/// skipping any that are locked already: liking, retweeting or replying holds
+/// a lock on the tweet through its foreign key, so a skipped tweet is one with
+/// a count change in flight. Once locked, a tweet's counts are recomputed in
+/// the same statement that stores them, so nothing committed in between is
+/// missed, and a delete still in flight takes its own row off afterwards.
+pub async fn reconcile(
+    pool: &Pool<Postgres>,
+    batch_size: u32,
+    dry_run: bool,
+    background: &Background,
+) -> Result<Report, sqlx::Error> {
+    let mut report = Report::default();
+    let mut after = None;
+
+    loop {
+        let _job = background.job();
+
+        let batch: Vec<Drift> = sqlx::query_as(
+            "
+            SELECT
+                t.id as tweet_id,
+                t.like_count,
+                (SELECT count(*) FROM likes l WHERE l.tweet_id = t.id) as actual_like_count,
+                t.retweet_count,
+                (SELECT count(*) FROM retweets r WHERE r.tweet_id = t.id) as actual_retweet_count,
+                t.reply_count,
+                (SELECT count(*) FROM tweets r WHERE r.responding_to = t.id) as actual_reply_count
+            FROM
+                (
+                    SELECT id, like_count, retweet_count, reply_count
+                    FROM tweets
+                    WHERE $1::uuid IS NULL OR id > $1
+                    ORDER BY id
+                    LIMIT $2
+                ) as t
+            ORDER BY
+                t.id",
+        )
+        .bind(after)
+        .bind(batch_size as i64)
+        .fetch_all(pool)
+        .await?;
+
+        let last = match batch.last() {
+            Some(last) => last.tweet_id,
+            None => break,
+        };
+        report.checked += batch.len() as u64;
+
+        let drifted: Vec<Drift> = batch.into_iter().filter(Drift::has_drifted).collect();
+        if !drifted.is_empty() && !dry_run {
+            let ids: Vec<Uuid> = drifted.iter().map(|drift| drift.tweet_id).collect();
+            let fixed = fix(pool, &ids).await?;
+
+            report.fixed += fixed;
+            report.skipped += ids.len() as u64 - fixed;
+        }
+        report.drifted.extend(drifted);
+
+        after = Some(last);
+    }
+
+    Ok(report)
+}
+
+/// Returns how many of `ids` were fixed, the rest having been locked.
+async fn fix(pool: &Pool<Postgres>, ids: &[Uuid]) -> Result<u64, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let locked: Vec<(Uuid,)> = sqlx::query_as(
+        "
+        SELECT id FROM tweets WHERE id = ANY($1)
+        FOR UPDATE SKIP LOCKED",
+    )
+    .bind(ids)
+    .fetch_all(&mut transaction)
+    .await?;
+    let locked: Vec<Uuid> = locked.into_iter().map(|(id,)| id).collect();
+
+    let fixed: Vec<(Uuid, Uuid, i64, i64, i64)> = sqlx::query_as(
+        "
+        UPDATE tweets as t
+        SET
+            like_count = (SELECT count(*) FROM likes l WHERE l.tweet_id = t.id),
+            retweet_count = (SELECT count(*) FROM retweets r WHERE r.tweet_id = t.id),
+            reply_count = (SELECT count(*) FROM tweets r WHERE r.responding_to = t.id)
+        WHERE t.id = ANY($1)
+        RETURNING t.id, t.user_id, t.like_count, t.retweet_count, t.reply_count",
+    )
+    .bind(&locked)
+    .fetch_all(&mut transaction)
+    .await?;
+
+    for (tweet_id, user_id, like_count, retweet_count, reply_count) in &fixed {
+        let counts = TweetCounts {
+            user_id: *user_id,
+            like_count: *like_count,
+            retweet_count: *retweet_count,
+            reply_count: *reply_count,
+        };
+
+        publish(&mut transaction, counts.changed(*tweet_id)).await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(fixed.len() as u64)
+}
+
+/// Queued by `spawn_reconciler`, and run by the job workers.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReconcileCounters {}
+
+impl Job for ReconcileCounters {
+    const KIND: &'static str = "reconcile_counters";
+    const MAX_ATTEMPTS: i32 = 3;
+}
+
+pub async fn reconcile_job(
+    pool: &Pool<Postgres>,
+    batch_size: u32,
+    background: &Background,
+) -> JobResult {
+    let report = reconcile(pool, batch_size, false, background).await?;
+
+    if report.drifted.is_empty() {
+        info!("Checked the counts of {} tweets", report.checked);
+    } else {
+        for drift in &report.drifted {
+            warn!("Counts drifted: {:?}", drift);
+        }
+        warn!(
+            "Checked the counts of {} tweets: fixed {}, skipped {} in use",
+            report.checked, report.fixed, report.skipped
+        );
+    }
+
+    Ok(())
+}
+
+/// Queues a `ReconcileCounters` job every `config.reconcile_interval_secs`,
+/// unless that is 0. It is unique, so however many instances do this, one
+/// run at a time happens.
+pub fn spawn_reconciler(pool: Pool<Postgres>, config: &CountersConfig) {
+    if config.reconcile_interval_secs == 0 {
+        return;
+    }
+
+    let period = Duration::from_secs(config.reconcile_interval_secs);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        // Not straight away at startup, which is busy enough.
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let options = Enqueue {
+                unique_key: Some("all".to_string()),
+                ..Enqueue::default()
+            };
+            if let Err(_e) = enqueue(&pool, &ReconcileCounters {}, options).await {
+                error!("Could not queue reconciling counts: {:#?}", _e);
+            }
+        }
+    });
+}
+
+/// `reconcile-counters [--dry-run]`, to report drifted counts and, without
+/// `--dry-run`, fix them.
+pub async fn command(
+    pool: &Pool<Postgres>,
+    config: &CountersConfig,
+    args: &[String],
+) -> Result<(), sqlx::Error> {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+
+    let report = reconcile(pool, config.batch_size, dry_run, &Background::default()).await?;
+
+    for drift in &report.drifted {
+        println!(
+            "{}  likes {} -> {}  retweets {} -> {}  replies {} -> {}",
+            drift.tweet_id,
+            drift.like_count,
+            drift.actual_like_count,
+            drift.retweet_count,
+            drift.actual_retweet_count,
+            drift.reply_count,
+            drift.actual_reply_count
+        );
+    }
+
+    println!(
+        "Checked {} tweets, {} drifted",
+        report.checked,
+        report.drifted.len()
+    );
+    if !dry_run {
+        println!(
+            "Fixed {}, skipped {} that were in use; run again to retry them",
+            report.fixed, report.skipped
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use sqlx::{Pool, Postgres};
+
+/// The database the app runs on. Production is always Postgres; with the
+/// `sqlite` feature, a `sqlite:` `database.url` runs the site on SQLite
+/// instead, for local development and demos.
+#[derive(Clone)]
+pub enum Database {
+    Postgres(Pool<Postgres>),
+    /// Only the stores run on it. What needs Postgres, such as events, jobs,
+    /// webhooks and webmentions, is turned off; see `app::without_postgres`.
+    #[cfg(feature = "sqlite")]
+    Sqlite(Pool<sqlx::Sqlite>),
+}
+
+/// Whether `url` names a SQLite database, e.g. `sqlite://bitter.db` or
+/// `sqlite::memory:`.
+pub fn is_sqlite_url(url: &str) -> bool {
+    url.starts_with("sqlite:")
+}
+
+impl Database {
+    /// `None` on SQLite.
+    pub fn postgres(&self) -> Option<&Pool<Postgres>> {
+        match self {
+            Database::Postgres(pool) => Some(pool),
+            #[cfg(feature = "sqlite")]
+            Database::Sqlite(_) => None,
+        }
+    }
+
+    /// Open connections, idle or not.
+    pub fn size(&self) -> u32 {
+        match self {
+            Database::Postgres(pool) => pool.size(),
+            #[cfg(feature = "sqlite")]
+            Database::Sqlite(pool) => pool.size(),
+        }
+    }
+
+    pub fn num_idle(&self) -> usize {
+        match self {
+            Database::Postgres(pool) => pool.num_idle(),
+            #[cfg(feature = "sqlite")]
+            Database::Sqlite(pool) => pool.num_idle(),
+        }
+    }
+
+    pub async fn close(&self) {
+        match self {
+            Database::Postgres(pool) => pool.close().await,
+            #[cfg(feature = "sqlite")]
+            Database::Sqlite(pool) => pool.close().await,
+        }
+    }
+}
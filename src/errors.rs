@@ -0,0 +1,334 @@
+use std::{any::Any, fmt, future::Future, panic::AssertUnwindSafe};
+
+use askama::Template;
+use futures::FutureExt;
+use log::{debug, error};
+use serde_json::json;
+use thruster::{
+    errors::ThrusterError, middleware::cookies::HasCookies, middleware_fn, Context, MiddlewareNext,
+    MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{app::Ctx, models::users::User, stores::StoreError, telemetry::route_template};
+
+/// Paths whose clients expect JSON errors rather than error pages.
+const API_PREFIXES: &[&str] = &[
+    "/api_tokens",
+    "/events",
+    "/metrics",
+    "/micropub",
+    "/streaming",
+    "/webmention",
+];
+
+#[derive(Debug)]
+pub enum AppError {
+    NotFound,
+    Unauthorized,
+    Forbidden,
+    /// The request was understood but can't be accepted. The message is shown
+    /// to the user.
+    Validation(String),
+    /// The message is shown to the user.
+    Conflict(String),
+    RateLimited {
+        retry_after_secs: u64,
+    },
+    /// Anything else. The details are logged rather than shown.
+    Internal(String),
+}
+
+impl AppError {
+    /// For input that doesn't parse, where the cause is only worth a debug line.
+    pub fn bad_request(e: impl fmt::Debug) -> AppError {
+        debug!("Bad request: {:?}", e);
+
+        AppError::Validation("Bad request".to_string())
+    }
+
+    /// For errors that come from thruster itself, or from middleware that
+    /// still uses its `ErrorSet`.
+    fn from_status(status: u16, message: String) -> AppError {
+        match status {
+            401 => AppError::Unauthorized,
+            403 => AppError::Forbidden,
+            404 => AppError::NotFound,
+            409 => AppError::Conflict(message),
+            429 => AppError::RateLimited {
+                retry_after_secs: 60,
+            },
+            400..=499 => AppError::Validation(message),
+            _ => AppError::Internal(message),
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        match self {
+            AppError::NotFound => 404,
+            AppError::Unauthorized => 401,
+            AppError::Forbidden => 403,
+            AppError::Validation(_) => 400,
+            AppError::Conflict(_) => 409,
+            AppError::RateLimited { .. } => 429,
+            AppError::Internal(_) => 500,
+        }
+    }
+
+    /// The machine-readable name used in JSON bodies.
+    fn code(&self) -> &'static str {
+        match self {
+            AppError::NotFound => "not_found",
+            AppError::Unauthorized => "unauthorized",
+            AppError::Forbidden => "forbidden",
+            AppError::Validation(_) => "invalid_request",
+            AppError::Conflict(_) => "conflict",
+            AppError::RateLimited { .. } => "rate_limited",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            AppError::NotFound => "Not found",
+            AppError::Unauthorized => "Sign in required",
+            AppError::Forbidden => "Not allowed",
+            AppError::Validation(_) => "Bad request",
+            AppError::Conflict(_) => "Conflict",
+            AppError::RateLimited { .. } => "Slow down",
+            AppError::Internal(_) => "Something went wrong",
+        }
+    }
+
+    /// Wraps this error for returning from a middleware function; `error_pages`
+    /// turns it back into a response. Headers already set on `context`, such
+    /// as cookies, go along with it.
+    pub fn into_thruster_error(self, context: &Ctx) -> ThrusterError<Ctx> {
+        let mut error_context = Ctx::new_without_request(context.extra.clone());
+        error_context.headers = context.headers.clone();
+        error_context.status(self.status());
+
+        ThrusterError {
+            context: error_context,
+            message: self.to_string(),
+            cause: Some(Box::new(self)),
+        }
+    }
+}
+
+/// What the user gets to see, so never the details of an internal error.
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::NotFound => write!(f, "There's nothing here."),
+            AppError::Unauthorized => write!(f, "You need to sign in to do that."),
+            AppError::Forbidden => write!(f, "You aren't allowed to do that."),
+            AppError::Validation(message) | AppError::Conflict(message) => {
+                write!(f, "{}", message)
+            }
+            AppError::RateLimited { retry_after_secs } => write!(
+                f,
+                "Too many requests. Try again in {} seconds.",
+                retry_after_secs
+            ),
+            AppError::Internal(_) => write!(f, "Something went wrong on our end."),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> AppError {
+        match &e {
+            sqlx::Error::RowNotFound => AppError::NotFound,
+            sqlx::Error::Database(db) => match db.code().as_deref() {
+                // unique_violation
+                Some("23505") => AppError::Conflict("That already exists.".to_string()),
+                // foreign_key_violation, e.g. liking a tweet that has gone
+                Some("23503") => AppError::NotFound,
+                // check_violation, e.g. following yourself
+                Some("23514") => AppError::Validation("Bad request".to_string()),
+                _ => AppError::Internal(format!("{:#?}", e)),
+            },
+            _ => AppError::Internal(format!("{:#?}", e)),
+        }
+    }
+}
+
+impl From<StoreError> for AppError {
+    fn from(e: StoreError) -> AppError {
+        match e {
+            StoreError::NotFound | StoreError::MissingReference => AppError::NotFound,
+            StoreError::Conflict => AppError::Conflict("That already exists.".to_string()),
+            StoreError::Invalid => AppError::Validation("Bad request".to_string()),
+            StoreError::Database(e) => AppError::from(e),
+        }
+    }
+}
+
+impl From<askama::Error> for AppError {
+    fn from(e: askama::Error) -> AppError {
+        AppError::Internal(format!("{:#?}", e))
+    }
+}
+
+/// `?` for results whose errors convert into an `AppError`.
+pub trait OrAppError<T> {
+    fn or_app_error(self, context: &Ctx) -> Result<T, ThrusterError<Ctx>>;
+}
+
+impl<T, E: Into<AppError>> OrAppError<T> for Result<T, E> {
+    fn or_app_error(self, context: &Ctx) -> Result<T, ThrusterError<Ctx>> {
+        self.map_err(|e| e.into().into_thruster_error(context))
+    }
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+pub struct ErrorPage<'a> {
+    pub user: Option<&'a User>,
+    pub status: u16,
+    pub title: &'a str,
+    pub message: String,
+    /// Logged alongside the details of an internal error, so that a user
+    /// reporting it can be matched up with the log line.
+    pub reference: Option<String>,
+}
+
+fn wants_json(context: &Ctx) -> bool {
+    let path = context
+        .hyper_request
+        .as_ref()
+        .map(|request| request.request.uri().path().to_string())
+        .unwrap_or_default();
+    let accepts_json = context
+        .get_header("Accept")
+        .iter()
+        .any(|accept| accept.contains("application/json") && !accept.contains("text/html"));
+
+    accepts_json
+        || path.ends_with(".json")
+        || API_PREFIXES.iter().any(|prefix| path.starts_with(prefix))
+}
+
+/// Turns errors from the rest of the chain into responses: an error page for
+/// browsers and a JSON body for API clients.
+#[middleware_fn]
+pub async fn error_pages(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let json = wants_json(&context);
+
+    let e = match next(context).await {
+        Ok(context) => return Ok(context),
+        Err(e) => e,
+    };
+
+    let app_error = match e.cause.map(|cause| cause.downcast::<AppError>()) {
+        Some(Ok(app_error)) => *app_error,
+        _ => AppError::from_status(e.context.status, e.message),
+    };
+    let reference = match &app_error {
+        AppError::Internal(details) => {
+            error!("_e: {}", details);
+
+            // The request id is on the log line too, through the request's span.
+            Some(e.context.extra.request_id.clone())
+                .filter(|request_id| !request_id.is_empty())
+                .or_else(|| Some(Uuid::new_v4().to_string()))
+        }
+        _ => None,
+    };
+
+    let mut context = e.context;
+    context.status(app_error.status());
+    if let AppError::RateLimited { retry_after_secs } = app_error {
+        context.set("Retry-After", &retry_after_secs.to_string());
+    }
+
+    if json {
+        context.set("Content-Type", "application/json");
+        context.body(
+            &json!({
+                "error": app_error.code(),
+                "error_description": app_error.to_string(),
+                "reference": reference,
+            })
+            .to_string(),
+        );
+    } else {
+        let page = context.extra.metrics.render(&ErrorPage {
+            user: context.extra.user.as_ref(),
+            status: app_error.status(),
+            title: app_error.title(),
+            message: app_error.to_string(),
+            reference,
+        });
+
+        match page {
+            Ok(page) => {
+                context.set("Content-Type", "text/html");
+                context.body(&page);
+            }
+            Err(_e) => {
+                error!("_e: {:#?}", _e);
+                context.set("Content-Type", "text/plain");
+                context.body(&app_error.to_string());
+            }
+        }
+    }
+
+    Ok(context)
+}
+
+/// The message a panic was raised with, when it has one.
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "a non-string payload".to_string())
+}
+
+/// Runs `future`, turning a panic into an internal error instead of taking
+/// down the task serving the request.
+pub async fn guard<T>(future: impl Future<Output = T>) -> Result<T, AppError> {
+    AssertUnwindSafe(future)
+        .catch_unwind()
+        .await
+        .map_err(|panic| AppError::Internal(format!("Panicked with {}", panic_message(&*panic))))
+}
+
+/// Sits inside `error_pages`, so that a panic further down is answered with a
+/// 500 page carrying a reference, like any other internal error.
+#[middleware_fn]
+pub async fn catch_panics(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let fallback = Ctx::new_without_request(context.extra.clone());
+
+    match guard(next(context)).await {
+        Ok(result) => result,
+        Err(e) => Err(e.into_thruster_error(&fallback)),
+    }
+}
+
+/// The router answers a path it has no route for with the route of its
+/// longest matching prefix, so that `/no/such/page` would get the home page.
+/// Those are turned into a 404 here instead.
+#[middleware_fn]
+pub async fn not_found(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let (method, path) = context
+        .hyper_request
+        .as_ref()
+        .map(|request| {
+            (
+                request.request.method().to_string(),
+                request.request.uri().path().to_string(),
+            )
+        })
+        .unwrap_or_default();
+
+    if route_template(&context, &method, &path).is_none() {
+        return Err(AppError::NotFound.into_thruster_error(&context));
+    }
+
+    next(context).await
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgListener, Pool, Postgres, Transaction};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// The Postgres channel every instance publishes to and listens on.
+pub const CHANNEL: &str = "bitter_events";
+
+/// How many events a slow subscriber may fall behind before it starts missing
+/// them. This is also how far back a reconnecting client can resume from.
+const CAPACITY: usize = 1024;
+
+/// How long the listener waits after failing to receive, doubling with each
+/// failure in a row up to `MAX_RETRY_DELAY`.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    TweetCreated {
+        tweet_id: Uuid,
+        user_id: Uuid,
+        responding_to: Option<Uuid>,
+        /// Author of the tweet being replied to.
+        responding_to_user_id: Option<Uuid>,
+        /// Lowercased usernames, without the `@`.
+        mentions: Vec<String>,
+        /// Lowercased hashtags, without the `#`.
+        hashtags: Vec<String>,
+    },
+    CountsChanged {
+        tweet_id: Uuid,
+        like_count: i64,
+        retweet_count: i64,
+        reply_count: i64,
+    },
+    TweetLiked {
+        tweet_id: Uuid,
+        tweet_user_id: Uuid,
+        user_id: Uuid,
+    },
+    TweetRetweeted {
+        tweet_id: Uuid,
+        tweet_user_id: Uuid,
+        user_id: Uuid,
+    },
+    UserFollowed {
+        follower_id: Uuid,
+        following_id: Uuid,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Event {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+impl Event {
+    pub fn new(kind: EventKind) -> Event {
+        Event {
+            id: Uuid::new_v4(),
+            kind,
+        }
+    }
+}
+
+/// Queues `event` on the transaction. Postgres only delivers it to listeners
+/// once the transaction commits, so a rolled back write never produces an event.
+pub async fn publish(
+    transaction: &mut Transaction<'_, Postgres>,
+    event: EventKind,
+) -> Result<(), sqlx::Error> {
+    let payload = serde_json::to_string(&Event::new(event))
+        .map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(CHANNEL)
+        .bind(payload)
+        .execute(transaction)
+        .await?;
+
+    Ok(())
+}
+
+/// Fans the events received on `CHANNEL` out to every subscriber in this
+/// instance, keeping the most recent ones around for clients that reconnect.
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+    recent: Arc<Mutex<VecDeque<Event>>>,
+}
+
+/// A live subscription, plus whatever was missed since the event a client last
+/// saw.
+pub struct Resumed {
+    pub missed: Vec<Event>,
+    /// `false` when the last seen event is no longer retained, in which case the
+    /// client has to reload rather than rely on `missed`.
+    pub complete: bool,
+    pub receiver: broadcast::Receiver<Event>,
+}
+
+impl Events {
+    /// Events that never arrive, for SQLite, where nothing publishes them.
+    pub fn without_listener() -> Events {
+        let (sender, _) = broadcast::channel(CAPACITY);
+
+        Events {
+            sender,
+            recent: Arc::new(Mutex::new(VecDeque::with_capacity(CAPACITY))),
+        }
+    }
+
+    pub async fn listen(pool: &Pool<Postgres>) -> Result<Events, sqlx::Error> {
+        let mut listener = PgListener::connect_with(pool).await?;
+        listener.listen(CHANNEL).await?;
+
+        let events = Events::without_listener();
+        let forwarder = events.clone();
+
+        tokio::spawn(async move {
+            let mut retry_delay = INITIAL_RETRY_DELAY;
+
+            loop {
+                // `recv` transparently reconnects when the connection drops, so
+                // an error here only means this attempt failed. Waiting keeps a
+                // database that is down, or a closed pool, from spinning here.
+                let notification = match listener.recv().await {
+                    Ok(notification) => {
+                        retry_delay = INITIAL_RETRY_DELAY;
+                        notification
+                    }
+                    Err(_e) => {
+                        error!(
+                            "Could not receive events, retrying in {:?}: {:#?}",
+                            retry_delay, _e
+                        );
+                        tokio::time::sleep(retry_delay).await;
+                        retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                        continue;
+                    }
+                };
+
+                match serde_json::from_str::<Event>(notification.payload()) {
+                    Ok(event) => forwarder.dispatch(event),
+                    Err(_e) => warn!("Ignoring malformed event: {:#?}", _e),
+                }
+            }
+        });
+
+        Ok(events)
+    }
+
+    fn dispatch(&self, event: Event) {
+        // Held across the send so that `resume` can never see an event in
+        // `recent` and then receive it again from the channel.
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+
+        if recent.len() == CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+
+        // Sending only fails when nobody is subscribed, which is fine.
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Subscribes, replaying every retained event after `last_event_id`.
+    pub fn resume(&self, last_event_id: Option<&Uuid>) -> Resumed {
+        let recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        let receiver = self.sender.subscribe();
+
+        let (missed, complete) = match last_event_id {
+            None => (vec![], true),
+            Some(last_event_id) => {
+                match recent.iter().position(|event| &event.id == last_event_id) {
+                    Some(position) => (recent.iter().skip(position + 1).cloned().collect(), true),
+                    None => (vec![], false),
+                }
+            }
+        };
+
+        Resumed {
+            missed,
+            complete,
+            receiver,
+        }
+    }
+}
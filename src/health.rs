@@ -0,0 +1,197 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::error;
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use tokio::{
+    sync::Notify,
+    time::{timeout, Instant},
+};
+
+use crate::{database::Database, migrations};
+
+/// How long the database gets to answer before it counts as down. Kept short,
+/// as probes are usually given only a few seconds themselves.
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Shared between the server, which flips it when shutting down, and
+/// `/readyz`, which reports it so that load balancers stop sending traffic
+/// before the connections are closed.
+#[derive(Clone, Default)]
+pub struct Health {
+    shutting_down: Arc<AtomicBool>,
+    shutdown_begun: Arc<Notify>,
+}
+
+impl Health {
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.shutdown_begun.notify_waiters();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once shutdown has begun, straight away if it already has. For
+    /// long-lived responses, which would otherwise hold up draining.
+    pub async fn shutdown_begun(&self) {
+        let notified = self.shutdown_begun.notified();
+
+        if !self.is_shutting_down() {
+            notified.await;
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct DatabaseCheck {
+    pub ok: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct MigrationsCheck {
+    pub ok: bool,
+    pub current: Option<i64>,
+    pub expected: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PoolCheck {
+    /// False when every connection is open and none are idle, so that new
+    /// requests would queue for one.
+    pub ok: bool,
+    pub size: u32,
+    pub idle: usize,
+    pub max: u32,
+}
+
+#[derive(Serialize)]
+pub struct ShutdownCheck {
+    pub ok: bool,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub database: DatabaseCheck,
+    /// `None` on SQLite, whose schema is created on connecting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub migrations: Option<MigrationsCheck>,
+    pub pool: PoolCheck,
+    pub shutdown: ShutdownCheck,
+}
+
+/// `query` is a `SELECT 1` against whichever database it is.
+async fn check_database<T>(query: impl Future<Output = Result<T, sqlx::Error>>) -> DatabaseCheck {
+    let start_time = Instant::now();
+
+    let error = match timeout(DATABASE_TIMEOUT, query).await {
+        Ok(Ok(_)) => None,
+        Ok(Err(_e)) => {
+            // The details stay in the log, as `/readyz` is often reachable by
+            // more than the operators.
+            error!("Readiness check could not query the database: {:#?}", _e);
+
+            Some("query failed".to_string())
+        }
+        Err(_) => Some(format!(
+            "no answer within {} seconds",
+            DATABASE_TIMEOUT.as_secs()
+        )),
+    };
+
+    DatabaseCheck {
+        ok: error.is_none(),
+        latency_ms: start_time.elapsed().as_millis() as u64,
+        error,
+    }
+}
+
+async fn check_migrations(pool: &Pool<Postgres>) -> MigrationsCheck {
+    let expected = migrations::latest_version();
+
+    match timeout(DATABASE_TIMEOUT, migrations::current_version(pool)).await {
+        Ok(Ok(current)) => MigrationsCheck {
+            ok: current == Some(expected),
+            current,
+            expected,
+            error: None,
+        },
+        Ok(Err(_e)) => {
+            error!("Readiness check could not read the migrations: {:#?}", _e);
+
+            MigrationsCheck {
+                ok: false,
+                current: None,
+                expected,
+                error: Some("could not read the applied migrations".to_string()),
+            }
+        }
+        Err(_) => MigrationsCheck {
+            ok: false,
+            current: None,
+            expected,
+            error: Some("timed out".to_string()),
+        },
+    }
+}
+
+/// Looked at before the database checks, which borrow a connection of their
+/// own.
+fn check_pool(database: &Database, max_connections: u32) -> PoolCheck {
+    let size = database.size();
+    let idle = database.num_idle();
+
+    PoolCheck {
+        ok: size < max_connections || idle > 0,
+        size,
+        idle,
+        max: max_connections,
+    }
+}
+
+impl Readiness {
+    pub async fn check(database: &Database, max_connections: u32, health: &Health) -> Readiness {
+        let shutdown = ShutdownCheck {
+            ok: !health.is_shutting_down(),
+        };
+
+        let (pool_check, database, migrations) = match database {
+            Database::Postgres(pool) => (
+                check_pool(database, max_connections),
+                check_database(sqlx::query("SELECT 1").execute(pool)).await,
+                Some(check_migrations(pool).await),
+            ),
+            #[cfg(feature = "sqlite")]
+            Database::Sqlite(pool) => (
+                check_pool(database, max_connections),
+                check_database(sqlx::query("SELECT 1").execute(pool)).await,
+                None,
+            ),
+        };
+
+        Readiness {
+            ready: database.ok
+                && migrations.as_ref().is_none_or(|migrations| migrations.ok)
+                && pool_check.ok
+                && shutdown.ok,
+            database,
+            migrations,
+            pool: pool_check,
+            shutdown,
+        }
+    }
+}
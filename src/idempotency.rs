@@ -0,0 +1,266 @@
+use std::time::Duration;
+
+use hyper::{
+    body::{Bytes, HttpBody},
+    Body,
+};
+use log::{error, info};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use thruster::{
+    middleware::cookies::HasCookies, middleware_fn, Context, MiddlewareNext, MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::Ctx,
+    errors::{AppError, OrAppError},
+    models::idempotency_keys::{IdempotencyKey, StoredResponse},
+};
+
+/// For API clients.
+pub const HEADER: &str = "Idempotency-Key";
+
+/// The hidden field forms carry the key in, as browsers can't send headers.
+pub const FIELD: &str = "idempotency_key";
+
+/// Set on replayed responses.
+const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+/// How often expired keys are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The most of a request body read into memory, far more than any form or
+/// Micropub request here needs.
+pub const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// How long a repeat waits for the first request to be answered, and how often
+/// it looks, before giving up with a conflict.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A key for a form to send in `FIELD`, new each time it is rendered.
+pub fn new_key() -> String {
+    Uuid::new_v4().to_string()
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= 255 && key.chars().all(|c| c.is_ascii_graphic())
+}
+
+/// Reads the request body, leaving it in place for the handler. Bodies over
+/// `MAX_BODY_BYTES` are refused rather than read.
+pub async fn request_body(context: &mut Ctx) -> Result<Bytes, AppError> {
+    let request = match context.hyper_request.as_mut() {
+        Some(request) => &mut request.request,
+        None => return Ok(Bytes::new()),
+    };
+    let too_large = || AppError::Validation("The request body is too large.".to_string());
+
+    let mut body = std::mem::take(request.body_mut());
+    if body.size_hint().lower() > MAX_BODY_BYTES as u64 {
+        return Err(too_large());
+    }
+
+    let mut read = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(AppError::bad_request)?;
+        if read.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(too_large());
+        }
+        read.extend_from_slice(&chunk);
+    }
+
+    let read = Bytes::from(read);
+    *request.body_mut() = Body::from(read.clone());
+
+    Ok(read)
+}
+
+/// Reads the response body, leaving it in place to be sent.
+async fn response_body(context: &mut Ctx) -> Result<Bytes, hyper::Error> {
+    let body = hyper::body::to_bytes(std::mem::take(&mut context.body)).await?;
+    context.body = Body::from(body.clone());
+
+    Ok(body)
+}
+
+fn response_header(context: &Ctx, name: &str) -> Option<String> {
+    context
+        .headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+/// Answers a repeat of a request with the same key, from the same user to the
+/// same endpoint, with the response to the first one instead of doing it
+/// again. The key comes from the `Idempotency-Key` header or the form's
+/// `FIELD`; requests without one, or without a user, go through as usual, as
+/// does everything on SQLite. Goes after `authenticate`.
+///
+/// A repeat that arrives while the first request is still being answered
+/// waits for its response, for up to `WAIT_TIMEOUT`.
+///
+/// Only responses that succeeded or were the client's fault are kept. After
+/// anything else, or an error from the handler, the key is given up so that
+/// retrying it does the request again. A repeat waiting on it then takes the
+/// key over and does the request itself.
+#[middleware_fn]
+pub async fn idempotent(mut context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let (user_id, pool) = match (
+        context.extra.user.as_ref(),
+        context.extra.database.postgres(),
+    ) {
+        (Some(user), Some(pool)) => (user.id, pool.clone()),
+        _ => return next(context).await,
+    };
+
+    // Before the body is read, which takes the request apart.
+    let header_key = context.get_header(HEADER).pop();
+    let request = request_body(&mut context).await.or_app_error(&context)?;
+    let key = header_key.or_else(|| {
+        serde_urlencoded::from_bytes::<Vec<(String, String)>>(&request)
+            .ok()?
+            .into_iter()
+            .find(|(name, _)| name == FIELD)
+            .map(|(_, value)| value)
+    });
+    let key = match key {
+        Some(key) if is_valid_key(&key) => key,
+        Some(_) => {
+            return Err(AppError::Validation(format!(
+                "The {} must be 1 to 255 printable characters.",
+                HEADER
+            ))
+            .into_thruster_error(&context))
+        }
+        None => return next(context).await,
+    };
+
+    let endpoint = context
+        .hyper_request
+        .as_ref()
+        .map(|request| {
+            format!(
+                "{} {}",
+                request.request.method(),
+                request.request.uri().path()
+            )
+        })
+        .unwrap_or_default();
+    let request_hash = format!("{:x}", Sha256::digest(&request));
+
+    let ttl_secs = context.extra.config.idempotency.ttl_secs;
+    let waiting_since = tokio::time::Instant::now();
+    let existing = loop {
+        let existing =
+            IdempotencyKey::claim(&pool, &user_id, &endpoint, &key, &request_hash, ttl_secs)
+                .await
+                .or_app_error(&context)?;
+
+        match existing {
+            Some(IdempotencyKey { status: None, .. }) if waiting_since.elapsed() < WAIT_TIMEOUT => {
+                tokio::time::sleep(WAIT_INTERVAL).await
+            }
+            existing => break existing,
+        }
+    };
+
+    match existing {
+        None => (),
+        Some(existing) if existing.request_hash != request_hash => {
+            return Err(AppError::Validation(format!(
+                "This {} was used for a different request.",
+                HEADER
+            ))
+            .into_thruster_error(&context));
+        }
+        Some(IdempotencyKey { status: None, .. }) => {
+            return Err(AppError::Conflict(format!(
+                "A request with this {} is still being answered.",
+                HEADER
+            ))
+            .into_thruster_error(&context));
+        }
+        Some(IdempotencyKey {
+            status: Some(status),
+            content_type,
+            location,
+            body,
+            ..
+        }) => {
+            info!("Replaying the response for {} {}", endpoint, key);
+
+            context.status(status as u16);
+            if let Some(content_type) = content_type {
+                context.set("Content-Type", &content_type);
+            }
+            if let Some(location) = location {
+                context.set("Location", &location);
+            }
+            context.set(REPLAYED_HEADER, "true");
+            context.body = Body::from(body.unwrap_or_default());
+
+            return Ok(context);
+        }
+    }
+
+    let mut context = match next(context).await {
+        Ok(context) => context,
+        Err(e) => {
+            // In the background, as the error can't be held across an await.
+            // A repeat waiting on the key sees it go soon after.
+            tokio::spawn(async move {
+                if let Err(_e) = IdempotencyKey::release(&pool, &user_id, &endpoint, &key).await {
+                    error!("Could not give up an idempotency key: {:#?}", _e);
+                }
+            });
+
+            return Err(e);
+        }
+    };
+
+    let stored = if context.status < 500 {
+        match response_body(&mut context).await {
+            Ok(body) => {
+                let content_type = response_header(&context, "Content-Type");
+                let location = response_header(&context, "Location");
+                let response = StoredResponse {
+                    status: i32::from(context.status),
+                    content_type: content_type.as_deref(),
+                    location: location.as_deref(),
+                    body: &body,
+                };
+
+                IdempotencyKey::complete(&pool, &user_id, &endpoint, &key, response).await
+            }
+            Err(e) => Err(sqlx::Error::Protocol(e.to_string())),
+        }
+    } else {
+        IdempotencyKey::release(&pool, &user_id, &endpoint, &key).await
+    };
+    if let Err(_e) = stored {
+        error!(
+            "Could not store the response for an idempotency key: {:#?}",
+            _e
+        );
+    }
+
+    Ok(context)
+}
+
+/// Deletes expired keys every `PRUNE_INTERVAL`.
+pub fn spawn_pruner(pool: Pool<Postgres>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(_e) = IdempotencyKey::delete_expired(&pool).await {
+                error!("Could not delete expired idempotency keys: {:#?}", _e);
+            }
+        }
+    });
+}
@@ -0,0 +1,372 @@
+use std::{
+    collections::HashMap, future::Future, panic::AssertUnwindSafe, sync::Arc, time::Duration,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use log::{error, info, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    Executor, FromRow, Pool, Postgres,
+};
+use uuid::Uuid;
+
+use crate::{
+    background::Background,
+    config::{Config, JobsConfig},
+    counters::{self, ReconcileCounters},
+    health::Health,
+    webhooks::{self, DeliverWebhook},
+};
+
+/// The first retry waits this long, and each one after twice as long as the
+/// last, up to `MAX_BACKOFF`.
+const BASE_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// How often one of the workers requeues jobs whose worker went away, and
+/// clears out finished ones.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Finished jobs are kept this long for inspection. Dead ones are kept until
+/// someone deals with them.
+const KEEP_DONE_FOR_DAYS: i32 = 7;
+
+pub type JobResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+/// A kind of work, stored as its JSON payload until a worker runs it.
+pub trait Job: Serialize + DeserializeOwned + Send + 'static {
+    /// Finds the handler for a stored job, so it must not change once jobs of
+    /// this kind have been queued.
+    const KIND: &'static str;
+    /// Including the first. A job that fails this many times is dead, and
+    /// stays in the table with its last error until dealt with.
+    const MAX_ATTEMPTS: i32 = 5;
+}
+
+#[derive(Debug, Default)]
+pub struct Enqueue {
+    /// Not before this time. Now by default.
+    pub run_at: Option<DateTime<Utc>>,
+    /// While a job of the same kind and key is queued or running, enqueueing
+    /// another is a no-op.
+    pub unique_key: Option<String>,
+}
+
+/// Queues `job`, on a transaction to have it run only if the transaction
+/// commits. Returns its id, or `None` when `unique_key` is already taken.
+pub async fn enqueue<'e, J: Job>(
+    executor: impl Executor<'e, Database = Postgres>,
+    job: &J,
+    options: Enqueue,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let payload = serde_json::to_string(job).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+
+    let id: Option<(Uuid,)> = sqlx::query_as(
+        "
+        INSERT INTO jobs (kind, payload, unique_key, max_attempts, run_at)
+        VALUES ($1, $2, $3, $4, COALESCE($5, now()))
+        ON CONFLICT (kind, unique_key)
+            WHERE unique_key IS NOT NULL AND status IN ('queued', 'running')
+            DO NOTHING
+        RETURNING id",
+    )
+    .bind(J::KIND)
+    .bind(payload)
+    .bind(options.unique_key)
+    .bind(J::MAX_ATTEMPTS)
+    .bind(options.run_at)
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(id.map(|(id,)| id))
+}
+
+#[derive(Debug, FromRow)]
+struct Claimed {
+    id: Uuid,
+    kind: String,
+    payload: String,
+    attempts: i32,
+    max_attempts: i32,
+}
+
+fn backoff(attempts: i32) -> Duration {
+    let doublings = attempts.saturating_sub(1).clamp(0, 16) as u32;
+
+    BASE_BACKOFF
+        .saturating_mul(2u32.pow(doublings))
+        .min(MAX_BACKOFF)
+}
+
+type Handler = Box<dyn Fn(String) -> BoxFuture<'static, JobResult> + Send + Sync>;
+
+/// Runs queued jobs with the handlers registered for their kinds. Workers
+/// claim jobs with `SKIP LOCKED`, so any number of them, in any number of
+/// processes, can share a queue.
+pub struct Worker {
+    handlers: HashMap<&'static str, Handler>,
+    config: JobsConfig,
+}
+
+impl Worker {
+    pub fn new(config: &JobsConfig) -> Worker {
+        Worker {
+            handlers: HashMap::new(),
+            config: config.clone(),
+        }
+    }
+
+    pub fn register<J, F, Fut>(mut self, handler: F) -> Worker
+    where
+        J: Job,
+        F: Fn(J) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = JobResult> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+
+        self.handlers.insert(
+            J::KIND,
+            Box::new(move |payload: String| {
+                let handler = handler.clone();
+
+                async move {
+                    let job: J = serde_json::from_str(&payload)?;
+
+                    (*handler)(job).await
+                }
+                .boxed()
+            }),
+        );
+
+        self
+    }
+
+    /// Starts `config.concurrency` workers, which stop claiming jobs once
+    /// shutdown begins. The job in hand is counted in `background`, so that
+    /// shutting down waits for it.
+    pub fn spawn(self, pool: Pool<Postgres>, health: Health, background: Background) {
+        let kinds: Vec<String> = self.handlers.keys().map(|kind| kind.to_string()).collect();
+        let worker = Arc::new(self);
+
+        info!(
+            "Starting {} job worker(s) for {}",
+            worker.config.concurrency,
+            kinds.join(", ")
+        );
+
+        for index in 0..worker.config.concurrency {
+            let worker = worker.clone();
+            let pool = pool.clone();
+            let health = health.clone();
+            let background = background.clone();
+            let kinds = kinds.clone();
+
+            tokio::spawn(async move {
+                worker
+                    .work(&pool, &health, &background, &kinds, index == 0)
+                    .await
+            });
+        }
+    }
+
+    async fn work(
+        &self,
+        pool: &Pool<Postgres>,
+        health: &Health,
+        background: &Background,
+        kinds: &[String],
+        maintains: bool,
+    ) {
+        let poll_interval = Duration::from_millis(self.config.poll_interval_ms);
+        let mut maintenance = tokio::time::interval(MAINTENANCE_INTERVAL);
+
+        while !health.is_shutting_down() {
+            if maintains && maintenance.tick().now_or_never().is_some() {
+                if let Err(_e) = self.maintain(pool).await {
+                    error!("Could not maintain the job queue: {:#?}", _e);
+                }
+            }
+
+            // Taken before claiming, so that a job is never claimed without
+            // shutdown waiting for it.
+            let job = background.job();
+
+            match claim(pool, kinds).await {
+                Ok(Some(claimed)) => self.perform(pool, claimed).await,
+                Ok(None) => {
+                    drop(job);
+                    tokio::time::sleep(poll_interval).await;
+                }
+                Err(_e) => {
+                    drop(job);
+                    error!("Could not claim a job: {:#?}", _e);
+                    tokio::time::sleep(poll_interval).await;
+                }
+            }
+        }
+    }
+
+    async fn perform(&self, pool: &Pool<Postgres>, claimed: Claimed) {
+        let handler = match self.handlers.get(claimed.kind.as_str()) {
+            Some(handler) => handler,
+            None => return,
+        };
+
+        let result = AssertUnwindSafe(handler(claimed.payload.clone()))
+            .catch_unwind()
+            .await
+            .unwrap_or_else(|_| Err("the handler panicked".into()));
+
+        let finished = match result {
+            Ok(()) => succeed(pool, &claimed).await,
+            Err(e) => fail(pool, &claimed, &e.to_string()).await,
+        };
+        if let Err(_e) = finished {
+            error!(
+                "Could not record the outcome of job {}: {:#?}",
+                claimed.id, _e
+            );
+        }
+    }
+
+    /// Jobs still running after `lease_secs` are taken to have lost their
+    /// worker, and are queued again. Their attempt still counts, so a job
+    /// that keeps taking its worker down ends up dead.
+    async fn maintain(&self, pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+        let requeued = sqlx::query(
+            "
+            UPDATE jobs
+            SET status = 'queued', locked_at = NULL, run_at = now()
+            WHERE
+                    status = 'running'
+                AND
+                    locked_at < now() - $1 * interval '1 second'",
+        )
+        .bind(self.config.lease_secs as f64)
+        .execute(pool)
+        .await?
+        .rows_affected();
+        if requeued > 0 {
+            warn!("Requeued {} job(s) that outlived their lease", requeued);
+        }
+
+        sqlx::query(
+            "
+            DELETE FROM jobs
+            WHERE
+                    status = 'done'
+                AND
+                    finished_at < now() - $1 * interval '1 day'",
+        )
+        .bind(KEEP_DONE_FOR_DAYS)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// The oldest due job of one of `kinds`, marked as running.
+async fn claim(pool: &Pool<Postgres>, kinds: &[String]) -> Result<Option<Claimed>, sqlx::Error> {
+    sqlx::query_as(
+        "
+        UPDATE jobs
+        SET status = 'running', attempts = attempts + 1, locked_at = now()
+        WHERE id = (
+            SELECT id
+            FROM jobs
+            WHERE
+                    status = 'queued'
+                AND
+                    run_at <= now()
+                AND
+                    kind = ANY($1)
+            ORDER BY run_at
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, kind, payload, attempts, max_attempts",
+    )
+    .bind(kinds)
+    .fetch_optional(pool)
+    .await
+}
+
+async fn succeed(pool: &Pool<Postgres>, claimed: &Claimed) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "
+        UPDATE jobs
+        SET status = 'done', locked_at = NULL, last_error = NULL, finished_at = now()
+        WHERE id = $1",
+    )
+    .bind(claimed.id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Queues the job again after a backoff, or out of attempts, marks it dead.
+async fn fail(pool: &Pool<Postgres>, claimed: &Claimed, error: &str) -> Result<(), sqlx::Error> {
+    let dead = claimed.attempts >= claimed.max_attempts;
+
+    sqlx::query(
+        "
+        UPDATE jobs
+        SET
+            status = CASE WHEN $2 THEN 'dead' ELSE 'queued' END,
+            run_at = now() + $3 * interval '1 second',
+            finished_at = CASE WHEN $2 THEN now() END,
+            locked_at = NULL,
+            last_error = $4
+        WHERE id = $1",
+    )
+    .bind(claimed.id)
+    .bind(dead)
+    .bind(backoff(claimed.attempts).as_secs_f64())
+    .bind(error)
+    .execute(pool)
+    .await?;
+
+    if dead {
+        error!(
+            "Job {} ({}) is dead after {} attempts: {}",
+            claimed.id, claimed.kind, claimed.attempts, error
+        );
+    } else {
+        warn!(
+            "Job {} ({}) failed attempt {} of {}: {}",
+            claimed.id, claimed.kind, claimed.attempts, claimed.max_attempts, error
+        );
+    }
+
+    Ok(())
+}
+
+/// A worker for every kind of job the app queues.
+pub fn worker(
+    pool: &Pool<Postgres>,
+    config: &Config,
+    background: &Background,
+) -> Result<Worker, reqwest::Error> {
+    let batch_size = config.counters.batch_size;
+    let webhooks_client = webhooks::client(&config.outbound)?;
+    let reconcile_pool = pool.clone();
+    let webhooks_pool = pool.clone();
+    let background = background.clone();
+
+    Ok(Worker::new(&config.jobs)
+        .register(move |_: ReconcileCounters| {
+            let pool = reconcile_pool.clone();
+            let background = background.clone();
+
+            async move { counters::reconcile_job(&pool, batch_size, &background).await }
+        })
+        .register(move |job: DeliverWebhook| {
+            let pool = webhooks_pool.clone();
+            let client = webhooks_client.clone();
+
+            async move { webhooks::deliver_job(&pool, &client, job).await }
+        }))
+}
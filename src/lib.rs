@@ -1,28 +1,58 @@
+// Middleware returns thruster's `ThrusterError`, which carries the whole
+// request context, so the lint would fire on every handler.
+#![allow(clippy::result_large_err)]
+
 use app::{Ctx, ServerConfig};
-use log::info;
+use background::Background;
+use bootstrap::Deployment;
+use config::Config;
+use health::Health;
 use shuttle_service::error::CustomError;
-use sqlx::{Executor, PgPool};
+use sqlx::PgPool;
 use thruster::{HyperServer, ThrusterServer};
 
 pub mod app;
+pub mod background;
+pub mod bootstrap;
+pub mod cache;
+pub mod config;
 pub mod controllers;
+pub mod counters;
+pub mod database;
+pub mod errors;
+pub mod events;
+pub mod health;
+pub mod idempotency;
+pub mod jobs;
+pub mod metrics;
+pub mod migrations;
 pub mod models;
+pub mod outbound;
+pub mod rate_limits;
+pub mod server;
+pub mod stores;
+pub mod telemetry;
+pub mod urls;
+pub mod webhooks;
+pub mod webmentions;
 
 #[shuttle_service::main]
 async fn shuttle(
     #[shuttle_aws_rds::Postgres] pool: PgPool,
 ) -> shuttle_service::ShuttleThruster<HyperServer<Ctx, ServerConfig>> {
-    info!("Starting server...");
-
-    pool.execute(include_str!("../schema.sql"))
-        .await
-        .map_err(|e| CustomError::new(e))?;
-
-    let app = app::app(pool)
-        .await
-        .map_err(|_e| CustomError::msg("Starting thruster server failed"))?;
+    // Shuttle owns the command line and the database, so only the file and
+    // environment layers of the config apply here.
+    let (config, _) = Config::load(&[]).map_err(CustomError::new)?;
 
-    info!("Server started...");
+    // Shuttle runs the server, and with it shutting down.
+    let (app, _) = bootstrap::start(
+        config,
+        Deployment::Shuttle(pool),
+        Health::default(),
+        Background::default(),
+    )
+    .await
+    .map_err(CustomError::new)?;
 
     Ok(HyperServer::new(app))
 }
@@ -0,0 +1,207 @@
+use std::time::Duration;
+
+use askama::Template;
+use prometheus::{
+    Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use sqlx::{Pool, Postgres};
+use tokio::time::{interval, Instant};
+
+use crate::database::Database;
+
+/// How often the pool is sampled for how long getting a connection takes.
+const POOL_SAMPLE_INTERVAL: Duration = Duration::from_secs(15);
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+const RENDER_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1];
+
+/// Everything exported on `/metrics`, in a registry of its own so that
+/// instances in the same process (as in tests) don't collide.
+pub struct Metrics {
+    registry: Registry,
+    request_duration: HistogramVec,
+    pool_connections: IntGaugeVec,
+    pool_acquire_duration: Histogram,
+    template_render_duration: HistogramVec,
+    cache_lookups: IntCounterVec,
+    cache_hit_ratio: GaugeVec,
+    pub tweets_created: IntCounter,
+    pub likes: IntCounter,
+    pub retweets: IntCounter,
+    pub signups: IntCounter,
+    pub sign_in_failures: IntCounter,
+    pub rate_limited: IntCounter,
+}
+
+fn counter(registry: &Registry, name: &str, help: &str) -> Result<IntCounter, prometheus::Error> {
+    let counter = IntCounter::new(name, help)?;
+    registry.register(Box::new(counter.clone()))?;
+
+    Ok(counter)
+}
+
+/// `Feed` for `crate::controllers::pages::Feed`, as a label.
+fn short_type_name<T>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    let name = name.split('<').next().unwrap_or(name);
+
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+impl Metrics {
+    pub fn new(max_connections: u32) -> Result<Metrics, prometheus::Error> {
+        let registry = Registry::new_custom(Some("bitter".to_string()), None)?;
+
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to answer requests, by route template and status",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["method", "route", "status"],
+        )?;
+        registry.register(Box::new(request_duration.clone()))?;
+
+        let pool_connections = IntGaugeVec::new(
+            Opts::new(
+                "db_pool_connections",
+                "Database connections open, by whether they are idle or in use",
+            ),
+            &["state"],
+        )?;
+        registry.register(Box::new(pool_connections.clone()))?;
+
+        let pool_max_connections = IntGauge::new(
+            "db_pool_max_connections",
+            "The most database connections the pool will open",
+        )?;
+        pool_max_connections.set(max_connections.into());
+        registry.register(Box::new(pool_max_connections.clone()))?;
+
+        let pool_acquire_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "db_pool_acquire_duration_seconds",
+                "Time taken to get a connection from the pool, sampled periodically",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+        )?;
+        registry.register(Box::new(pool_acquire_duration.clone()))?;
+
+        let template_render_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "template_render_duration_seconds",
+                "Time taken to render templates",
+            )
+            .buckets(RENDER_BUCKETS.to_vec()),
+            &["template"],
+        )?;
+        registry.register(Box::new(template_render_duration.clone()))?;
+
+        let cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "cache_lookups_total",
+                "Cache lookups, by cache and whether they were a hit or a miss",
+            ),
+            &["cache", "result"],
+        )?;
+        registry.register(Box::new(cache_lookups.clone()))?;
+
+        let cache_hit_ratio = GaugeVec::new(
+            Opts::new(
+                "cache_hit_ratio",
+                "Share of cache lookups that were hits since startup, by cache",
+            ),
+            &["cache"],
+        )?;
+        registry.register(Box::new(cache_hit_ratio.clone()))?;
+
+        Ok(Metrics {
+            tweets_created: counter(&registry, "tweets_created_total", "Tweets and replies")?,
+            likes: counter(&registry, "likes_total", "Tweets liked")?,
+            retweets: counter(&registry, "retweets_total", "Tweets retweeted")?,
+            signups: counter(&registry, "signups_total", "Accounts created")?,
+            sign_in_failures: counter(
+                &registry,
+                "sign_in_failures_total",
+                "Sign ins refused for a wrong username or password",
+            )?,
+            rate_limited: counter(
+                &registry,
+                "rate_limited_total",
+                "Requests refused for going over a rate limit",
+            )?,
+            registry,
+            request_duration,
+            pool_connections,
+            pool_acquire_duration,
+            template_render_duration,
+            cache_lookups,
+            cache_hit_ratio,
+        })
+    }
+
+    /// The hit and miss counters and the hit ratio gauge for the cache named
+    /// `cache`.
+    pub fn cache(&self, cache: &str) -> (IntCounter, IntCounter, Gauge) {
+        (
+            self.cache_lookups.with_label_values(&[cache, "hit"]),
+            self.cache_lookups.with_label_values(&[cache, "miss"]),
+            self.cache_hit_ratio.with_label_values(&[cache]),
+        )
+    }
+
+    pub fn observe_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        self.request_duration
+            .with_label_values(&[method, route, &status.to_string()])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Renders `template`, timing it under its type's name.
+    pub fn render<T: Template>(&self, template: &T) -> askama::Result<String> {
+        let _timer = self
+            .template_render_duration
+            .with_label_values(&[short_type_name::<T>()])
+            .start_timer();
+
+        template.render()
+    }
+
+    /// Times getting a connection every `POOL_SAMPLE_INTERVAL`. sqlx doesn't
+    /// report how long requests wait for one, so this stands in for it.
+    pub fn spawn_pool_sampler(metrics: std::sync::Arc<Metrics>, pool: Pool<Postgres>) {
+        tokio::spawn(async move {
+            let mut ticks = interval(POOL_SAMPLE_INTERVAL);
+
+            loop {
+                ticks.tick().await;
+
+                let start_time = Instant::now();
+                if pool.acquire().await.is_ok() {
+                    metrics
+                        .pool_acquire_duration
+                        .observe(start_time.elapsed().as_secs_f64());
+                }
+            }
+        });
+    }
+
+    /// The Prometheus text exposition of everything, with the pool gauges
+    /// brought up to date first.
+    pub fn export(&self, database: &Database) -> Result<String, prometheus::Error> {
+        let size = i64::from(database.size());
+        let idle = database.num_idle() as i64;
+        self.pool_connections.with_label_values(&["idle"]).set(idle);
+        self.pool_connections
+            .with_label_values(&["in_use"])
+            .set(size - idle);
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
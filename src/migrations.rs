@@ -0,0 +1,537 @@
+use std::fmt;
+
+use log::info;
+use sha2::{Digest, Sha256};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    Executor, FromRow, Pool, Postgres, Transaction,
+};
+
+/// Held for the length of a run, so that instances starting at the same time
+/// don't both apply the same migration.
+const LOCK_KEY: i64 = 0x6269_7474_6572;
+
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+    /// Finds rows that would make `up` fail, as `(problem, row_count)` pairs, so
+    /// that they can be reported and cleaned up before migrating.
+    pub check: Option<&'static str>,
+}
+
+impl Migration {
+    /// Recorded when the migration is applied, so that editing it afterwards is
+    /// caught instead of silently leaving databases on different schemas.
+    pub fn checksum(&self) -> String {
+        format!("{:x}", Sha256::digest(self.up.as_bytes()))
+    }
+}
+
+/// Every migration this binary knows about, oldest first. New ones go on the
+/// end with the next version; ones that have shipped must not be edited.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        up: include_str!("../migrations/0001_initial.up.sql"),
+        down: include_str!("../migrations/0001_initial.down.sql"),
+        check: None,
+    },
+    Migration {
+        version: 2,
+        name: "constraints",
+        up: include_str!("../migrations/0002_constraints.up.sql"),
+        down: include_str!("../migrations/0002_constraints.down.sql"),
+        check: Some(include_str!("../migrations/0002_constraints.check.sql")),
+    },
+    Migration {
+        version: 3,
+        name: "jobs",
+        up: include_str!("../migrations/0003_jobs.up.sql"),
+        down: include_str!("../migrations/0003_jobs.down.sql"),
+        check: None,
+    },
+    Migration {
+        version: 4,
+        name: "session_metadata",
+        up: include_str!("../migrations/0004_session_metadata.up.sql"),
+        down: include_str!("../migrations/0004_session_metadata.down.sql"),
+        check: None,
+    },
+    Migration {
+        version: 5,
+        name: "rate_limits",
+        up: include_str!("../migrations/0005_rate_limits.up.sql"),
+        down: include_str!("../migrations/0005_rate_limits.down.sql"),
+        check: None,
+    },
+    Migration {
+        version: 6,
+        name: "idempotency_keys",
+        up: include_str!("../migrations/0006_idempotency_keys.up.sql"),
+        down: include_str!("../migrations/0006_idempotency_keys.down.sql"),
+        check: None,
+    },
+    Migration {
+        version: 7,
+        name: "hash_api_tokens",
+        up: include_str!("../migrations/0007_hash_api_tokens.up.sql"),
+        down: include_str!("../migrations/0007_hash_api_tokens.down.sql"),
+        check: None,
+    },
+    Migration {
+        version: 8,
+        name: "webhook_retries",
+        up: include_str!("../migrations/0008_webhook_retries.up.sql"),
+        down: include_str!("../migrations/0008_webhook_retries.down.sql"),
+        check: None,
+    },
+    Migration {
+        version: 9,
+        name: "api_token_scopes",
+        up: include_str!("../migrations/0009_api_token_scopes.up.sql"),
+        down: include_str!("../migrations/0009_api_token_scopes.down.sql"),
+        check: None,
+    },
+];
+
+#[derive(Debug, FromRow)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub name: String,
+    pub checksum: String,
+    pub applied_at: DateTime<Utc>,
+}
+
+/// Rows that would stop a migration from applying.
+#[derive(Debug)]
+pub struct Problem {
+    pub migration: &'static Migration,
+    pub description: String,
+    pub row_count: i64,
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Database(sqlx::Error),
+    /// The database has a migration this binary doesn't, most likely because a
+    /// newer release has already run against it.
+    DatabaseAhead {
+        version: i64,
+        name: String,
+    },
+    ChecksumMismatch {
+        version: i64,
+        name: String,
+    },
+    /// A pending migration's check found rows that have to be cleaned up first.
+    Blocked(Vec<Problem>),
+    /// `migrate` was given a command it doesn't have.
+    UnknownCommand(String),
+    /// `migrate down` was given a step count that isn't a number.
+    InvalidSteps(String),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Database(e) => write!(f, "Database error: {}", e),
+            MigrationError::DatabaseAhead { version, name } => write!(
+                f,
+                "Database has migration {:04}_{} which this binary does not know about; refusing to run against a newer schema",
+                version, name
+            ),
+            MigrationError::ChecksumMismatch { version, name } => write!(
+                f,
+                "Migration {:04}_{} has changed since it was applied",
+                version, name
+            ),
+            MigrationError::Blocked(problems) => {
+                write!(f, "Migrations are blocked by existing rows:")?;
+                for problem in problems {
+                    write!(
+                        f,
+                        "\n  {:04}_{}: {} {}",
+                        problem.migration.version,
+                        problem.migration.name,
+                        problem.row_count,
+                        problem.description
+                    )?;
+                }
+
+                Ok(())
+            }
+            MigrationError::UnknownCommand(command) => write!(
+                f,
+                "Unknown migrate command {:?}\nUsage: migrate [status | preflight | plan | up [--dry-run] | down [steps] [--dry-run]]",
+                command
+            ),
+            MigrationError::InvalidSteps(steps) => write!(
+                f,
+                "Invalid step count {:?} for migrate down; expected a number",
+                steps
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<sqlx::Error> for MigrationError {
+    fn from(e: sqlx::Error) -> MigrationError {
+        MigrationError::Database(e)
+    }
+}
+
+/// A known migration and, if it has been applied, when.
+pub struct MigrationStatus {
+    pub migration: &'static Migration,
+    pub applied_at: Option<DateTime<Utc>>,
+}
+
+/// Starts a transaction holding the migration lock, with the migrations table
+/// in place.
+async fn begin(pool: &Pool<Postgres>) -> Result<Transaction<'static, Postgres>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(LOCK_KEY)
+        .execute(&mut transaction)
+        .await?;
+
+    transaction
+        .execute(
+            "
+            CREATE TABLE IF NOT EXISTS schema_migrations (
+                version BIGINT PRIMARY KEY,
+                name TEXT NOT NULL,
+                checksum TEXT NOT NULL,
+                applied_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+            )",
+        )
+        .await?;
+
+    Ok(transaction)
+}
+
+/// The applied migrations, after checking that each is one this binary knows
+/// and hasn't changed.
+async fn applied(
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<AppliedMigration>, MigrationError> {
+    let applied: Vec<AppliedMigration> = sqlx::query_as(
+        "
+        SELECT version, name, checksum, applied_at
+        FROM schema_migrations
+        ORDER BY version",
+    )
+    .fetch_all(transaction)
+    .await?;
+
+    for migration in &applied {
+        match MIGRATIONS
+            .iter()
+            .find(|known| known.version == migration.version)
+        {
+            None => {
+                return Err(MigrationError::DatabaseAhead {
+                    version: migration.version,
+                    name: migration.name.clone(),
+                })
+            }
+            Some(known) if known.checksum() != migration.checksum => {
+                return Err(MigrationError::ChecksumMismatch {
+                    version: migration.version,
+                    name: migration.name.clone(),
+                })
+            }
+            Some(_) => (),
+        }
+    }
+
+    Ok(applied)
+}
+
+fn pending(applied: &[AppliedMigration]) -> Vec<&'static Migration> {
+    MIGRATIONS
+        .iter()
+        .filter(|migration| applied.iter().all(|a| a.version != migration.version))
+        .collect()
+}
+
+/// The newest `steps` applied migrations, newest first.
+fn to_roll_back(applied: &[AppliedMigration], steps: usize) -> Vec<&'static Migration> {
+    applied
+        .iter()
+        .rev()
+        .take(steps)
+        .filter_map(|a| MIGRATIONS.iter().find(|known| known.version == a.version))
+        .collect()
+}
+
+/// The version every migration in this binary brings the database to.
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// The newest applied version, without taking the migration lock, so that it
+/// is cheap enough for health checks. `None` before anything has been applied.
+pub async fn current_version(pool: &Pool<Postgres>) -> Result<Option<i64>, sqlx::Error> {
+    let (version,): (Option<i64>,) = sqlx::query_as(
+        "
+        SELECT max(version)
+        FROM schema_migrations",
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(version)
+}
+
+/// Reads what has been applied without the lock or creating the migrations
+/// table, so that it is safe to run against a database that is only being
+/// looked at. A database without the table has nothing applied.
+pub async fn status(pool: &Pool<Postgres>) -> Result<Vec<MigrationStatus>, MigrationError> {
+    let mut transaction = pool.begin().await?;
+    transaction.execute("SET TRANSACTION READ ONLY").await?;
+
+    let (exists,): (bool,) = sqlx::query_as("SELECT to_regclass('schema_migrations') IS NOT NULL")
+        .fetch_one(&mut transaction)
+        .await?;
+    let applied = if exists {
+        applied(&mut transaction).await?
+    } else {
+        vec![]
+    };
+    transaction.rollback().await?;
+
+    Ok(MIGRATIONS
+        .iter()
+        .map(|migration| MigrationStatus {
+            migration,
+            applied_at: applied
+                .iter()
+                .find(|a| a.version == migration.version)
+                .map(|a| a.applied_at),
+        })
+        .collect())
+}
+
+async fn check(
+    transaction: &mut Transaction<'_, Postgres>,
+    migration: &'static Migration,
+) -> Result<Vec<Problem>, sqlx::Error> {
+    let check = match migration.check {
+        Some(check) => check,
+        None => return Ok(vec![]),
+    };
+
+    let rows: Vec<(String, i64)> = sqlx::query_as(check).fetch_all(transaction).await?;
+
+    Ok(rows
+        .into_iter()
+        .filter(|(_, row_count)| *row_count > 0)
+        .map(|(description, row_count)| Problem {
+            migration,
+            description,
+            row_count,
+        })
+        .collect())
+}
+
+/// Checks and applies each migration in turn, so that every check sees the
+/// schema the migrations before it leave behind. Stops at the first migration
+/// with problems, since the ones after it can't be checked.
+async fn apply(
+    transaction: &mut Transaction<'_, Postgres>,
+    pending: &[&'static Migration],
+) -> Result<Vec<Problem>, sqlx::Error> {
+    for migration in pending {
+        let problems = check(transaction, migration).await?;
+        if !problems.is_empty() {
+            return Ok(problems);
+        }
+
+        info!(
+            "Applying migration {:04}_{}",
+            migration.version, migration.name
+        );
+
+        transaction.execute(migration.up).await?;
+        sqlx::query(
+            "
+            INSERT INTO schema_migrations (version, name, checksum)
+            VALUES ($1, $2, $3)",
+        )
+        .bind(migration.version)
+        .bind(migration.name)
+        .bind(migration.checksum())
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    Ok(vec![])
+}
+
+/// Applies every pending migration in order, all in one transaction. With
+/// `dry_run` they are applied and then rolled back, so that the plan returned
+/// is known to work against this database.
+pub async fn up(
+    pool: &Pool<Postgres>,
+    dry_run: bool,
+) -> Result<Vec<&'static Migration>, MigrationError> {
+    let mut transaction = begin(pool).await?;
+    let pending = pending(&applied(&mut transaction).await?);
+
+    let problems = apply(&mut transaction, &pending).await?;
+    if !problems.is_empty() {
+        return Err(MigrationError::Blocked(problems));
+    }
+
+    if dry_run {
+        transaction.rollback().await?;
+    } else {
+        transaction.commit().await?;
+    }
+
+    Ok(pending)
+}
+
+/// Reports the rows that would stop the pending migrations from applying,
+/// without changing anything.
+pub async fn preflight(pool: &Pool<Postgres>) -> Result<Vec<Problem>, MigrationError> {
+    let mut transaction = begin(pool).await?;
+    let pending = pending(&applied(&mut transaction).await?);
+
+    let problems = apply(&mut transaction, &pending).await?;
+    transaction.rollback().await?;
+
+    Ok(problems)
+}
+
+/// Rolls back the newest `steps` applied migrations, newest first.
+pub async fn down(
+    pool: &Pool<Postgres>,
+    steps: usize,
+    dry_run: bool,
+) -> Result<Vec<&'static Migration>, MigrationError> {
+    let mut transaction = begin(pool).await?;
+    let to_roll_back = to_roll_back(&applied(&mut transaction).await?, steps);
+
+    if dry_run {
+        transaction.rollback().await?;
+        return Ok(to_roll_back);
+    }
+
+    for migration in &to_roll_back {
+        info!(
+            "Rolling back migration {:04}_{}",
+            migration.version, migration.name
+        );
+
+        transaction.execute(migration.down).await?;
+        sqlx::query("DELETE FROM schema_migrations WHERE version = $1")
+            .bind(migration.version)
+            .execute(&mut transaction)
+            .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(to_roll_back)
+}
+
+/// Run on startup: refuses a database that is ahead of this binary or whose
+/// migrations have changed, then applies anything pending.
+///
+/// Databases created from the old `schema.sql` have no migrations table yet;
+/// 0001 only creates what is missing, so they pick it up as already in place.
+pub async fn run(pool: &Pool<Postgres>) -> Result<(), MigrationError> {
+    let applied = up(pool, false).await?;
+
+    if applied.is_empty() {
+        info!("Database schema is up to date");
+    }
+
+    Ok(())
+}
+
+/// The `migrate` subcommand: `status`, `preflight`, `up`, `down [steps]` and
+/// `plan`, with `--dry-run` to print what `up` or `down` would do without doing it.
+pub async fn command(pool: &Pool<Postgres>, args: &[String]) -> Result<(), MigrationError> {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let mut args = args.iter().filter(|arg| *arg != "--dry-run");
+
+    let describe = |migration: &Migration| format!("{:04}_{}", migration.version, migration.name);
+
+    match args.next().map(|arg| arg.as_str()) {
+        Some("status") | None => {
+            for status in status(pool).await? {
+                match status.applied_at {
+                    Some(applied_at) => println!(
+                        "applied  {}  {}",
+                        describe(status.migration),
+                        applied_at.to_rfc3339()
+                    ),
+                    None => println!("pending  {}", describe(status.migration)),
+                }
+            }
+        }
+        Some("preflight") => {
+            let problems = preflight(pool).await?;
+            if problems.is_empty() {
+                println!("No rows block the pending migrations");
+            }
+
+            for problem in problems {
+                println!(
+                    "{:04}_{}  {} {}",
+                    problem.migration.version,
+                    problem.migration.name,
+                    problem.row_count,
+                    problem.description
+                );
+            }
+        }
+        Some("plan") => print_plan("apply", up(pool, true).await?, describe),
+        Some("up") => print_plan(
+            if dry_run { "apply" } else { "applied" },
+            up(pool, dry_run).await?,
+            describe,
+        ),
+        Some("down") => {
+            let steps = match args.next() {
+                Some(steps) => steps
+                    .parse()
+                    .map_err(|_| MigrationError::InvalidSteps(steps.to_string()))?,
+                None => 1,
+            };
+
+            print_plan(
+                if dry_run { "roll back" } else { "rolled back" },
+                down(pool, steps, dry_run).await?,
+                describe,
+            )
+        }
+        Some(other) => return Err(MigrationError::UnknownCommand(other.to_string())),
+    }
+
+    Ok(())
+}
+
+fn print_plan(
+    verb: &str,
+    migrations: Vec<&'static Migration>,
+    describe: impl Fn(&Migration) -> String,
+) {
+    if migrations.is_empty() {
+        println!("Nothing to do");
+    }
+
+    for migration in migrations {
+        println!("{}  {}", verb, describe(migration));
+    }
+}
@@ -0,0 +1,86 @@
+use sha2::{Digest, Sha256};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    FromRow, Pool, Postgres,
+};
+use uuid::Uuid;
+
+/// What a token may do besides reading, space separated as in OAuth: `create`
+/// is for posting over Micropub. Any token can stream and query.
+pub const SCOPES: &[&str] = &["create"];
+
+/// For tokens issued without asking for particular scopes.
+pub const DEFAULT_SCOPES: &str = "create";
+
+/// A long-lived credential for API clients and bots, sent as
+/// `Authorization: Bearer <token>`. Only a hash of the token is stored, so the
+/// table alone can't be used to sign in.
+#[derive(Clone, Debug, FromRow)]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub token_hash: String,
+    pub user_id: Uuid,
+    pub name: String,
+    pub scopes: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What is stored for `token`. Tokens are random enough that a fast hash
+/// without a salt is all they need.
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+impl ApiToken {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes
+            .split_whitespace()
+            .any(|granted| granted == scope)
+    }
+
+    /// Returns the new token along with its row, as this is the only time it
+    /// is known.
+    pub async fn create_api_token(
+        pool: &Pool<Postgres>,
+        user_id: &Uuid,
+        name: &str,
+        scopes: &str,
+    ) -> Result<(ApiToken, String), sqlx::Error> {
+        let token = format!(
+            "{:x}",
+            Sha256::new()
+                .chain_update(Uuid::new_v4().to_bytes_le())
+                .finalize()
+        );
+
+        let api_token = sqlx::query_as(
+            "
+            INSERT INTO api_tokens (token_hash, user_id, name, scopes)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id, token_hash, user_id, name, scopes, created_at",
+        )
+        .bind(hash_token(&token))
+        .bind(user_id)
+        .bind(name)
+        .bind(scopes)
+        .fetch_one(pool)
+        .await?;
+
+        Ok((api_token, token))
+    }
+
+    pub async fn get_api_token_from_token(
+        pool: &Pool<Postgres>,
+        token: &str,
+    ) -> Result<ApiToken, sqlx::Error> {
+        sqlx::query_as(
+            "
+            SELECT id, token_hash, user_id, name, scopes, created_at
+            FROM api_tokens
+            WHERE token_hash = $1",
+        )
+        .bind(hash_token(token))
+        .fetch_one(pool)
+        .await
+    }
+}
@@ -4,7 +4,9 @@ use sqlx::{
 };
 use uuid::Uuid;
 
-#[derive(Debug, FromRow)]
+use crate::events::{publish, EventKind};
+
+#[derive(Clone, Debug, FromRow)]
 pub struct Follow {
     pub follower_id: Uuid,
     pub following_id: Uuid,
@@ -22,7 +24,9 @@ impl Follow {
         follower_id: &Uuid,
         following_id: &Uuid,
     ) -> Result<Follow, sqlx::Error> {
-        sqlx::query_as(
+        let mut transaction = pool.begin().await?;
+
+        let follow = sqlx::query_as(
             "
             INSERT INTO follows (follower_id, following_id)
             VALUES ($1, $2)
@@ -30,8 +34,20 @@ impl Follow {
         )
         .bind(follower_id)
         .bind(following_id)
-        .fetch_one(pool)
-        .await
+        .fetch_one(&mut transaction)
+        .await?;
+
+        publish(
+            &mut transaction,
+            EventKind::UserFollowed {
+                follower_id: *follower_id,
+                following_id: *following_id,
+            },
+        )
+        .await?;
+        transaction.commit().await?;
+
+        Ok(follow)
     }
 
     pub async fn get_follow_count(
@@ -40,7 +56,7 @@ impl Follow {
     ) -> Result<i64, sqlx::Error> {
         let follows: Follows = sqlx::query_as(
             "
-            SELECT COUNT(follower_id) as follows FROM follows WHERE following_id = $1",
+            SELECT COUNT(follower_id) as count FROM follows WHERE following_id = $1",
         )
         .bind(following_id)
         .fetch_one(pool)
@@ -48,4 +64,17 @@ impl Follow {
 
         Ok(follows.count)
     }
+
+    pub async fn get_following_ids(
+        pool: &Pool<Postgres>,
+        follower_id: &Uuid,
+    ) -> Result<Vec<Uuid>, sqlx::Error> {
+        sqlx::query_scalar(
+            "
+            SELECT following_id FROM follows WHERE follower_id = $1",
+        )
+        .bind(follower_id)
+        .fetch_all(pool)
+        .await
+    }
 }
@@ -0,0 +1,150 @@
+use sqlx::{FromRow, Pool, Postgres};
+use uuid::Uuid;
+
+/// A request made with an idempotency key, and the response to it once there
+/// is one. Keys are per user and endpoint.
+#[derive(Debug, FromRow)]
+pub struct IdempotencyKey {
+    pub request_hash: String,
+    /// `None` while the first request is still being answered.
+    pub status: Option<i32>,
+    pub content_type: Option<String>,
+    pub location: Option<String>,
+    pub body: Option<Vec<u8>>,
+}
+
+/// What `complete` stores to replay.
+#[derive(Debug)]
+pub struct StoredResponse<'a> {
+    pub status: i32,
+    pub content_type: Option<&'a str>,
+    pub location: Option<&'a str>,
+    pub body: &'a [u8],
+}
+
+impl IdempotencyKey {
+    /// Takes `key` for answering a request, returning `None`, or returns what
+    /// the request that took it first has stored. An expired key is taken
+    /// over as if it were new, as is one left unanswered for a minute, whose
+    /// request won't be answered now.
+    pub async fn claim(
+        pool: &Pool<Postgres>,
+        user_id: &Uuid,
+        endpoint: &str,
+        key: &str,
+        request_hash: &str,
+        ttl_secs: u64,
+    ) -> Result<Option<IdempotencyKey>, sqlx::Error> {
+        // Twice, in case the first request gave its key up in between.
+        for _ in 0..2 {
+            let claimed: Option<(Uuid,)> = sqlx::query_as(
+                "
+                INSERT INTO idempotency_keys (user_id, endpoint, key, request_hash, expires_at)
+                VALUES ($1, $2, $3, $4, now() + $5 * interval '1 second')
+                ON CONFLICT (user_id, endpoint, key) DO UPDATE
+                SET
+                    request_hash = EXCLUDED.request_hash,
+                    status = NULL,
+                    content_type = NULL,
+                    location = NULL,
+                    body = NULL,
+                    created_at = now(),
+                    expires_at = EXCLUDED.expires_at
+                WHERE
+                        idempotency_keys.expires_at < now()
+                    OR
+                        (
+                            idempotency_keys.status IS NULL
+                            AND
+                            idempotency_keys.created_at < now() - interval '1 minute'
+                        )
+                RETURNING user_id",
+            )
+            .bind(user_id)
+            .bind(endpoint)
+            .bind(key)
+            .bind(request_hash)
+            .bind(ttl_secs as f64)
+            .fetch_optional(pool)
+            .await?;
+            if claimed.is_some() {
+                return Ok(None);
+            }
+
+            let existing = sqlx::query_as(
+                "
+                SELECT request_hash, status, content_type, location, body
+                FROM idempotency_keys
+                WHERE user_id = $1 AND endpoint = $2 AND key = $3",
+            )
+            .bind(user_id)
+            .bind(endpoint)
+            .bind(key)
+            .fetch_optional(pool)
+            .await?;
+            if existing.is_some() {
+                return Ok(existing);
+            }
+        }
+
+        Err(sqlx::Error::RowNotFound)
+    }
+
+    /// Stores the response to replay for the key.
+    pub async fn complete(
+        pool: &Pool<Postgres>,
+        user_id: &Uuid,
+        endpoint: &str,
+        key: &str,
+        response: StoredResponse<'_>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "
+            UPDATE idempotency_keys
+            SET status = $4, content_type = $5, location = $6, body = $7
+            WHERE user_id = $1 AND endpoint = $2 AND key = $3",
+        )
+        .bind(user_id)
+        .bind(endpoint)
+        .bind(key)
+        .bind(response.status)
+        .bind(response.content_type)
+        .bind(response.location)
+        .bind(response.body)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Gives up a key whose request failed, so that it can be retried.
+    pub async fn release(
+        pool: &Pool<Postgres>,
+        user_id: &Uuid,
+        endpoint: &str,
+        key: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "
+            DELETE FROM idempotency_keys
+            WHERE user_id = $1 AND endpoint = $2 AND key = $3 AND status IS NULL",
+        )
+        .bind(user_id)
+        .bind(endpoint)
+        .bind(key)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_expired(pool: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+        Ok(sqlx::query(
+            "
+            DELETE FROM idempotency_keys WHERE expires_at < now()",
+        )
+        .execute(pool)
+        .await?
+        .rows_affected())
+    }
+}
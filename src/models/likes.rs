@@ -4,7 +4,12 @@ use sqlx::{
 };
 use uuid::Uuid;
 
-#[derive(Debug, FromRow)]
+use crate::{
+    events::{publish, EventKind},
+    models::tweets::TweetCounts,
+};
+
+#[derive(Clone, Debug, FromRow)]
 pub struct Like {
     pub tweet_id: Uuid,
     pub user_id: Uuid,
@@ -30,20 +35,34 @@ impl Like {
         .fetch_one(&mut transaction)
         .await?;
 
-        sqlx::query(
+        let counts: TweetCounts = sqlx::query_as(
             "
         UPDATE tweets
         SET like_count = like_count + 1
-        WHERE id = $1",
+        WHERE id = $1
+        RETURNING user_id, like_count, retweet_count, reply_count",
         )
         .bind(tweet_id)
-        .execute(&mut transaction)
+        .fetch_one(&mut transaction)
+        .await?;
+
+        publish(&mut transaction, counts.changed(*tweet_id)).await?;
+        publish(
+            &mut transaction,
+            EventKind::TweetLiked {
+                tweet_id: *tweet_id,
+                tweet_user_id: counts.user_id,
+                user_id: *user_id,
+            },
+        )
         .await?;
         transaction.commit().await?;
 
         Ok(like)
     }
 
+    /// `RowNotFound` when `user_id` hadn't liked the tweet, so that the count
+    /// is only taken down for a like that existed.
     pub async fn delete_like(
         pool: &Pool<Postgres>,
         tweet_id: &Uuid,
@@ -53,23 +72,28 @@ impl Like {
 
         sqlx::query(
             "
-            DELETE FROM likes WHERE tweet_id = $1 AND user_id = $2",
+            DELETE FROM likes WHERE tweet_id = $1 AND user_id = $2
+            RETURNING tweet_id",
         )
         .bind(tweet_id)
         .bind(user_id)
-        .execute(&mut transaction)
+        .fetch_one(&mut transaction)
         .await?;
 
-        sqlx::query(
+        let counts: TweetCounts = sqlx::query_as(
             "
         UPDATE tweets
         SET like_count = like_count - 1
-        WHERE id = $1",
+        WHERE id = $1
+        RETURNING user_id, like_count, retweet_count, reply_count",
         )
         .bind(tweet_id)
-        .execute(&mut transaction)
+        .fetch_one(&mut transaction)
         .await?;
 
+        publish(&mut transaction, counts.changed(*tweet_id)).await?;
+        transaction.commit().await?;
+
         Ok(())
     }
 }
@@ -1,6 +1,10 @@
+pub mod api_tokens;
 pub mod follows;
+pub mod idempotency_keys;
 pub mod likes;
 pub mod retweets;
 pub mod sessions;
 pub mod tweets;
 pub mod users;
+pub mod webhooks;
+pub mod webmentions;
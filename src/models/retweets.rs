@@ -4,7 +4,12 @@ use sqlx::{
 };
 use uuid::Uuid;
 
-#[derive(Debug, FromRow)]
+use crate::{
+    events::{publish, EventKind},
+    models::tweets::TweetCounts,
+};
+
+#[derive(Clone, Debug, FromRow)]
 pub struct Retweet {
     pub tweet_id: Uuid,
     pub user_id: Uuid,
@@ -30,17 +35,64 @@ impl Retweet {
         .fetch_one(&mut transaction)
         .await?;
 
-        sqlx::query(
+        let counts: TweetCounts = sqlx::query_as(
             "
         UPDATE tweets
         SET retweet_count = retweet_count + 1
-        WHERE id = $1",
+        WHERE id = $1
+        RETURNING user_id, like_count, retweet_count, reply_count",
         )
         .bind(tweet_id)
-        .execute(&mut transaction)
+        .fetch_one(&mut transaction)
+        .await?;
+
+        publish(&mut transaction, counts.changed(*tweet_id)).await?;
+        publish(
+            &mut transaction,
+            EventKind::TweetRetweeted {
+                tweet_id: *tweet_id,
+                tweet_user_id: counts.user_id,
+                user_id: *user_id,
+            },
+        )
         .await?;
         transaction.commit().await?;
 
         Ok(like)
     }
+
+    /// `RowNotFound` when `user_id` hadn't retweeted the tweet.
+    pub async fn delete_retweet(
+        pool: &Pool<Postgres>,
+        tweet_id: &Uuid,
+        user_id: &Uuid,
+    ) -> Result<(), sqlx::Error> {
+        let mut transaction = pool.begin().await?;
+
+        sqlx::query(
+            "
+            DELETE FROM retweets WHERE tweet_id = $1 AND user_id = $2
+            RETURNING tweet_id",
+        )
+        .bind(tweet_id)
+        .bind(user_id)
+        .fetch_one(&mut transaction)
+        .await?;
+
+        let counts: TweetCounts = sqlx::query_as(
+            "
+        UPDATE tweets
+        SET retweet_count = retweet_count - 1
+        WHERE id = $1
+        RETURNING user_id, like_count, retweet_count, reply_count",
+        )
+        .bind(tweet_id)
+        .fetch_one(&mut transaction)
+        .await?;
+
+        publish(&mut transaction, counts.changed(*tweet_id)).await?;
+        transaction.commit().await?;
+
+        Ok(())
+    }
 }
@@ -5,15 +5,93 @@ use sqlx::{
 };
 use uuid::Uuid;
 
-#[derive(Debug, FromRow)]
+use crate::{
+    cache::{self, Invalidation},
+    models::users::User,
+};
+
+/// What sessions from signing in may do.
+pub const DEFAULT_SCOPES: &str = "read write";
+
+#[derive(Clone, Debug, FromRow)]
 pub struct Session {
     pub id: Uuid,
     pub token: String,
     pub user_id: Uuid,
     pub created_at: DateTime<Utc>,
+    /// Never, when `None`. Expired sessions aren't found.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Space separated, as in OAuth.
+    pub scopes: String,
+    pub two_factor_verified: bool,
+}
+
+/// A session and its user, looked up together for every signed in request.
+#[derive(Clone, Debug)]
+pub struct SessionWithUser {
+    pub session: Session,
+    pub user: User,
+}
+
+/// `SessionWithUser` as selected, with the user's columns renamed to keep
+/// them apart from the session's.
+#[derive(Debug, FromRow)]
+pub(crate) struct SessionWithUserRow {
+    id: Uuid,
+    token: String,
+    user_id: Uuid,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    scopes: String,
+    two_factor_verified: bool,
+    username: String,
+    password: String,
+    user_created_at: DateTime<Utc>,
+}
+
+impl From<SessionWithUserRow> for SessionWithUser {
+    fn from(row: SessionWithUserRow) -> SessionWithUser {
+        SessionWithUser {
+            session: Session {
+                id: row.id,
+                token: row.token,
+                user_id: row.user_id,
+                created_at: row.created_at,
+                expires_at: row.expires_at,
+                scopes: row.scopes,
+                two_factor_verified: row.two_factor_verified,
+            },
+            user: User {
+                id: row.user_id,
+                password: row.password,
+                username: row.username,
+                created_at: row.user_created_at,
+            },
+        }
+    }
+}
+
+/// Random, and long enough not to be guessed.
+pub fn new_session_token() -> String {
+    format!(
+        "{:x}",
+        Sha256::new()
+            .chain_update(Uuid::new_v4().to_bytes_le())
+            .finalize()
+    )
 }
 
 impl Session {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes
+            .split_whitespace()
+            .any(|granted| granted == scope)
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+
     pub async fn create_session(
         pool: &Pool<Postgres>,
         user_id: &Uuid,
@@ -22,29 +100,58 @@ impl Session {
             "
             INSERT INTO sessions (token, user_id)
             VALUES ($1, $2)
-            RETURNING id, token, user_id, created_at",
+            RETURNING id, token, user_id, created_at, expires_at, scopes, two_factor_verified",
         )
-        .bind(format!(
-            "{:x}",
-            Sha256::new()
-                .chain_update(Uuid::new_v4().to_bytes_le())
-                .finalize()
-        ))
+        .bind(new_session_token())
         .bind(user_id)
         .fetch_one(pool)
         .await
     }
 
-    pub async fn get_session_from_token(
+    /// Unless it has expired.
+    pub async fn get_session_with_user(
         pool: &Pool<Postgres>,
         token: &str,
-    ) -> Result<Session, sqlx::Error> {
-        sqlx::query_as(
+    ) -> Result<SessionWithUser, sqlx::Error> {
+        let row: SessionWithUserRow = sqlx::query_as(
             "
-            SELECT id, token, user_id, created_at FROM sessions WHERE token = $1",
+            SELECT
+                s.id, s.token, s.user_id, s.created_at, s.expires_at, s.scopes,
+                s.two_factor_verified, u.username, u.password,
+                u.created_at as user_created_at
+            FROM
+                sessions s
+            JOIN
+                users u ON u.id = s.user_id
+            WHERE
+                    s.token = $1
+                AND
+                    (s.expires_at IS NULL OR s.expires_at > now())",
         )
         .bind(token)
         .fetch_one(pool)
-        .await
+        .await?;
+
+        Ok(row.into())
+    }
+
+    /// Signs the session out, on every instance's cache too.
+    pub async fn revoke_session(pool: &Pool<Postgres>, token: &str) -> Result<(), sqlx::Error> {
+        let mut transaction = pool.begin().await?;
+
+        let (id,): (Uuid,) = sqlx::query_as(
+            "
+            DELETE FROM sessions WHERE token = $1
+            RETURNING id",
+        )
+        .bind(token)
+        .fetch_one(&mut transaction)
+        .await?;
+
+        cache::invalidate(&mut transaction, Invalidation::Session { id }).await?;
+
+        transaction.commit().await?;
+
+        Ok(())
     }
 }
@@ -5,7 +5,12 @@ use sqlx::{
 };
 use uuid::Uuid;
 
-#[derive(Debug, FromRow)]
+use crate::{
+    cache::{self, Invalidation},
+    events::{publish, EventKind},
+};
+
+#[derive(Clone, Debug, FromRow)]
 pub struct Tweet {
     pub id: Uuid,
     pub user_id: Uuid,
@@ -19,6 +24,26 @@ pub struct Tweet {
 }
 
 #[derive(Debug, FromRow)]
+pub struct TweetCounts {
+    /// The tweet's author, so that events can be routed to them.
+    pub user_id: Uuid,
+    pub like_count: i64,
+    pub retweet_count: i64,
+    pub reply_count: i64,
+}
+
+impl TweetCounts {
+    pub fn changed(&self, tweet_id: Uuid) -> EventKind {
+        EventKind::CountsChanged {
+            tweet_id,
+            like_count: self.like_count,
+            retweet_count: self.retweet_count,
+            reply_count: self.reply_count,
+        }
+    }
+}
+
+#[derive(Clone, Debug, FromRow)]
 pub struct TweetWithUserInfo {
     pub id: Uuid,
     pub user_id: Uuid,
@@ -43,7 +68,10 @@ impl Tweet {
     ) -> Result<Tweet, sqlx::Error> {
         let mut transaction = pool.begin().await?;
 
-        let tweet = sqlx::query_as(
+        let mentions = mentions(&content);
+        let hashtags = hashtags(&content);
+
+        let tweet: Tweet = sqlx::query_as(
             "
             INSERT INTO tweets (user_id, responding_to, content)
             VALUES ($1, $2, $3)
@@ -55,23 +83,85 @@ impl Tweet {
         .fetch_one(&mut transaction)
         .await?;
 
+        let mut responding_to_user_id = None;
+
         if let Some(tweet_id) = responding_to {
-            sqlx::query(
+            let counts: TweetCounts = sqlx::query_as(
                 "
             UPDATE tweets
             SET reply_count = reply_count + 1
-            WHERE id = $1",
+            WHERE id = $1
+            RETURNING user_id, like_count, retweet_count, reply_count",
             )
             .bind(tweet_id)
-            .execute(&mut transaction)
+            .fetch_one(&mut transaction)
             .await?;
+
+            publish(&mut transaction, counts.changed(tweet_id)).await?;
+            responding_to_user_id = Some(counts.user_id);
         }
 
+        publish(
+            &mut transaction,
+            EventKind::TweetCreated {
+                tweet_id: tweet.id,
+                user_id: tweet.user_id,
+                responding_to: tweet.responding_to,
+                responding_to_user_id,
+                mentions,
+                hashtags,
+            },
+        )
+        .await?;
+
         transaction.commit().await?;
 
         Ok(tweet)
     }
 
+    /// Only by its author; `RowNotFound` otherwise. Replies to it are kept,
+    /// and no longer count as replies.
+    pub async fn delete_tweet(
+        pool: &Pool<Postgres>,
+        id: &Uuid,
+        user_id: &Uuid,
+    ) -> Result<(), sqlx::Error> {
+        let mut transaction = pool.begin().await?;
+
+        let (responding_to,): (Option<Uuid>,) = sqlx::query_as(
+            "
+            DELETE FROM tweets WHERE id = $1 AND user_id = $2
+            RETURNING responding_to",
+        )
+        .bind(id)
+        .bind(user_id)
+        .fetch_one(&mut transaction)
+        .await?;
+
+        if let Some(tweet_id) = responding_to {
+            // The tweet replied to may have gone already.
+            let counts: Option<TweetCounts> = sqlx::query_as(
+                "
+            UPDATE tweets
+            SET reply_count = reply_count - 1
+            WHERE id = $1
+            RETURNING user_id, like_count, retweet_count, reply_count",
+            )
+            .bind(tweet_id)
+            .fetch_optional(&mut transaction)
+            .await?;
+
+            if let Some(counts) = counts {
+                publish(&mut transaction, counts.changed(tweet_id)).await?;
+            }
+        }
+        cache::invalidate(&mut transaction, Invalidation::Tweet { id: *id }).await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
     pub async fn get_tweet_for_id(pool: &Pool<Postgres>, id: &Uuid) -> Result<Tweet, sqlx::Error> {
         sqlx::query_as(
             "
@@ -208,4 +298,113 @@ impl Tweet {
         .fetch_all(pool)
         .await
     }
+
+    pub async fn get_recent_tweets_for_author_with_user_info(
+        pool: &Pool<Postgres>,
+        author_id: &Uuid,
+        user_id: Option<&Uuid>,
+        offset: Option<DateTime<Utc>>,
+    ) -> Result<Vec<TweetWithUserInfo>, sqlx::Error> {
+        sqlx::query_as(
+            "
+            SELECT
+                t.id, t.user_id, t.responding_to, t.content,
+                t.created_at, t.updated_at, u.username, t.like_count,
+                t.retweet_count, t.reply_count,
+                l.user_id IS NOT NULL as user_has_liked,
+                r.user_id IS NOT NULL as user_has_retweeted
+            FROM
+                tweets as t
+            JOIN
+                users as u ON t.user_id = u.id
+            LEFT JOIN
+                likes as l ON l.tweet_id = t.id AND l.user_id = $3
+            LEFT JOIN
+                retweets as r ON r.tweet_id = t.id AND r.user_id = $3
+            WHERE
+                    t.created_at < $2
+                AND
+                    t.user_id = $1
+                AND
+                    t.responding_to IS NULL
+            ORDER BY
+                t.created_at DESC
+            LIMIT 20",
+        )
+        .bind(author_id)
+        .bind(offset.unwrap_or_else(|| Utc::now() + Duration::days(1)))
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// `hashtag` is matched as a whole word, without the leading `#`. Callers are
+    /// expected to have checked it with `is_valid_hashtag` first.
+    pub async fn get_recent_tweets_for_hashtag_with_user_info(
+        pool: &Pool<Postgres>,
+        hashtag: &str,
+        user_id: Option<&Uuid>,
+        offset: Option<DateTime<Utc>>,
+    ) -> Result<Vec<TweetWithUserInfo>, sqlx::Error> {
+        sqlx::query_as(
+            "
+            SELECT
+                t.id, t.user_id, t.responding_to, t.content,
+                t.created_at, t.updated_at, u.username, t.like_count,
+                t.retweet_count, t.reply_count,
+                l.user_id IS NOT NULL as user_has_liked,
+                r.user_id IS NOT NULL as user_has_retweeted
+            FROM
+                tweets as t
+            JOIN
+                users as u ON t.user_id = u.id
+            LEFT JOIN
+                likes as l ON l.tweet_id = t.id AND l.user_id = $3
+            LEFT JOIN
+                retweets as r ON r.tweet_id = t.id AND r.user_id = $3
+            WHERE
+                    t.created_at < $2
+                AND
+                    t.content ~* ('(^|[^[:alnum:]_])#' || $1 || '([^[:alnum:]_]|$)')
+            ORDER BY
+                t.created_at DESC
+            LIMIT 20",
+        )
+        .bind(hashtag)
+        .bind(offset.unwrap_or_else(|| Utc::now() + Duration::days(1)))
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+    }
+}
+
+pub fn is_valid_hashtag(hashtag: &str) -> bool {
+    !hashtag.is_empty()
+        && hashtag.len() <= 100
+        && hashtag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Words in `content` starting with `sigil`, lowercased and without the sigil.
+fn tagged_words(content: &str, sigil: char) -> Vec<String> {
+    let mut words: Vec<String> = content
+        .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == sigil))
+        .filter_map(|word| word.strip_prefix(sigil))
+        .filter(|word| is_valid_hashtag(word))
+        .map(|word| word.to_lowercase())
+        .collect();
+
+    words.sort();
+    words.dedup();
+
+    words
+}
+
+pub fn hashtags(content: &str) -> Vec<String> {
+    tagged_words(content, '#')
+}
+
+pub fn mentions(content: &str) -> Vec<String> {
+    tagged_words(content, '@')
 }
@@ -20,12 +20,12 @@ impl User {
     ) -> Result<User, sqlx::Error> {
         sqlx::query_as(
             "
-            INSERT INTO users (LOWER(username), password)
-            VALUES ($1, $2)
+            INSERT INTO users (username, password)
+            VALUES (LOWER($1), $2)
             RETURNING id, username, password, created_at",
         )
         .bind(username)
-        .bind(&password_hash)
+        .bind(password_hash)
         .fetch_one(pool)
         .await
     }
@@ -0,0 +1,276 @@
+use sha2::{Digest, Sha256};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    FromRow, Pool, Postgres,
+};
+use uuid::Uuid;
+
+/// Consecutive failed deliveries after which a webhook is switched off.
+pub const MAX_CONSECUTIVE_FAILURES: i32 = 10;
+
+#[derive(Clone, Debug, FromRow)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub url: String,
+    /// Key for the HMAC signature sent with every delivery.
+    pub secret: String,
+    pub event_types: Vec<String>,
+    pub consecutive_failures: i32,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, FromRow)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub webhook_id: Uuid,
+    pub event_id: Uuid,
+    pub event_type: String,
+    pub payload: String,
+    pub attempts: i32,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    /// When the next retry is due. `None` once delivered or given up on.
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook {
+    pub async fn get_webhook(pool: &Pool<Postgres>, id: &Uuid) -> Result<Webhook, sqlx::Error> {
+        sqlx::query_as(
+            "
+            SELECT id, user_id, url, secret, event_types, consecutive_failures, disabled_at, created_at
+            FROM webhooks
+            WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await
+    }
+
+    pub async fn create_webhook(
+        pool: &Pool<Postgres>,
+        user_id: &Uuid,
+        url: &str,
+        event_types: &[String],
+    ) -> Result<Webhook, sqlx::Error> {
+        sqlx::query_as(
+            "
+            INSERT INTO webhooks (user_id, url, secret, event_types)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id, user_id, url, secret, event_types, consecutive_failures, disabled_at, created_at",
+        )
+        .bind(user_id)
+        .bind(url)
+        .bind(format!(
+            "{:x}",
+            Sha256::new()
+                .chain_update(Uuid::new_v4().to_bytes_le())
+                .finalize()
+        ))
+        .bind(event_types)
+        .fetch_one(pool)
+        .await
+    }
+
+    pub async fn get_webhooks_for_user(
+        pool: &Pool<Postgres>,
+        user_id: &Uuid,
+    ) -> Result<Vec<Webhook>, sqlx::Error> {
+        sqlx::query_as(
+            "
+            SELECT id, user_id, url, secret, event_types, consecutive_failures, disabled_at, created_at
+            FROM webhooks
+            WHERE user_id = $1
+            ORDER BY created_at DESC",
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Enabled webhooks subscribed to `event_type` belonging to any of `user_ids`.
+    pub async fn get_active_webhooks(
+        pool: &Pool<Postgres>,
+        user_ids: &[Uuid],
+        event_type: &str,
+    ) -> Result<Vec<Webhook>, sqlx::Error> {
+        sqlx::query_as(
+            "
+            SELECT id, user_id, url, secret, event_types, consecutive_failures, disabled_at, created_at
+            FROM webhooks
+            WHERE
+                    user_id = ANY($1)
+                AND
+                    $2 = ANY(event_types)
+                AND
+                    disabled_at IS NULL",
+        )
+        .bind(user_ids)
+        .bind(event_type)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Like `get_active_webhooks`, for users identified by their username.
+    pub async fn get_active_webhooks_for_usernames(
+        pool: &Pool<Postgres>,
+        usernames: &[String],
+        event_type: &str,
+    ) -> Result<Vec<Webhook>, sqlx::Error> {
+        sqlx::query_as(
+            "
+            SELECT
+                w.id, w.user_id, w.url, w.secret, w.event_types,
+                w.consecutive_failures, w.disabled_at, w.created_at
+            FROM
+                webhooks as w
+            JOIN
+                users as u ON w.user_id = u.id
+            WHERE
+                    u.username = ANY($1)
+                AND
+                    $2 = ANY(w.event_types)
+                AND
+                    w.disabled_at IS NULL",
+        )
+        .bind(usernames)
+        .bind(event_type)
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn record_success(pool: &Pool<Postgres>, id: &Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "
+            UPDATE webhooks
+            SET consecutive_failures = 0
+            WHERE id = $1",
+        )
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Counts a delivery that ran out of retries, disabling the webhook once it
+    /// reaches `MAX_CONSECUTIVE_FAILURES`. Returns whether it is now disabled.
+    pub async fn record_failure(pool: &Pool<Postgres>, id: &Uuid) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(
+            "
+            UPDATE webhooks
+            SET
+                consecutive_failures = consecutive_failures + 1,
+                disabled_at = CASE
+                    WHEN consecutive_failures + 1 >= $2 THEN COALESCE(disabled_at, now())
+                    ELSE disabled_at
+                END
+            WHERE id = $1
+            RETURNING disabled_at IS NOT NULL",
+        )
+        .bind(id)
+        .bind(MAX_CONSECUTIVE_FAILURES)
+        .fetch_one(pool)
+        .await
+    }
+}
+
+impl WebhookDelivery {
+    /// Claims the delivery of `event_id` to `webhook_id`. Every instance sees
+    /// every event, so only the one whose insert succeeds gets `Some` back and
+    /// goes on to deliver it.
+    pub async fn claim_delivery(
+        pool: &Pool<Postgres>,
+        webhook_id: &Uuid,
+        event_id: &Uuid,
+        event_type: &str,
+        payload: &str,
+    ) -> Result<Option<WebhookDelivery>, sqlx::Error> {
+        sqlx::query_as(
+            "
+            INSERT INTO webhook_deliveries (webhook_id, event_id, event_type, payload)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (webhook_id, event_id) DO NOTHING
+            RETURNING
+                id, webhook_id, event_id, event_type, payload, attempts,
+                status_code, error, delivered_at, next_attempt_at, created_at",
+        )
+        .bind(webhook_id)
+        .bind(event_id)
+        .bind(event_type)
+        .bind(payload)
+        .fetch_optional(pool)
+        .await
+    }
+
+    pub async fn get_delivery(
+        pool: &Pool<Postgres>,
+        id: &Uuid,
+    ) -> Result<WebhookDelivery, sqlx::Error> {
+        sqlx::query_as(
+            "
+            SELECT
+                id, webhook_id, event_id, event_type, payload, attempts,
+                status_code, error, delivered_at, next_attempt_at, created_at
+            FROM webhook_deliveries
+            WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Records an attempt, along with when the next one is due if there is to
+    /// be one.
+    pub async fn record_attempt(
+        pool: &Pool<Postgres>,
+        id: &Uuid,
+        status_code: Option<i32>,
+        error: Option<&str>,
+        delivered: bool,
+        next_attempt_at: Option<DateTime<Utc>>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "
+            UPDATE webhook_deliveries
+            SET
+                attempts = attempts + 1,
+                status_code = $2,
+                error = $3,
+                delivered_at = CASE WHEN $4 THEN now() ELSE NULL END,
+                next_attempt_at = $5
+            WHERE id = $1",
+        )
+        .bind(id)
+        .bind(status_code)
+        .bind(error)
+        .bind(delivered)
+        .bind(next_attempt_at)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_recent_deliveries_for_webhook(
+        pool: &Pool<Postgres>,
+        webhook_id: &Uuid,
+    ) -> Result<Vec<WebhookDelivery>, sqlx::Error> {
+        sqlx::query_as(
+            "
+            SELECT
+                id, webhook_id, event_id, event_type, payload, attempts,
+                status_code, error, delivered_at, next_attempt_at, created_at
+            FROM webhook_deliveries
+            WHERE webhook_id = $1
+            ORDER BY created_at DESC
+            LIMIT 20",
+        )
+        .bind(webhook_id)
+        .fetch_all(pool)
+        .await
+    }
+}
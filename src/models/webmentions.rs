@@ -0,0 +1,134 @@
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    FromRow, Pool, Postgres,
+};
+use uuid::Uuid;
+
+pub const PENDING: &str = "pending";
+pub const VERIFIED: &str = "verified";
+pub const REJECTED: &str = "rejected";
+
+/// A page elsewhere on the web that links to one of our tweets.
+#[derive(Debug, FromRow)]
+pub struct Webmention {
+    pub id: Uuid,
+    pub tweet_id: Uuid,
+    pub source: String,
+    pub target: String,
+    pub status: String,
+    /// The source page's `<title>`, once verified.
+    pub title: Option<String>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub verified_at: Option<DateTime<Utc>>,
+}
+
+impl Webmention {
+    /// Records a webmention for verification. Re-sending one that already
+    /// exists puts it back to pending, since the source page may have changed.
+    pub async fn create_webmention(
+        pool: &Pool<Postgres>,
+        tweet_id: &Uuid,
+        source: &str,
+        target: &str,
+    ) -> Result<Webmention, sqlx::Error> {
+        sqlx::query_as(
+            "
+            INSERT INTO webmentions (tweet_id, source, target, status)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (source, target) DO UPDATE SET status = EXCLUDED.status, error = NULL
+            RETURNING id, tweet_id, source, target, status, title, error, created_at, verified_at",
+        )
+        .bind(tweet_id)
+        .bind(source)
+        .bind(target)
+        .bind(PENDING)
+        .fetch_one(pool)
+        .await
+    }
+
+    pub async fn get_webmention_for_id(
+        pool: &Pool<Postgres>,
+        id: &Uuid,
+    ) -> Result<Webmention, sqlx::Error> {
+        sqlx::query_as(
+            "
+            SELECT id, tweet_id, source, target, status, title, error, created_at, verified_at
+            FROM webmentions
+            WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await
+    }
+
+    pub async fn get_pending_webmention_ids(
+        pool: &Pool<Postgres>,
+    ) -> Result<Vec<Uuid>, sqlx::Error> {
+        sqlx::query_scalar(
+            "
+            SELECT id FROM webmentions WHERE status = $1 ORDER BY created_at",
+        )
+        .bind(PENDING)
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn get_verified_webmentions_for_tweet(
+        pool: &Pool<Postgres>,
+        tweet_id: &Uuid,
+    ) -> Result<Vec<Webmention>, sqlx::Error> {
+        sqlx::query_as(
+            "
+            SELECT id, tweet_id, source, target, status, title, error, created_at, verified_at
+            FROM webmentions
+            WHERE tweet_id = $1 AND status = $2
+            ORDER BY verified_at DESC
+            LIMIT 50",
+        )
+        .bind(tweet_id)
+        .bind(VERIFIED)
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn mark_verified(
+        pool: &Pool<Postgres>,
+        id: &Uuid,
+        title: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "
+            UPDATE webmentions
+            SET status = $2, title = $3, error = NULL, verified_at = now()
+            WHERE id = $1",
+        )
+        .bind(id)
+        .bind(VERIFIED)
+        .bind(title)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn mark_rejected(
+        pool: &Pool<Postgres>,
+        id: &Uuid,
+        error: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "
+            UPDATE webmentions
+            SET status = $2, error = $3
+            WHERE id = $1",
+        )
+        .bind(id)
+        .bind(REJECTED)
+        .bind(error)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect::{Attempt, Policy},
+    RequestBuilder, Url,
+};
+
+use crate::config::OutboundConfig;
+
+/// Redirects followed before giving up, as reqwest does by default.
+const MAX_REDIRECTS: usize = 10;
+
+fn is_global_v4(ip: &Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network"
+        || a == 0
+        // Shared address space, for carrier-grade NAT
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking
+        || (a == 198 && (18..20).contains(&b))
+        // Reserved
+        || a >= 240)
+}
+
+fn is_global_v6(ip: &Ipv6Addr) -> bool {
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return is_global_v4(&ip);
+    }
+    let first = ip.segments()[0];
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local
+        || (first & 0xfe00) == 0xfc00
+        // Link local
+        || (first & 0xffc0) == 0xfe80
+        // Documentation
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+/// Whether `ip` is on the public internet, rather than loopback, a private
+/// network, link local (such as cloud metadata at 169.254.169.254) or
+/// otherwise reserved.
+pub fn is_global(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_global_v4(ip),
+        IpAddr::V6(ip) => is_global_v6(ip),
+    }
+}
+
+/// Whether `url` is http(s), with a host that isn't an IP address off the
+/// public internet. Hostnames are checked as they are resolved.
+fn is_public_url(url: &Url) -> bool {
+    let host = url.host_str().unwrap_or_default();
+    let host_is_public = match host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        Ok(ip) => is_global(&ip),
+        Err(_) => !host.is_empty(),
+    };
+
+    matches!(url.scheme(), "http" | "https") && host_is_public
+}
+
+/// Resolves as usual, leaving out addresses off the public internet.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_global(&addr.ip()))
+                .collect();
+
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether requests to `url` are allowed at all. Addresses its hostname
+/// resolves to are only checked once a request is made.
+pub fn is_allowed(config: &OutboundConfig, url: &str) -> bool {
+    match Url::parse(url) {
+        Ok(url) => {
+            matches!(url.scheme(), "http" | "https")
+                && (config.allow_private_addresses || is_public_url(&url))
+        }
+        Err(_) => false,
+    }
+}
+
+fn follow_public(attempt: Attempt) -> reqwest::redirect::Action {
+    if attempt.previous().len() >= MAX_REDIRECTS {
+        attempt.error("too many redirects")
+    } else if !is_public_url(attempt.url()) {
+        attempt.error("redirected to an address that isn't public")
+    } else {
+        attempt.follow()
+    }
+}
+
+/// For requests to URLs users hand us, such as webhook receivers and
+/// webmention sources, which must not be a way into the server's own network.
+/// Every address is checked, whether it comes from the URL, DNS or a redirect.
+#[derive(Clone)]
+pub struct OutboundClient {
+    client: reqwest::Client,
+    allow_private_addresses: bool,
+}
+
+impl OutboundClient {
+    pub fn new(
+        config: &OutboundConfig,
+        timeout: Duration,
+        user_agent: &str,
+    ) -> Result<OutboundClient, reqwest::Error> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(timeout)
+            .user_agent(user_agent);
+        if !config.allow_private_addresses {
+            builder = builder
+                .dns_resolver(Arc::new(PublicResolver))
+                .redirect(Policy::custom(follow_public));
+        }
+
+        Ok(OutboundClient {
+            client: builder.build()?,
+            allow_private_addresses: config.allow_private_addresses,
+        })
+    }
+
+    fn check(&self, url: &str) -> Result<Url, String> {
+        let url = Url::parse(url).map_err(|e| e.to_string())?;
+
+        if !matches!(url.scheme(), "http" | "https") {
+            Err(format!("{} isn't an http(s) URL", url))
+        } else if self.allow_private_addresses || is_public_url(&url) {
+            Ok(url)
+        } else {
+            Err(format!("{} isn't a public address", url))
+        }
+    }
+
+    pub fn get(&self, url: &str) -> Result<RequestBuilder, String> {
+        Ok(self.client.get(self.check(url)?))
+    }
+
+    pub fn post(&self, url: &str) -> Result<RequestBuilder, String> {
+        Ok(self.client.post(self.check(url)?))
+    }
+}
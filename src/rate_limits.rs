@@ -0,0 +1,418 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use log::{error, warn};
+use sqlx::{Pool, Postgres};
+use thruster::{middleware_fn, Context, MiddlewareNext, MiddlewareResult};
+use tokio::time::Instant;
+
+use crate::{app::Ctx, config::RateLimitConfig, errors::AppError, telemetry::route_template};
+
+/// The memory store drops buckets that have filled up again once it holds
+/// this many.
+const PRUNE_AT: usize = 10_000;
+
+/// How often full buckets are deleted from Postgres.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A token bucket holding up to `burst` requests, refilled at `per_second`.
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    pub burst: f64,
+    pub per_second: f64,
+}
+
+impl Limit {
+    pub fn new(requests_per_minute: u32, burst: u32) -> Limit {
+        Limit {
+            burst: burst as f64,
+            per_second: requests_per_minute as f64 / 60.0,
+        }
+    }
+
+    /// `tokens` are what the bucket holds after the request.
+    fn decide(&self, tokens: f64, allowed: bool) -> Decision {
+        let secs_for = |missing: f64| (missing / self.per_second).ceil().max(0.0) as u64;
+
+        Decision {
+            allowed,
+            limit: self.burst as u64,
+            remaining: tokens.floor().max(0.0) as u64,
+            reset_secs: secs_for(self.burst - tokens),
+            retry_after_secs: if allowed {
+                0
+            } else {
+                secs_for(1.0 - tokens).max(1)
+            },
+        }
+    }
+}
+
+/// The outcome of taking a request from a bucket, as the `RateLimit-*`
+/// headers describe it.
+#[derive(Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    /// Until the bucket is full again.
+    pub reset_secs: u64,
+    /// Until a request would be let through; 0 when this one was.
+    pub retry_after_secs: u64,
+}
+
+impl Decision {
+    fn set_headers(&self, context: &mut Ctx) {
+        context.set("RateLimit-Limit", &self.limit.to_string());
+        context.set("RateLimit-Remaining", &self.remaining.to_string());
+        context.set("RateLimit-Reset", &self.reset_secs.to_string());
+    }
+}
+
+/// Where the buckets are kept.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a request from the bucket `key`, which starts full, if there is
+    /// one to take.
+    async fn take(&self, key: &str, limit: &Limit) -> Result<Decision, sqlx::Error>;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    full_at: Instant,
+}
+
+/// Buckets for this instance alone.
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn take(&self, key: &str, limit: &Limit) -> Result<Decision, sqlx::Error> {
+        let now = Instant::now();
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if buckets.len() >= PRUNE_AT {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: limit.burst,
+            updated_at: now,
+            full_at: now,
+        });
+
+        let refilled = (now - bucket.updated_at).as_secs_f64() * limit.per_second;
+        let tokens = (bucket.tokens + refilled).min(limit.burst);
+        let allowed = tokens >= 1.0;
+
+        bucket.tokens = if allowed { tokens - 1.0 } else { tokens };
+        bucket.updated_at = now;
+        bucket.full_at =
+            now + Duration::from_secs_f64((limit.burst - bucket.tokens) / limit.per_second);
+
+        Ok(limit.decide(bucket.tokens, allowed))
+    }
+}
+
+/// Buckets shared by every instance on the database. Each request is a
+/// single statement, two for a new bucket, locking only its own bucket.
+pub struct PgRateLimitStore {
+    pool: Pool<Postgres>,
+}
+
+impl PgRateLimitStore {
+    pub fn new(pool: Pool<Postgres>) -> PgRateLimitStore {
+        PgRateLimitStore { pool }
+    }
+
+    async fn take_existing(
+        &self,
+        key: &str,
+        limit: &Limit,
+    ) -> Result<Option<(f64, bool)>, sqlx::Error> {
+        sqlx::query_as(
+            "
+            WITH refilled AS (
+                SELECT
+                    key,
+                    LEAST(
+                        $2,
+                        tokens + EXTRACT(EPOCH FROM now() - updated_at)::float8 * $3
+                    ) as tokens
+                FROM rate_limit_buckets
+                WHERE key = $1
+                FOR UPDATE
+            ), taken AS (
+                SELECT
+                    key,
+                    tokens >= 1 as allowed,
+                    CASE WHEN tokens >= 1 THEN tokens - 1 ELSE tokens END as tokens
+                FROM refilled
+            )
+            UPDATE rate_limit_buckets as b
+            SET
+                tokens = t.tokens,
+                allowed = t.allowed,
+                updated_at = now(),
+                full_at = now() + ($2 - t.tokens) / $3 * interval '1 second'
+            FROM taken t
+            WHERE b.key = t.key
+            RETURNING b.tokens, b.allowed",
+        )
+        .bind(key)
+        .bind(limit.burst)
+        .bind(limit.per_second)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Deletes buckets that have filled up again every `PRUNE_INTERVAL`.
+    pub fn spawn_pruner(pool: Pool<Postgres>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                let pruned = sqlx::query(
+                    "
+                    DELETE FROM rate_limit_buckets WHERE full_at < now()",
+                )
+                .execute(&pool)
+                .await;
+                if let Err(_e) = pruned {
+                    error!("Could not prune rate limit buckets: {:#?}", _e);
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for PgRateLimitStore {
+    async fn take(&self, key: &str, limit: &Limit) -> Result<Decision, sqlx::Error> {
+        if let Some((tokens, allowed)) = self.take_existing(key, limit).await? {
+            return Ok(limit.decide(tokens, allowed));
+        }
+
+        let inserted: Option<(f64,)> = sqlx::query_as(
+            "
+            INSERT INTO rate_limit_buckets (key, tokens, allowed, full_at)
+            VALUES ($1, $2 - 1, true, now() + interval '1 second' / $3)
+            ON CONFLICT (key) DO NOTHING
+            RETURNING tokens",
+        )
+        .bind(key)
+        .bind(limit.burst)
+        .bind(limit.per_second)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match inserted {
+            Some((tokens,)) => Ok(limit.decide(tokens, true)),
+            // Another request made the bucket first.
+            None => {
+                let (tokens, allowed) = self
+                    .take_existing(key, limit)
+                    .await?
+                    .ok_or(sqlx::Error::RowNotFound)?;
+
+                Ok(limit.decide(tokens, allowed))
+            }
+        }
+    }
+}
+
+/// What a rule counts requests by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Key {
+    Ip,
+    User,
+    ApiToken,
+}
+
+impl Key {
+    /// `None` for anything but `ip`, `user` and `api_token`, which
+    /// `Config::validate` refuses.
+    pub(crate) fn parse(key: &str) -> Option<Key> {
+        match key {
+            "ip" => Some(Key::Ip),
+            "user" => Some(Key::User),
+            "api_token" => Some(Key::ApiToken),
+            _ => None,
+        }
+    }
+}
+
+struct Rule {
+    /// e.g. `POST /tweets/:id/likes`.
+    route: String,
+    key: Key,
+    limit: Limit,
+}
+
+/// The rules from `[rate_limits]`, and the store their buckets are kept in.
+pub struct RateLimiter {
+    rules: Vec<Rule>,
+    /// For routes no rule names.
+    default: Limit,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    /// Rules with a key `Config::validate` would refuse are left out.
+    pub fn new(config: &RateLimitConfig, store: Arc<dyn RateLimitStore>) -> RateLimiter {
+        RateLimiter {
+            rules: config
+                .rules
+                .iter()
+                .filter_map(|rule| match Key::parse(&rule.key) {
+                    Some(key) => Some(Rule {
+                        route: rule.route.clone(),
+                        key,
+                        limit: Limit::new(rule.requests_per_minute, rule.burst),
+                    }),
+                    None => {
+                        warn!(
+                            "Ignoring the rate limit for {}: unknown key {:?}",
+                            rule.route, rule.key
+                        );
+                        None
+                    }
+                })
+                .collect(),
+            default: Limit::new(config.requests_per_minute, config.burst),
+            store,
+        }
+    }
+
+    /// With the store `config.store` names, or `None` when rate limits are off.
+    /// `pool` is `None` on SQLite, where the config only allows the `memory`
+    /// store.
+    pub fn from_config(
+        config: &RateLimitConfig,
+        pool: Option<&Pool<Postgres>>,
+    ) -> Option<RateLimiter> {
+        if !config.enabled {
+            return None;
+        }
+
+        let store: Arc<dyn RateLimitStore> = match pool {
+            Some(pool) if config.store == "postgres" => {
+                PgRateLimitStore::spawn_pruner(pool.clone());
+
+                Arc::new(PgRateLimitStore::new(pool.clone()))
+            }
+            _ => Arc::new(MemoryRateLimitStore::default()),
+        };
+
+        Some(RateLimiter::new(config, store))
+    }
+
+    /// The bucket for the request, and its limit.
+    fn bucket(&self, context: &Ctx) -> (String, Limit) {
+        let (method, path) = context
+            .hyper_request
+            .as_ref()
+            .map(|request| {
+                (
+                    request.request.method().to_string(),
+                    request.request.uri().path().to_string(),
+                )
+            })
+            .unwrap_or_default();
+        let route = format!(
+            "{} {}",
+            method,
+            route_template(context, &method, &path).unwrap_or(path)
+        );
+
+        let (name, key, limit) = match self.rules.iter().find(|rule| rule.route == route) {
+            Some(rule) => (rule.route.as_str(), rule.key, rule.limit),
+            None => ("*", Key::User, self.default),
+        };
+
+        let ip = context
+            .hyper_request
+            .as_ref()
+            .and_then(|request| request.ip)
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let client = match key {
+            Key::User => context
+                .extra
+                .user
+                .as_ref()
+                .map(|user| format!("user:{}", user.id)),
+            Key::ApiToken => context
+                .extra
+                .api_token
+                .as_ref()
+                .map(|api_token| format!("api_token:{}", api_token.id)),
+            Key::Ip => None,
+        }
+        .unwrap_or_else(|| format!("ip:{}", ip));
+
+        (format!("{}|{}", name, client), limit)
+    }
+}
+
+/// Lets the request through if its bucket has room, answering with 429
+/// otherwise, and with `RateLimit-*` headers either way. Goes after the
+/// middleware that looks up the user or API token, for rules keyed by them.
+///
+/// A store that can't be reached lets requests through rather than take the
+/// site down with it.
+#[middleware_fn]
+pub async fn rate_limit(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let limiter = match context.extra.rate_limiter.clone() {
+        Some(limiter) => limiter,
+        None => return next(context).await,
+    };
+
+    let (key, limit) = limiter.bucket(&context);
+    let decision = match limiter.store.take(&key, &limit).await {
+        Ok(decision) => decision,
+        Err(_e) => {
+            error!("Could not check the rate limit: {:#?}", _e);
+
+            return next(context).await;
+        }
+    };
+
+    if !decision.allowed {
+        context.extra.metrics.rate_limited.inc();
+
+        let mut e = AppError::RateLimited {
+            retry_after_secs: decision.retry_after_secs,
+        }
+        .into_thruster_error(&context);
+        decision.set_headers(&mut e.context);
+
+        return Err(e);
+    }
+
+    match next(context).await {
+        Ok(mut context) => {
+            decision.set_headers(&mut context);
+
+            Ok(context)
+        }
+        Err(mut e) => {
+            decision.set_headers(&mut e.context);
+
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+use hyper::{
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Request, Server,
+};
+use log::{info, warn};
+use thruster::{App, HyperRequest};
+use tokio::{
+    sync::oneshot,
+    time::{timeout, timeout_at, Instant},
+};
+
+use crate::{
+    app::{Ctx, ServerConfig},
+    background::Background,
+    config::HttpConfig,
+    database::Database,
+    health::Health,
+};
+
+const POOL_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the server winds down after the signal.
+pub struct Shutdown {
+    pub health: Health,
+    pub background: Background,
+    pub database: Database,
+    /// How long requests are still accepted with `/readyz` failing.
+    pub grace: Duration,
+    /// For draining requests, counted from when they stop being accepted.
+    pub timeout: Duration,
+    /// For flushing background work, counted from when requests are drained.
+    pub background_timeout: Duration,
+}
+
+impl Shutdown {
+    pub fn new(
+        config: &HttpConfig,
+        health: Health,
+        background: Background,
+        database: Database,
+    ) -> Shutdown {
+        Shutdown {
+            health,
+            background,
+            database,
+            grace: Duration::from_secs(config.shutdown_grace_secs),
+            timeout: Duration::from_secs(config.shutdown_timeout_secs),
+            background_timeout: Duration::from_secs(config.shutdown_background_timeout_secs),
+        }
+    }
+}
+
+/// Resolves on SIGTERM, as sent by orchestrators and `docker stop`, or on
+/// SIGINT.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => info!("Received SIGTERM"),
+                    _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+                }
+            }
+            Err(_e) => {
+                warn!("Could not listen for SIGTERM: {}", _e);
+                let _ = tokio::signal::ctrl_c().await;
+                info!("Received SIGINT");
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Received Ctrl-C");
+    }
+}
+
+fn socket_addr(host: &str, port: u16) -> std::io::Result<SocketAddr> {
+    (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("{} does not resolve", host),
+        )
+    })
+}
+
+/// Serves `app` on the configured address until SIGTERM or SIGINT. See `run`.
+pub async fn serve(
+    app: App<HyperRequest, Ctx, ServerConfig>,
+    host: &str,
+    port: u16,
+    shutdown: Shutdown,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(socket_addr(host, port)?)?;
+
+    run(app, listener, signal(), shutdown).await
+}
+
+/// Serves `app` like thruster's `HyperServer` until `signal` resolves. Then
+/// `/readyz` fails for the grace period while requests are still served, so
+/// that load balancers see it and stop sending them. After that it stops
+/// accepting connections, gives the requests in flight the shutdown timeout to
+/// finish, waits out background work for a timeout of its own, and closes the
+/// pool.
+///
+/// Long-lived responses such as `/events` and `/streaming` end themselves once
+/// shutdown begins, and anything else still running is cut off when the time
+/// is up.
+pub async fn run(
+    app: App<HyperRequest, Ctx, ServerConfig>,
+    listener: TcpListener,
+    signal: impl Future<Output = ()>,
+    shutdown: Shutdown,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = Arc::new(app.commit());
+    let addr = listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+
+    let service = make_service_fn(move |socket: &AddrStream| {
+        let app = app.clone();
+        let ip = socket.remote_addr().ip();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let mut request = HyperRequest::new(request);
+                request.ip = Some(ip);
+
+                // As thruster's own server does, so that the route's
+                // parameters are set on the request.
+                app.match_and_resolve(request)
+            }))
+        }
+    });
+
+    let (signalled, mut signalled_at) = oneshot::channel();
+    let health = shutdown.health.clone();
+    let grace = shutdown.grace;
+    let server = Server::from_tcp(listener)?
+        .serve(service)
+        .with_graceful_shutdown(async move {
+            signal.await;
+
+            // `/readyz` fails from here on, while requests are still served.
+            health.begin_shutdown();
+            if !grace.is_zero() {
+                info!("Failing readiness for {:?} before draining", grace);
+                tokio::time::sleep(grace).await;
+            }
+            let _ = signalled.send(Instant::now());
+        });
+    tokio::pin!(server);
+
+    info!("Listening on {}", addr);
+
+    // With nothing in flight the server can finish in the same poll as the
+    // signal arrives, in which case there is nothing left to drain.
+    let mut drained = false;
+    let deadline = tokio::select! {
+        result = &mut server => {
+            result?;
+            drained = true;
+
+            Instant::now() + shutdown.timeout
+        }
+        Ok(signalled_at) = &mut signalled_at => signalled_at + shutdown.timeout,
+    };
+
+    if !drained {
+        info!("Draining requests for up to {:?}", shutdown.timeout);
+        match timeout_at(deadline, &mut server).await {
+            Ok(result) => result?,
+            Err(_) => warn!("Shutdown timeout reached with requests still in flight"),
+        }
+    }
+
+    let running = shutdown.background.running();
+    if running > 0 {
+        info!("Waiting for {} background job(s)", running);
+    }
+    if timeout(shutdown.background_timeout, shutdown.background.drain())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Gave up on {} background job(s)",
+            shutdown.background.running()
+        );
+    }
+
+    close_database(&shutdown.database).await;
+    info!("Shut down");
+
+    Ok(())
+}
+
+/// Connections that are never handed back, such as the one listening for
+/// events, would otherwise hold this up for good.
+async fn close_database(database: &Database) {
+    if timeout(POOL_CLOSE_TIMEOUT, database.close()).await.is_err() {
+        warn!("Gave up waiting for database connections to close");
+    }
+}
@@ -0,0 +1,264 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use sqlx::types::chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::{
+    cache::{Caches, Invalidation},
+    models::{
+        follows::Follow,
+        likes::Like,
+        retweets::Retweet,
+        sessions::{Session, SessionWithUser},
+        tweets::{Tweet, TweetWithUserInfo},
+        users::User,
+    },
+    stores::{SessionStore, SocialGraphStore, StoreResult, Stores, TweetStore, UserStore},
+};
+
+/// Caches single sessions, users and tweets in front of other stores. Lists
+/// such as timelines are always read from them.
+///
+/// Changes made here are dropped from the caches straight away. Changes made
+/// by other instances arrive through `Caches::listen`, so until then what is
+/// read here may be up to the cache's ttl old.
+pub struct CachedStore {
+    inner: Stores,
+    caches: Arc<Caches>,
+}
+
+impl CachedStore {
+    pub fn new(inner: Stores, caches: Arc<Caches>) -> CachedStore {
+        CachedStore { inner, caches }
+    }
+
+    fn invalidate_tweet(&self, id: &Uuid) {
+        self.caches.invalidate(&Invalidation::Tweet { id: *id });
+    }
+}
+
+#[async_trait]
+impl TweetStore for CachedStore {
+    async fn create_tweet(
+        &self,
+        user_id: &Uuid,
+        responding_to: Option<Uuid>,
+        content: String,
+    ) -> StoreResult<Tweet> {
+        let tweet = self
+            .inner
+            .tweets
+            .create_tweet(user_id, responding_to, content)
+            .await?;
+
+        if let Some(tweet_id) = &tweet.responding_to {
+            self.invalidate_tweet(tweet_id);
+        }
+
+        Ok(tweet)
+    }
+
+    async fn delete_tweet(&self, id: &Uuid, user_id: &Uuid) -> StoreResult<()> {
+        // For the tweet responded to, whose reply count goes down.
+        let responding_to = self
+            .get_tweet(id)
+            .await
+            .ok()
+            .and_then(|tweet| tweet.responding_to);
+
+        let deleted = self.inner.tweets.delete_tweet(id, user_id).await;
+
+        self.invalidate_tweet(id);
+        if let Some(tweet_id) = &responding_to {
+            self.invalidate_tweet(tweet_id);
+        }
+
+        deleted
+    }
+
+    async fn get_tweet(&self, id: &Uuid) -> StoreResult<Tweet> {
+        if let Some(tweet) = self.caches.tweets.get(id) {
+            return Ok(tweet);
+        }
+
+        let generation = self.caches.tweets.generation();
+        let tweet = self.inner.tweets.get_tweet(id).await?;
+        self.caches.tweets.insert(generation, *id, tweet.clone());
+
+        Ok(tweet)
+    }
+
+    async fn get_tweet_with_user_info(
+        &self,
+        id: &Uuid,
+        viewer_id: Option<&Uuid>,
+    ) -> StoreResult<TweetWithUserInfo> {
+        let key = (*id, viewer_id.copied());
+        if let Some(tweet) = self.caches.tweets_with_user_info.get(&key) {
+            return Ok(tweet);
+        }
+
+        let generation = self.caches.tweets_with_user_info.generation();
+        let tweet = self
+            .inner
+            .tweets
+            .get_tweet_with_user_info(id, viewer_id)
+            .await?;
+        self.caches
+            .tweets_with_user_info
+            .insert(generation, key, tweet.clone());
+
+        Ok(tweet)
+    }
+
+    async fn get_recent_tweets(
+        &self,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        self.inner.tweets.get_recent_tweets(viewer_id, before).await
+    }
+
+    async fn get_replies(
+        &self,
+        tweet_id: &Uuid,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        self.inner
+            .tweets
+            .get_replies(tweet_id, viewer_id, before)
+            .await
+    }
+
+    async fn get_recent_tweets_for_author(
+        &self,
+        author_id: &Uuid,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        self.inner
+            .tweets
+            .get_recent_tweets_for_author(author_id, viewer_id, before)
+            .await
+    }
+
+    async fn get_recent_tweets_for_hashtag(
+        &self,
+        hashtag: &str,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        self.inner
+            .tweets
+            .get_recent_tweets_for_hashtag(hashtag, viewer_id, before)
+            .await
+    }
+}
+
+#[async_trait]
+impl UserStore for CachedStore {
+    async fn create_user(&self, username: &str, password_hash: &str) -> StoreResult<User> {
+        self.inner.users.create_user(username, password_hash).await
+    }
+
+    async fn get_user(&self, id: &Uuid) -> StoreResult<User> {
+        if let Some(user) = self.caches.users.get(id) {
+            return Ok(user);
+        }
+
+        let generation = self.caches.users.generation();
+        let user = self.inner.users.get_user(id).await?;
+        self.caches.users.insert(generation, *id, user.clone());
+
+        Ok(user)
+    }
+
+    async fn get_user_for_username(&self, username: &str) -> StoreResult<User> {
+        self.inner.users.get_user_for_username(username).await
+    }
+}
+
+#[async_trait]
+impl SessionStore for CachedStore {
+    async fn create_session(&self, user_id: &Uuid) -> StoreResult<Session> {
+        self.inner.sessions.create_session(user_id).await
+    }
+
+    /// Only sessions that exist are cached, so a token that isn't one is
+    /// looked up every time. A session revoked while it is being looked up
+    /// isn't cached, as the revocation bumps the cache's generation.
+    async fn get_session_with_user(&self, token: &str) -> StoreResult<SessionWithUser> {
+        let token = token.to_string();
+        match self.caches.sessions.get(&token) {
+            Some(signed_in) if !signed_in.session.is_expired(Utc::now()) => return Ok(signed_in),
+            Some(_) => self.caches.sessions.remove(&token),
+            None => (),
+        }
+
+        let generation = self.caches.sessions.generation();
+        let signed_in = self.inner.sessions.get_session_with_user(&token).await?;
+        self.caches
+            .sessions
+            .insert(generation, token, signed_in.clone());
+
+        Ok(signed_in)
+    }
+
+    async fn revoke_session(&self, token: &str) -> StoreResult<()> {
+        let revoked = self.inner.sessions.revoke_session(token).await;
+        self.caches.sessions.remove(&token.to_string());
+
+        revoked
+    }
+}
+
+#[async_trait]
+impl SocialGraphStore for CachedStore {
+    async fn follow(&self, follower_id: &Uuid, following_id: &Uuid) -> StoreResult<Follow> {
+        self.inner
+            .social_graph
+            .follow(follower_id, following_id)
+            .await
+    }
+
+    async fn get_follower_count(&self, following_id: &Uuid) -> StoreResult<i64> {
+        self.inner
+            .social_graph
+            .get_follower_count(following_id)
+            .await
+    }
+
+    async fn get_following_ids(&self, follower_id: &Uuid) -> StoreResult<Vec<Uuid>> {
+        self.inner.social_graph.get_following_ids(follower_id).await
+    }
+
+    async fn like(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<Like> {
+        let like = self.inner.social_graph.like(tweet_id, user_id).await;
+        self.invalidate_tweet(tweet_id);
+
+        like
+    }
+
+    async fn unlike(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<()> {
+        let unliked = self.inner.social_graph.unlike(tweet_id, user_id).await;
+        self.invalidate_tweet(tweet_id);
+
+        unliked
+    }
+
+    async fn retweet(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<Retweet> {
+        let retweet = self.inner.social_graph.retweet(tweet_id, user_id).await;
+        self.invalidate_tweet(tweet_id);
+
+        retweet
+    }
+
+    async fn unretweet(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<()> {
+        let unretweeted = self.inner.social_graph.unretweet(tweet_id, user_id).await;
+        self.invalidate_tweet(tweet_id);
+
+        unretweeted
+    }
+}
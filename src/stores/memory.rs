@@ -0,0 +1,468 @@
+use std::{
+    cmp::Reverse,
+    sync::{Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use chrono::Duration;
+use sqlx::types::chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        follows::Follow,
+        likes::Like,
+        retweets::Retweet,
+        sessions::{new_session_token, Session, SessionWithUser, DEFAULT_SCOPES},
+        tweets::{hashtags, Tweet, TweetWithUserInfo},
+        users::User,
+    },
+    stores::{SessionStore, SocialGraphStore, StoreError, StoreResult, TweetStore, UserStore},
+};
+
+/// Matches the page size of the Postgres queries.
+const PAGE_SIZE: usize = 20;
+
+/// Keeps everything in memory, enforcing the same keys and constraints as the
+/// database schema. Publishes no events, as those go through Postgres.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    users: Vec<User>,
+    sessions: Vec<Session>,
+    tweets: Vec<Tweet>,
+    follows: Vec<Follow>,
+    likes: Vec<Like>,
+    retweets: Vec<Retweet>,
+}
+
+impl State {
+    fn user(&self, id: &Uuid) -> StoreResult<&User> {
+        self.users
+            .iter()
+            .find(|user| user.id == *id)
+            .ok_or(StoreError::NotFound)
+    }
+
+    fn tweet_mut(&mut self, id: &Uuid) -> StoreResult<&mut Tweet> {
+        self.tweets
+            .iter_mut()
+            .find(|tweet| tweet.id == *id)
+            .ok_or(StoreError::NotFound)
+    }
+
+    fn has_user(&self, id: &Uuid) -> bool {
+        self.users.iter().any(|user| user.id == *id)
+    }
+
+    fn has_tweet(&self, id: &Uuid) -> bool {
+        self.tweets.iter().any(|tweet| tweet.id == *id)
+    }
+
+    /// `None` when the author has gone, as the join in the queries would drop
+    /// it.
+    fn with_user_info(&self, tweet: &Tweet, viewer_id: Option<&Uuid>) -> Option<TweetWithUserInfo> {
+        let author = self.user(&tweet.user_id).ok()?;
+        let by_viewer = |tweet_id: &Uuid, user_id: &Uuid| {
+            viewer_id.is_some_and(|viewer_id| tweet_id == &tweet.id && user_id == viewer_id)
+        };
+
+        Some(TweetWithUserInfo {
+            id: tweet.id,
+            user_id: tweet.user_id,
+            responding_to: tweet.responding_to,
+            content: tweet.content.clone(),
+            username: author.username.clone(),
+            user_has_retweeted: self
+                .retweets
+                .iter()
+                .any(|retweet| by_viewer(&retweet.tweet_id, &retweet.user_id)),
+            user_has_liked: self
+                .likes
+                .iter()
+                .any(|like| by_viewer(&like.tweet_id, &like.user_id)),
+            like_count: tweet.like_count,
+            retweet_count: tweet.retweet_count,
+            reply_count: tweet.reply_count,
+            created_at: tweet.created_at,
+            updated_at: tweet.updated_at,
+        })
+    }
+
+    fn page(
+        &self,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+        include: impl Fn(&Tweet) -> bool,
+    ) -> Vec<TweetWithUserInfo> {
+        let before = before.unwrap_or_else(|| Utc::now() + Duration::days(1));
+
+        let mut tweets: Vec<&Tweet> = self
+            .tweets
+            .iter()
+            .filter(|tweet| tweet.created_at < before && include(tweet))
+            .collect();
+        tweets.sort_by_key(|tweet| Reverse(tweet.created_at));
+
+        tweets
+            .into_iter()
+            .filter_map(|tweet| self.with_user_info(tweet, viewer_id))
+            .take(PAGE_SIZE)
+            .collect()
+    }
+}
+
+impl MemoryStore {
+    fn state(&self) -> MutexGuard<'_, State> {
+        // A panic while holding the lock can't leave the state half changed,
+        // as every change is made after its checks.
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[async_trait]
+impl TweetStore for MemoryStore {
+    async fn create_tweet(
+        &self,
+        user_id: &Uuid,
+        responding_to: Option<Uuid>,
+        content: String,
+    ) -> StoreResult<Tweet> {
+        let mut state = self.state();
+
+        if !state.has_user(user_id) {
+            return Err(StoreError::MissingReference);
+        }
+        if let Some(responding_to) = responding_to {
+            state
+                .tweet_mut(&responding_to)
+                .map_err(|_| StoreError::MissingReference)?
+                .reply_count += 1;
+        }
+
+        let now = Utc::now();
+        let tweet = Tweet {
+            id: Uuid::new_v4(),
+            user_id: *user_id,
+            responding_to,
+            content,
+            like_count: 0,
+            retweet_count: 0,
+            reply_count: 0,
+            created_at: now,
+            updated_at: now,
+        };
+        state.tweets.push(tweet.clone());
+
+        Ok(tweet)
+    }
+
+    async fn delete_tweet(&self, id: &Uuid, user_id: &Uuid) -> StoreResult<()> {
+        let mut state = self.state();
+
+        let index = state
+            .tweets
+            .iter()
+            .position(|tweet| tweet.id == *id && tweet.user_id == *user_id)
+            .ok_or(StoreError::NotFound)?;
+        let tweet = state.tweets.remove(index);
+
+        if let Some(responding_to) = tweet.responding_to {
+            if let Ok(replied_to) = state.tweet_mut(&responding_to) {
+                replied_to.reply_count -= 1;
+            }
+        }
+        for reply in state.tweets.iter_mut() {
+            if reply.responding_to == Some(*id) {
+                reply.responding_to = None;
+            }
+        }
+        state.likes.retain(|like| like.tweet_id != *id);
+        state.retweets.retain(|retweet| retweet.tweet_id != *id);
+
+        Ok(())
+    }
+
+    async fn get_tweet(&self, id: &Uuid) -> StoreResult<Tweet> {
+        Ok(self.state().tweet_mut(id)?.clone())
+    }
+
+    async fn get_tweet_with_user_info(
+        &self,
+        id: &Uuid,
+        viewer_id: Option<&Uuid>,
+    ) -> StoreResult<TweetWithUserInfo> {
+        let state = self.state();
+
+        state
+            .tweets
+            .iter()
+            .find(|tweet| tweet.id == *id)
+            .and_then(|tweet| state.with_user_info(tweet, viewer_id))
+            .ok_or(StoreError::NotFound)
+    }
+
+    async fn get_recent_tweets(
+        &self,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        Ok(self
+            .state()
+            .page(viewer_id, before, |tweet| tweet.responding_to.is_none()))
+    }
+
+    async fn get_replies(
+        &self,
+        tweet_id: &Uuid,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        Ok(self.state().page(viewer_id, before, |tweet| {
+            tweet.responding_to == Some(*tweet_id)
+        }))
+    }
+
+    async fn get_recent_tweets_for_author(
+        &self,
+        author_id: &Uuid,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        Ok(self.state().page(viewer_id, before, |tweet| {
+            tweet.user_id == *author_id && tweet.responding_to.is_none()
+        }))
+    }
+
+    async fn get_recent_tweets_for_hashtag(
+        &self,
+        hashtag: &str,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        let hashtag = hashtag.to_lowercase();
+
+        Ok(self.state().page(viewer_id, before, |tweet| {
+            hashtags(&tweet.content).contains(&hashtag)
+        }))
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryStore {
+    async fn create_user(&self, username: &str, password_hash: &str) -> StoreResult<User> {
+        let mut state = self.state();
+        let username = username.to_lowercase();
+
+        if state.users.iter().any(|user| user.username == username) {
+            return Err(StoreError::Conflict);
+        }
+
+        let user = User {
+            id: Uuid::new_v4(),
+            password: password_hash.to_string(),
+            username,
+            created_at: Utc::now(),
+        };
+        state.users.push(user.clone());
+
+        Ok(user)
+    }
+
+    async fn get_user(&self, id: &Uuid) -> StoreResult<User> {
+        Ok(self.state().user(id)?.clone())
+    }
+
+    async fn get_user_for_username(&self, username: &str) -> StoreResult<User> {
+        let username = username.to_lowercase();
+
+        self.state()
+            .users
+            .iter()
+            .find(|user| user.username == username)
+            .cloned()
+            .ok_or(StoreError::NotFound)
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn create_session(&self, user_id: &Uuid) -> StoreResult<Session> {
+        let mut state = self.state();
+
+        if !state.has_user(user_id) {
+            return Err(StoreError::MissingReference);
+        }
+
+        let session = Session {
+            id: Uuid::new_v4(),
+            token: new_session_token(),
+            user_id: *user_id,
+            created_at: Utc::now(),
+            expires_at: None,
+            scopes: DEFAULT_SCOPES.to_string(),
+            two_factor_verified: false,
+        };
+        state.sessions.push(session.clone());
+
+        Ok(session)
+    }
+
+    async fn get_session_with_user(&self, token: &str) -> StoreResult<SessionWithUser> {
+        let state = self.state();
+
+        let session = state
+            .sessions
+            .iter()
+            .find(|session| session.token == token && !session.is_expired(Utc::now()))
+            .cloned()
+            .ok_or(StoreError::NotFound)?;
+        let user = state.user(&session.user_id)?.clone();
+
+        Ok(SessionWithUser { session, user })
+    }
+
+    async fn revoke_session(&self, token: &str) -> StoreResult<()> {
+        let mut state = self.state();
+
+        let before = state.sessions.len();
+        state.sessions.retain(|session| session.token != token);
+
+        if state.sessions.len() == before {
+            return Err(StoreError::NotFound);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SocialGraphStore for MemoryStore {
+    async fn follow(&self, follower_id: &Uuid, following_id: &Uuid) -> StoreResult<Follow> {
+        let mut state = self.state();
+
+        if follower_id == following_id {
+            return Err(StoreError::Invalid);
+        }
+        if !state.has_user(follower_id) || !state.has_user(following_id) {
+            return Err(StoreError::MissingReference);
+        }
+        if state.follows.iter().any(|follow| {
+            follow.follower_id == *follower_id && follow.following_id == *following_id
+        }) {
+            return Err(StoreError::Conflict);
+        }
+
+        let follow = Follow {
+            follower_id: *follower_id,
+            following_id: *following_id,
+            created_at: Utc::now(),
+        };
+        state.follows.push(follow.clone());
+
+        Ok(follow)
+    }
+
+    async fn get_follower_count(&self, following_id: &Uuid) -> StoreResult<i64> {
+        Ok(self
+            .state()
+            .follows
+            .iter()
+            .filter(|follow| follow.following_id == *following_id)
+            .count() as i64)
+    }
+
+    async fn get_following_ids(&self, follower_id: &Uuid) -> StoreResult<Vec<Uuid>> {
+        Ok(self
+            .state()
+            .follows
+            .iter()
+            .filter(|follow| follow.follower_id == *follower_id)
+            .map(|follow| follow.following_id)
+            .collect())
+    }
+
+    async fn like(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<Like> {
+        let mut state = self.state();
+
+        if !state.has_tweet(tweet_id) || !state.has_user(user_id) {
+            return Err(StoreError::MissingReference);
+        }
+        if state
+            .likes
+            .iter()
+            .any(|like| like.tweet_id == *tweet_id && like.user_id == *user_id)
+        {
+            return Err(StoreError::Conflict);
+        }
+
+        state.tweet_mut(tweet_id)?.like_count += 1;
+        let like = Like {
+            tweet_id: *tweet_id,
+            user_id: *user_id,
+            created_at: Utc::now(),
+        };
+        state.likes.push(like.clone());
+
+        Ok(like)
+    }
+
+    async fn unlike(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<()> {
+        let mut state = self.state();
+
+        let index = state
+            .likes
+            .iter()
+            .position(|like| like.tweet_id == *tweet_id && like.user_id == *user_id)
+            .ok_or(StoreError::NotFound)?;
+        state.tweet_mut(tweet_id)?.like_count -= 1;
+        state.likes.remove(index);
+
+        Ok(())
+    }
+
+    async fn retweet(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<Retweet> {
+        let mut state = self.state();
+
+        if !state.has_tweet(tweet_id) || !state.has_user(user_id) {
+            return Err(StoreError::MissingReference);
+        }
+        if state
+            .retweets
+            .iter()
+            .any(|retweet| retweet.tweet_id == *tweet_id && retweet.user_id == *user_id)
+        {
+            return Err(StoreError::Conflict);
+        }
+
+        state.tweet_mut(tweet_id)?.retweet_count += 1;
+        let retweet = Retweet {
+            tweet_id: *tweet_id,
+            user_id: *user_id,
+            created_at: Utc::now(),
+        };
+        state.retweets.push(retweet.clone());
+
+        Ok(retweet)
+    }
+
+    async fn unretweet(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<()> {
+        let mut state = self.state();
+
+        let index = state
+            .retweets
+            .iter()
+            .position(|retweet| retweet.tweet_id == *tweet_id && retweet.user_id == *user_id)
+            .ok_or(StoreError::NotFound)?;
+        state.tweet_mut(tweet_id)?.retweet_count -= 1;
+        state.retweets.remove(index);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+use std::{fmt, sync::Arc};
+
+use async_trait::async_trait;
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    Pool, Postgres,
+};
+use uuid::Uuid;
+
+use crate::{
+    cache::Caches,
+    models::{
+        follows::Follow,
+        likes::Like,
+        retweets::Retweet,
+        sessions::{Session, SessionWithUser},
+        tweets::{Tweet, TweetWithUserInfo},
+        users::User,
+    },
+};
+
+pub mod cached;
+pub mod memory;
+pub mod postgres;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// What can go wrong in a store, whichever backend it is. Each backend maps
+/// its own failures onto these so that callers needn't know which one they
+/// have.
+#[derive(Debug)]
+pub enum StoreError {
+    NotFound,
+    /// A unique key is taken, e.g. a username or liking a tweet twice.
+    Conflict,
+    /// Something being referred to doesn't exist, e.g. liking a tweet that has
+    /// gone.
+    MissingReference,
+    /// The data breaks a rule of its own, e.g. following yourself.
+    Invalid,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::NotFound => write!(f, "Not found"),
+            StoreError::Conflict => write!(f, "Already exists"),
+            StoreError::MissingReference => write!(f, "Refers to something missing"),
+            StoreError::Invalid => write!(f, "Invalid"),
+            StoreError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<sqlx::Error> for StoreError {
+    fn from(e: sqlx::Error) -> StoreError {
+        match &e {
+            sqlx::Error::RowNotFound => StoreError::NotFound,
+            // Postgres' SQLSTATEs, then SQLite's extended result codes.
+            sqlx::Error::Database(db) => match db.code().as_deref() {
+                // unique_violation
+                Some("23505") => StoreError::Conflict,
+                // foreign_key_violation
+                Some("23503") => StoreError::MissingReference,
+                // check_violation
+                Some("23514") => StoreError::Invalid,
+                // SQLITE_CONSTRAINT_UNIQUE and SQLITE_CONSTRAINT_PRIMARYKEY
+                Some("2067") | Some("1555") => StoreError::Conflict,
+                // SQLITE_CONSTRAINT_FOREIGNKEY
+                Some("787") => StoreError::MissingReference,
+                // SQLite only checks foreign keys once the statement is done,
+                // which after a `RETURNING` row comes back as a plain
+                // SQLITE_ERROR.
+                Some("1") if db.message() == "FOREIGN KEY constraint failed" => {
+                    StoreError::MissingReference
+                }
+                // SQLITE_CONSTRAINT_CHECK
+                Some("275") => StoreError::Invalid,
+                _ => StoreError::Database(e),
+            },
+            _ => StoreError::Database(e),
+        }
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// Timelines are newest first, 20 at a time, and `before` pages back through
+/// them by `created_at`. `viewer_id` fills in `user_has_liked` and
+/// `user_has_retweeted`.
+#[async_trait]
+pub trait TweetStore: Send + Sync {
+    /// Also counts the reply on the tweet responded to.
+    async fn create_tweet(
+        &self,
+        user_id: &Uuid,
+        responding_to: Option<Uuid>,
+        content: String,
+    ) -> StoreResult<Tweet>;
+
+    /// Only by its author, `NotFound` otherwise. Also uncounts the reply on
+    /// the tweet responded to; replies to it stay, no longer as replies.
+    async fn delete_tweet(&self, id: &Uuid, user_id: &Uuid) -> StoreResult<()>;
+
+    async fn get_tweet(&self, id: &Uuid) -> StoreResult<Tweet>;
+
+    async fn get_tweet_with_user_info(
+        &self,
+        id: &Uuid,
+        viewer_id: Option<&Uuid>,
+    ) -> StoreResult<TweetWithUserInfo>;
+
+    /// Tweets that aren't replies.
+    async fn get_recent_tweets(
+        &self,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>>;
+
+    async fn get_replies(
+        &self,
+        tweet_id: &Uuid,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>>;
+
+    /// The author's tweets that aren't replies.
+    async fn get_recent_tweets_for_author(
+        &self,
+        author_id: &Uuid,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>>;
+
+    /// `hashtag` is without the `#`, and matched regardless of case.
+    async fn get_recent_tweets_for_hashtag(
+        &self,
+        hashtag: &str,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>>;
+}
+
+/// Usernames are stored and looked up lowercased.
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    async fn create_user(&self, username: &str, password_hash: &str) -> StoreResult<User>;
+
+    async fn get_user(&self, id: &Uuid) -> StoreResult<User>;
+
+    async fn get_user_for_username(&self, username: &str) -> StoreResult<User>;
+}
+
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// With a new random token.
+    async fn create_session(&self, user_id: &Uuid) -> StoreResult<Session>;
+
+    /// With its user, unless it has expired.
+    async fn get_session_with_user(&self, token: &str) -> StoreResult<SessionWithUser>;
+
+    /// `NotFound` when there is no such session.
+    async fn revoke_session(&self, token: &str) -> StoreResult<()>;
+}
+
+/// Follows, likes and retweets. Likes and retweets keep the counts on their
+/// tweet up to date.
+#[async_trait]
+pub trait SocialGraphStore: Send + Sync {
+    async fn follow(&self, follower_id: &Uuid, following_id: &Uuid) -> StoreResult<Follow>;
+
+    async fn get_follower_count(&self, following_id: &Uuid) -> StoreResult<i64>;
+
+    async fn get_following_ids(&self, follower_id: &Uuid) -> StoreResult<Vec<Uuid>>;
+
+    async fn like(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<Like>;
+
+    /// `NotFound` when the tweet wasn't liked by `user_id`.
+    async fn unlike(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<()>;
+
+    async fn retweet(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<Retweet>;
+
+    /// `NotFound` when the tweet wasn't retweeted by `user_id`.
+    async fn unretweet(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<()>;
+}
+
+/// The stores a request works with. Each is usually the same backend.
+#[derive(Clone)]
+pub struct Stores {
+    pub tweets: Arc<dyn TweetStore>,
+    pub users: Arc<dyn UserStore>,
+    pub sessions: Arc<dyn SessionStore>,
+    pub social_graph: Arc<dyn SocialGraphStore>,
+}
+
+impl Stores {
+    pub fn postgres(pool: Pool<Postgres>) -> Stores {
+        let store = Arc::new(postgres::PgStore::new(pool));
+
+        Stores {
+            tweets: store.clone(),
+            users: store.clone(),
+            sessions: store.clone(),
+            social_graph: store,
+        }
+    }
+
+    /// For running locally without Postgres; see `sqlite::connect`.
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite(pool: Pool<sqlx::Sqlite>) -> Stores {
+        let store = Arc::new(sqlite::SqliteStore::new(pool));
+
+        Stores {
+            tweets: store.clone(),
+            users: store.clone(),
+            sessions: store.clone(),
+            social_graph: store,
+        }
+    }
+
+    /// `inner` behind `caches`; see `cached::CachedStore`.
+    pub fn cached(inner: Stores, caches: Arc<Caches>) -> Stores {
+        let store = Arc::new(cached::CachedStore::new(inner, caches));
+
+        Stores {
+            tweets: store.clone(),
+            users: store.clone(),
+            sessions: store.clone(),
+            social_graph: store,
+        }
+    }
+
+    /// Everything kept in memory and lost on exit, for tests.
+    pub fn memory() -> Stores {
+        let store = Arc::new(memory::MemoryStore::default());
+
+        Stores {
+            tweets: store.clone(),
+            users: store.clone(),
+            sessions: store.clone(),
+            social_graph: store,
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use async_trait::async_trait;
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    Pool, Postgres,
+};
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        follows::Follow,
+        likes::Like,
+        retweets::Retweet,
+        sessions::{Session, SessionWithUser},
+        tweets::{Tweet, TweetWithUserInfo},
+        users::User,
+    },
+    stores::{SessionStore, SocialGraphStore, StoreResult, TweetStore, UserStore},
+};
+
+/// The stores on top of the models, which also publish events for the
+/// changes they make.
+pub struct PgStore {
+    pool: Pool<Postgres>,
+}
+
+impl PgStore {
+    pub fn new(pool: Pool<Postgres>) -> PgStore {
+        PgStore { pool }
+    }
+}
+
+#[async_trait]
+impl TweetStore for PgStore {
+    async fn create_tweet(
+        &self,
+        user_id: &Uuid,
+        responding_to: Option<Uuid>,
+        content: String,
+    ) -> StoreResult<Tweet> {
+        Ok(Tweet::create_tweet(&self.pool, user_id, responding_to, content).await?)
+    }
+
+    async fn delete_tweet(&self, id: &Uuid, user_id: &Uuid) -> StoreResult<()> {
+        Ok(Tweet::delete_tweet(&self.pool, id, user_id).await?)
+    }
+
+    async fn get_tweet(&self, id: &Uuid) -> StoreResult<Tweet> {
+        Ok(Tweet::get_tweet_for_id(&self.pool, id).await?)
+    }
+
+    async fn get_tweet_with_user_info(
+        &self,
+        id: &Uuid,
+        viewer_id: Option<&Uuid>,
+    ) -> StoreResult<TweetWithUserInfo> {
+        Ok(Tweet::get_tweet_with_user_info(&self.pool, id, viewer_id).await?)
+    }
+
+    async fn get_recent_tweets(
+        &self,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        Ok(Tweet::get_recent_tweets_with_user_info(&self.pool, viewer_id, before).await?)
+    }
+
+    async fn get_replies(
+        &self,
+        tweet_id: &Uuid,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        Ok(
+            Tweet::get_tweet_replies_with_user_info(&self.pool, tweet_id, viewer_id, before)
+                .await?,
+        )
+    }
+
+    async fn get_recent_tweets_for_author(
+        &self,
+        author_id: &Uuid,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        Ok(Tweet::get_recent_tweets_for_author_with_user_info(
+            &self.pool, author_id, viewer_id, before,
+        )
+        .await?)
+    }
+
+    async fn get_recent_tweets_for_hashtag(
+        &self,
+        hashtag: &str,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        Ok(Tweet::get_recent_tweets_for_hashtag_with_user_info(
+            &self.pool, hashtag, viewer_id, before,
+        )
+        .await?)
+    }
+}
+
+#[async_trait]
+impl UserStore for PgStore {
+    async fn create_user(&self, username: &str, password_hash: &str) -> StoreResult<User> {
+        Ok(User::create_user(&self.pool, username, password_hash).await?)
+    }
+
+    async fn get_user(&self, id: &Uuid) -> StoreResult<User> {
+        Ok(User::get_user_for_id(&self.pool, id).await?)
+    }
+
+    async fn get_user_for_username(&self, username: &str) -> StoreResult<User> {
+        Ok(User::get_user_for_username(&self.pool, username).await?)
+    }
+}
+
+#[async_trait]
+impl SessionStore for PgStore {
+    async fn create_session(&self, user_id: &Uuid) -> StoreResult<Session> {
+        Ok(Session::create_session(&self.pool, user_id).await?)
+    }
+
+    async fn get_session_with_user(&self, token: &str) -> StoreResult<SessionWithUser> {
+        Ok(Session::get_session_with_user(&self.pool, token).await?)
+    }
+
+    async fn revoke_session(&self, token: &str) -> StoreResult<()> {
+        Ok(Session::revoke_session(&self.pool, token).await?)
+    }
+}
+
+#[async_trait]
+impl SocialGraphStore for PgStore {
+    async fn follow(&self, follower_id: &Uuid, following_id: &Uuid) -> StoreResult<Follow> {
+        Ok(Follow::create_follow(&self.pool, follower_id, following_id).await?)
+    }
+
+    async fn get_follower_count(&self, following_id: &Uuid) -> StoreResult<i64> {
+        Ok(Follow::get_follow_count(&self.pool, following_id).await?)
+    }
+
+    async fn get_following_ids(&self, follower_id: &Uuid) -> StoreResult<Vec<Uuid>> {
+        Ok(Follow::get_following_ids(&self.pool, follower_id).await?)
+    }
+
+    async fn like(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<Like> {
+        Ok(Like::create_like(&self.pool, tweet_id, user_id).await?)
+    }
+
+    async fn unlike(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<()> {
+        Ok(Like::delete_like(&self.pool, tweet_id, user_id).await?)
+    }
+
+    async fn retweet(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<Retweet> {
+        Ok(Retweet::create_retweet(&self.pool, tweet_id, user_id).await?)
+    }
+
+    async fn unretweet(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<()> {
+        Ok(Retweet::delete_retweet(&self.pool, tweet_id, user_id).await?)
+    }
+}
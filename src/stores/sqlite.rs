@@ -0,0 +1,542 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use chrono::{Duration, SecondsFormat};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    types::chrono::{DateTime, Utc},
+    Executor, Pool, Sqlite,
+};
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        follows::Follow,
+        likes::Like,
+        retweets::Retweet,
+        sessions::{new_session_token, Session, SessionWithUser, SessionWithUserRow},
+        tweets::{hashtags, Tweet, TweetWithUserInfo},
+        users::User,
+    },
+    stores::{SessionStore, SocialGraphStore, StoreResult, TweetStore, UserStore},
+};
+
+const SCHEMA: &str = include_str!("../../migrations/sqlite/schema.sql");
+
+/// Everything but the `WHERE`, with the viewer as `?1`.
+const SELECT_WITH_USER_INFO: &str = "
+            SELECT
+                t.id, t.user_id, t.responding_to, t.content,
+                t.created_at, t.updated_at, u.username, t.like_count,
+                t.retweet_count, t.reply_count,
+                l.user_id IS NOT NULL as user_has_liked,
+                r.user_id IS NOT NULL as user_has_retweeted
+            FROM
+                tweets as t
+            JOIN
+                users as u ON t.user_id = u.id
+            LEFT JOIN
+                likes as l ON l.tweet_id = t.id AND l.user_id = ?1
+            LEFT JOIN
+                retweets as r ON r.tweet_id = t.id AND r.user_id = ?1";
+
+/// Matches the page size of the Postgres queries.
+const PAGE_SIZE: usize = 20;
+
+/// SQLite has a single writer anyway, and an in-memory database lives and dies
+/// with its connection.
+pub const MAX_CONNECTIONS: u32 = 1;
+
+/// Opens the database at `url`, e.g. `sqlite://bitter.db` or
+/// `sqlite::memory:`, creating it and its tables if need be.
+pub async fn connect(url: &str) -> Result<Pool<Sqlite>, sqlx::Error> {
+    let options = SqliteConnectOptions::from_str(url)?
+        .create_if_missing(true)
+        .foreign_keys(true);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(MAX_CONNECTIONS)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect_with(options)
+        .await?;
+
+    pool.execute(SCHEMA).await?;
+
+    Ok(pool)
+}
+
+/// Fixed to microseconds, so that timestamps compare correctly as text.
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn before_or_now(before: Option<DateTime<Utc>>) -> String {
+    timestamp(before.unwrap_or_else(|| Utc::now() + Duration::days(1)))
+}
+
+/// The same models as `PgStore` on SQLite, for running locally without
+/// Postgres. Publishes no events, as those go through Postgres.
+pub struct SqliteStore {
+    pool: Pool<Sqlite>,
+}
+
+impl SqliteStore {
+    pub fn new(pool: Pool<Sqlite>) -> SqliteStore {
+        SqliteStore { pool }
+    }
+}
+
+#[async_trait]
+impl TweetStore for SqliteStore {
+    async fn create_tweet(
+        &self,
+        user_id: &Uuid,
+        responding_to: Option<Uuid>,
+        content: String,
+    ) -> StoreResult<Tweet> {
+        let mut transaction = self.pool.begin().await?;
+        let now = timestamp(Utc::now());
+
+        let tweet = sqlx::query_as(
+            "
+            INSERT INTO tweets (id, user_id, responding_to, content, created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+            RETURNING id, user_id, responding_to, content, created_at, updated_at, like_count, retweet_count, reply_count",
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(responding_to)
+        .bind(content)
+        .bind(now)
+        .fetch_one(&mut transaction)
+        .await?;
+
+        if let Some(tweet_id) = responding_to {
+            sqlx::query(
+                "
+            UPDATE tweets
+            SET reply_count = reply_count + 1
+            WHERE id = ?1",
+            )
+            .bind(tweet_id)
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(tweet)
+    }
+
+    async fn delete_tweet(&self, id: &Uuid, user_id: &Uuid) -> StoreResult<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        let (responding_to,): (Option<Uuid>,) = sqlx::query_as(
+            "
+            DELETE FROM tweets WHERE id = ?1 AND user_id = ?2
+            RETURNING responding_to",
+        )
+        .bind(id)
+        .bind(user_id)
+        .fetch_one(&mut transaction)
+        .await?;
+
+        if let Some(tweet_id) = responding_to {
+            sqlx::query(
+                "
+            UPDATE tweets
+            SET reply_count = reply_count - 1
+            WHERE id = ?1",
+            )
+            .bind(tweet_id)
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_tweet(&self, id: &Uuid) -> StoreResult<Tweet> {
+        Ok(sqlx::query_as(
+            "
+            SELECT id, user_id, responding_to, content, created_at, updated_at, like_count, retweet_count, reply_count FROM tweets WHERE id = ?1",
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn get_tweet_with_user_info(
+        &self,
+        id: &Uuid,
+        viewer_id: Option<&Uuid>,
+    ) -> StoreResult<TweetWithUserInfo> {
+        Ok(sqlx::query_as(&format!(
+            "{}
+            WHERE
+                t.id = ?2",
+            SELECT_WITH_USER_INFO
+        ))
+        .bind(viewer_id)
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn get_recent_tweets(
+        &self,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        Ok(sqlx::query_as(&format!(
+            "{}
+            WHERE
+                    t.created_at < ?2
+                AND
+                    t.responding_to IS NULL
+            ORDER BY
+                t.created_at DESC
+            LIMIT 20",
+            SELECT_WITH_USER_INFO
+        ))
+        .bind(viewer_id)
+        .bind(before_or_now(before))
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn get_replies(
+        &self,
+        tweet_id: &Uuid,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        Ok(sqlx::query_as(&format!(
+            "{}
+            WHERE
+                    t.created_at < ?2
+                AND
+                    t.responding_to = ?3
+            ORDER BY
+                t.created_at DESC
+            LIMIT 20",
+            SELECT_WITH_USER_INFO
+        ))
+        .bind(viewer_id)
+        .bind(before_or_now(before))
+        .bind(tweet_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn get_recent_tweets_for_author(
+        &self,
+        author_id: &Uuid,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        Ok(sqlx::query_as(&format!(
+            "{}
+            WHERE
+                    t.created_at < ?2
+                AND
+                    t.user_id = ?3
+                AND
+                    t.responding_to IS NULL
+            ORDER BY
+                t.created_at DESC
+            LIMIT 20",
+            SELECT_WITH_USER_INFO
+        ))
+        .bind(viewer_id)
+        .bind(before_or_now(before))
+        .bind(author_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    /// SQLite has no regular expressions, so `LIKE` narrows it down and
+    /// `hashtags` decides, as it does when the tweet is created.
+    async fn get_recent_tweets_for_hashtag(
+        &self,
+        hashtag: &str,
+        viewer_id: Option<&Uuid>,
+        before: Option<DateTime<Utc>>,
+    ) -> StoreResult<Vec<TweetWithUserInfo>> {
+        let hashtag = hashtag.to_lowercase();
+
+        let tweets: Vec<TweetWithUserInfo> = sqlx::query_as(&format!(
+            "{}
+            WHERE
+                    t.created_at < ?2
+                AND
+                    t.content LIKE '%#' || ?3 || '%'
+            ORDER BY
+                t.created_at DESC",
+            SELECT_WITH_USER_INFO
+        ))
+        .bind(viewer_id)
+        .bind(before_or_now(before))
+        .bind(&hashtag)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(tweets
+            .into_iter()
+            .filter(|tweet| hashtags(&tweet.content).contains(&hashtag))
+            .take(PAGE_SIZE)
+            .collect())
+    }
+}
+
+#[async_trait]
+impl UserStore for SqliteStore {
+    async fn create_user(&self, username: &str, password_hash: &str) -> StoreResult<User> {
+        Ok(sqlx::query_as(
+            "
+            INSERT INTO users (id, username, password, created_at)
+            VALUES (?1, LOWER(?2), ?3, ?4)
+            RETURNING id, username, password, created_at",
+        )
+        .bind(Uuid::new_v4())
+        .bind(username)
+        .bind(password_hash)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn get_user(&self, id: &Uuid) -> StoreResult<User> {
+        Ok(sqlx::query_as(
+            "
+            SELECT id, username, password, created_at FROM users WHERE id = ?1",
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn get_user_for_username(&self, username: &str) -> StoreResult<User> {
+        Ok(sqlx::query_as(
+            "
+            SELECT id, username, password, created_at FROM users WHERE username = LOWER(?1)",
+        )
+        .bind(username)
+        .fetch_one(&self.pool)
+        .await?)
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteStore {
+    async fn create_session(&self, user_id: &Uuid) -> StoreResult<Session> {
+        Ok(sqlx::query_as(
+            "
+            INSERT INTO sessions (id, token, user_id, created_at)
+            VALUES (?1, ?2, ?3, ?4)
+            RETURNING id, token, user_id, created_at, expires_at, scopes, two_factor_verified",
+        )
+        .bind(Uuid::new_v4())
+        .bind(new_session_token())
+        .bind(user_id)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn get_session_with_user(&self, token: &str) -> StoreResult<SessionWithUser> {
+        let row: SessionWithUserRow = sqlx::query_as(
+            "
+            SELECT
+                s.id, s.token, s.user_id, s.created_at, s.expires_at, s.scopes,
+                s.two_factor_verified, u.username, u.password,
+                u.created_at as user_created_at
+            FROM
+                sessions s
+            JOIN
+                users u ON u.id = s.user_id
+            WHERE
+                    s.token = ?1
+                AND
+                    (s.expires_at IS NULL OR s.expires_at > ?2)",
+        )
+        .bind(token)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.into())
+    }
+
+    async fn revoke_session(&self, token: &str) -> StoreResult<()> {
+        sqlx::query(
+            "
+            DELETE FROM sessions WHERE token = ?1
+            RETURNING id",
+        )
+        .bind(token)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SocialGraphStore for SqliteStore {
+    async fn follow(&self, follower_id: &Uuid, following_id: &Uuid) -> StoreResult<Follow> {
+        Ok(sqlx::query_as(
+            "
+            INSERT INTO follows (follower_id, following_id, created_at)
+            VALUES (?1, ?2, ?3)
+            RETURNING follower_id, following_id, created_at",
+        )
+        .bind(follower_id)
+        .bind(following_id)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn get_follower_count(&self, following_id: &Uuid) -> StoreResult<i64> {
+        let (count,): (i64,) = sqlx::query_as(
+            "
+            SELECT COUNT(*) FROM follows WHERE following_id = ?1",
+        )
+        .bind(following_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(count)
+    }
+
+    async fn get_following_ids(&self, follower_id: &Uuid) -> StoreResult<Vec<Uuid>> {
+        let rows: Vec<(Uuid,)> = sqlx::query_as(
+            "
+            SELECT following_id FROM follows WHERE follower_id = ?1",
+        )
+        .bind(follower_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|(id,)| id).collect())
+    }
+
+    async fn like(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<Like> {
+        let mut transaction = self.pool.begin().await?;
+
+        let like = sqlx::query_as(
+            "
+            INSERT INTO likes (tweet_id, user_id, created_at)
+            VALUES (?1, ?2, ?3)
+            RETURNING tweet_id, user_id, created_at",
+        )
+        .bind(tweet_id)
+        .bind(user_id)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&mut transaction)
+        .await?;
+
+        sqlx::query(
+            "
+            UPDATE tweets
+            SET like_count = like_count + 1
+            WHERE id = ?1",
+        )
+        .bind(tweet_id)
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(like)
+    }
+
+    async fn unlike(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        sqlx::query(
+            "
+            DELETE FROM likes WHERE tweet_id = ?1 AND user_id = ?2
+            RETURNING tweet_id",
+        )
+        .bind(tweet_id)
+        .bind(user_id)
+        .fetch_one(&mut transaction)
+        .await?;
+
+        sqlx::query(
+            "
+            UPDATE tweets
+            SET like_count = like_count - 1
+            WHERE id = ?1",
+        )
+        .bind(tweet_id)
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn retweet(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<Retweet> {
+        let mut transaction = self.pool.begin().await?;
+
+        let retweet = sqlx::query_as(
+            "
+            INSERT INTO retweets (tweet_id, user_id, created_at)
+            VALUES (?1, ?2, ?3)
+            RETURNING tweet_id, user_id, created_at",
+        )
+        .bind(tweet_id)
+        .bind(user_id)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(&mut transaction)
+        .await?;
+
+        sqlx::query(
+            "
+            UPDATE tweets
+            SET retweet_count = retweet_count + 1
+            WHERE id = ?1",
+        )
+        .bind(tweet_id)
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(retweet)
+    }
+
+    async fn unretweet(&self, tweet_id: &Uuid, user_id: &Uuid) -> StoreResult<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        sqlx::query(
+            "
+            DELETE FROM retweets WHERE tweet_id = ?1 AND user_id = ?2
+            RETURNING tweet_id",
+        )
+        .bind(tweet_id)
+        .bind(user_id)
+        .fetch_one(&mut transaction)
+        .await?;
+
+        sqlx::query(
+            "
+            UPDATE tweets
+            SET retweet_count = retweet_count - 1
+            WHERE id = ?1",
+        )
+        .bind(tweet_id)
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+}
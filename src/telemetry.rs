@@ -0,0 +1,168 @@
+use std::sync::{Arc, RwLock};
+
+use thruster::{
+    context::context_ext::ContextExt, middleware::cookies::HasCookies, middleware_fn, Context,
+    MiddlewareNext, MiddlewareResult,
+};
+use tokio::time::Instant;
+use tracing::{field, info, info_span, Instrument, Span};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+use crate::{app::Ctx, config::LoggingConfig};
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Longer ids from upstream proxies are replaced rather than trusted.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Sends `tracing` output, and the `log` records from the rest of the app and
+/// from sqlx, to stdout as text or JSON. Verbosity comes from `RUST_LOG`.
+///
+/// Does nothing when something else already installed a logger, as Shuttle
+/// does before handing over.
+pub fn init(config: &LoggingConfig) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let _ = if config.format == "json" {
+        tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_env_filter(filter)
+            .try_init()
+    } else {
+        tracing_subscriber::fmt().with_env_filter(filter).try_init()
+    };
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// The method and template of each route, e.g. `POST /tweets/:id/likes`, as
+/// they are added in `app::app`.
+#[derive(Clone, Default)]
+pub struct Routes {
+    templates: Arc<RwLock<Vec<(String, String)>>>,
+}
+
+impl Routes {
+    pub fn add(&self, method: &str, template: &str) {
+        self.templates
+            .write()
+            .unwrap()
+            .push((method.to_string(), template.to_string()));
+    }
+}
+
+/// The template of the route the router matched, e.g. `/tweets/:id/likes`, so
+/// that requests group by route rather than by tweet: the one whose
+/// parameters are the ones the router took from `path`. `None` when no route
+/// matched.
+pub(crate) fn route_template(context: &Ctx, method: &str, path: &str) -> Option<String> {
+    // The router sends methods it has no tree for, such as HEAD, to GET's.
+    let method = match method {
+        "OPTIONS" | "POST" | "PUT" | "DELETE" | "PATCH" => method,
+        _ => "GET",
+    };
+    let params = context.params();
+    let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+    let matches = |template: &str| {
+        let template: Vec<&str> = template.trim_end_matches('/').split('/').collect();
+
+        template.len() == segments.len()
+            && template
+                .iter()
+                .zip(&segments)
+                .all(|(part, segment)| match part.strip_prefix(':') {
+                    Some(name) => {
+                        matches!(params.get(name), Some(param) if param.param == *segment)
+                    }
+                    None => part == segment,
+                })
+    };
+
+    context
+        .extra
+        .routes
+        .templates
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(route_method, template)| route_method == method && matches(template))
+        .map(|(_, template)| template.clone())
+}
+
+/// Records the signed in user on the request's span, once a middleware has
+/// looked them up.
+pub fn record_user(context: &Ctx) {
+    if let Some(user) = context.extra.user.as_ref() {
+        Span::current().record("user_id", field::display(user.id));
+    }
+}
+
+/// Runs the rest of the request in a span carrying its id, which is taken from
+/// an incoming `X-Request-Id` or generated, and echoed back in the response.
+/// Also where request latency is recorded for `/metrics`.
+#[middleware_fn]
+pub async fn trace_requests(mut context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let start_time = Instant::now();
+
+    let request_id = context
+        .get_header(REQUEST_ID_HEADER)
+        .pop()
+        .filter(|id| is_valid_request_id(id))
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let (method, path) = context
+        .hyper_request
+        .as_ref()
+        .map(|request| {
+            (
+                request.request.method().to_string(),
+                // Never the query, which can carry an `access_token`.
+                request.request.uri().path().to_string(),
+            )
+        })
+        .unwrap_or_default();
+    let route = route_template(&context, &method, &path);
+
+    context.extra.request_id = request_id.clone();
+
+    let span = info_span!(
+        "request",
+        request_id = %request_id,
+        method = %method,
+        route = %route.as_deref().unwrap_or(&path),
+        user_id = field::Empty,
+        status = field::Empty,
+    );
+
+    context = match next(context).instrument(span.clone()).await {
+        Ok(context) => context,
+        Err(e) => e.context,
+    };
+
+    context.set(REQUEST_ID_HEADER, &request_id);
+
+    // Paths that matched no route would each get a series of their own.
+    let elapsed = start_time.elapsed();
+    let metrics_route = route.as_deref().unwrap_or("unmatched");
+    context
+        .extra
+        .metrics
+        .observe_request(&method, metrics_route, context.status, elapsed);
+
+    span.record("status", context.status);
+    info!(
+        parent: &span,
+        elapsed_us = elapsed.as_micros() as u64,
+        "finished request"
+    );
+
+    Ok(context)
+}
@@ -0,0 +1,65 @@
+use std::str::FromStr;
+
+use uuid::Uuid;
+
+use crate::app::Ctx;
+
+/// The configured `server.public_url` without a trailing slash, e.g.
+/// `https://bitter.example`. Never taken from the request, as feeds built
+/// from it are cached publicly.
+pub fn base_url(context: &Ctx) -> String {
+    context
+        .extra
+        .config
+        .server
+        .public_url
+        .trim_end_matches('/')
+        .to_string()
+}
+
+pub fn tweet_url(base_url: &str, tweet_id: &Uuid) -> String {
+    format!("{}/tweets/{}", base_url, tweet_id)
+}
+
+/// Whether `url` has the scheme, host and port of `base_url`, as from
+/// `base_url()`.
+pub fn is_same_origin(url: &str, base_url: &str) -> bool {
+    match (reqwest::Url::parse(url), reqwest::Url::parse(base_url)) {
+        (Ok(url), Ok(base_url)) => url.origin() == base_url.origin(),
+        _ => false,
+    }
+}
+
+/// Where to send the user back to after a form, from a `Referer` that can't
+/// be trusted: a path on this site, or `/` for anywhere else.
+pub fn local_redirect(context: &Ctx, url: &str) -> String {
+    let is_path = url.starts_with('/') && !url.starts_with("//") && !url.starts_with("/\\");
+
+    if is_path {
+        url.to_string()
+    } else if is_same_origin(url, &base_url(context)) {
+        reqwest::Url::parse(url)
+            .map(|url| match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            })
+            .unwrap_or_else(|_| "/".to_string())
+    } else {
+        "/".to_string()
+    }
+}
+
+/// The id of the tweet a permalink such as `https://bitter.example/tweets/<id>`
+/// points at. Only the path is checked, so links to any host running Bitter
+/// are accepted; check `is_same_origin` too where only ours will do.
+pub fn tweet_id_from_url(url: &str) -> Option<Uuid> {
+    let path = url.split(&['?', '#'][..]).next()?;
+    let mut segments = path.trim_end_matches('/').rsplit('/');
+    let id = segments.next()?;
+
+    if segments.next()? != "tweets" {
+        return None;
+    }
+
+    Uuid::from_str(id).ok()
+}
@@ -0,0 +1,354 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{Pool, Postgres};
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::{
+    background::Background,
+    config::OutboundConfig,
+    events::{Event, EventKind, Events},
+    jobs::{self, Enqueue, Job, JobResult},
+    models::webhooks::{Webhook, WebhookDelivery},
+    outbound::OutboundClient,
+};
+
+pub const EVENT_TYPES: [&str; 4] = ["reply", "mention", "like", "follow"];
+
+/// How many times, and how far apart, a delivery is attempted.
+#[derive(Clone, Debug)]
+pub struct Retries {
+    /// Including the first attempt.
+    pub max_attempts: u32,
+    /// Delay before the first retry. Doubles after every failed attempt.
+    pub initial_backoff: Duration,
+}
+
+impl Default for Retries {
+    fn default() -> Retries {
+        Retries {
+            max_attempts: 6,
+            initial_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    id: &'a Uuid,
+    #[serde(rename = "type")]
+    event_type: &'a str,
+    created_at: String,
+    data: &'a EventKind,
+}
+
+/// Who should hear about an event: webhooks of type `event_type` belonging to
+/// either `user_ids` or `usernames`.
+struct Recipients {
+    event_type: &'static str,
+    user_ids: Vec<Uuid>,
+    usernames: Vec<String>,
+}
+
+fn recipients(event: &EventKind) -> Vec<Recipients> {
+    match event {
+        EventKind::TweetCreated {
+            user_id,
+            responding_to_user_id,
+            mentions,
+            ..
+        } => {
+            let mut recipients = vec![Recipients {
+                event_type: "mention",
+                user_ids: vec![],
+                usernames: mentions.clone(),
+            }];
+
+            if let Some(responding_to_user_id) =
+                responding_to_user_id.filter(|parent| parent != user_id)
+            {
+                recipients.push(Recipients {
+                    event_type: "reply",
+                    user_ids: vec![responding_to_user_id],
+                    usernames: vec![],
+                });
+            }
+
+            recipients
+        }
+        EventKind::TweetLiked {
+            tweet_user_id,
+            user_id,
+            ..
+        } if tweet_user_id != user_id => vec![Recipients {
+            event_type: "like",
+            user_ids: vec![*tweet_user_id],
+            usernames: vec![],
+        }],
+        EventKind::UserFollowed { following_id, .. } => vec![Recipients {
+            event_type: "follow",
+            user_ids: vec![*following_id],
+            usernames: vec![],
+        }],
+        _ => vec![],
+    }
+}
+
+/// `sha256=<hex>` of `<timestamp>.<body>`, keyed with the webhook's secret.
+/// Including the timestamp lets receivers reject replayed deliveries.
+pub fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+/// Makes one delivery attempt, returning the response status if there was one
+/// and an error unless it was a 2xx.
+async fn attempt(
+    client: &OutboundClient,
+    webhook: &Webhook,
+    delivery: &WebhookDelivery,
+) -> (Option<i32>, Result<(), String>) {
+    let timestamp = Utc::now().timestamp();
+
+    // Checked again on every attempt, as where a hostname points can change.
+    let request = match client.post(&webhook.url) {
+        Ok(request) => request,
+        Err(e) => return (None, Err(e)),
+    };
+    let response = request
+        .header("Content-Type", "application/json")
+        .header("X-Bitter-Event", &delivery.event_type)
+        .header("X-Bitter-Delivery", delivery.id.to_string())
+        .header("X-Bitter-Timestamp", timestamp.to_string())
+        .header(
+            "X-Bitter-Signature",
+            signature(&webhook.secret, timestamp, &delivery.payload),
+        )
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    match response {
+        Ok(response) => {
+            let status = response.status();
+
+            (
+                Some(status.as_u16() as i32),
+                if status.is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("Receiver responded with {}", status))
+                },
+            )
+        }
+        Err(e) => (None, Err(e.to_string())),
+    }
+}
+
+/// What came of a delivery attempt.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Delivered,
+    RetryAt(DateTime<Utc>),
+    /// Out of attempts, which counts against the webhook.
+    GaveUp,
+}
+
+/// Makes the next attempt at `delivery`, logging it against the delivery. A
+/// failed attempt is retried after a backoff that doubles every time, until
+/// `retries` runs out.
+pub async fn deliver(
+    pool: &Pool<Postgres>,
+    client: &OutboundClient,
+    webhook: &Webhook,
+    delivery: &WebhookDelivery,
+    retries: &Retries,
+) -> Result<Outcome, sqlx::Error> {
+    let attempt_number = delivery.attempts as u32 + 1;
+    let (status_code, result) = attempt(client, webhook, delivery).await;
+
+    let outcome = match &result {
+        Ok(()) => Outcome::Delivered,
+        Err(_) if attempt_number < retries.max_attempts => {
+            let backoff = retries.initial_backoff * 2u32.pow(attempt_number.min(16) - 1);
+
+            Outcome::RetryAt(
+                Utc::now() + chrono::Duration::milliseconds(backoff.as_millis() as i64),
+            )
+        }
+        Err(_) => Outcome::GaveUp,
+    };
+
+    WebhookDelivery::record_attempt(
+        pool,
+        &delivery.id,
+        status_code,
+        result.as_ref().err().map(|e| e.as_str()),
+        result.is_ok(),
+        match outcome {
+            Outcome::RetryAt(at) => Some(at),
+            _ => None,
+        },
+    )
+    .await?;
+
+    match (&outcome, result) {
+        (Outcome::Delivered, _) => Webhook::record_success(pool, &webhook.id).await?,
+        (Outcome::RetryAt(at), Err(e)) => warn!(
+            "Webhook {} delivery {} failed, retrying at {}: {}",
+            webhook.id, delivery.id, at, e
+        ),
+        (_, Err(e)) => {
+            warn!(
+                "Webhook {} delivery {} failed, giving up: {}",
+                webhook.id, delivery.id, e
+            );
+            if Webhook::record_failure(pool, &webhook.id).await? {
+                info!("Disabled webhook {} after repeated failures", webhook.id);
+            }
+        }
+        (_, Ok(())) => (),
+    }
+
+    Ok(outcome)
+}
+
+/// The next attempt at a delivery. Each retry is a job of its own, queued for
+/// when it is due, so that a restart doesn't lose it.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeliverWebhook {
+    pub delivery_id: Uuid,
+}
+
+impl Job for DeliverWebhook {
+    const KIND: &'static str = "deliver_webhook";
+}
+
+pub async fn deliver_job(
+    pool: &Pool<Postgres>,
+    client: &OutboundClient,
+    job: DeliverWebhook,
+) -> JobResult {
+    let delivery = WebhookDelivery::get_delivery(pool, &job.delivery_id).await?;
+    let webhook = Webhook::get_webhook(pool, &delivery.webhook_id).await?;
+    if delivery.delivered_at.is_some() || webhook.disabled_at.is_some() {
+        return Ok(());
+    }
+
+    if let Outcome::RetryAt(run_at) =
+        deliver(pool, client, &webhook, &delivery, &Retries::default()).await?
+    {
+        jobs::enqueue(
+            pool,
+            &job,
+            Enqueue {
+                run_at: Some(run_at),
+                ..Enqueue::default()
+            },
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// A client for delivering to receivers, which checks their addresses.
+pub fn client(config: &OutboundConfig) -> Result<OutboundClient, reqwest::Error> {
+    OutboundClient::new(config, REQUEST_TIMEOUT, "Bitter-Webhooks/1.0")
+}
+
+async fn dispatch(pool: &Pool<Postgres>, event: &Event) {
+    for recipients in recipients(&event.kind) {
+        let webhooks = if recipients.usernames.is_empty() {
+            Webhook::get_active_webhooks(pool, &recipients.user_ids, recipients.event_type).await
+        } else {
+            Webhook::get_active_webhooks_for_usernames(
+                pool,
+                &recipients.usernames,
+                recipients.event_type,
+            )
+            .await
+        };
+        let webhooks = match webhooks {
+            Ok(webhooks) => webhooks,
+            Err(_e) => {
+                error!("_e: {:#?}", _e);
+                continue;
+            }
+        };
+
+        let payload = match serde_json::to_string(&Payload {
+            id: &event.id,
+            event_type: recipients.event_type,
+            created_at: Utc::now().to_rfc3339(),
+            data: &event.kind,
+        }) {
+            Ok(payload) => payload,
+            Err(_e) => {
+                error!("_e: {:#?}", _e);
+                continue;
+            }
+        };
+
+        for webhook in webhooks {
+            let delivery = WebhookDelivery::claim_delivery(
+                pool,
+                &webhook.id,
+                &event.id,
+                recipients.event_type,
+                &payload,
+            )
+            .await;
+
+            match delivery {
+                Ok(Some(delivery)) => {
+                    let job = DeliverWebhook {
+                        delivery_id: delivery.id,
+                    };
+
+                    if let Err(_e) = jobs::enqueue(pool, &job, Enqueue::default()).await {
+                        error!("_e: {:#?}", _e);
+                    }
+                }
+                // Another instance claimed it first.
+                Ok(None) => (),
+                Err(_e) => error!("_e: {:#?}", _e),
+            }
+        }
+    }
+}
+
+/// Queues deliveries of account events to the webhooks registered for them,
+/// for as long as the event stream is open. The job workers deliver them.
+pub fn spawn_dispatcher(pool: Pool<Postgres>, events: &Events, background: Background) {
+    let mut receiver = events.subscribe();
+
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let _job = background.job();
+
+                    dispatch(&pool, &event).await
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Webhook dispatcher skipped {} events", missed)
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
@@ -0,0 +1,362 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use log::{error, info, warn};
+use reqwest::Url;
+use sqlx::{Pool, Postgres};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    background::Background, config::OutboundConfig, models::webmentions::Webmention,
+    outbound::OutboundClient,
+};
+
+pub type FetchError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Pages larger than this are cut off before looking for links in them.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Received webmentions waiting to be verified. Past this, more are left
+/// pending for the next sweep.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// How often pending webmentions are queued again, for those that found the
+/// queue full.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+pub struct FetchedPage {
+    /// Where the request ended up after redirects.
+    pub url: String,
+    pub status: u16,
+    pub link_headers: Vec<String>,
+    pub body: String,
+}
+
+/// The HTTP calls webmentions need, so that tests can stand in a local server
+/// or a canned implementation.
+#[async_trait]
+pub trait Fetcher: Send + Sync {
+    async fn get(&self, url: &str) -> Result<FetchedPage, FetchError>;
+
+    /// Posts a form, returning the response status.
+    async fn post_form(&self, url: &str, form: &[(&str, &str)]) -> Result<u16, FetchError>;
+}
+
+/// Fetches over the network, refusing addresses off the public internet, as
+/// sources and targets come from anyone.
+pub struct ReqwestFetcher {
+    client: OutboundClient,
+}
+
+impl ReqwestFetcher {
+    pub fn new(config: &OutboundConfig) -> Result<ReqwestFetcher, reqwest::Error> {
+        Ok(ReqwestFetcher {
+            client: OutboundClient::new(config, REQUEST_TIMEOUT, "Bitter-Webmention/1.0")?,
+        })
+    }
+}
+
+#[async_trait]
+impl Fetcher for ReqwestFetcher {
+    async fn get(&self, url: &str) -> Result<FetchedPage, FetchError> {
+        let mut response = self.client.get(url)?.send().await?;
+
+        let url = response.url().to_string();
+        let status = response.status().as_u16();
+        let link_headers = response
+            .headers()
+            .get_all("Link")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .map(|value| value.to_string())
+            .collect();
+
+        let mut body = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() >= MAX_BODY_SIZE {
+                body.truncate(MAX_BODY_SIZE);
+                break;
+            }
+        }
+
+        Ok(FetchedPage {
+            url,
+            status,
+            link_headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+
+    async fn post_form(&self, url: &str, form: &[(&str, &str)]) -> Result<u16, FetchError> {
+        Ok(self
+            .client
+            .post(url)?
+            .form(form)
+            .send()
+            .await?
+            .status()
+            .as_u16())
+    }
+}
+
+/// The http(s) links in a tweet, each once, in the order they appear.
+pub fn links(content: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+
+    content
+        .split_whitespace()
+        .filter(|word| word.starts_with("https://") || word.starts_with("http://"))
+        .map(|word| word.trim_end_matches(&['.', ',', ';', ':', '!', '?', ')', '"', '\''][..]))
+        .filter(|link| Url::parse(link).is_ok())
+        .map(|link| link.to_string())
+        .filter(|link| seen.insert(link.clone()))
+        .collect()
+}
+
+fn has_webmention_rel(rel: &str) -> bool {
+    rel.split_whitespace()
+        .any(|rel| rel.eq_ignore_ascii_case("webmention"))
+}
+
+/// The value of `name` in an HTML start tag such as `<link rel="webmention" href="/wm">`.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lowercase = tag.to_ascii_lowercase();
+    let mut search_from = 0;
+
+    while let Some(found) = lowercase[search_from..].find(name) {
+        let start = search_from + found;
+        search_from = start + name.len();
+
+        let preceded_by_space = lowercase[..start]
+            .chars()
+            .last()
+            .is_some_and(|c| c.is_whitespace());
+        let rest = tag[search_from..].trim_start();
+        if !preceded_by_space || !rest.starts_with('=') {
+            continue;
+        }
+
+        let value = rest[1..].trim_start();
+        return Some(match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next()?.to_string(),
+            _ => value
+                .split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+                .next()?
+                .to_string(),
+        });
+    }
+
+    None
+}
+
+/// Finds the webmention endpoint advertised by a page, first in its `Link`
+/// headers and then in `<link>` and `<a>` elements, resolved against the page.
+pub fn discover_endpoint(page: &FetchedPage) -> Option<String> {
+    let base = Url::parse(&page.url).ok()?;
+
+    let from_headers = page
+        .link_headers
+        .iter()
+        .flat_map(|header| header.split(','))
+        .find_map(|link| {
+            let (target, params) = link.split_once(';')?;
+            let rel = params
+                .split(';')
+                .filter_map(|param| param.trim().strip_prefix("rel="))
+                .next()?;
+
+            has_webmention_rel(rel.trim_matches('"')).then(|| {
+                target
+                    .trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            })
+        });
+
+    let from_html = || {
+        page.body.split('<').skip(1).find_map(|tag| {
+            let tag = tag.split('>').next()?;
+            let name = tag.split_whitespace().next()?.to_ascii_lowercase();
+
+            if (name == "link" || name == "a")
+                && attribute(tag, "rel").is_some_and(|rel| has_webmention_rel(&rel))
+            {
+                attribute(tag, "href")
+            } else {
+                None
+            }
+        })
+    };
+
+    from_headers
+        .or_else(from_html)
+        .and_then(|endpoint| base.join(&endpoint).ok())
+        .map(|endpoint| endpoint.to_string())
+}
+
+fn title(body: &str) -> Option<String> {
+    let lowercase = body.to_ascii_lowercase();
+    let start = lowercase.find("<title")?;
+    let start = start + lowercase[start..].find('>')? + 1;
+    let end = start + lowercase[start..].find("</title")?;
+    let title = body[start..end].trim();
+
+    (!title.is_empty()).then(|| title.chars().take(200).collect())
+}
+
+/// Checks that the source of a received webmention really links to its target.
+async fn verify(
+    pool: &Pool<Postgres>,
+    fetcher: &dyn Fetcher,
+    id: &Uuid,
+) -> Result<(), sqlx::Error> {
+    let webmention = Webmention::get_webmention_for_id(pool, id).await?;
+
+    let page = match fetcher.get(&webmention.source).await {
+        Ok(page) => page,
+        Err(e) => return Webmention::mark_rejected(pool, id, &e.to_string()).await,
+    };
+
+    if !(200..300).contains(&page.status) {
+        Webmention::mark_rejected(pool, id, &format!("Source responded with {}", page.status)).await
+    } else if !page.body.contains(&webmention.target) {
+        Webmention::mark_rejected(pool, id, "Source does not link to target").await
+    } else {
+        Webmention::mark_verified(pool, id, title(&page.body).as_deref()).await
+    }
+}
+
+/// Sends a webmention from `source` to each page linked from `content` that
+/// advertises an endpoint.
+async fn send(fetcher: &dyn Fetcher, source: &str, content: &str) {
+    for target in links(content) {
+        let endpoint = match fetcher.get(&target).await {
+            Ok(page) => discover_endpoint(&page),
+            Err(e) => {
+                warn!("Could not fetch {} for webmention discovery: {}", target, e);
+                continue;
+            }
+        };
+
+        if let Some(endpoint) = endpoint {
+            match fetcher
+                .post_form(&endpoint, &[("source", source), ("target", &target)])
+                .await
+            {
+                Ok(status) if (200..300).contains(&status) => {
+                    info!("Sent webmention for {} to {}", target, endpoint)
+                }
+                Ok(status) => warn!("Webmention endpoint {} responded with {}", endpoint, status),
+                Err(e) => warn!("Could not send webmention to {}: {}", endpoint, e),
+            }
+        }
+    }
+}
+
+/// Sends webmentions for new tweets and verifies received ones in the
+/// background, so that neither holds up a request.
+#[derive(Clone)]
+pub struct Webmentions {
+    fetcher: Arc<dyn Fetcher>,
+    queue: mpsc::Sender<Uuid>,
+    /// Off when webmentions are turned off in the config.
+    sending: bool,
+    background: Background,
+}
+
+impl Webmentions {
+    /// Starts the verifier, picking up anything left pending by a previous run
+    /// or by a full queue.
+    pub fn spawn(
+        pool: Pool<Postgres>,
+        fetcher: Arc<dyn Fetcher>,
+        sending: bool,
+        background: Background,
+    ) -> Webmentions {
+        let (queue, mut queued) = mpsc::channel(QUEUE_CAPACITY);
+        let webmentions = Webmentions {
+            fetcher: fetcher.clone(),
+            queue,
+            sending,
+            background: background.clone(),
+        };
+
+        let requeue = webmentions.queue.clone();
+        let requeue_pool = pool.clone();
+        tokio::spawn(async move {
+            let mut sweep = tokio::time::interval(SWEEP_INTERVAL);
+
+            loop {
+                sweep.tick().await;
+
+                // Those still queued from the last sweep are verified twice,
+                // which does no harm.
+                match Webmention::get_pending_webmention_ids(&requeue_pool).await {
+                    Ok(ids) => {
+                        for id in ids {
+                            if requeue.send(id).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(_e) => error!("_e: {:#?}", _e),
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            while let Some(id) = queued.recv().await {
+                // Anything still queued at shutdown stays pending for the next
+                // start; only the one being verified is waited for.
+                let _job = background.job();
+
+                if let Err(_e) = verify(&pool, fetcher.as_ref(), &id).await {
+                    error!("_e: {:#?}", _e);
+                }
+            }
+        });
+
+        webmentions
+    }
+
+    /// Neither sends nor verifies, for SQLite, where there is nowhere to keep
+    /// received webmentions.
+    pub fn off(fetcher: Arc<dyn Fetcher>, background: Background) -> Webmentions {
+        let (queue, _) = mpsc::channel(1);
+
+        Webmentions {
+            fetcher,
+            queue,
+            sending: false,
+            background,
+        }
+    }
+
+    pub fn verify_later(&self, id: Uuid) {
+        // Either way the webmention stays pending, to be picked up by the next
+        // sweep or start.
+        match self.queue.try_send(id) {
+            Ok(()) => (),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!("Webmention queue is full; {} waits for the next sweep", id)
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => (),
+        }
+    }
+
+    pub fn send_later(&self, source: String, content: String) {
+        if !self.sending {
+            return;
+        }
+
+        let fetcher = self.fetcher.clone();
+
+        self.background
+            .spawn(async move { send(fetcher.as_ref(), &source, &content).await });
+    }
+}
@@ -0,0 +1,47 @@
+//! `TtlCache` on its own: what it evicts once full, and that a value loaded
+//! before an invalidation isn't put back after it.
+
+use brutalist_twitter::{cache::TtlCache, config::CacheConfig, metrics::Metrics};
+
+fn cache(capacity: usize) -> TtlCache<u32, &'static str> {
+    let config = CacheConfig {
+        enabled: true,
+        ttl_secs: 60,
+        capacity,
+    };
+
+    TtlCache::new("test", &config, &Metrics::new(1).unwrap())
+}
+
+#[test]
+fn the_oldest_entry_goes_once_full() {
+    let cache = cache(2);
+
+    cache.insert(cache.generation(), 1, "one");
+    cache.insert(cache.generation(), 2, "two");
+    // Putting it in again makes it the newest.
+    cache.insert(cache.generation(), 1, "one again");
+    cache.insert(cache.generation(), 3, "three");
+
+    assert_eq!(cache.get(&1), Some("one again"));
+    assert_eq!(cache.get(&2), None);
+    assert_eq!(cache.get(&3), Some("three"));
+}
+
+#[test]
+fn values_loaded_before_a_removal_are_not_cached() {
+    let cache = cache(10);
+
+    let generation = cache.generation();
+    cache.remove(&1);
+    cache.insert(generation, 1, "revoked");
+    assert_eq!(cache.get(&1), None);
+
+    let generation = cache.generation();
+    cache.remove_where(|key, _| *key == 2);
+    cache.insert(generation, 1, "revoked");
+    assert_eq!(cache.get(&1), None);
+
+    cache.insert(cache.generation(), 1, "current");
+    assert_eq!(cache.get(&1), Some("current"));
+}
@@ -0,0 +1,272 @@
+//! `Config::load`: the file, then `BITTER_*` environment variables, then
+//! flags, and the settings `validate` turns away.
+
+use std::{fs, path::PathBuf, sync::Mutex};
+
+use brutalist_twitter::config::{Config, ConfigError};
+use uuid::Uuid;
+
+/// Held while a test loads a config, as the environment is shared by every
+/// test in this file.
+static ENV: Mutex<()> = Mutex::new(());
+
+/// Loads `file` as the config file, with `env` set in the environment and
+/// `args` on the command line.
+fn load_with(
+    file: &str,
+    env: &[(&str, &str)],
+    args: &[&str],
+) -> Result<(Config, Vec<String>), ConfigError> {
+    let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    let path = std::env::temp_dir().join(format!("bitter-{}.toml", Uuid::new_v4().simple()));
+    fs::write(&path, file).unwrap();
+
+    for (name, value) in env {
+        std::env::set_var(name, value);
+    }
+    let mut command_line = vec!["--config".to_string(), path.display().to_string()];
+    command_line.extend(args.iter().map(|arg| arg.to_string()));
+    let loaded = Config::load(&command_line);
+    for (name, _) in env {
+        std::env::remove_var(name);
+    }
+
+    fs::remove_file(&path).unwrap();
+
+    loaded
+}
+
+fn load(file: &str, args: &[&str]) -> Result<Config, ConfigError> {
+    load_with(file, &[], args).map(|(config, _)| config)
+}
+
+fn invalid_key(result: Result<Config, ConfigError>) -> &'static str {
+    match result {
+        Err(ConfigError::Invalid { key, .. }) => key,
+        Err(e) => panic!("Expected a validation error, got {}", e),
+        Ok(_) => panic!("Expected a validation error, got a config"),
+    }
+}
+
+#[test]
+fn flags_override_the_environment_which_overrides_the_file() {
+    let file = r#"
+        [server]
+        host = "127.0.0.1"
+        port = 1000
+
+        [jobs]
+        concurrency = 2
+        lease_secs = 7
+    "#;
+    let env = [
+        ("BITTER_SERVER_PORT", "2000"),
+        ("BITTER_JOBS_CONCURRENCY", "3"),
+    ];
+
+    let (config, rest) = load_with(
+        file,
+        &env,
+        &["--server-port", "3000", "migrate", "--jobs-lease-secs=9"],
+    )
+    .unwrap();
+
+    assert_eq!(config.server.host, "127.0.0.1");
+    assert_eq!(config.server.port, 3000);
+    assert_eq!(config.jobs.concurrency, 3);
+    assert_eq!(config.jobs.lease_secs, 9);
+    assert_eq!(rest, vec!["migrate".to_string()]);
+}
+
+#[test]
+fn defaults_fill_in_what_the_file_leaves_out() {
+    let config = load("", &[]).unwrap();
+
+    assert_eq!(config.server.port, 4321);
+    assert_eq!(config.rate_limits.store, "memory");
+    assert!(!config.outbound.allow_private_addresses);
+}
+
+#[test]
+fn database_url_is_used_without_our_prefix_but_loses_to_it() {
+    let (config, _) =
+        load_with("", &[("DATABASE_URL", "postgres://platform/bitter")], &[]).unwrap();
+    assert_eq!(config.database.url, "postgres://platform/bitter");
+
+    let (config, _) = load_with(
+        "",
+        &[
+            ("DATABASE_URL", "postgres://platform/bitter"),
+            ("BITTER_DATABASE_URL", "postgres://ours/bitter"),
+        ],
+        &[],
+    )
+    .unwrap();
+    assert_eq!(config.database.url, "postgres://ours/bitter");
+}
+
+#[test]
+fn values_that_do_not_parse_say_where_they_came_from() {
+    match load_with("", &[("BITTER_SERVER_PORT", "high")], &[]) {
+        Err(ConfigError::InvalidValue { key, source, .. }) => {
+            assert_eq!(key, "server.port");
+            assert_eq!(source, "environment variable BITTER_SERVER_PORT");
+        }
+        _ => panic!("Expected an invalid value"),
+    }
+
+    match load("", &["--cache-enabled", "maybe"]) {
+        Err(ConfigError::InvalidValue { key, source, .. }) => {
+            assert_eq!(key, "cache.enabled");
+            assert_eq!(source, "--cache-enabled");
+        }
+        _ => panic!("Expected an invalid value"),
+    }
+}
+
+#[test]
+fn a_flag_at_the_end_needs_a_value() {
+    assert!(matches!(
+        load("", &["--server-port"]),
+        Err(ConfigError::MissingValue { flag }) if flag == "--server-port"
+    ));
+}
+
+#[test]
+fn files_that_cannot_be_read_or_parsed_are_errors() {
+    let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    let missing: PathBuf = std::env::temp_dir().join(format!("bitter-{}.toml", Uuid::new_v4()));
+    assert!(matches!(
+        Config::load(&["--config".to_string(), missing.display().to_string()]),
+        Err(ConfigError::Read { .. })
+    ));
+    drop(_env);
+
+    assert!(matches!(
+        load("[server]\ncolour = \"red\"", &[]),
+        Err(ConfigError::Parse { .. })
+    ));
+}
+
+#[test]
+fn each_invalid_setting_is_named() {
+    let cases: &[(&[&str], &str)] = &[
+        (
+            &["--database-url", "mysql://localhost/bitter"],
+            "database.url",
+        ),
+        (
+            &["--database-max-connections", "0"],
+            "database.max_connections",
+        ),
+        (
+            &[
+                "--database-max-connections",
+                "2",
+                "--database-min-connections",
+                "3",
+            ],
+            "database.min_connections",
+        ),
+        (&["--server-host", " "], "server.host"),
+        (
+            &["--server-public-url", "https://bitter.example/app"],
+            "server.public_url",
+        ),
+        (
+            &["--server-public-url", "ftp://bitter.example"],
+            "server.public_url",
+        ),
+        (&["--cookies-same-site", "none"], "cookies.same_site"),
+        (
+            &["--rate-limits-requests-per-minute", "0"],
+            "rate_limits.requests_per_minute",
+        ),
+        (&["--rate-limits-burst", "0"], "rate_limits.burst"),
+        (&["--rate-limits-store", "redis"], "rate_limits.store"),
+        (&["--logging-format", "xml"], "logging.format"),
+        (&["--metrics-token", "   "], "metrics.token"),
+        (&["--counters-batch-size", "0"], "counters.batch_size"),
+        (&["--jobs-concurrency", "0"], "jobs.concurrency"),
+        (&["--jobs-poll-interval-ms", "0"], "jobs.poll_interval_ms"),
+        (&["--jobs-lease-secs", "0"], "jobs.lease_secs"),
+        (&["--cache-ttl-secs", "0"], "cache.ttl_secs"),
+        (&["--cache-capacity", "0"], "cache.capacity"),
+        (&["--idempotency-ttl-secs", "0"], "idempotency.ttl_secs"),
+    ];
+
+    for (args, key) in cases {
+        assert_eq!(invalid_key(load("", args)), *key, "for {:?}", args);
+    }
+}
+
+#[test]
+fn limits_that_are_off_are_not_validated() {
+    let config = load(
+        "",
+        &[
+            "--rate-limits-enabled",
+            "false",
+            "--rate-limits-burst",
+            "0",
+            "--cache-enabled",
+            "false",
+            "--cache-capacity",
+            "0",
+        ],
+    )
+    .unwrap();
+
+    assert!(!config.rate_limits.enabled);
+    assert!(!config.cache.enabled);
+}
+
+#[test]
+fn each_invalid_rate_limit_rule_is_named() {
+    let rule = |route: &str, key: &str, burst: u32| {
+        format!(
+            "[[rate_limits.rules]]\nroute = {:?}\nkey = {:?}\nrequests_per_minute = 10\nburst = {}",
+            route, key, burst
+        )
+    };
+
+    for file in [
+        rule("/tweets", "user", 5),
+        rule("POST tweets", "user", 5),
+        rule("POST /tweets", "session", 5),
+        rule("POST /tweets", "user", 0),
+    ] {
+        assert_eq!(
+            invalid_key(load(&file, &[])),
+            "rate_limits.rules",
+            "for {}",
+            file
+        );
+    }
+
+    assert_eq!(
+        load(&rule("POST /tweets/:id/likes", "api_token", 5), &[])
+            .unwrap()
+            .rate_limits
+            .rules
+            .len(),
+        1
+    );
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn rate_limits_are_kept_in_memory_on_sqlite() {
+    assert_eq!(
+        invalid_key(load(
+            "",
+            &[
+                "--database-url",
+                "sqlite://bitter.db",
+                "--rate-limits-store",
+                "postgres"
+            ],
+        )),
+        "rate_limits.store"
+    );
+}
@@ -0,0 +1,93 @@
+//! `counters::reconcile` against drift made behind the stores' backs. Skipped
+//! without `TEST_DATABASE_URL`; see `support`.
+
+mod support;
+
+use brutalist_twitter::{background::Background, counters, stores::Stores};
+use support::TestDatabase;
+
+#[tokio::test]
+async fn drifted_counts_are_reported_and_fixed() {
+    let database = match TestDatabase::create().await {
+        Some(database) => database,
+        None => {
+            eprintln!("TEST_DATABASE_URL is not set; skipping");
+            return;
+        }
+    };
+    let stores = Stores::postgres(database.pool.clone());
+    let background = Background::default();
+
+    let alice = stores.users.create_user("alice", "hash").await.unwrap();
+    let bob = stores.users.create_user("bob", "hash").await.unwrap();
+    let tweet = stores
+        .tweets
+        .create_tweet(&alice.id, None, "Count me".to_string())
+        .await
+        .unwrap();
+    let untouched = stores
+        .tweets
+        .create_tweet(&alice.id, None, "Leave me be".to_string())
+        .await
+        .unwrap();
+    stores
+        .tweets
+        .create_tweet(&bob.id, Some(tweet.id), "A reply".to_string())
+        .await
+        .unwrap();
+    stores.social_graph.like(&tweet.id, &bob.id).await.unwrap();
+
+    sqlx::query(
+        "
+        UPDATE tweets
+        SET like_count = 7, retweet_count = 3, reply_count = 0
+        WHERE id = $1",
+    )
+    .bind(tweet.id)
+    .execute(&database.pool)
+    .await
+    .unwrap();
+
+    // A batch size of 1 so that paging through is covered too.
+    let report = counters::reconcile(&database.pool, 1, true, &background)
+        .await
+        .unwrap();
+    assert_eq!(report.checked, 3);
+    assert_eq!(report.drifted.len(), 1);
+    assert_eq!(report.fixed, 0);
+    let drift = &report.drifted[0];
+    assert_eq!(drift.tweet_id, tweet.id);
+    assert_eq!((drift.like_count, drift.actual_like_count), (7, 1));
+    assert_eq!((drift.retweet_count, drift.actual_retweet_count), (3, 0));
+    assert_eq!((drift.reply_count, drift.actual_reply_count), (0, 1));
+    assert_eq!(
+        stores.tweets.get_tweet(&tweet.id).await.unwrap().like_count,
+        7
+    );
+
+    let report = counters::reconcile(&database.pool, 1, false, &background)
+        .await
+        .unwrap();
+    assert_eq!(report.drifted.len(), 1);
+    assert_eq!(report.fixed, 1);
+    assert_eq!(report.skipped, 0);
+
+    let fixed = stores.tweets.get_tweet(&tweet.id).await.unwrap();
+    assert_eq!(fixed.like_count, 1);
+    assert_eq!(fixed.retweet_count, 0);
+    assert_eq!(fixed.reply_count, 1);
+    assert_eq!(
+        stores
+            .tweets
+            .get_tweet(&untouched.id)
+            .await
+            .unwrap()
+            .like_count,
+        0
+    );
+
+    let report = counters::reconcile(&database.pool, 1, false, &background)
+        .await
+        .unwrap();
+    assert!(report.drifted.is_empty());
+}
@@ -0,0 +1,160 @@
+//! Repeated POSTs with an idempotency key. Skipped without
+//! `TEST_DATABASE_URL`; see `support`.
+
+#[macro_use]
+mod support;
+
+use reqwest::StatusCode;
+use sqlx::{Pool, Postgres};
+use support::{assert_redirect, assert_status};
+
+async fn tweet_count(pool: &Pool<Postgres>) -> i64 {
+    let (count,): (i64,) = sqlx::query_as("SELECT count(*) FROM tweets")
+        .fetch_one(pool)
+        .await
+        .unwrap();
+
+    count
+}
+
+#[tokio::test]
+async fn resubmitted_forms_tweet_once() {
+    let app = test_app!();
+    let alice = app.signed_up("alice").await;
+    let form = [("content", "Only once"), ("idempotency_key", "form-1")];
+
+    let first = alice.post_form("/tweets", &form).await;
+    assert_redirect(&first, "/");
+    assert!(first.headers().get("Idempotent-Replayed").is_none());
+
+    let second = alice.post_form("/tweets", &form).await;
+    assert_redirect(&second, "/");
+    assert_eq!(second.headers()["Idempotent-Replayed"], "true");
+
+    assert_eq!(tweet_count(&app.pool).await, 1);
+
+    // Without a key, every request counts.
+    alice.post_tweet("Twice").await;
+    alice.post_tweet("Twice").await;
+    assert_eq!(tweet_count(&app.pool).await, 3);
+}
+
+#[tokio::test]
+async fn the_header_works_as_the_form_field_does() {
+    let app = test_app!();
+    let alice = app.signed_up("alice").await;
+
+    for _ in 0..2 {
+        let response = alice
+            .client
+            .post(alice.url("/tweets"))
+            .header("Idempotency-Key", "header-1")
+            .form(&[("content", "From the API")])
+            .send()
+            .await
+            .unwrap();
+        assert_redirect(&response, "/");
+    }
+
+    assert_eq!(tweet_count(&app.pool).await, 1);
+}
+
+#[tokio::test]
+async fn keys_are_not_reused_for_other_requests() {
+    let app = test_app!();
+    let alice = app.signed_up("alice").await;
+
+    alice
+        .post_form("/tweets", &[("content", "First"), ("idempotency_key", "k")])
+        .await;
+    let response = alice
+        .post_form(
+            "/tweets",
+            &[("content", "Second"), ("idempotency_key", "k")],
+        )
+        .await;
+    assert_status(&response, StatusCode::BAD_REQUEST);
+
+    let too_long = "k".repeat(256);
+    let response = alice
+        .post_form(
+            "/tweets",
+            &[("content", "Third"), ("idempotency_key", &too_long)],
+        )
+        .await;
+    assert_status(&response, StatusCode::BAD_REQUEST);
+
+    assert_eq!(tweet_count(&app.pool).await, 1);
+}
+
+#[tokio::test]
+async fn keys_are_per_user() {
+    let app = test_app!();
+    let alice = app.signed_up("alice").await;
+    let bob = app.signed_up("bob").await;
+    let form = [("content", "Same words"), ("idempotency_key", "shared")];
+
+    assert_redirect(&alice.post_form("/tweets", &form).await, "/");
+    let response = bob.post_form("/tweets", &form).await;
+    assert_redirect(&response, "/");
+    assert!(response.headers().get("Idempotent-Replayed").is_none());
+
+    assert_eq!(tweet_count(&app.pool).await, 2);
+}
+
+#[tokio::test]
+async fn a_repeat_in_flight_waits_for_the_first_response() {
+    let app = test_app!();
+    let alice = app.signed_up("alice").await;
+    let form = [("content", "At the same time"), ("idempotency_key", "race")];
+
+    let (first, second) = tokio::join!(
+        alice.post_form("/tweets", &form),
+        alice.post_form("/tweets", &form)
+    );
+    assert_redirect(&first, "/");
+    assert_redirect(&second, "/");
+
+    assert_eq!(tweet_count(&app.pool).await, 1);
+}
+
+#[tokio::test]
+async fn likes_are_replayed() {
+    let app = test_app!();
+    let alice = app.signed_up("alice").await;
+    alice.post_tweet("Like me").await;
+    let tweet = app.tweet("Like me").await;
+
+    let mut replayed = vec![];
+    for _ in 0..2 {
+        let response = alice
+            .client
+            .post(alice.url(&format!("/tweets/{}/likes", tweet.id)))
+            .header("Idempotency-Key", "like-1")
+            .send()
+            .await
+            .unwrap();
+        assert_redirect(&response, "/");
+        replayed.push(response.headers().get("Idempotent-Replayed").is_some());
+    }
+
+    assert_eq!(replayed, vec![false, true]);
+}
+
+#[tokio::test]
+async fn large_bodies_are_refused_before_being_read() {
+    let app = test_app!();
+    let alice = app.signed_up("alice").await;
+    let content = "x".repeat(brutalist_twitter::idempotency::MAX_BODY_BYTES);
+
+    let response = alice
+        .post_form(
+            "/tweets",
+            &[("content", &content), ("idempotency_key", "large")],
+        )
+        .await;
+    assert_status(&response, StatusCode::BAD_REQUEST);
+    assert!(response.text().await.unwrap().contains("too large"));
+
+    assert_eq!(tweet_count(&app.pool).await, 0);
+}
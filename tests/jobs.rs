@@ -0,0 +1,227 @@
+//! The job queue on a schema of its own. Skipped without `TEST_DATABASE_URL`;
+//! see `support`.
+
+mod support;
+
+use std::time::Duration;
+
+use brutalist_twitter::{
+    background::Background,
+    config::JobsConfig,
+    health::Health,
+    jobs::{enqueue, Enqueue, Job, JobResult, Worker},
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use support::TestDatabase;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Greet {
+    name: String,
+}
+
+impl Job for Greet {
+    const KIND: &'static str = "test_greet";
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Fail {}
+
+impl Job for Fail {
+    const KIND: &'static str = "test_fail";
+    const MAX_ATTEMPTS: i32 = 1;
+}
+
+macro_rules! test_database {
+    () => {
+        match TestDatabase::create().await {
+            Some(database) => database,
+            None => {
+                eprintln!("TEST_DATABASE_URL is not set; skipping");
+                return;
+            }
+        }
+    };
+}
+
+fn config() -> JobsConfig {
+    JobsConfig {
+        poll_interval_ms: 10,
+        ..JobsConfig::default()
+    }
+}
+
+async fn status(pool: &Pool<Postgres>, id: Uuid) -> (String, i32, Option<String>) {
+    sqlx::query_as("SELECT status, attempts, last_error FROM jobs WHERE id = $1")
+        .bind(id)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+/// Polls until the job leaves `queued` and `running`.
+async fn finished(pool: &Pool<Postgres>, id: Uuid) -> (String, i32, Option<String>) {
+    for _ in 0..500 {
+        let status = status(pool, id).await;
+        if status.0 != "queued" && status.0 != "running" {
+            return status;
+        }
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    panic!("job {} did not finish", id);
+}
+
+#[tokio::test]
+async fn unique_keys_are_taken_while_queued() {
+    let database = test_database!();
+    let keyed = || Enqueue {
+        unique_key: Some("alice".to_string()),
+        ..Enqueue::default()
+    };
+    let greet = Greet {
+        name: "alice".to_string(),
+    };
+
+    let first = enqueue(&database.pool, &greet, keyed()).await.unwrap();
+    assert!(first.is_some());
+    assert_eq!(
+        enqueue(&database.pool, &greet, keyed()).await.unwrap(),
+        None
+    );
+    assert!(enqueue(&database.pool, &greet, Enqueue::default())
+        .await
+        .unwrap()
+        .is_some());
+
+    sqlx::query("UPDATE jobs SET status = 'done' WHERE id = $1")
+        .bind(first.unwrap())
+        .execute(&database.pool)
+        .await
+        .unwrap();
+    assert!(enqueue(&database.pool, &greet, keyed())
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+async fn workers_run_due_jobs_and_bury_failures() {
+    let database = test_database!();
+    let background = Background::default();
+    let health = Health::default();
+    let (sender, mut received) = mpsc::unbounded_channel();
+
+    let now = enqueue(
+        &database.pool,
+        &Greet {
+            name: "now".to_string(),
+        },
+        Enqueue::default(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let later = enqueue(
+        &database.pool,
+        &Greet {
+            name: "later".to_string(),
+        },
+        Enqueue {
+            run_at: Some(Utc::now() + chrono::Duration::hours(1)),
+            ..Enqueue::default()
+        },
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let failing = enqueue(&database.pool, &Fail {}, Enqueue::default())
+        .await
+        .unwrap()
+        .unwrap();
+
+    Worker::new(&config())
+        .register(move |greet: Greet| {
+            let sender = sender.clone();
+
+            async move {
+                sender.send(greet.name)?;
+
+                JobResult::Ok(())
+            }
+        })
+        .register(|_: Fail| async { JobResult::Err("no luck".into()) })
+        .spawn(database.pool.clone(), health.clone(), background.clone());
+
+    assert_eq!(received.recv().await.unwrap(), "now");
+    assert_eq!(
+        finished(&database.pool, now).await,
+        ("done".to_string(), 1, None)
+    );
+    assert_eq!(
+        finished(&database.pool, failing).await,
+        ("dead".to_string(), 1, Some("no luck".to_string()))
+    );
+    assert_eq!(status(&database.pool, later).await.0, "queued");
+
+    health.begin_shutdown();
+    tokio::time::timeout(Duration::from_secs(5), background.drain())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn failures_are_retried_later() {
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Flaky {}
+
+    impl Job for Flaky {
+        const KIND: &'static str = "test_flaky";
+    }
+
+    let database = test_database!();
+    let background = Background::default();
+    let health = Health::default();
+
+    let id = enqueue(&database.pool, &Flaky {}, Enqueue::default())
+        .await
+        .unwrap()
+        .unwrap();
+
+    Worker::new(&config())
+        .register(|_: Flaky| async { JobResult::Err("try again".into()) })
+        .spawn(database.pool.clone(), health.clone(), background.clone());
+
+    let (status, attempts, error) = finished_attempt(&database.pool, id).await;
+    assert_eq!(status, "queued");
+    assert_eq!(attempts, 1);
+    assert_eq!(error.as_deref(), Some("try again"));
+
+    let (delay,): (f64,) =
+        sqlx::query_as("SELECT EXTRACT(EPOCH FROM run_at - now())::float8 FROM jobs WHERE id = $1")
+            .bind(id)
+            .fetch_one(&database.pool)
+            .await
+            .unwrap();
+    assert!(delay > 5.0, "retried after {}s", delay);
+
+    health.begin_shutdown();
+}
+
+/// Polls until the first attempt has been recorded.
+async fn finished_attempt(pool: &Pool<Postgres>, id: Uuid) -> (String, i32, Option<String>) {
+    for _ in 0..500 {
+        let status = status(pool, id).await;
+        if status.2.is_some() {
+            return status;
+        }
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    panic!("job {} was not attempted", id);
+}
@@ -0,0 +1,49 @@
+//! `migrations::status` and the `migrate` command against a schema of their
+//! own. Skipped without `TEST_DATABASE_URL`; see `support`.
+
+mod support;
+
+use brutalist_twitter::migrations::{self, MigrationError};
+use support::TestDatabase;
+
+#[tokio::test]
+async fn status_does_not_create_the_migrations_table() {
+    let database = match TestDatabase::create().await {
+        Some(database) => database,
+        None => {
+            eprintln!("TEST_DATABASE_URL is not set; skipping");
+            return;
+        }
+    };
+    sqlx::query("DROP TABLE schema_migrations")
+        .execute(&database.pool)
+        .await
+        .unwrap();
+
+    let status = migrations::status(&database.pool).await.unwrap();
+    assert!(status.iter().all(|status| status.applied_at.is_none()));
+
+    let (exists,): (bool,) = sqlx::query_as("SELECT to_regclass('schema_migrations') IS NOT NULL")
+        .fetch_one(&database.pool)
+        .await
+        .unwrap();
+    assert!(!exists);
+}
+
+#[tokio::test]
+async fn down_refuses_a_step_count_that_is_not_a_number() {
+    let database = match TestDatabase::create().await {
+        Some(database) => database,
+        None => {
+            eprintln!("TEST_DATABASE_URL is not set; skipping");
+            return;
+        }
+    };
+
+    let result =
+        migrations::command(&database.pool, &["down".to_string(), "all".to_string()]).await;
+    assert!(matches!(result, Err(MigrationError::InvalidSteps(steps)) if steps == "all"));
+
+    let status = migrations::status(&database.pool).await.unwrap();
+    assert!(status.iter().all(|status| status.applied_at.is_some()));
+}
@@ -0,0 +1,84 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHasher,
+};
+use askama::Template;
+use brutalist_twitter::{
+    controllers::users::verify_password,
+    errors::{guard, panic_message, AppError, ErrorPage},
+};
+use futures::executor::block_on;
+
+#[test]
+fn guard_passes_through_results() {
+    assert_eq!(block_on(guard(async { 42 })).unwrap(), 42);
+}
+
+#[test]
+fn guard_turns_panics_into_internal_errors() {
+    let result = block_on(guard(async {
+        if true {
+            panic!("template exploded");
+        }
+    }));
+
+    match result {
+        Err(AppError::Internal(details)) => assert!(details.contains("template exploded")),
+        other => panic!("expected an internal error, got {:?}", other),
+    }
+}
+
+#[test]
+fn internal_errors_do_not_leak_details() {
+    let error = AppError::Internal("Panicked with secret details".to_string());
+
+    assert_eq!(error.status(), 500);
+    assert!(!error.to_string().contains("secret"));
+}
+
+#[test]
+fn panic_messages_are_read_from_str_and_string_payloads() {
+    let from_str: Box<dyn std::any::Any + Send> = Box::new("static message");
+    let from_string: Box<dyn std::any::Any + Send> = Box::new(format!("formatted {}", 1));
+    let other: Box<dyn std::any::Any + Send> = Box::new(1);
+
+    assert_eq!(panic_message(&*from_str), "static message");
+    assert_eq!(panic_message(&*from_string), "formatted 1");
+    assert_eq!(panic_message(&*other), "a non-string payload");
+}
+
+#[test]
+fn error_page_shows_the_reference() {
+    let page = ErrorPage {
+        user: None,
+        status: 500,
+        title: "Something went wrong",
+        message: "Something went wrong on our end.".to_string(),
+        reference: Some("0b7c2f5e-correlation".to_string()),
+    }
+    .render()
+    .unwrap();
+
+    assert!(page.contains("500: Something went wrong"));
+    assert!(page.contains("0b7c2f5e-correlation"));
+}
+
+#[test]
+fn corrupt_password_hash_is_an_error_not_a_panic() {
+    match verify_password("hunter2", "not a phc string") {
+        Err(AppError::Internal(_)) => (),
+        other => panic!("expected an internal error, got {:?}", other),
+    }
+}
+
+#[test]
+fn valid_password_hash_verifies() {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(b"hunter2", salt.as_ref())
+        .unwrap()
+        .to_string();
+
+    assert!(verify_password("hunter2", &hash).unwrap());
+    assert!(!verify_password("hunter3", &hash).unwrap());
+}
@@ -0,0 +1,116 @@
+//! The rate limit stores, and the limits on routes. The Postgres store and the
+//! routes are skipped without `TEST_DATABASE_URL`; see `support`.
+
+#[macro_use]
+mod support;
+
+use std::time::Duration;
+
+use brutalist_twitter::{
+    config::RateLimitRule,
+    rate_limits::{Limit, MemoryRateLimitStore, PgRateLimitStore, RateLimitStore},
+};
+use reqwest::StatusCode;
+use support::{assert_redirect, assert_status, PASSWORD};
+
+async fn buckets_hold_the_burst_and_refill(store: &dyn RateLimitStore) {
+    // One request every 100ms.
+    let limit = Limit::new(600, 3);
+
+    for remaining in [2, 1, 0] {
+        let decision = store.take("alice", &limit).await.unwrap();
+        assert!(decision.allowed);
+        assert_eq!(decision.limit, 3);
+        assert_eq!(decision.remaining, remaining);
+    }
+
+    let refused = store.take("alice", &limit).await.unwrap();
+    assert!(!refused.allowed);
+    assert_eq!(refused.remaining, 0);
+    assert_eq!(refused.retry_after_secs, 1);
+
+    // Each key has a bucket of its own.
+    assert!(store.take("bob", &limit).await.unwrap().allowed);
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(store.take("alice", &limit).await.unwrap().allowed);
+}
+
+#[tokio::test]
+async fn memory_buckets_hold_the_burst_and_refill() {
+    buckets_hold_the_burst_and_refill(&MemoryRateLimitStore::default()).await;
+}
+
+#[tokio::test]
+async fn postgres_buckets_hold_the_burst_and_refill() {
+    let database = match support::TestDatabase::create().await {
+        Some(database) => database,
+        None => {
+            eprintln!("TEST_DATABASE_URL is not set; skipping");
+            return;
+        }
+    };
+
+    buckets_hold_the_burst_and_refill(&PgRateLimitStore::new(database.pool.clone())).await;
+}
+
+fn rule(route: &str, key: &str, burst: u32) -> RateLimitRule {
+    RateLimitRule {
+        route: route.to_string(),
+        key: key.to_string(),
+        requests_per_minute: 1,
+        burst,
+    }
+}
+
+#[tokio::test]
+async fn signing_in_is_limited_by_ip() {
+    let app = test_app!(|config| {
+        config.rate_limits.enabled = true;
+        config.rate_limits.store = "postgres".to_string();
+        config.rate_limits.rules = vec![rule("POST /sessions", "ip", 2)];
+    });
+    app.signed_up("alice").await;
+    let client = app.client();
+
+    let response = client.sign_in("alice", "wrong password").await;
+    assert_status(&response, StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()["RateLimit-Limit"], "2");
+    assert_eq!(response.headers()["RateLimit-Remaining"], "1");
+    client.sign_in("alice", "wrong password").await;
+
+    // Even with the right password, and from another client on the same IP.
+    let response = app.client().sign_in("alice", PASSWORD).await;
+    assert_status(&response, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["RateLimit-Limit"], "2");
+    assert_eq!(response.headers()["RateLimit-Remaining"], "0");
+    assert!(response.headers().contains_key("RateLimit-Reset"));
+    let retry_after: u64 = response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= 60);
+}
+
+#[tokio::test]
+async fn tweeting_is_limited_by_user() {
+    let app = test_app!(|config| {
+        config.rate_limits.enabled = true;
+        config.rate_limits.rules = vec![rule("POST /tweets", "user", 1)];
+    });
+    let alice = app.signed_up("alice").await;
+    let bob = app.signed_up("bob").await;
+
+    assert_redirect(&alice.post_tweet("First").await, "/");
+    assert_status(
+        &alice.post_tweet("Second").await,
+        StatusCode::TOO_MANY_REQUESTS,
+    );
+    assert_redirect(&bob.post_tweet("Bob's first").await, "/");
+
+    // Other routes fall back to the default limit.
+    let response = alice.get("/").await;
+    assert_status(&response, StatusCode::OK);
+    assert_eq!(response.headers()["RateLimit-Limit"], "20");
+}
@@ -0,0 +1,604 @@
+//! Every route in `app::app`, signed in and not. Skipped without
+//! `TEST_DATABASE_URL`; see `support`.
+
+#[macro_use]
+mod support;
+
+use brutalist_twitter::models::{likes::Like, retweets::Retweet};
+use reqwest::StatusCode;
+use support::{assert_redirect, assert_status, PASSWORD};
+
+#[tokio::test]
+async fn ping_and_health_checks_answer() {
+    let app = test_app!();
+    let client = app.client();
+
+    let response = client.get("/ping").await;
+    assert_status(&response, StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "pong");
+
+    assert_status(&client.get("/healthz").await, StatusCode::OK);
+
+    // The job workers and listeners starting up can briefly take the whole
+    // pool, which is rightly not ready, so this waits them out like a probe.
+    let mut response = client.get("/readyz").await;
+    for _ in 0..50 {
+        if response.status() == StatusCode::OK {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        response = client.get("/readyz").await;
+    }
+    assert_status(&response, StatusCode::OK);
+    let readiness: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(readiness["ready"], true);
+    assert_eq!(readiness["migrations"]["ok"], true);
+}
+
+#[tokio::test]
+async fn metrics_are_exported_and_can_require_a_token() {
+    let app = test_app!();
+    let client = app.client();
+
+    let response = client.get("/metrics").await;
+    assert_status(&response, StatusCode::OK);
+    let exported = response.text().await.unwrap();
+    assert!(exported.contains("bitter_signups_total"));
+    assert!(exported.contains(r#"bitter_cache_lookups_total{cache="sessions",result="hit"}"#));
+
+    let app = support::TestApp::spawn_with(|config| {
+        config.metrics.token = Some("scraper".to_string());
+    })
+    .await
+    .unwrap();
+    let client = app.client();
+
+    assert_status(&client.get("/metrics").await, StatusCode::UNAUTHORIZED);
+    let response = client
+        .client
+        .get(client.url("/metrics"))
+        .bearer_auth("scraper")
+        .send()
+        .await
+        .unwrap();
+    assert_status(&response, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn pages_render_signed_out() {
+    let app = test_app!();
+    let client = app.client();
+
+    for path in ["/", "/signup", "/signin"] {
+        let response = client.get(path).await;
+        assert_status(&response, StatusCode::OK);
+        assert!(response.headers()["Content-Type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+    }
+}
+
+#[tokio::test]
+async fn unknown_paths_are_not_found() {
+    let app = test_app!();
+
+    assert_status(
+        &app.client().get("/no/such/page").await,
+        StatusCode::NOT_FOUND,
+    );
+}
+
+#[tokio::test]
+async fn signing_up_signs_in() {
+    let app = test_app!();
+    let client = app.signed_up("alice").await;
+
+    client.post_tweet("Hello from alice").await;
+
+    let response = client.get("/").await;
+    assert_status(&response, StatusCode::OK);
+    assert!(response.text().await.unwrap().contains("Hello from alice"));
+}
+
+#[tokio::test]
+async fn taken_usernames_are_a_conflict() {
+    let app = test_app!();
+    app.signed_up("alice").await;
+
+    let response = app.client().sign_up("alice", PASSWORD).await;
+    assert_status(&response, StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn signing_in_checks_the_password() {
+    let app = test_app!();
+    app.signed_up("alice").await;
+
+    let client = app.client();
+    assert_status(
+        &client.sign_in("alice", "wrong password").await,
+        StatusCode::UNAUTHORIZED,
+    );
+    assert_status(
+        &client.sign_in("nobody", PASSWORD).await,
+        StatusCode::UNAUTHORIZED,
+    );
+
+    let response = client.sign_in("alice", PASSWORD).await;
+    assert_redirect(&response, "/");
+    assert_redirect(&client.post_tweet("Signed in").await, "/");
+}
+
+#[tokio::test]
+async fn signing_out_revokes_the_session() {
+    let app = test_app!();
+    let client = app.signed_up("alice").await;
+    let (token,): (String,) = sqlx::query_as("SELECT token FROM sessions")
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+
+    assert_redirect(&client.post_form("/signout", &[]).await, "/");
+    assert_status(
+        &client.post_tweet("Signed out").await,
+        StatusCode::UNAUTHORIZED,
+    );
+
+    // A copy of the cookie stops working too.
+    let response = app
+        .client()
+        .client
+        .post(app.client().url("/tweets"))
+        .header("Cookie", format!("Session={}", token))
+        .form(&[("content", "With a copied cookie")])
+        .send()
+        .await
+        .unwrap();
+    assert_status(&response, StatusCode::UNAUTHORIZED);
+
+    // Signing out again, or never having signed in, is fine.
+    assert_redirect(&client.post_form("/signout", &[]).await, "/");
+}
+
+#[tokio::test]
+async fn expired_sessions_are_signed_out() {
+    // Expiring it behind the app's back, which a cached session wouldn't see.
+    let app = test_app!(|config| config.cache.enabled = false);
+    let client = app.signed_up("alice").await;
+
+    sqlx::query("UPDATE sessions SET expires_at = now() - interval '1 minute'")
+        .execute(&app.pool)
+        .await
+        .unwrap();
+
+    assert_status(
+        &client.post_tweet("Expired").await,
+        StatusCode::UNAUTHORIZED,
+    );
+}
+
+#[tokio::test]
+async fn signed_out_visitors_are_turned_away() {
+    let app = test_app!();
+    let author = app.signed_up("alice").await;
+    author.post_tweet("Someone else's tweet").await;
+    let tweet = app.tweet("Someone else's tweet").await;
+    let user = app.user("alice").await;
+
+    let client = app.client();
+    let responses = vec![
+        client.post_tweet("Not signed in").await,
+        client.like(&tweet.id).await,
+        client.retweet(&tweet.id).await,
+        client.get(&format!("/tweets/{}/replies", tweet.id)).await,
+        client.reply(&tweet.id, "Not signed in").await,
+        client.follow(&user.id).await,
+        client.post_form("/api_tokens", &[("name", "cli")]).await,
+        client.get("/webhooks").await,
+        client
+            .post_form(
+                "/webhooks",
+                &[
+                    ("url", "https://example.com/hook"),
+                    ("event_types", "reply"),
+                ],
+            )
+            .await,
+        client.get("/micropub?q=config").await,
+        client
+            .post_form("/micropub", &[("h", "entry"), ("content", "Nope")])
+            .await,
+        client.get("/streaming").await,
+    ];
+
+    for response in responses {
+        assert_status(&response, StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[tokio::test]
+async fn a_bad_session_cookie_is_cleared() {
+    let app = test_app!();
+
+    let response = app
+        .client()
+        .client
+        .get(app.client().url("/"))
+        .header("Cookie", "Session=not-a-session")
+        .send()
+        .await
+        .unwrap();
+
+    assert_status(&response, StatusCode::OK);
+    assert!(response
+        .headers()
+        .get_all("Set-Cookie")
+        .iter()
+        .any(|cookie| cookie.to_str().unwrap().starts_with("Session=;")));
+}
+
+#[tokio::test]
+async fn tweets_replies_likes_and_retweets() {
+    let app = test_app!();
+    let client = app.signed_up("alice").await;
+
+    assert_redirect(&client.post_tweet("First").await, "/");
+    let tweet = app.tweet("First").await;
+
+    assert_status(
+        &client.get(&format!("/tweets/{}", tweet.id)).await,
+        StatusCode::OK,
+    );
+    assert_status(
+        &client.get(&format!("/tweets/{}/replies", tweet.id)).await,
+        StatusCode::OK,
+    );
+    assert_redirect(&client.reply(&tweet.id, "A reply").await, "/");
+    assert_eq!(app.tweet("A reply").await.responding_to, Some(tweet.id));
+
+    assert!(client.like(&tweet.id).await.status().is_redirection());
+    assert!(client.retweet(&tweet.id).await.status().is_redirection());
+
+    let user = app.user("alice").await;
+    let likes: Vec<Like> = sqlx::query_as("SELECT * FROM likes WHERE user_id = $1")
+        .bind(user.id)
+        .fetch_all(&app.pool)
+        .await
+        .unwrap();
+    let retweets: Vec<Retweet> = sqlx::query_as("SELECT * FROM retweets WHERE user_id = $1")
+        .bind(user.id)
+        .fetch_all(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(likes.len(), 1);
+    assert_eq!(retweets.len(), 1);
+}
+
+#[tokio::test]
+async fn missing_tweets_are_not_found() {
+    let app = test_app!();
+    let client = app.signed_up("alice").await;
+    let missing = uuid::Uuid::new_v4();
+
+    assert_status(
+        &client.get(&format!("/tweets/{}", missing)).await,
+        StatusCode::NOT_FOUND,
+    );
+    assert_status(
+        &client.get("/tweets/not-a-uuid").await,
+        StatusCode::NOT_FOUND,
+    );
+    assert_status(&client.like(&missing).await, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn following() {
+    let app = test_app!();
+    let alice = app.signed_up("alice").await;
+    app.signed_up("bob").await;
+    let bob = app.user("bob").await;
+
+    assert!(alice.follow(&bob.id).await.status().is_redirection());
+
+    let alice_id = app.user("alice").await.id;
+    assert_status(&alice.follow(&alice_id).await, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn following_only_redirects_back_to_this_site() {
+    let app = test_app!();
+    let alice = app.signed_up("alice").await;
+
+    let cases = [
+        ("carol", "/users/carol".to_string(), "/users/carol"),
+        (
+            "dave",
+            format!("{}/tweets?page=2", app.base_url),
+            "/tweets?page=2",
+        ),
+        ("erin", "https://evil.example/phish".to_string(), "/"),
+        ("frank", "//evil.example/phish".to_string(), "/"),
+    ];
+    for (username, referer, location) in cases {
+        app.signed_up(username).await;
+        let user_id = app.user(username).await.id.to_string();
+
+        let response = alice
+            .client
+            .post(alice.url("/follows"))
+            .header("Referer", referer)
+            .form(&[("user_id", user_id.as_str())])
+            .send()
+            .await
+            .unwrap();
+        assert_redirect(&response, location);
+    }
+}
+
+#[tokio::test]
+async fn api_tokens_work_for_micropub() {
+    let app = test_app!();
+    let client = app.signed_up("alice").await;
+
+    let response = client.post_form("/api_tokens", &[("name", "cli")]).await;
+    assert_status(&response, StatusCode::CREATED);
+    let token = response.text().await.unwrap();
+
+    // Only its hash is stored.
+    let (stored,): (i64,) = sqlx::query_as("SELECT count(*) FROM api_tokens WHERE token_hash = $1")
+        .bind(&token)
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, 0);
+
+    let response = client
+        .client
+        .get(client.url("/micropub?q=config"))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_status(&response, StatusCode::OK);
+
+    // Tokens in the query string are for websockets only.
+    let response = app
+        .client()
+        .get(&format!("/micropub?q=config&access_token={}", token))
+        .await;
+    assert_status(&response, StatusCode::UNAUTHORIZED);
+
+    let response = app
+        .client()
+        .client
+        .post(client.url("/micropub"))
+        .bearer_auth(&token)
+        .form(&[("h", "entry"), ("content", "Posted over micropub")])
+        .send()
+        .await
+        .unwrap();
+    assert_status(&response, StatusCode::CREATED);
+    let tweet = app.tweet("Posted over micropub").await;
+    assert!(response.headers()["Location"]
+        .to_str()
+        .unwrap()
+        .ends_with(&tweet.id.to_string()));
+
+    // Micropub clients may send the token in the form instead.
+    let response = app
+        .client()
+        .post_form(
+            "/micropub",
+            &[
+                ("h", "entry"),
+                ("content", "Posted with a form token"),
+                ("access_token", &token),
+            ],
+        )
+        .await;
+    assert_status(&response, StatusCode::CREATED);
+    app.tweet("Posted with a form token").await;
+
+    // Posting takes the create scope.
+    let response = client
+        .post_form("/api_tokens", &[("name", "reader"), ("scope", "")])
+        .await;
+    assert_status(&response, StatusCode::CREATED);
+    let read_only = response.text().await.unwrap();
+    let response = app
+        .client()
+        .client
+        .post(client.url("/micropub"))
+        .bearer_auth(&read_only)
+        .form(&[("h", "entry"), ("content", "Not allowed")])
+        .send()
+        .await
+        .unwrap();
+    assert_status(&response, StatusCode::FORBIDDEN);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "insufficient_scope");
+    assert_status(
+        &client
+            .post_form("/api_tokens", &[("name", "admin"), ("scope", "admin")])
+            .await,
+        StatusCode::BAD_REQUEST,
+    );
+
+    // Not a websocket upgrade, but signed in.
+    let response = app
+        .client()
+        .client
+        .get(client.url("/streaming"))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_status(&response, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn webhooks() {
+    let app = test_app!();
+    let client = app.signed_up("alice").await;
+
+    assert_status(&client.get("/webhooks").await, StatusCode::OK);
+
+    let response = client
+        .post_form(
+            "/webhooks",
+            &[
+                ("url", "https://example.com/hook"),
+                ("event_types", "reply"),
+            ],
+        )
+        .await;
+    assert_redirect(&response, "/webhooks");
+
+    let response = client.post_form("/webhooks", &[("url", "not a url")]).await;
+    assert_status(&response, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn webmentions_are_accepted_for_tweets_only() {
+    let app = test_app!();
+    let client = app.signed_up("alice").await;
+    client.post_tweet("Mention me").await;
+    let tweet = app.tweet("Mention me").await;
+
+    let target = client.url(&format!("/tweets/{}", tweet.id));
+    let response = client
+        .post_form(
+            "/webmention",
+            &[("source", "https://example.com/post"), ("target", &target)],
+        )
+        .await;
+    assert_status(&response, StatusCode::ACCEPTED);
+
+    let response = client
+        .post_form(
+            "/webmention",
+            &[
+                ("source", "https://example.com/post"),
+                ("target", "https://example.com/elsewhere"),
+            ],
+        )
+        .await;
+    assert_status(&response, StatusCode::BAD_REQUEST);
+
+    // The tweet's path, on someone else's host.
+    let elsewhere = format!("https://example.com/tweets/{}", tweet.id);
+    let response = client
+        .post_form(
+            "/webmention",
+            &[
+                ("source", "https://example.com/post"),
+                ("target", &elsewhere),
+            ],
+        )
+        .await;
+    assert_status(&response, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn feeds() {
+    let app = test_app!();
+    let client = app.signed_up("alice").await;
+    client.post_tweet("In the feed #rust").await;
+    let user = app.user("alice").await;
+
+    for (extension, content_type) in [
+        ("atom", "application/atom+xml; charset=utf-8"),
+        ("rss", "application/rss+xml; charset=utf-8"),
+        ("json", "application/feed+json; charset=utf-8"),
+    ] {
+        for path in [
+            format!("/feed.{}", extension),
+            format!("/users/{}/feed.{}", user.id, extension),
+            format!("/hashtags/rust/feed.{}", extension),
+        ] {
+            let response = client.get(&path).await;
+            assert_status(&response, StatusCode::OK);
+            assert_eq!(response.headers()["Content-Type"], content_type);
+            assert!(response.headers().contains_key("ETag"));
+            assert!(response.headers().contains_key("Last-Modified"));
+
+            let body = response.text().await.unwrap();
+            assert!(body.contains("In the feed"));
+            // Links come from `server.public_url`, whatever the request said.
+            assert!(body.contains(&app.base_url));
+        }
+    }
+
+    let response = client.get("/feed.atom").await;
+    let etag = response.headers()["ETag"].to_str().unwrap().to_string();
+    let last_modified = response.headers()["Last-Modified"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let conditional_get = |name: &'static str, value: String| {
+        client
+            .client
+            .get(client.url("/feed.atom"))
+            .header(name, value)
+            .send()
+    };
+    assert_status(
+        &conditional_get("If-None-Match", etag).await.unwrap(),
+        StatusCode::NOT_MODIFIED,
+    );
+    assert_status(
+        &conditional_get("If-Modified-Since", last_modified)
+            .await
+            .unwrap(),
+        StatusCode::NOT_MODIFIED,
+    );
+    assert_status(
+        &conditional_get("If-None-Match", "W/\"stale\"".to_string())
+            .await
+            .unwrap(),
+        StatusCode::OK,
+    );
+
+    let response = client
+        .client
+        .get(client.url("/feed.atom"))
+        .header("Host", "evil.example")
+        .send()
+        .await
+        .unwrap();
+    assert!(!response.text().await.unwrap().contains("evil.example"));
+
+    for path in [
+        "/users/not-a-uuid/feed.atom".to_string(),
+        format!("/users/{}/feed.atom", uuid::Uuid::new_v4()),
+    ] {
+        assert_status(&client.get(&path).await, StatusCode::NOT_FOUND);
+    }
+}
+
+#[tokio::test]
+async fn live_events_stream() {
+    let app = test_app!();
+
+    let response = app.client().get("/events").await;
+    assert_status(&response, StatusCode::OK);
+    assert!(response.headers()["Content-Type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/event-stream"));
+}
+
+#[tokio::test]
+async fn turned_off_features_have_no_routes() {
+    let app = test_app!(|config| {
+        config.features.feeds = false;
+        config.features.webhooks = false;
+        config.metrics.enabled = false;
+    });
+    let client = app.signed_up("alice").await;
+
+    for path in ["/feed.atom", "/webhooks", "/metrics"] {
+        assert_status(&client.get(path).await, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,112 @@
+//! The site on an in-memory SQLite database, as for local development. Needs
+//! `--features sqlite`, and no Postgres.
+
+#![cfg(feature = "sqlite")]
+
+mod support;
+
+use std::{net::TcpListener, time::Duration};
+
+use brutalist_twitter::{
+    app,
+    background::Background,
+    config::Config,
+    database::Database,
+    health::Health,
+    server::{self, Shutdown},
+    stores::sqlite,
+};
+use reqwest::{redirect::Policy, Client, StatusCode};
+use support::{assert_redirect, assert_status, TestClient, PASSWORD};
+use tokio::sync::oneshot;
+
+/// Serves the app until the returned sender is dropped.
+async fn spawn() -> (TestClient, oneshot::Sender<()>) {
+    let pool = sqlite::connect("sqlite::memory:").await.unwrap();
+    let database = Database::Sqlite(pool);
+
+    let mut config = Config::default();
+    config.database.url = "sqlite::memory:".to_string();
+    config.metrics.enabled = true;
+    config.rate_limits.enabled = false;
+
+    let health = Health::default();
+    let background = Background::default();
+    let app = app::app(database.clone(), config, health.clone(), background.clone())
+        .await
+        .expect("Could not create the app");
+
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind a port");
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let (stop, stopped) = oneshot::channel();
+    let shutdown = Shutdown {
+        health,
+        background,
+        database,
+        grace: Duration::ZERO,
+        timeout: Duration::from_secs(5),
+        background_timeout: Duration::from_secs(5),
+    };
+    tokio::spawn(async move {
+        let signal = async {
+            let _ = stopped.await;
+        };
+
+        if let Err(e) = server::run(app, listener, signal, shutdown).await {
+            eprintln!("Test server failed: {}", e);
+        }
+    });
+
+    let client = TestClient {
+        base_url,
+        client: Client::builder()
+            .cookie_store(true)
+            .redirect(Policy::none())
+            .build()
+            .unwrap(),
+    };
+
+    (client, stop)
+}
+
+#[tokio::test]
+async fn the_site_runs_without_postgres() {
+    let (client, _stop) = spawn().await;
+
+    assert_redirect(&client.sign_up("alice", PASSWORD).await, "/");
+    assert_redirect(&client.post_tweet("Hello from #sqlite").await, "/");
+
+    let response = client.get("/").await;
+    assert_status(&response, StatusCode::OK);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("Hello from #sqlite"));
+
+    let response = client.get("/hashtags/sqlite/feed.json").await;
+    assert_status(&response, StatusCode::OK);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("Hello from #sqlite"));
+
+    let response = client.get("/readyz").await;
+    assert_status(&response, StatusCode::OK);
+    assert!(!response.text().await.unwrap().contains("migrations"));
+    assert_status(&client.get("/metrics").await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn what_needs_postgres_is_turned_off() {
+    let (client, _stop) = spawn().await;
+    assert_redirect(&client.sign_up("alice", PASSWORD).await, "/");
+
+    for path in ["/events", "/streaming", "/micropub", "/webhooks"] {
+        assert_status(&client.get(path).await, StatusCode::NOT_FOUND);
+    }
+    for path in ["/api_tokens", "/webmention"] {
+        assert_status(&client.post_form(path, &[]).await, StatusCode::NOT_FOUND);
+    }
+}
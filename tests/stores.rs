@@ -0,0 +1,414 @@
+//! The same checks against every `Stores` backend, so that they keep the same
+//! semantics. The Postgres ones are skipped without `TEST_DATABASE_URL`; see
+//! `support`. The SQLite ones need `--features sqlite`.
+
+mod support;
+
+use std::sync::Arc;
+
+use brutalist_twitter::{
+    cache::Caches,
+    config::CacheConfig,
+    metrics::Metrics,
+    models::users::User,
+    stores::{StoreError, Stores},
+};
+use uuid::Uuid;
+
+async fn user(stores: &Stores, username: &str) -> User {
+    stores.users.create_user(username, "hash").await.unwrap()
+}
+
+async fn users_are_unique_regardless_of_case(stores: Stores) {
+    let alice = stores.users.create_user("Alice", "hash").await.unwrap();
+    assert_eq!(alice.username, "alice");
+
+    assert!(matches!(
+        stores.users.create_user("ALICE", "hash").await,
+        Err(StoreError::Conflict)
+    ));
+    assert_eq!(
+        stores
+            .users
+            .get_user_for_username("aLiCe")
+            .await
+            .unwrap()
+            .id,
+        alice.id
+    );
+    assert_eq!(stores.users.get_user(&alice.id).await.unwrap().id, alice.id);
+    assert!(matches!(
+        stores.users.get_user(&Uuid::new_v4()).await,
+        Err(StoreError::NotFound)
+    ));
+    assert!(matches!(
+        stores.users.get_user_for_username("bob").await,
+        Err(StoreError::NotFound)
+    ));
+}
+
+async fn sessions_are_found_by_token(stores: Stores) {
+    let alice = user(&stores, "alice").await;
+
+    let session = stores.sessions.create_session(&alice.id).await.unwrap();
+    let other = stores.sessions.create_session(&alice.id).await.unwrap();
+    assert_ne!(session.token, other.token);
+
+    assert_eq!(session.expires_at, None);
+    assert!(session.has_scope("read") && session.has_scope("write"));
+    assert!(!session.two_factor_verified);
+
+    let found = stores
+        .sessions
+        .get_session_with_user(&session.token)
+        .await
+        .unwrap();
+    assert_eq!(found.session.id, session.id);
+    assert_eq!(found.session.user_id, alice.id);
+    assert_eq!(found.session.scopes, session.scopes);
+    assert_eq!(found.user.id, alice.id);
+    assert_eq!(found.user.username, "alice");
+    assert!(matches!(
+        stores.sessions.get_session_with_user("not-a-token").await,
+        Err(StoreError::NotFound)
+    ));
+    assert!(matches!(
+        stores.sessions.create_session(&Uuid::new_v4()).await,
+        Err(StoreError::MissingReference)
+    ));
+}
+
+async fn revoked_sessions_are_gone(stores: Stores) {
+    let alice = user(&stores, "alice").await;
+    let session = stores.sessions.create_session(&alice.id).await.unwrap();
+    let other = stores.sessions.create_session(&alice.id).await.unwrap();
+    stores
+        .sessions
+        .get_session_with_user(&session.token)
+        .await
+        .unwrap();
+
+    stores
+        .sessions
+        .revoke_session(&session.token)
+        .await
+        .unwrap();
+    assert!(matches!(
+        stores.sessions.get_session_with_user(&session.token).await,
+        Err(StoreError::NotFound)
+    ));
+    assert!(matches!(
+        stores.sessions.revoke_session(&session.token).await,
+        Err(StoreError::NotFound)
+    ));
+    assert_eq!(
+        stores
+            .sessions
+            .get_session_with_user(&other.token)
+            .await
+            .unwrap()
+            .session
+            .id,
+        other.id
+    );
+}
+
+async fn replies_are_counted(stores: Stores) {
+    let alice = user(&stores, "alice").await;
+    let tweets = &stores.tweets;
+
+    let tweet = tweets
+        .create_tweet(&alice.id, None, "First".to_string())
+        .await
+        .unwrap();
+    assert_eq!(tweet.reply_count, 0);
+
+    let reply = tweets
+        .create_tweet(&alice.id, Some(tweet.id), "A reply".to_string())
+        .await
+        .unwrap();
+    assert_eq!(reply.responding_to, Some(tweet.id));
+    assert_eq!(tweets.get_tweet(&tweet.id).await.unwrap().reply_count, 1);
+
+    let replies = tweets.get_replies(&tweet.id, None, None).await.unwrap();
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].id, reply.id);
+
+    assert!(matches!(
+        tweets
+            .create_tweet(&alice.id, Some(Uuid::new_v4()), "Lost".to_string())
+            .await,
+        Err(StoreError::MissingReference)
+    ));
+    assert!(matches!(
+        tweets
+            .create_tweet(&Uuid::new_v4(), None, "Nobody".to_string())
+            .await,
+        Err(StoreError::MissingReference)
+    ));
+    assert!(matches!(
+        tweets.get_tweet(&Uuid::new_v4()).await,
+        Err(StoreError::NotFound)
+    ));
+}
+
+async fn timelines_are_newest_first_without_replies(stores: Stores) {
+    let alice = user(&stores, "alice").await;
+    let bob = user(&stores, "bob").await;
+    let tweets = &stores.tweets;
+
+    let first = tweets
+        .create_tweet(&alice.id, None, "First #Rust".to_string())
+        .await
+        .unwrap();
+    let second = tweets
+        .create_tweet(&bob.id, None, "Second #rust".to_string())
+        .await
+        .unwrap();
+    tweets
+        .create_tweet(&bob.id, Some(first.id), "A reply #rust".to_string())
+        .await
+        .unwrap();
+
+    let recent = tweets.get_recent_tweets(None, None).await.unwrap();
+    let ids: Vec<Uuid> = recent.iter().map(|tweet| tweet.id).collect();
+    assert_eq!(ids, vec![second.id, first.id]);
+    assert_eq!(recent[0].username, "bob");
+
+    let older = tweets
+        .get_recent_tweets(None, Some(second.created_at))
+        .await
+        .unwrap();
+    let ids: Vec<Uuid> = older.iter().map(|tweet| tweet.id).collect();
+    assert_eq!(ids, vec![first.id]);
+
+    let by_bob = tweets
+        .get_recent_tweets_for_author(&bob.id, None, None)
+        .await
+        .unwrap();
+    let ids: Vec<Uuid> = by_bob.iter().map(|tweet| tweet.id).collect();
+    assert_eq!(ids, vec![second.id]);
+
+    let tagged = tweets
+        .get_recent_tweets_for_hashtag("RUST", None, None)
+        .await
+        .unwrap();
+    let ids: Vec<Uuid> = tagged.iter().map(|tweet| tweet.id).collect();
+    assert_eq!(ids.len(), 3);
+    assert!(ids.contains(&first.id) && ids.contains(&second.id));
+}
+
+async fn likes_and_retweets_are_counted_once(stores: Stores) {
+    let alice = user(&stores, "alice").await;
+    let bob = user(&stores, "bob").await;
+    let social_graph = &stores.social_graph;
+
+    let tweet = stores
+        .tweets
+        .create_tweet(&alice.id, None, "Like me".to_string())
+        .await
+        .unwrap();
+
+    social_graph.like(&tweet.id, &bob.id).await.unwrap();
+    social_graph.retweet(&tweet.id, &bob.id).await.unwrap();
+    assert!(matches!(
+        social_graph.like(&tweet.id, &bob.id).await,
+        Err(StoreError::Conflict)
+    ));
+    assert!(matches!(
+        social_graph.retweet(&tweet.id, &bob.id).await,
+        Err(StoreError::Conflict)
+    ));
+    assert!(matches!(
+        social_graph.like(&Uuid::new_v4(), &bob.id).await,
+        Err(StoreError::MissingReference)
+    ));
+    assert!(matches!(
+        social_graph.retweet(&Uuid::new_v4(), &bob.id).await,
+        Err(StoreError::MissingReference)
+    ));
+
+    let counted = stores.tweets.get_tweet(&tweet.id).await.unwrap();
+    assert_eq!(counted.like_count, 1);
+    assert_eq!(counted.retweet_count, 1);
+
+    let seen_by_bob = stores
+        .tweets
+        .get_tweet_with_user_info(&tweet.id, Some(&bob.id))
+        .await
+        .unwrap();
+    assert!(seen_by_bob.user_has_liked);
+    assert!(seen_by_bob.user_has_retweeted);
+
+    let seen_by_alice = stores
+        .tweets
+        .get_tweet_with_user_info(&tweet.id, Some(&alice.id))
+        .await
+        .unwrap();
+    assert!(!seen_by_alice.user_has_liked);
+    assert!(!seen_by_alice.user_has_retweeted);
+}
+
+async fn deletes_take_the_counts_back_down(stores: Stores) {
+    let alice = user(&stores, "alice").await;
+    let bob = user(&stores, "bob").await;
+    let social_graph = &stores.social_graph;
+
+    let tweet = stores
+        .tweets
+        .create_tweet(&alice.id, None, "Changed my mind".to_string())
+        .await
+        .unwrap();
+    let reply = stores
+        .tweets
+        .create_tweet(&bob.id, Some(tweet.id), "A reply".to_string())
+        .await
+        .unwrap();
+    social_graph.like(&tweet.id, &bob.id).await.unwrap();
+    social_graph.retweet(&tweet.id, &bob.id).await.unwrap();
+
+    social_graph.unlike(&tweet.id, &bob.id).await.unwrap();
+    social_graph.unretweet(&tweet.id, &bob.id).await.unwrap();
+    assert!(matches!(
+        social_graph.unlike(&tweet.id, &bob.id).await,
+        Err(StoreError::NotFound)
+    ));
+    assert!(matches!(
+        social_graph.unretweet(&tweet.id, &bob.id).await,
+        Err(StoreError::NotFound)
+    ));
+
+    assert!(matches!(
+        stores.tweets.delete_tweet(&reply.id, &alice.id).await,
+        Err(StoreError::NotFound)
+    ));
+    stores
+        .tweets
+        .delete_tweet(&reply.id, &bob.id)
+        .await
+        .unwrap();
+    assert!(matches!(
+        stores.tweets.get_tweet(&reply.id).await,
+        Err(StoreError::NotFound)
+    ));
+
+    let counted = stores.tweets.get_tweet(&tweet.id).await.unwrap();
+    assert_eq!(counted.like_count, 0);
+    assert_eq!(counted.retweet_count, 0);
+    assert_eq!(counted.reply_count, 0);
+
+    // Liking again works once the like is gone.
+    social_graph.like(&tweet.id, &bob.id).await.unwrap();
+}
+
+async fn follows_are_unique_and_not_of_yourself(stores: Stores) {
+    let alice = user(&stores, "alice").await;
+    let bob = user(&stores, "bob").await;
+    let carol = user(&stores, "carol").await;
+    let social_graph = &stores.social_graph;
+
+    social_graph.follow(&alice.id, &bob.id).await.unwrap();
+    social_graph.follow(&carol.id, &bob.id).await.unwrap();
+    social_graph.follow(&alice.id, &carol.id).await.unwrap();
+
+    assert!(matches!(
+        social_graph.follow(&alice.id, &bob.id).await,
+        Err(StoreError::Conflict)
+    ));
+    assert!(matches!(
+        social_graph.follow(&alice.id, &alice.id).await,
+        Err(StoreError::Invalid)
+    ));
+    assert!(matches!(
+        social_graph.follow(&alice.id, &Uuid::new_v4()).await,
+        Err(StoreError::MissingReference)
+    ));
+
+    assert_eq!(social_graph.get_follower_count(&bob.id).await.unwrap(), 2);
+    assert_eq!(social_graph.get_follower_count(&alice.id).await.unwrap(), 0);
+
+    let mut following = social_graph.get_following_ids(&alice.id).await.unwrap();
+    following.sort();
+    let mut expected = vec![bob.id, carol.id];
+    expected.sort();
+    assert_eq!(following, expected);
+}
+
+/// In front of memory stores, which are not told about changes any other way,
+/// so that it is what the cache drops itself that is checked.
+fn cached() -> Stores {
+    let metrics = Metrics::new(1).unwrap();
+    let caches = Arc::new(Caches::new(&CacheConfig::default(), &metrics));
+
+    Stores::cached(Stores::memory(), caches)
+}
+
+/// A test per check for each backend, each on stores of its own.
+macro_rules! conformance {
+    ($($check:ident),* $(,)?) => {
+        mod memory {
+            use super::*;
+
+            $(
+                #[tokio::test]
+                async fn $check() {
+                    super::$check(Stores::memory()).await;
+                }
+            )*
+        }
+
+        mod cached {
+            $(
+                #[tokio::test]
+                async fn $check() {
+                    super::$check(super::cached()).await;
+                }
+            )*
+        }
+
+        mod postgres {
+            use super::*;
+
+            $(
+                #[tokio::test]
+                async fn $check() {
+                    let database = match support::TestDatabase::create().await {
+                        Some(database) => database,
+                        None => {
+                            eprintln!("TEST_DATABASE_URL is not set; skipping");
+                            return;
+                        }
+                    };
+
+                    super::$check(Stores::postgres(database.pool.clone())).await;
+                }
+            )*
+        }
+
+        #[cfg(feature = "sqlite")]
+        mod sqlite {
+            use super::*;
+            use brutalist_twitter::stores::sqlite;
+
+            $(
+                #[tokio::test]
+                async fn $check() {
+                    let pool = sqlite::connect("sqlite::memory:").await.unwrap();
+
+                    super::$check(Stores::sqlite(pool)).await;
+                }
+            )*
+        }
+    };
+}
+
+conformance!(
+    users_are_unique_regardless_of_case,
+    sessions_are_found_by_token,
+    revoked_sessions_are_gone,
+    replies_are_counted,
+    timelines_are_newest_first_without_replies,
+    likes_and_retweets_are_counted_once,
+    deletes_take_the_counts_back_down,
+    follows_are_unique_and_not_of_yourself,
+);
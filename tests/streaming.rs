@@ -0,0 +1,99 @@
+//! The WebSocket streaming API. Skipped without `TEST_DATABASE_URL`; see
+//! `support`.
+
+#[macro_use]
+mod support;
+
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use support::{assert_redirect, assert_status, TestClient};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn api_token(client: &TestClient) -> String {
+    let response = client.post_form("/api_tokens", &[("name", "stream")]).await;
+    assert_status(&response, StatusCode::CREATED);
+
+    response.text().await.unwrap()
+}
+
+async fn connect(client: &TestClient, query: &str) -> Socket {
+    let url = client
+        .url(&format!("/streaming?{}", query))
+        .replacen("http", "ws", 1);
+    let (socket, _) = connect_async(url).await.expect("Could not connect");
+
+    socket
+}
+
+/// The next JSON message, skipping pings.
+async fn next_message(socket: &mut Socket) -> Value {
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("Timed out waiting for a message")
+            .expect("The socket closed")
+            .unwrap();
+
+        if let Message::Text(text) = message {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+async fn subscribe_to_timeline(socket: &mut Socket) {
+    let subscribe = json!({ "type": "subscribe", "streams": [{ "stream": "timeline" }] });
+    socket
+        .send(Message::Text(subscribe.to_string()))
+        .await
+        .unwrap();
+
+    assert_eq!(next_message(socket).await["type"], "subscribed");
+}
+
+/// The next new tweet's event id and tweet id, skipping other messages.
+async fn next_tweet(socket: &mut Socket) -> (String, String) {
+    loop {
+        let message = next_message(socket).await;
+
+        if message["type"] == "event" && message["event"]["type"] == "tweet_created" {
+            return (
+                message["id"].as_str().unwrap().to_string(),
+                message["event"]["tweet_id"].as_str().unwrap().to_string(),
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn reconnecting_resumes_from_the_last_event() {
+    let app = test_app!();
+    let alice = app.signed_up("alice").await;
+    let token = api_token(&alice).await;
+
+    let mut socket = connect(&alice, &format!("access_token={}", token)).await;
+    subscribe_to_timeline(&mut socket).await;
+    assert_redirect(&alice.post_tweet("Seen live").await, "/");
+    let (last_event_id, tweet_id) = next_tweet(&mut socket).await;
+    assert_eq!(tweet_id, app.tweet("Seen live").await.id.to_string());
+    socket.close(None).await.unwrap();
+
+    assert_redirect(&alice.post_tweet("Missed").await, "/");
+    // Long enough for the event to come back over LISTEN/NOTIFY, so that it is
+    // among those resumed rather than live.
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut socket = connect(
+        &alice,
+        &format!("access_token={}&last_event_id={}", token, last_event_id),
+    )
+    .await;
+    subscribe_to_timeline(&mut socket).await;
+    let (_, tweet_id) = next_tweet(&mut socket).await;
+    assert_eq!(tweet_id, app.tweet("Missed").await.id.to_string());
+}
@@ -0,0 +1,354 @@
+//! Runs the app from `app::app` on a local port against a schema of its own,
+//! so that tests can run side by side on one database.
+//!
+//! Set `TEST_DATABASE_URL` to a database the tests may create schemas in.
+//! Without it, `TestApp::spawn` returns `None` and the tests skip themselves.
+
+#![allow(dead_code, unused_macros)]
+
+use std::{net::TcpListener, str::FromStr, time::Duration};
+
+use brutalist_twitter::{
+    app,
+    background::Background,
+    config::Config,
+    database::Database,
+    health::Health,
+    migrations,
+    models::{tweets::Tweet, users::User},
+    server::{self, Shutdown},
+};
+use reqwest::{redirect::Policy, Client, Response, StatusCode};
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    Executor, Pool, Postgres,
+};
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+pub const PASSWORD: &str = "correct horse battery staple";
+
+/// Returns early from a test when there's no database to run it against. Needs
+/// `#[macro_use] mod support;`. Takes an optional closure to change the config,
+/// as `TestApp::spawn_with` does.
+macro_rules! test_app {
+    () => {
+        test_app!(|_| ())
+    };
+    ($configure:expr) => {
+        match support::TestApp::spawn_with($configure).await {
+            Some(app) => app,
+            None => {
+                eprintln!("TEST_DATABASE_URL is not set; skipping");
+                return;
+            }
+        }
+    };
+}
+
+/// A schema of its own on the database at `TEST_DATABASE_URL`, migrated, and
+/// dropped along with this.
+pub struct TestDatabase {
+    pub pool: Pool<Postgres>,
+    database_url: String,
+    schema: String,
+}
+
+impl TestDatabase {
+    pub async fn create() -> Option<TestDatabase> {
+        let database_url = std::env::var("TEST_DATABASE_URL").ok()?;
+        let schema = format!("test_{}", Uuid::new_v4().simple());
+
+        let admin = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&database_url)
+            .await
+            .expect("Could not connect to TEST_DATABASE_URL");
+        admin
+            .execute(format!("CREATE SCHEMA {}", schema).as_str())
+            .await
+            .expect("Could not create the test schema");
+        admin.close().await;
+
+        // Extensions such as uuid-ossp may already live in public.
+        let search_path = format!("SET search_path TO {}, public", schema);
+        // Named after the schema, so that dropping it can find them.
+        let options = PgConnectOptions::from_str(&database_url)
+            .expect("Could not parse TEST_DATABASE_URL")
+            .application_name(&schema);
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .after_connect(move |connection, _| {
+                let search_path = search_path.clone();
+
+                Box::pin(async move {
+                    connection.execute(search_path.as_str()).await?;
+
+                    Ok(())
+                })
+            })
+            .connect_with(options)
+            .await
+            .expect("Could not connect to the test schema");
+
+        migrations::run(&pool)
+            .await
+            .expect("Could not migrate the test schema");
+
+        Some(TestDatabase {
+            pool,
+            database_url,
+            schema,
+        })
+    }
+}
+
+/// Drops the schema. This runs on a thread of its own, as there is no runtime
+/// to await on while dropping. The pool's connections are closed first: one
+/// still waiting on its task to roll a transaction back would otherwise hold
+/// locks the drop waits on, while blocking that task's runtime.
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        let database_url = self.database_url.clone();
+        let schema = self.schema.clone();
+        let _ = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            runtime.block_on(async {
+                if let Ok(admin) = PgPoolOptions::new()
+                    .max_connections(1)
+                    .connect(&database_url)
+                    .await
+                {
+                    let _ = sqlx::query(
+                        "
+                        SELECT pg_terminate_backend(pid)
+                        FROM pg_stat_activity
+                        WHERE application_name = $1",
+                    )
+                    .bind(&schema)
+                    .execute(&admin)
+                    .await;
+                    let _ = admin
+                        .execute(format!("DROP SCHEMA IF EXISTS {} CASCADE", schema).as_str())
+                        .await;
+                    admin.close().await;
+                }
+            });
+        })
+        .join();
+    }
+}
+
+pub struct TestApp {
+    pub base_url: String,
+    /// Connected to the test's schema, for setting up and checking state
+    /// behind the app's back.
+    pub pool: Pool<Postgres>,
+    stop: Option<oneshot::Sender<()>>,
+    /// Dropped after the server has been told to stop.
+    _database: TestDatabase,
+}
+
+impl TestApp {
+    pub async fn spawn() -> Option<TestApp> {
+        TestApp::spawn_with(|_| ()).await
+    }
+
+    /// Like `spawn`, with a chance to change the config first, e.g. to turn a
+    /// feature off.
+    pub async fn spawn_with(configure: impl FnOnce(&mut Config)) -> Option<TestApp> {
+        let database = TestDatabase::create().await?;
+        let pool = database.pool.clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind a port");
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+        let mut config = Config::default();
+        config.database.url = database.database_url.clone();
+        config.server.public_url = base_url.clone();
+        config.metrics.enabled = true;
+        // Every client is on 127.0.0.1, so only tests of the limits want them.
+        config.rate_limits.enabled = false;
+        configure(&mut config);
+
+        let health = Health::default();
+        let background = Background::default();
+        let app = app::app(
+            Database::Postgres(pool.clone()),
+            config,
+            health.clone(),
+            background.clone(),
+        )
+        .await
+        .expect("Could not create the app");
+
+        let (stop, stopped) = oneshot::channel();
+        let shutdown = Shutdown {
+            health,
+            background,
+            database: Database::Postgres(pool.clone()),
+            grace: Duration::ZERO,
+            timeout: Duration::from_secs(5),
+            background_timeout: Duration::from_secs(5),
+        };
+        tokio::spawn(async move {
+            let signal = async {
+                let _ = stopped.await;
+            };
+
+            if let Err(e) = server::run(app, listener, signal, shutdown).await {
+                eprintln!("Test server failed: {}", e);
+            }
+        });
+
+        Some(TestApp {
+            base_url,
+            pool,
+            stop: Some(stop),
+            _database: database,
+        })
+    }
+
+    /// A client with a cookie jar of its own, so each one is a separate
+    /// visitor.
+    pub fn client(&self) -> TestClient {
+        TestClient {
+            base_url: self.base_url.clone(),
+            client: Client::builder()
+                .cookie_store(true)
+                .redirect(Policy::none())
+                .build()
+                .unwrap(),
+        }
+    }
+
+    /// A client that has signed up as `username`, and so is signed in.
+    pub async fn signed_up(&self, username: &str) -> TestClient {
+        let client = self.client();
+
+        let response = client.sign_up(username, PASSWORD).await;
+        assert_redirect(&response, "/");
+
+        client
+    }
+
+    pub async fn user(&self, username: &str) -> User {
+        User::get_user_for_username(&self.pool, username)
+            .await
+            .unwrap()
+    }
+
+    /// The newest tweet with `content`.
+    pub async fn tweet(&self, content: &str) -> Tweet {
+        let (id,): (Uuid,) = sqlx::query_as(
+            "
+            SELECT id
+            FROM tweets
+            WHERE content = $1
+            ORDER BY created_at DESC
+            LIMIT 1",
+        )
+        .bind(content)
+        .fetch_one(&self.pool)
+        .await
+        .unwrap();
+
+        Tweet::get_tweet_for_id(&self.pool, &id).await.unwrap()
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+pub struct TestClient {
+    pub base_url: String,
+    pub client: Client,
+}
+
+impl TestClient {
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    pub async fn get(&self, path: &str) -> Response {
+        self.client.get(self.url(path)).send().await.unwrap()
+    }
+
+    /// Posts a form, as the pages do.
+    pub async fn post_form(&self, path: &str, form: &[(&str, &str)]) -> Response {
+        self.client
+            .post(self.url(path))
+            .form(form)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    pub async fn sign_up(&self, username: &str, password: &str) -> Response {
+        self.post_form("/users", &[("username", username), ("password", password)])
+            .await
+    }
+
+    pub async fn sign_in(&self, username: &str, password: &str) -> Response {
+        self.post_form(
+            "/sessions",
+            &[("username", username), ("password", password)],
+        )
+        .await
+    }
+
+    pub async fn post_tweet(&self, content: &str) -> Response {
+        self.post_form("/tweets", &[("content", content)]).await
+    }
+
+    pub async fn reply(&self, tweet_id: &Uuid, content: &str) -> Response {
+        self.post_form(
+            &format!("/tweets/{}/replies", tweet_id),
+            &[("content", content)],
+        )
+        .await
+    }
+
+    pub async fn like(&self, tweet_id: &Uuid) -> Response {
+        self.post_form(&format!("/tweets/{}/likes", tweet_id), &[])
+            .await
+    }
+
+    pub async fn retweet(&self, tweet_id: &Uuid) -> Response {
+        self.post_form(&format!("/tweets/{}/retweets", tweet_id), &[])
+            .await
+    }
+
+    pub async fn follow(&self, user_id: &Uuid) -> Response {
+        self.post_form("/follows", &[("user_id", &user_id.to_string())])
+            .await
+    }
+}
+
+pub fn assert_redirect(response: &Response, location: &str) {
+    assert!(
+        response.status().is_redirection(),
+        "expected a redirect, got {}",
+        response.status()
+    );
+    assert_eq!(
+        response
+            .headers()
+            .get("Location")
+            .and_then(|location| location.to_str().ok()),
+        Some(location)
+    );
+}
+
+pub fn assert_status(response: &Response, status: StatusCode) {
+    assert_eq!(response.status(), status, "for {}", response.url());
+}
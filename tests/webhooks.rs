@@ -0,0 +1,360 @@
+//! Webhook deliveries to a receiver on a local port. Skipped without
+//! `TEST_DATABASE_URL`; see `support`.
+
+#[macro_use]
+mod support;
+
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use brutalist_twitter::{
+    config::OutboundConfig,
+    models::webhooks::{Webhook, WebhookDelivery, MAX_CONSECUTIVE_FAILURES},
+    outbound::OutboundClient,
+    webhooks::{self, Outcome, Retries},
+};
+use chrono::Utc;
+use hyper::{
+    header::HeaderMap,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use support::{assert_redirect, assert_status, TestApp};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+struct Received {
+    headers: HeaderMap,
+    body: String,
+}
+
+#[derive(Default)]
+struct State {
+    received: Vec<Received>,
+    /// Answered in turn, then 200s.
+    statuses: VecDeque<StatusCode>,
+}
+
+/// Records what it's sent, and answers with the statuses it's told to.
+struct Receiver {
+    url: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl Receiver {
+    fn spawn() -> Receiver {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let state = state.clone();
+
+                    async move {
+                        let (parts, body) = request.into_parts();
+                        let body = hyper::body::to_bytes(body).await.unwrap();
+
+                        let mut state = state.lock().unwrap();
+                        state.received.push(Received {
+                            headers: parts.headers,
+                            body: String::from_utf8(body.to_vec()).unwrap(),
+                        });
+                        let status = state.statuses.pop_front().unwrap_or(StatusCode::OK);
+
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_service));
+
+        Receiver { url, state }
+    }
+
+    fn respond_with(&self, statuses: &[StatusCode]) {
+        self.state.lock().unwrap().statuses.extend(statuses);
+    }
+
+    fn received(&self) -> usize {
+        self.state.lock().unwrap().received.len()
+    }
+
+    async fn wait_for(&self, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while self.received() < count {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for deliveries"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+}
+
+async fn webhook(app: &TestApp, username: &str) -> Webhook {
+    let user = app.user(username).await;
+
+    Webhook::get_webhooks_for_user(&app.pool, &user.id)
+        .await
+        .unwrap()
+        .remove(0)
+}
+
+async fn delivery(app: &TestApp, webhook: &Webhook) -> WebhookDelivery {
+    WebhookDelivery::claim_delivery(&app.pool, &webhook.id, &Uuid::new_v4(), "like", "{}")
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+fn fast_retries(max_attempts: u32) -> Retries {
+    Retries {
+        max_attempts,
+        initial_backoff: Duration::from_millis(50),
+    }
+}
+
+/// A client that may reach receivers on this machine.
+fn local_client() -> OutboundClient {
+    webhooks::client(&OutboundConfig {
+        allow_private_addresses: true,
+    })
+    .unwrap()
+}
+
+/// Makes attempts at `delivery` until there are no more to make.
+async fn deliver_until_done(
+    app: &TestApp,
+    client: &OutboundClient,
+    hook: &Webhook,
+    delivery: &WebhookDelivery,
+    retries: &Retries,
+) -> Vec<Outcome> {
+    let mut outcomes = vec![];
+
+    loop {
+        let delivery = WebhookDelivery::get_delivery(&app.pool, &delivery.id)
+            .await
+            .unwrap();
+        let outcome = webhooks::deliver(&app.pool, client, hook, &delivery, retries)
+            .await
+            .unwrap();
+        let done = !matches!(outcome, Outcome::RetryAt(_));
+        outcomes.push(outcome);
+
+        if done {
+            return outcomes;
+        }
+    }
+}
+
+#[tokio::test]
+async fn likes_are_delivered_signed() {
+    let app = test_app!(|config| config.outbound.allow_private_addresses = true);
+    let receiver = Receiver::spawn();
+    let alice = app.signed_up("alice").await;
+    let bob = app.signed_up("bob").await;
+
+    let response = bob
+        .post_form(
+            "/webhooks",
+            &[("url", receiver.url.as_str()), ("event_types", "like")],
+        )
+        .await;
+    assert_redirect(&response, "/webhooks");
+    let secret = webhook(&app, "bob").await.secret;
+
+    bob.post_tweet("Like this").await;
+    alice.like(&app.tweet("Like this").await.id).await;
+    receiver.wait_for(1).await;
+
+    let state = receiver.state.lock().unwrap();
+    let received = &state.received[0];
+    let header = |name: &str| received.headers[name].to_str().unwrap().to_string();
+    assert_eq!(header("X-Bitter-Event"), "like");
+    let timestamp: i64 = header("X-Bitter-Timestamp").parse().unwrap();
+    assert_eq!(
+        header("X-Bitter-Signature"),
+        webhooks::signature(&secret, timestamp, &received.body)
+    );
+    // Signed with another secret, it would not match.
+    assert_ne!(
+        header("X-Bitter-Signature"),
+        webhooks::signature("not the secret", timestamp, &received.body)
+    );
+}
+
+#[tokio::test]
+async fn retries_are_queued_as_jobs() {
+    let app = test_app!(|config| config.outbound.allow_private_addresses = true);
+    let receiver = Receiver::spawn();
+    let alice = app.signed_up("alice").await;
+    let bob = app.signed_up("bob").await;
+
+    bob.post_form(
+        "/webhooks",
+        &[("url", receiver.url.as_str()), ("event_types", "like")],
+    )
+    .await;
+    receiver.respond_with(&[StatusCode::INTERNAL_SERVER_ERROR]);
+    bob.post_tweet("Like this").await;
+    alice.like(&app.tweet("Like this").await.id).await;
+    receiver.wait_for(1).await;
+
+    // The retry is in the database, so it outlives this process.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let (queued,): (i64,) = sqlx::query_as(
+            "SELECT count(*) FROM jobs WHERE kind = 'deliver_webhook' AND status = 'queued'",
+        )
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+        if queued == 1 {
+            break;
+        }
+        assert!(Instant::now() < deadline, "Timed out waiting for the retry");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let delivery = WebhookDelivery::get_recent_deliveries_for_webhook(
+        &app.pool,
+        &webhook(&app, "bob").await.id,
+    )
+    .await
+    .unwrap()
+    .remove(0);
+    assert_eq!(delivery.attempts, 1);
+    assert!(delivery.next_attempt_at.unwrap() > delivery.created_at);
+}
+
+#[tokio::test]
+async fn failed_deliveries_are_retried_with_backoff() {
+    let app = test_app!();
+    let receiver = Receiver::spawn();
+    app.signed_up("alice").await;
+    let client = local_client();
+
+    let user = app.user("alice").await;
+    Webhook::create_webhook(&app.pool, &user.id, &receiver.url, &["like".to_string()])
+        .await
+        .unwrap();
+    let hook = webhook(&app, "alice").await;
+
+    // Two failures, then a success.
+    receiver.respond_with(&[
+        StatusCode::INTERNAL_SERVER_ERROR,
+        StatusCode::SERVICE_UNAVAILABLE,
+    ]);
+    let first = delivery(&app, &hook).await;
+    let started = Utc::now();
+    let outcomes = deliver_until_done(&app, &client, &hook, &first, &fast_retries(6)).await;
+    assert_eq!(receiver.received(), 3);
+    assert_eq!(outcomes.len(), 3);
+    assert_eq!(outcomes[2], Outcome::Delivered);
+    match (&outcomes[0], &outcomes[1]) {
+        (Outcome::RetryAt(first_retry), Outcome::RetryAt(second_retry)) => {
+            assert!(*first_retry - started >= chrono::Duration::milliseconds(50));
+            assert!(*second_retry - started >= chrono::Duration::milliseconds(100));
+        }
+        outcomes => panic!("Expected retries, got {:?}", outcomes),
+    }
+
+    let recorded = WebhookDelivery::get_recent_deliveries_for_webhook(&app.pool, &hook.id)
+        .await
+        .unwrap()
+        .remove(0);
+    assert_eq!(recorded.attempts, 3);
+    assert_eq!(recorded.status_code, Some(200));
+    assert!(recorded.delivered_at.is_some());
+    assert!(recorded.next_attempt_at.is_none());
+    assert_eq!(webhook(&app, "alice").await.consecutive_failures, 0);
+
+    // Out of attempts, it counts as one failure.
+    receiver.respond_with(&[StatusCode::INTERNAL_SERVER_ERROR; 2]);
+    let second = delivery(&app, &hook).await;
+    let outcomes = deliver_until_done(&app, &client, &hook, &second, &fast_retries(2)).await;
+    assert_eq!(outcomes.last(), Some(&Outcome::GaveUp));
+    assert_eq!(receiver.received(), 5);
+    assert_eq!(webhook(&app, "alice").await.consecutive_failures, 1);
+}
+
+#[tokio::test]
+async fn repeated_failures_disable_the_webhook() {
+    let app = test_app!();
+    let receiver = Receiver::spawn();
+    app.signed_up("alice").await;
+    let client = local_client();
+
+    let user = app.user("alice").await;
+    Webhook::create_webhook(&app.pool, &user.id, &receiver.url, &["like".to_string()])
+        .await
+        .unwrap();
+    let hook = webhook(&app, "alice").await;
+
+    for failures in 1..=MAX_CONSECUTIVE_FAILURES {
+        assert!(webhook(&app, "alice").await.disabled_at.is_none());
+
+        receiver.respond_with(&[StatusCode::INTERNAL_SERVER_ERROR]);
+        let delivery = delivery(&app, &hook).await;
+        webhooks::deliver(&app.pool, &client, &hook, &delivery, &fast_retries(1))
+            .await
+            .unwrap();
+
+        assert_eq!(webhook(&app, "alice").await.consecutive_failures, failures);
+    }
+
+    assert!(webhook(&app, "alice").await.disabled_at.is_some());
+}
+
+#[tokio::test]
+async fn private_addresses_are_refused() {
+    let app = test_app!();
+    let receiver = Receiver::spawn();
+    let alice = app.signed_up("alice").await;
+
+    for url in [
+        receiver.url.as_str(),
+        "http://169.254.169.254/latest/meta-data/",
+        "http://[::1]/hook",
+        "http://10.0.0.1/hook",
+    ] {
+        let response = alice
+            .post_form("/webhooks", &[("url", url), ("event_types", "like")])
+            .await;
+        assert_status(&response, StatusCode::BAD_REQUEST);
+    }
+
+    // One registered before, or by a name resolving to a private address, is
+    // refused when delivering.
+    let user = app.user("alice").await;
+    let local_url = receiver.url.replace("127.0.0.1", "localhost");
+    Webhook::create_webhook(&app.pool, &user.id, &local_url, &["like".to_string()])
+        .await
+        .unwrap();
+    let hook = webhook(&app, "alice").await;
+    let client = webhooks::client(&OutboundConfig::default()).unwrap();
+    let delivery = delivery(&app, &hook).await;
+    let outcome = webhooks::deliver(&app.pool, &client, &hook, &delivery, &fast_retries(1))
+        .await
+        .unwrap();
+
+    assert_eq!(outcome, Outcome::GaveUp);
+    assert_eq!(receiver.received(), 0);
+}
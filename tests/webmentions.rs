@@ -0,0 +1,176 @@
+//! Sending and verifying webmentions through a fake `Fetcher`. Verification
+//! is skipped without `TEST_DATABASE_URL`; see `support`.
+
+#[macro_use]
+mod support;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use brutalist_twitter::{
+    background::Background,
+    config::OutboundConfig,
+    models::webmentions::{Webmention, PENDING, REJECTED, VERIFIED},
+    webmentions::{links, FetchError, FetchedPage, Fetcher, ReqwestFetcher, Webmentions},
+};
+use support::TestApp;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// Serves the pages it's given, and fails to fetch anything else.
+#[derive(Default)]
+struct FakeFetcher {
+    pages: Mutex<HashMap<String, (u16, String)>>,
+}
+
+impl FakeFetcher {
+    fn serve(&self, url: &str, status: u16, body: &str) {
+        self.pages
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), (status, body.to_string()));
+    }
+}
+
+#[async_trait]
+impl Fetcher for FakeFetcher {
+    async fn get(&self, url: &str) -> Result<FetchedPage, FetchError> {
+        let (status, body) = self
+            .pages
+            .lock()
+            .unwrap()
+            .get(url)
+            .cloned()
+            .ok_or_else(|| format!("Could not connect to {}", url))?;
+
+        Ok(FetchedPage {
+            url: url.to_string(),
+            status,
+            link_headers: vec![],
+            body,
+        })
+    }
+
+    async fn post_form(&self, _url: &str, _form: &[(&str, &str)]) -> Result<u16, FetchError> {
+        Ok(202)
+    }
+}
+
+/// Queues the webmention for verification, and waits for the outcome.
+async fn verified(
+    app: &TestApp,
+    webmentions: &Webmentions,
+    tweet_id: &Uuid,
+    source: &str,
+    target: &str,
+) -> Webmention {
+    let webmention = Webmention::create_webmention(&app.pool, tweet_id, source, target)
+        .await
+        .unwrap();
+    webmentions.verify_later(webmention.id);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let webmention = Webmention::get_webmention_for_id(&app.pool, &webmention.id)
+            .await
+            .unwrap();
+        if webmention.status != PENDING {
+            return webmention;
+        }
+
+        assert!(Instant::now() < deadline, "Timed out verifying {}", source);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[test]
+fn links_are_found_once_each_in_order() {
+    assert_eq!(
+        links("See https://a.example/one, https://b.example and https://a.example/one."),
+        vec!["https://a.example/one", "https://b.example"]
+    );
+}
+
+#[tokio::test]
+async fn the_real_fetcher_refuses_private_addresses() {
+    let fetcher = ReqwestFetcher::new(&OutboundConfig::default()).unwrap();
+
+    for url in [
+        "http://127.0.0.1:1/",
+        "http://169.254.169.254/latest/meta-data/",
+        "http://[::1]/",
+        "http://192.168.1.1/",
+        "http://localhost:1/",
+        "file:///etc/passwd",
+    ] {
+        assert!(fetcher.get(url).await.is_err(), "{} was fetched", url);
+        assert!(
+            fetcher.post_form(url, &[]).await.is_err(),
+            "{} was posted to",
+            url
+        );
+    }
+}
+
+#[tokio::test]
+async fn sources_are_verified_against_the_target() {
+    let app = test_app!();
+    let client = app.signed_up("alice").await;
+    client.post_tweet("Mention me").await;
+    let tweet = app.tweet("Mention me").await;
+    let target = client.url(&format!("/tweets/{}", tweet.id));
+
+    let fetcher = Arc::new(FakeFetcher::default());
+    let webmentions = Webmentions::spawn(
+        app.pool.clone(),
+        fetcher.clone(),
+        false,
+        Background::default(),
+    );
+
+    let source = "https://example.com/post";
+    fetcher.serve(
+        source,
+        200,
+        &format!(
+            "<title>A reply</title><a href=\"{}\">Alice said</a>",
+            target
+        ),
+    );
+    let webmention = verified(&app, &webmentions, &tweet.id, source, &target).await;
+    assert_eq!(webmention.status, VERIFIED);
+    assert_eq!(webmention.title.as_deref(), Some("A reply"));
+
+    // Sent again after the link was taken out.
+    fetcher.serve(source, 200, "<title>A reply</title>Nothing to see");
+    let webmention = verified(&app, &webmentions, &tweet.id, source, &target).await;
+    assert_eq!(webmention.status, REJECTED);
+    assert_eq!(
+        webmention.error.as_deref(),
+        Some("Source does not link to target")
+    );
+    assert!(
+        Webmention::get_verified_webmentions_for_tweet(&app.pool, &tweet.id)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    // Gone, and never there.
+    let gone = "https://example.com/deleted";
+    fetcher.serve(gone, 404, "Not found");
+    let webmention = verified(&app, &webmentions, &tweet.id, gone, &target).await;
+    assert_eq!(webmention.status, REJECTED);
+    assert_eq!(
+        webmention.error.as_deref(),
+        Some("Source responded with 404")
+    );
+
+    let missing = "https://nowhere.example/post";
+    let webmention = verified(&app, &webmentions, &tweet.id, missing, &target).await;
+    assert_eq!(webmention.status, REJECTED);
+}